@@ -0,0 +1,34 @@
+//! Benchmarks live in `benches/` and use criterion for warm up, statistics and comparing runs.
+//! Run with `cargo bench --features axum-recipe`, the report ends up in `target/criterion`.
+//! Running it again on another commit shows whether the change made it faster or slower.
+
+use std::hint::black_box;
+
+use asdf::recipes::axum_server::decode_chars;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_chars");
+    for len in [16, 1024, 65536] {
+        let codes = "bar"
+            .chars()
+            .cycle()
+            .take(len)
+            .map(u32::from)
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &codes, |b, codes| {
+            // Cloning is part of every iteration, `iter_batched` keeps it out of the measurement
+            b.iter_batched(
+                || codes.clone(),
+                // Without black_box the compiler may optimize away work whose result is unused
+                |codes| black_box(decode_chars(black_box(codes))),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // tonic-build is an optional build dependency so protoc is only needed with the grpc recipe
+    #[cfg(feature = "grpc-recipe")]
+    tonic_build::compile_protos("proto/greeter.proto")?;
+    Ok(())
+}
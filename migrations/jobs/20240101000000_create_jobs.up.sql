@@ -0,0 +1,12 @@
+CREATE TABLE jobs (
+    id BIGSERIAL PRIMARY KEY,
+    queue TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    max_attempts INT NOT NULL,
+    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ,
+    last_error TEXT,
+    failed_at TIMESTAMPTZ
+);
+CREATE INDEX jobs_ready ON jobs (queue, run_at) WHERE failed_at IS NULL;
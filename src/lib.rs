@@ -0,0 +1,4 @@
+//! Every recipe is part of this library so it can be tested and reused, `main.rs` only picks one to run
+
+pub mod recipes;
+pub mod scaffold;
@@ -0,0 +1,132 @@
+//! Picking a locale from the `Accept-Language` header
+//! Requires `cargo add axum`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev tower -F util`
+
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+    routing::get,
+    Router,
+};
+use tokio::net::TcpListener;
+
+/// Locales we have translations for. The first one is the default.
+pub const SUPPORTED: &[&str] = &["en", "fr", "de"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale(pub &'static str);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Locale {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        Ok(Locale(negotiate(header, SUPPORTED)))
+    }
+}
+
+/// Returns the supported locale the client prefers most, falling back to the first supported one.
+/// A language range like `en-US` also matches the supported locale `en`.
+pub fn negotiate(accept_language: &str, supported: &[&'static str]) -> &'static str {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty())?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        // q=0 means "not acceptable"
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // Stable sort keeps the order of the header for equal qualities
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    ranges
+        .iter()
+        .find_map(|(tag, _)| {
+            // `*` matches anything so the default is as good as any
+            if *tag == "*" {
+                return supported.first().copied();
+            }
+            supported.iter().copied().find(|locale| {
+                let (prefix, _) = tag.split_once('-').unwrap_or((tag, ""));
+                locale.eq_ignore_ascii_case(tag) || locale.eq_ignore_ascii_case(prefix)
+            })
+        })
+        .unwrap_or(supported[0])
+}
+
+async fn greet(Locale(locale): Locale) -> &'static str {
+    match locale {
+        "fr" => "Bonjour",
+        "de" => "Hallo",
+        _ => "Hello",
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let app = Router::new().route("/greeting", get(greet));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn picks_highest_quality() {
+        assert_eq!(negotiate("fr;q=0.9, en;q=0.8", SUPPORTED), "fr");
+        assert_eq!(negotiate("de;q=0.5, fr;q=0.9, en;q=0.1", SUPPORTED), "fr");
+        // Without q the quality is 1
+        assert_eq!(negotiate("en;q=0.8, de", SUPPORTED), "de");
+    }
+
+    #[test]
+    fn skips_unsupported_languages() {
+        assert_eq!(negotiate("es, fr;q=0.5", SUPPORTED), "fr");
+    }
+
+    #[test]
+    fn matches_language_prefix() {
+        assert_eq!(negotiate("de-AT", SUPPORTED), "de");
+    }
+
+    #[test]
+    fn falls_back_to_default() {
+        assert_eq!(negotiate("", SUPPORTED), SUPPORTED[0]);
+        assert_eq!(negotiate("es, it;q=0.8", SUPPORTED), SUPPORTED[0]);
+        assert_eq!(negotiate("fr;q=0", SUPPORTED), SUPPORTED[0]);
+        assert_eq!(negotiate("*", SUPPORTED), SUPPORTED[0]);
+        assert_eq!(negotiate("es, *;q=0.5", SUPPORTED), SUPPORTED[0]);
+        assert_eq!(negotiate("q=0.5;fr,,", SUPPORTED), SUPPORTED[0]);
+    }
+
+    #[tokio::test]
+    async fn greets_in_the_negotiated_language() {
+        let app = Router::new().route("/greeting", get(greet));
+        let req = axum::http::Request::get("/greeting")
+            .header(header::ACCEPT_LANGUAGE, "fr-CH, fr;q=0.9, en;q=0.8")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "Bonjour");
+    }
+}
@@ -0,0 +1,220 @@
+//! Registering users with argon2 hashed passwords and logging them in
+//! Requires `cargo add argon2 -F std`
+//! `cargo add axum`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add sqlx -F runtime-tokio -F postgres`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Builds on the `database` recipe for the pool and the `auth` recipe for the tokens handed out on login.
+//!
+//! Create the table first:
+//! `psql "$DATABASE_URL" -c "CREATE TABLE users (id BIGSERIAL PRIMARY KEY, username TEXT NOT NULL UNIQUE, password_hash TEXT NOT NULL)"`
+//! The hash string contains the algorithm, its parameters and the salt, so stronger parameters can
+//! be rolled out later without breaking the hashes that are already stored.
+
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use axum::{
+    extract::{FromRef, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::net::TcpListener;
+
+use crate::recipes::{
+    auth::{self, AuthError, AuthUser, Keys},
+    database::{connect, DatabaseConfig},
+};
+
+const MIN_PASSWORD_LENGTH: usize = 12;
+
+#[derive(Debug, Parser)]
+pub struct AccountsConfig {
+    #[command(flatten)]
+    pub database: DatabaseConfig,
+    #[command(flatten)]
+    pub auth: auth::Config,
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pool: PgPool,
+    keys: Keys,
+}
+
+/// Lets the `AuthUser` extractor of the auth recipe find the keys
+impl FromRef<AppState> for Keys {
+    fn from_ref(state: &AppState) -> Self {
+        state.keys.clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    UsernameTaken,
+    WeakPassword,
+    Internal,
+}
+
+impl IntoResponse for RegisterError {
+    fn into_response(self) -> Response {
+        match self {
+            RegisterError::UsernameTaken => {
+                (StatusCode::CONFLICT, "Username is already taken").into_response()
+            }
+            RegisterError::WeakPassword => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Passwords need at least {MIN_PASSWORD_LENGTH} characters"),
+            )
+                .into_response(),
+            RegisterError::Internal => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+/// Hashing takes tens of milliseconds on purpose, so it runs on the blocking thread pool
+/// instead of stalling the other requests on this worker thread
+pub async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || hash_password_blocking(&password))
+        .await
+        .expect("Hashing does not panic")
+}
+
+fn hash_password_blocking(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Default argon2 parameters are valid")
+        .to_string()
+}
+
+/// The comparison inside `verify_password` runs in constant time
+pub async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Verified against when the user does not exist, so unknown usernames take as long as wrong
+/// passwords and can't be told apart by timing
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password_blocking("not the password of anyone"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+}
+
+async fn register(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Result<(StatusCode, Json<User>), RegisterError> {
+    // Counting chars instead of bytes so non ascii passwords are not favored
+    if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(RegisterError::WeakPassword);
+    }
+    let hash = hash_password(credentials.password).await;
+    let user = sqlx::query_as::<_, User>(
+        "INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING id, username",
+    )
+    .bind(credentials.username)
+    .bind(hash)
+    .fetch_one(&state.pool)
+    .await
+    // Relying on the unique constraint instead of checking first can't race with another registration
+    .map_err(|e| match e {
+        sqlx::Error::Database(e) if e.is_unique_violation() => RegisterError::UsernameTaken,
+        e => {
+            eprintln!("Database error: {e}");
+            RegisterError::Internal
+        }
+    })?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+async fn login(
+    State(state): State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> Result<Json<auth::TokenResponse>, Response> {
+    let user: Option<(i64, String)> =
+        sqlx::query_as("SELECT id, password_hash FROM users WHERE username = $1")
+            .bind(credentials.username)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| {
+                eprintln!("Database error: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })?;
+    let (id, hash) = match user {
+        Some((id, hash)) => (Some(id), hash),
+        None => (None, dummy_hash().to_owned()),
+    };
+    let valid = verify_password(credentials.password, hash).await;
+    let Some(id) = id.filter(|_| valid) else {
+        // Same answer for unknown users and wrong passwords
+        return Err(AuthError::WrongCredentials.into_response());
+    };
+    Ok(Json(auth::TokenResponse {
+        access_token: state.keys.issue(&id.to_string()),
+        token_type: "Bearer",
+        expires_in: auth::TOKEN_LIFETIME.as_secs(),
+    }))
+}
+
+async fn me(user: AuthUser) -> String {
+    format!("Logged in as user {}", user.id)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = AccountsConfig::parse();
+    let state = AppState {
+        pool: connect(&config.database).await.unwrap(),
+        keys: Keys::new(config.auth.jwt_secret.as_bytes()),
+    };
+    let app = Router::new()
+        .route("/register", post(register))
+        .route("/login", post(login))
+        .route("/me", get(me))
+        .with_state(state);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn verifies_only_the_hashed_password() {
+        let hash = hash_password("correct horse battery staple".to_owned()).await;
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse battery staple".to_owned(), hash.clone()).await);
+        assert!(!verify_password("Correct horse battery staple".to_owned(), hash).await);
+        assert!(!verify_password("anything".to_owned(), "not a hash".to_owned()).await);
+    }
+}
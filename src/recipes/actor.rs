@@ -0,0 +1,174 @@
+//! An actor owning some state, the other tasks talk to it through a cloneable handle
+//! Requires `cargo add thiserror`
+//! `cargo add tokio -F macros -F rt-multi-thread -F sync`
+//!
+//! Instead of sharing the state behind an `Arc<Mutex<_>>` a single task owns it and handles one
+//! command after another. Nothing can hold a lock across an await or forget to release it, and
+//! the state can contain things that are not `Send` or `Sync` as long as the actor creates them.
+//! See the `concurrency` recipe for the channels this is built from.
+//!
+//! The actor stops when it is told to shut down, or when the last handle is dropped. Commands
+//! sent before a shutdown are still handled, calls made after it fail with `ActorError`.
+
+use std::collections::HashMap;
+
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("The actor has stopped")]
+pub struct ActorError;
+
+enum Command {
+    Get {
+        key: String,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    Set {
+        key: String,
+        value: String,
+        /// Gets the previous value
+        reply: oneshot::Sender<Option<String>>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<HashMap<String, String>>,
+    },
+}
+
+/// Private, only the actor's own task ever touches it
+struct Store {
+    values: HashMap<String, String>,
+    commands: mpsc::Receiver<Command>,
+}
+
+impl Store {
+    async fn run(mut self) -> HashMap<String, String> {
+        while let Some(command) = self.commands.recv().await {
+            let Command::Shutdown { reply } = command else {
+                self.handle(command);
+                continue;
+            };
+            // No new commands can be sent, the ones already queued are still handled
+            self.commands.close();
+            while let Some(command) = self.commands.recv().await {
+                self.handle(command);
+            }
+            let _ = reply.send(self.values.clone());
+            break;
+        }
+        self.values
+    }
+
+    /// Must not block or await for long, every other caller waits meanwhile
+    fn handle(&mut self, command: Command) {
+        // The caller may have been cancelled, that's not the actor's problem
+        match command {
+            Command::Get { key, reply } => drop(reply.send(self.values.get(&key).cloned())),
+            Command::Set { key, value, reply } => drop(reply.send(self.values.insert(key, value))),
+            // Dropping the reply tells a second caller the actor is already stopping
+            Command::Shutdown { .. } => {}
+        }
+    }
+}
+
+/// Cheap to clone, every clone talks to the same actor
+#[derive(Clone)]
+pub struct StoreHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl StoreHandle {
+    /// Spawns the actor. The join handle resolves to the final state once it stopped.
+    pub fn spawn() -> (Self, JoinHandle<HashMap<String, String>>) {
+        // Bounded, a slow actor makes callers wait instead of queueing commands without end
+        let (tx, rx) = mpsc::channel(64);
+        let store = Store {
+            values: HashMap::new(),
+            commands: rx,
+        };
+        (Self { commands: tx }, tokio::spawn(store.run()))
+    }
+
+    async fn call<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> Result<T, ActorError> {
+        let (reply, answer) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| ActorError)?;
+        // The actor dropped the reply without answering because it was stopping
+        answer.await.map_err(|_| ActorError)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, ActorError> {
+        let key = key.to_owned();
+        self.call(|reply| Command::Get { key, reply }).await
+    }
+
+    /// Returns the previous value
+    pub async fn set(
+        &self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<Option<String>, ActorError> {
+        let (key, value) = (key.into(), value.into());
+        self.call(|reply| Command::Set { key, value, reply }).await
+    }
+
+    /// Stops the actor for all handles and returns the final state
+    pub async fn shutdown(&self) -> Result<HashMap<String, String>, ActorError> {
+        self.call(|reply| Command::Shutdown { reply }).await
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let (store, actor) = StoreHandle::spawn();
+    let writers = (0..4).map(|i| {
+        let store = store.clone();
+        tokio::spawn(async move { store.set(format!("worker-{i}"), "done").await })
+    });
+    for writer in writers {
+        writer.await.unwrap().unwrap();
+    }
+    println!("worker-2 is {:?}", store.get("worker-2").await.unwrap());
+    println!("Final state: {:?}", store.shutdown().await.unwrap());
+    println!("After shutdown: {:?}", store.get("worker-2").await);
+    actor.await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn gets_and_sets() {
+        let (store, _) = StoreHandle::spawn();
+        assert_eq!(store.get("a").await, Ok(None));
+        assert_eq!(store.set("a", "1").await, Ok(None));
+        assert_eq!(store.clone().set("a", "2").await, Ok(Some("1".to_owned())));
+        assert_eq!(store.get("a").await, Ok(Some("2".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn shutdown_handles_queued_commands_and_rejects_new_ones() {
+        let (store, actor) = StoreHandle::spawn();
+        store.set("a", "1").await.unwrap();
+        let state = store.shutdown().await.unwrap();
+        assert_eq!(state.get("a").map(String::as_str), Some("1"));
+        assert_eq!(store.get("a").await, Err(ActorError));
+        assert_eq!(actor.await.unwrap(), state);
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_last_handle_is_dropped() {
+        let (store, actor) = StoreHandle::spawn();
+        store.set("a", "1").await.unwrap();
+        drop(store);
+        assert_eq!(actor.await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+//! Triggering graceful shutdown from an authenticated admin endpoint
+//! Requires `cargo add axum subtle tokio-util tracing`
+//! `cargo add clap -F derive -F env`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net`
+//! Tests require `cargo add --dev tower -F util`
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    routing::{get, post},
+    Router,
+};
+use clap::Parser;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    #[clap(long, env, default_value = "0.0.0.0:8080")]
+    pub bind_addr: SocketAddr,
+    /// Keep this on an address that is not reachable from the outside
+    #[clap(long, env, default_value = "127.0.0.1:9090")]
+    pub admin_bind_addr: SocketAddr,
+    /// Bearer token required for admin endpoints
+    #[clap(long, env, hide_env_values = true, value_parser = parse_admin_token)]
+    pub admin_token: String,
+}
+
+/// An empty token would let `Authorization: Bearer ` through
+fn parse_admin_token(token: &str) -> Result<String, String> {
+    if token.trim().is_empty() {
+        return Err("The admin token must not be empty".to_owned());
+    }
+    Ok(token.to_owned())
+}
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<str>,
+    shutdown: CancellationToken,
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::parse();
+    let shutdown = CancellationToken::new();
+
+    // Signals and the admin endpoint cancel the same token
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    let public_listener = TcpListener::bind(config.bind_addr).await.unwrap();
+    let admin_listener = TcpListener::bind(config.admin_bind_addr).await.unwrap();
+    serve(
+        public_listener,
+        admin_listener,
+        &config.admin_token,
+        shutdown,
+    )
+    .await
+    .unwrap();
+}
+
+/// Serves until `shutdown` is cancelled and every open request is answered
+pub async fn serve(
+    public_listener: TcpListener,
+    admin_listener: TcpListener,
+    admin_token: &str,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let public = Router::new().route("/", get(|| async { "Hello, world!" }));
+    // The admin router is served on its own listener so it can never be reached through the public one
+    let admin = admin_app(admin_token, shutdown.clone());
+    let (public, admin) = tokio::join!(
+        axum::serve(public_listener, public)
+            .with_graceful_shutdown(shutdown.clone().cancelled_owned()),
+        axum::serve(admin_listener, admin).with_graceful_shutdown(shutdown.cancelled_owned()),
+    );
+    public.and(admin)
+}
+
+pub fn admin_app(token: &str, shutdown: CancellationToken) -> Router {
+    Router::new()
+        .route("/admin/shutdown", post(trigger_shutdown))
+        .with_state(AdminState {
+            token: token.into(),
+            shutdown,
+        })
+}
+
+/// Kubernetes and systemd stop processes with SIGTERM, ctrl-c sends SIGINT
+async fn shutdown_signal() {
+    let ctrl_c = async { tokio::signal::ctrl_c().await.unwrap() };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Returns 202 right away, the draining happens after the response was sent.
+/// Calling it again while already shutting down is fine and also returns 202.
+async fn trigger_shutdown(State(state): State<AdminState>, headers: HeaderMap) -> StatusCode {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Comparing in constant time does not leak how much of the token was right
+        .is_some_and(|token| token.as_bytes().ct_eq(state.token.as_bytes()).into());
+    if !authorized {
+        return StatusCode::UNAUTHORIZED;
+    }
+    if !state.shutdown.is_cancelled() {
+        info!("Shutdown requested via admin endpoint");
+        state.shutdown.cancel();
+    }
+    StatusCode::ACCEPTED
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, extract::Request};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn post_shutdown(app: Router, authorization: Option<&str>) -> StatusCode {
+        let mut req = Request::post("/admin/shutdown");
+        if let Some(authorization) = authorization {
+            req = req.header(header::AUTHORIZATION, authorization);
+        }
+        let req = req.body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn requires_the_admin_token() {
+        let shutdown = CancellationToken::new();
+        let app = admin_app("secret", shutdown.clone());
+        assert_eq!(
+            post_shutdown(app.clone(), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_shutdown(app.clone(), Some("Bearer wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_shutdown(app, Some("Bearer ")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(!shutdown.is_cancelled());
+    }
+
+    #[test]
+    fn refuses_to_start_without_a_token() {
+        let config = |token: &str| {
+            Config::try_parse_from(["admin-shutdown", "--admin-token", token])
+                .map(|config| config.admin_token)
+        };
+        assert!(config("").is_err());
+        assert!(config("  ").is_err());
+        assert_eq!(config("secret").unwrap(), "secret");
+    }
+
+    #[tokio::test]
+    async fn calling_twice_is_fine() {
+        let shutdown = CancellationToken::new();
+        let app = admin_app("secret", shutdown.clone());
+        for _ in 0..2 {
+            let status = post_shutdown(app.clone(), Some("Bearer secret")).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert!(shutdown.is_cancelled());
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_both_listeners() {
+        let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (public_addr, admin_addr) = (public.local_addr().unwrap(), admin.local_addr().unwrap());
+        let server = tokio::spawn(serve(public, admin, "secret", CancellationToken::new()));
+
+        let mut stream = TcpStream::connect(admin_addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /admin/shutdown HTTP/1.1\r\nHost: localhost\r\n\
+                  Authorization: Bearer secret\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 202 Accepted"), "{res}");
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not shut down")
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(public_addr).await.is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! A typed client for a http api, wrapping reqwest instead of building requests all over the code
+//! Requires `cargo add reqwest -F json`
+//! `cargo add serde -F derive`
+//! `cargo add thiserror`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//! Tests require `cargo add --dev wiremock serde_json`
+//! Builds on the `reqwest` recipe for timeouts and retries.
+//!
+//! Callers see methods with typed arguments and results, while urls, auth and status handling
+//! live in one place. When the api changes only this module has to follow.
+
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::recipes::http_client::{HttpClient, RetryBudget, RetryPolicy};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    /// The api could not be reached or answered with something that is not the expected json
+    #[error("Request to httpbin failed: {0}")]
+    Request(#[from] reqwest::Error),
+    /// The body usually explains what was wrong with the request
+    #[error("httpbin answered {status}: {body}")]
+    Status { status: StatusCode, body: String },
+}
+
+/// Cheap to clone, clones share the connection pool
+#[derive(Debug, Clone)]
+pub struct HttpbinClient {
+    http: HttpClient,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl HttpbinClient {
+    pub fn new(http: HttpClient, base_url: &str) -> Self {
+        Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token: None,
+        }
+    }
+
+    /// Sent as bearer token with every request
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, &format!("{}{path}", self.base_url));
+        match &self.token {
+            // Marks the header as sensitive so it is not printed in debug output
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, ApiError> {
+        let res = self.http.send(request).await?;
+        Ok(check_status(res).await?.json().await?)
+    }
+
+    /// httpbin echoes the json body back, a stand in for creating a resource
+    pub async fn anything<T: Serialize + DeserializeOwned>(&self, data: &T) -> Result<T, ApiError> {
+        #[derive(Deserialize)]
+        struct Echo<T> {
+            json: T,
+        }
+
+        let echo: Echo<T> = self
+            .send(self.request(Method::POST, "/anything").json(data))
+            .await?;
+        Ok(echo.json)
+    }
+
+    /// The address httpbin sees requests coming from
+    pub async fn ip(&self) -> Result<String, ApiError> {
+        #[derive(Deserialize)]
+        struct Ip {
+            origin: String,
+        }
+
+        let ip: Ip = self.send(self.request(Method::GET, "/ip")).await?;
+        Ok(ip.origin)
+    }
+
+    /// Fails with a 401 status error without a token
+    pub async fn check_token(&self) -> Result<(), ApiError> {
+        let res = self.http.send(self.request(Method::GET, "/bearer")).await?;
+        check_status(res).await.map(drop)
+    }
+}
+
+/// A status code that is not 2xx is not an error for reqwest unless we make it one
+async fn check_status(res: Response) -> Result<Response, ApiError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().await.unwrap_or_default();
+    Err(ApiError::Status { status, body })
+}
+
+/// This uses serde for serializing and deserializing this struct more info on serde.rs
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Thing {
+    pub foo: String,
+    pub bar: Vec<u32>,
+}
+
+#[tokio::main]
+pub async fn main() {
+    // This client should not be created for every request, clone it instead as it holds the
+    // connection pool
+    let http = HttpClient::new(Client::new(), RetryPolicy::default(), RetryBudget::new(10));
+    let client = HttpbinClient::new(http, "https://httpbin.org").with_token("secret");
+    let thing = Thing {
+        foo: "Foo".into(),
+        bar: vec![2, 3, 4],
+    };
+    println!("{:?}", client.anything(&thing).await.unwrap());
+    println!("Calling from {}", client.ip().await.unwrap());
+    client.check_token().await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn client(server: &MockServer) -> HttpbinClient {
+        let http = HttpClient::new(Client::new(), RetryPolicy::default(), RetryBudget::new(10));
+        HttpbinClient::new(http, &server.uri())
+    }
+
+    #[tokio::test]
+    async fn decodes_response_json() {
+        // Every test gets its own server on a random port so tests can run in parallel
+        let server = MockServer::start().await;
+        let data = json!({"foo": "Foo", "bar": [2, 3, 4]});
+        Mock::given(method("POST"))
+            .and(path("/anything"))
+            .and(body_json(&data))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "json": data })))
+            // Fails the test when the server is dropped if the client did not call it exactly once
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let thing = Thing {
+            foo: "Foo".into(),
+            bar: vec![2, 3, 4],
+        };
+        assert_eq!(client(&server).anything(&thing).await.unwrap(), thing);
+    }
+
+    #[tokio::test]
+    async fn sends_the_token() {
+        let server = MockServer::start().await;
+        Mock::given(path("/bearer"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        client(&server)
+            .with_token("secret")
+            .check_token()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_error_statuses_with_their_body() {
+        let server = MockServer::start().await;
+        Mock::given(path("/bearer"))
+            .respond_with(ResponseTemplate::new(401).set_body_string("Missing token"))
+            .mount(&server)
+            .await;
+
+        let err = client(&server).check_token().await.unwrap_err();
+        assert!(
+            matches!(&err, ApiError::Status { status, body } if *status == 401 && body == "Missing token"),
+            "{err:?}"
+        );
+    }
+}
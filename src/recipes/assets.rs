@@ -0,0 +1,82 @@
+//! Serving a frontend from disk during development and embedded into the binary for release
+//! Requires `cargo add axum`
+//! `cargo add tower-http -F fs`
+//! `cargo add rust-embed -F mime-guess --optional`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! Without the `embed-assets` feature the files in `dist/` are read by ServeDir on every request,
+//! so a rebuilt frontend shows up without restarting the server. With it they are compiled into the
+//! binary, which then runs without the directory, e.g. in a scratch container image:
+//! `cargo build --release --features assets-recipe,embed-assets`.
+//! `cargo run -- init` keeps the feature as `embed-assets = ["dep:rust-embed"]`, declare it the
+//! same way in a project of your own.
+//! See the `precompressed_assets` recipe for serving brotli and gzip variants.
+
+use axum::Router;
+use tokio::net::TcpListener;
+
+/// Relative to the working directory, unlike the folder of the embedded files
+#[cfg(not(feature = "embed-assets"))]
+pub fn assets() -> Router {
+    Router::new().fallback_service(tower_http::services::ServeDir::new("dist"))
+}
+
+#[cfg(feature = "embed-assets")]
+pub fn assets() -> Router {
+    Router::new().fallback(embedded::serve)
+}
+
+#[cfg(feature = "embed-assets")]
+mod embedded {
+    use axum::{
+        http::{header, HeaderMap, StatusCode, Uri},
+        response::{IntoResponse, Response},
+    };
+
+    /// Relative to `Cargo.toml`
+    #[derive(rust_embed::Embed)]
+    #[folder = "dist/"]
+    // Lets the crate build before the frontend was built for the first time
+    #[allow_missing = true]
+    struct Files;
+
+    pub async fn serve(uri: Uri, headers: HeaderMap) -> Response {
+        let mut path = uri.path().trim_start_matches('/').to_owned();
+        // Like ServeDir, directories are answered with their index.html
+        if path.is_empty() || path.ends_with('/') {
+            path.push_str("index.html");
+        }
+        let Some(file) = Files::get(&path) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        // The hash is computed at compile time, so it only changes when the file does
+        let etag = format!("\"{}\"", hex(&file.metadata.sha256_hash()));
+        if headers
+            .get(header::IF_NONE_MATCH)
+            .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+        {
+            return StatusCode::NOT_MODIFIED.into_response();
+        }
+        (
+            [
+                (header::CONTENT_TYPE, file.metadata.mimetype().to_owned()),
+                (header::ETAG, etag),
+            ],
+            file.data,
+        )
+            .into_response()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let app = Router::new()
+        // .nest("/api", api_routes())
+        .merge(assets());
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
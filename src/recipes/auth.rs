@@ -0,0 +1,285 @@
+//! Issuing JWTs on login and requiring them on protected routes
+//! Requires `cargo add axum subtle jsonwebtoken -F jsonwebtoken/rust_crypto`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! `curl -X POST localhost:8080/login -H 'content-type: application/json' -d '{"username": "admin", "password": "admin"}'`
+//! returns a token which is then sent as `Authorization: Bearer <token>` to `/me`.
+//! See the `jwt_leeway` recipe for tolerating clock skew between services.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, State},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    /// Secret used to sign and verify tokens, changing it logs everyone out
+    #[clap(long, env)]
+    pub jwt_secret: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// The user id
+    pub sub: String,
+    /// Seconds since the unix epoch
+    pub iat: u64,
+    pub exp: u64,
+}
+
+#[derive(Clone)]
+pub struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Arc<Validation>,
+}
+
+impl Keys {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            validation: Arc::new(validation),
+        }
+    }
+
+    pub fn issue(&self, user_id: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time is after the unix epoch")
+            .as_secs();
+        let claims = Claims {
+            sub: user_id.to_owned(),
+            iat: now,
+            exp: now + TOKEN_LIFETIME.as_secs(),
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .expect("HS256 signing does not fail")
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        jsonwebtoken::decode(token, &self.decoding, &self.validation).map(|data| data.claims)
+    }
+}
+
+/// Add this as an argument to a handler to make its route require a valid token
+#[derive(Debug)]
+pub struct AuthUser {
+    pub id: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    WrongCredentials,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let message = match self {
+            AuthError::MissingToken => "Missing bearer token",
+            // Don't tell an attacker whether the token expired or the signature was wrong
+            AuthError::InvalidToken => "Invalid token",
+            AuthError::WrongCredentials => "Wrong username or password",
+        };
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            message,
+        )
+            .into_response()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    Keys: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingToken)?;
+        let claims = Keys::from_ref(state)
+            .verify(token)
+            .map_err(|_| AuthError::InvalidToken)?;
+        Ok(AuthUser { id: claims.sub })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+}
+
+/// Replace the hardcoded user with a lookup of a password hash in your database, see the `accounts` recipe
+async fn login(
+    State(keys): State<Keys>,
+    Json(login): Json<Login>,
+) -> Result<Json<TokenResponse>, AuthError> {
+    let username_ok: bool = login.username.as_bytes().ct_eq(b"admin").into();
+    let password_ok: bool = login.password.as_bytes().ct_eq(b"admin").into();
+    if !(username_ok && password_ok) {
+        return Err(AuthError::WrongCredentials);
+    }
+    Ok(Json(TokenResponse {
+        access_token: keys.issue(&login.username),
+        token_type: "Bearer",
+        expires_in: TOKEN_LIFETIME.as_secs(),
+    }))
+}
+
+async fn me(user: AuthUser) -> String {
+    format!("Hello {}", user.id)
+}
+
+pub fn app(keys: Keys) -> Router {
+    Router::new()
+        .route("/login", post(login))
+        .route("/me", get(me))
+        .with_state(keys)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::parse();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(Keys::new(config.jwt_secret.as_bytes())))
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn keys() -> Keys {
+        Keys::new(b"test secret")
+    }
+
+    async fn send(req: Request<Body>) -> (StatusCode, String) {
+        let res = app(keys()).oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn login_request(username: &str, password: &str) -> Request<Body> {
+        Request::post("/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+                r#"{{"username": "{username}", "password": "{password}"}}"#
+            )))
+            .unwrap()
+    }
+
+    fn me_request(token: &str) -> Request<Body> {
+        Request::get("/me")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_from_the_login_open_protected_routes() {
+        let (status, body) = send(login_request("admin", "admin")).await;
+        assert_eq!(status, StatusCode::OK);
+        let token: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(token["expires_in"], TOKEN_LIFETIME.as_secs());
+
+        let token = token["access_token"].as_str().unwrap();
+        assert_eq!(
+            send(me_request(token)).await,
+            (StatusCode::OK, "Hello admin".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_get_no_token() {
+        let (status, body) = send(login_request("admin", "hunter2")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "Wrong username or password");
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_bearer_token_are_rejected() {
+        let req = Request::get("/me")
+            .header(header::AUTHORIZATION, "Basic YWRtaW46YWRtaW4=")
+            .body(Body::empty())
+            .unwrap();
+        let res = app(keys()).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "Missing bearer token");
+    }
+
+    #[tokio::test]
+    async fn forged_and_expired_tokens_are_rejected() {
+        let forged = Keys::new(b"another secret").issue("admin");
+        let expired = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &Claims {
+                sub: "admin".to_owned(),
+                iat: 0,
+                exp: 60,
+            },
+            &keys().encoding,
+        )
+        .unwrap();
+        for token in [forged.as_str(), expired.as_str(), "not a jwt"] {
+            assert_eq!(
+                send(me_request(token)).await,
+                (StatusCode::UNAUTHORIZED, "Invalid token".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let claims = keys().verify(&keys().issue("42")).unwrap();
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.exp - claims.iat, TOKEN_LIFETIME.as_secs());
+    }
+}
@@ -0,0 +1,72 @@
+//! Http server using axum
+//! This example requires:
+//! `cargo add axum`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net`
+//! `cargo add serde -F derive`
+
+use std::char::CharTryFromError;
+
+use axum::{extract::Path, http::StatusCode, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+// This macro allows out main function to be async
+#[tokio::main]
+pub async fn main() {
+    // Fast shutdown on crl_c
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app())
+        .with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+        .await
+        .unwrap();
+}
+
+/// Building the router in its own function lets tests call it without binding a socket, see `tests/axum_server.rs`
+pub fn app() -> Router {
+    Router::new()
+        .route("/", get(return_json).post(decode_json))
+        .route("/hello/:name", get(greet))
+}
+
+async fn greet(Path(path): Path<String>) -> String {
+    format!("Hello {path}")
+}
+
+/// This uses serde for serializing and deserializing this struct more info on serde.rs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MyJson {
+    pub foo: String,
+    pub bar: Vec<u32>,
+}
+
+async fn return_json() -> Json<MyJson> {
+    Json(MyJson {
+        foo: "foo".to_string(),
+        bar: vec![98, 97, 114],
+    })
+}
+
+/// A handler can take any number of arguments that implement [`FromRequestParts`](https://docs.rs/axum/latest/axum/extract/trait.FromRequestParts.html)
+/// followed by zero or one argument that implements [`FromRequest`](https://docs.rs/axum/latest/axum/extract/trait.FromRequest.html)
+/// The return type has to implement [`IntoResponse`](https://docs.rs/axum/latest/axum/response/trait.IntoResponse.html)
+/// Mapping every error to a bare status code is fine for a demo, see the `error` recipe for a real error type
+/// Any body that deserializes is accepted, see the `validation` recipe for checking its values as well
+async fn decode_json(Json(my_json): Json<MyJson>) -> axum::response::Result<String> {
+    // We map whatever error we got to a StatusCode which implements IntoResponse
+    let decoded = decode_chars(my_json.bar).map_err(|_| StatusCode::BAD_REQUEST)?; // We then use ? here to return the error if we got one
+
+    // We can do this although we don't return a Result<String, StatusCode> because the ? operator implicitly performs a conversion using the From trait
+    // And because out Error Type implements IntoResponse it can be converted
+    Ok(format!("{} {}", my_json.foo, decoded))
+}
+
+/// Plain functions like this one are easier to test and benchmark than the handler, see `benches/decode.rs`
+pub fn decode_chars(codes: Vec<u32>) -> Result<String, CharTryFromError> {
+    codes
+        .into_iter()
+        // We map each u32 to a char which may fail
+        .map(char::try_from) // That is why we now have an iterator over results
+        // We can use collect to say it should collect to the first error if there is any otherwise we should collect the chars into a string
+        // ::<> Syntax tells Rust what type to collect to as it can't be inferred in this case
+        .collect::<Result<String, _>>() // The _ in this case tells the compiler to try to infer the Error type of the Result
+}
@@ -0,0 +1,196 @@
+//! Load testing the axum server with concurrent reqwest clients
+//! Requires `cargo add axum reqwest`
+//! `cargo add clap -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F time`
+//! Builds on the `axum` recipe and benchmarks its router, swap in the router of your own app.
+//! Always run it in release mode otherwise you are mostly benchmarking the debug build:
+//! `cargo run --release -- --concurrency 64 --duration 10`
+//! Add `--fresh-connections` to open a new tcp connection for every request
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use reqwest::Client;
+use tokio::net::TcpListener;
+
+use crate::recipes::axum_server::app;
+
+/// Drive the server with concurrent clients and report throughput and latency percentiles
+#[derive(Debug, Parser)]
+pub struct BenchArgs {
+    /// Number of concurrent clients
+    #[clap(long, default_value_t = 32)]
+    pub concurrency: usize,
+    /// How long to run the benchmark in seconds
+    #[clap(long, default_value_t = 10)]
+    pub duration: u64,
+    /// Path to request on the server
+    #[clap(long, default_value = "/")]
+    pub path: String,
+    /// Open a new connection for every request instead of reusing pooled connections
+    #[clap(long)]
+    pub fresh_connections: bool,
+}
+
+#[tokio::main]
+pub async fn main() {
+    let args = BenchArgs::parse();
+    // Binding to port 0 lets the OS pick a free port so we never clash with a running instance
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app()).await.unwrap() });
+
+    let report = run(addr, &args).await;
+    println!("{report}");
+}
+
+pub async fn run(addr: SocketAddr, args: &BenchArgs) -> Report {
+    let url = format!("http://{addr}{}", args.path);
+    let duration = Duration::from_secs(args.duration);
+    let mut clients = Vec::with_capacity(args.concurrency);
+    for _ in 0..args.concurrency {
+        let client = client(args.fresh_connections);
+        // Warm up the pool before measuring so the first handshake of each worker is not counted
+        // as request latency. With fresh connections every request pays for the handshake anyways.
+        if !args.fresh_connections {
+            if let Ok(res) = client.get(&url).send().await {
+                let _ = res.bytes().await;
+            }
+        }
+        clients.push(client);
+    }
+
+    // Started before the workers, they begin sending as soon as they are spawned
+    let start = Instant::now();
+    let workers = clients
+        .into_iter()
+        .map(|client| tokio::spawn(worker(client, url.clone(), duration)))
+        .collect::<Vec<_>>();
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for worker in workers {
+        let (mut worker_latencies, worker_errors) = worker.await.unwrap();
+        latencies.append(&mut worker_latencies);
+        errors += worker_errors;
+    }
+    Report::new(latencies, errors, start.elapsed().max(duration))
+}
+
+fn client(fresh_connections: bool) -> Client {
+    let builder = Client::builder();
+    if fresh_connections {
+        // Without idle connections in the pool every request has to open a new one
+        builder.pool_max_idle_per_host(0).build().unwrap()
+    } else {
+        builder.build().unwrap()
+    }
+}
+
+async fn worker(client: Client, url: String, duration: Duration) -> (Vec<Duration>, usize) {
+    let deadline = Instant::now() + duration;
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        // The body has to be read to the end. Otherwise the connection is not returned to the
+        // pool and every request silently ends up on a fresh connection.
+        let result = match client.get(&url).send().await {
+            Ok(res) if res.status().is_success() => res.bytes().await.map(|_| ()),
+            Ok(res) => res.error_for_status().map(|_| ()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => latencies.push(start.elapsed()),
+            Err(_) => errors += 1,
+        }
+    }
+    (latencies, errors)
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub requests: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Report {
+    fn new(mut latencies: Vec<Duration>, errors: usize, elapsed: Duration) -> Self {
+        latencies.sort_unstable();
+        Self {
+            requests: latencies.len(),
+            errors,
+            elapsed,
+            p50: percentile(&latencies, 50.0),
+            p95: percentile(&latencies, 95.0),
+            p99: percentile(&latencies, 99.0),
+            max: latencies.last().copied().unwrap_or_default(),
+        }
+    }
+
+    pub fn throughput(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// How to read this: throughput is only meaningful together with the error count and the
+/// percentiles. A high p99 compared to p50 usually means requests are queuing somewhere,
+/// which gets worse with more concurrency than the server has cores.
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "requests:   {} ({} errors)", self.requests, self.errors)?;
+        writeln!(f, "throughput: {:.1} req/s", self.throughput())?;
+        writeln!(f, "p50:        {:?}", self.p50)?;
+        writeln!(f, "p95:        {:?}", self.p95)?;
+        writeln!(f, "p99:        {:?}", self.p99)?;
+        write!(f, "max:        {:?}", self.max)
+    }
+}
+
+/// Nearest rank percentile of an already sorted slice
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn measures_the_axum_router() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app()).await.unwrap() });
+
+        let args = BenchArgs {
+            concurrency: 2,
+            duration: 1,
+            path: "/hello/bench".to_owned(),
+            fresh_connections: false,
+        };
+        let report = run(addr, &args).await;
+        assert!(report.requests > 0);
+        assert_eq!(report.errors, 0);
+        assert!(report.elapsed >= Duration::from_secs(1));
+        assert!(report.p50 <= report.p99 && report.p99 <= report.max);
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted = (1..=10).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 95.0), Duration::from_millis(10));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
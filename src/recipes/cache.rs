@@ -0,0 +1,285 @@
+//! Read through caching in Redis with invalidation on writes
+//! Requires `cargo add axum serde_json tracing tracing-subscriber`
+//! `cargo add redis -F tokio-comp -F connection-manager`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F sync`
+//!
+//! Reads try Redis first and fill it from the database on a miss. Writes go to the database and
+//! then delete the cached value so the next read loads the new one.
+//! Redis being down only makes requests slower, it never fails them. That includes startup, the
+//! connection is made on the first request and retried on later ones.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    AsyncCommands, RedisResult,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// Bounds how stale a value can get if an invalidation is lost, e.g. because Redis was unreachable
+const TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: u32,
+    pub name: String,
+}
+
+/// Stands in for your real database
+type Db = Arc<Mutex<HashMap<u32, User>>>;
+
+#[derive(Clone)]
+pub struct AppState {
+    /// Reconnects by itself and is cheap to clone, one per process is enough
+    redis: ConnectionManager,
+    db: Db,
+}
+
+/// Only fails on an invalid url, nothing is connected until the first command
+pub fn connect_redis(url: &str) -> RedisResult<ConnectionManager> {
+    let config = ConnectionManagerConfig::new()
+        // While Redis is down every request tries to reconnect, that must not take long
+        .set_connection_timeout(Some(Duration::from_millis(250)))
+        .set_response_timeout(Some(Duration::from_millis(250)))
+        .set_number_of_retries(1);
+    ConnectionManager::new_lazy_with_config(redis::Client::open(url)?, config)
+}
+
+/// Keys are namespaced and versioned so changing the cached type doesn't read old entries
+fn user_key(id: u32) -> String {
+    format!("user:v1:{id}")
+}
+
+pub async fn cache_get<T: DeserializeOwned>(redis: &ConnectionManager, key: &str) -> Option<T> {
+    let mut conn = redis.clone();
+    let cached: Option<String> = conn
+        .get(key)
+        .await
+        .inspect_err(|e| warn!("Cache read of {key} failed: {e}"))
+        .ok()?;
+    // A value that doesn't parse anymore is treated like a miss and overwritten
+    serde_json::from_str(&cached?).ok()
+}
+
+pub async fn cache_set<T: Serialize>(redis: &ConnectionManager, key: &str, value: &T) {
+    let mut conn = redis.clone();
+    let json = serde_json::to_string(value).expect("Value serializes to json");
+    if let Err(e) = conn.set_ex::<_, _, ()>(key, json, TTL.as_secs()).await {
+        warn!("Cache write of {key} failed: {e}");
+    }
+}
+
+pub async fn cache_invalidate(redis: &ConnectionManager, key: &str) {
+    let mut conn = redis.clone();
+    if let Err(e) = conn.del::<_, ()>(key).await {
+        warn!("Cache invalidation of {key} failed: {e}");
+    }
+}
+
+async fn get_user(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+) -> Result<Json<User>, StatusCode> {
+    let key = user_key(id);
+    if let Some(user) = cache_get(&state.redis, &key).await {
+        return Ok(Json(user));
+    }
+    let user = state
+        .db
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    cache_set(&state.redis, &key, &user).await;
+    Ok(Json(user))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUser {
+    pub name: String,
+}
+
+async fn update_user(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    Json(update): Json<UpdateUser>,
+) -> Json<User> {
+    let user = User {
+        id,
+        name: update.name,
+    };
+    state.db.lock().unwrap().insert(id, user.clone());
+    // Deleting instead of writing the new value means two concurrent writes can't leave the older
+    // value cached. A read that loaded the old value right before this write can still put it back,
+    // the TTL bounds how long that lasts.
+    cache_invalidate(&state.redis, &user_key(id)).await;
+    Json(user)
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let redis = connect_redis("redis://127.0.0.1/").unwrap();
+    let state = AppState {
+        redis,
+        db: Db::default(),
+    };
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(state)).await.unwrap();
+}
+
+pub fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/users/:id", get(get_user).put(update_user))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn keys_are_namespaced_and_versioned() {
+        assert_eq!(user_key(42), "user:v1:42");
+    }
+
+    fn state() -> AppState {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL to point at Redis");
+        let redis = connect_redis(&url).unwrap();
+        AppState {
+            redis,
+            db: Db::default(),
+        }
+    }
+
+    /// Unique per test run so runs sharing a Redis don't see each other's values
+    fn user_id(n: u32) -> u32 {
+        std::process::id() * 10 + n
+    }
+
+    async fn cached(state: &AppState, id: u32) -> Option<String> {
+        state.redis.clone().get(user_key(id)).await.unwrap()
+    }
+
+    async fn send(state: &AppState, req: Request<Body>) -> (StatusCode, String) {
+        let res = app(state.clone()).oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn get_user_request(id: u32) -> Request<Body> {
+        Request::get(format!("/users/{id}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn reads_fill_the_cache_and_writes_invalidate_it() {
+        let state = state();
+        let id = user_id(1);
+        let ada = User {
+            id,
+            name: "Ada".to_owned(),
+        };
+        state.db.lock().unwrap().insert(id, ada);
+        let (status, body) = send(&state, get_user_request(id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cached(&state, id).await.as_deref(), Some(body.as_str()));
+
+        // Served from the cache even though the database changed behind its back
+        state.db.lock().unwrap().get_mut(&id).unwrap().name = "Grace".to_owned();
+        let (_, cached_body) = send(&state, get_user_request(id)).await;
+        assert_eq!(cached_body, body);
+
+        let update = Request::put(format!("/users/{id}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name": "Linus"}"#))
+            .unwrap();
+        send(&state, update).await;
+        assert_eq!(cached(&state, id).await, None);
+        let (_, body) = send(&state, get_user_request(id)).await;
+        assert!(body.contains("Linus"), "{body}");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn unreadable_cache_entries_are_replaced() {
+        let state = state();
+        let id = user_id(2);
+        let ada = User {
+            id,
+            name: "Ada".to_owned(),
+        };
+        state.db.lock().unwrap().insert(id, ada);
+        // Written by an older version of the type
+        let _: () = state
+            .redis
+            .clone()
+            .set(user_key(id), r#"{"id": "not a number"}"#)
+            .await
+            .unwrap();
+
+        let (status, body) = send(&state, get_user_request(id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cached(&state, id).await.as_deref(), Some(body.as_str()));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn unknown_users_are_not_cached() {
+        let state = state();
+        let id = user_id(3);
+        assert_eq!(
+            send(&state, get_user_request(id)).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(cached(&state, id).await, None);
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_while_redis_is_down() {
+        // Nothing listens there
+        let state = AppState {
+            redis: connect_redis("redis://127.0.0.1:1/").unwrap(),
+            db: Db::default(),
+        };
+        let ada = User {
+            id: 1,
+            name: "Ada".to_owned(),
+        };
+        state.db.lock().unwrap().insert(1, ada);
+        let (status, body) = send(&state, get_user_request(1)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("Ada"), "{body}");
+
+        let update = Request::put("/users/1")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name": "Grace"}"#))
+            .unwrap();
+        assert_eq!(send(&state, update).await.0, StatusCode::OK);
+        let (_, body) = send(&state, get_user_request(1)).await;
+        assert!(body.contains("Grace"), "{body}");
+    }
+}
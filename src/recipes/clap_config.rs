@@ -0,0 +1,119 @@
+//! Using clap as a config tool and/or cli interface with subcommands
+//! Requires `cargo add clap -F derive -F env`
+//! `cargo add clap_complete`
+//! `cargo add tokio -F rt-multi-thread -F net`
+//! Once there are more than a handful of settings have a look at the layered `config` recipe
+//! See the `command_dispatch` recipe for commands sharing setup like a database connection
+//! and the `migrate` recipe for a database cli.
+//!
+//! Try `cargo run -- serve --help`, `cargo run -- check-config 0.0.0.0:0` or
+//! `cargo run -- completions zsh > ~/.zfunc/_asdf`.
+
+use std::{io, net::SocketAddr};
+
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use tokio::net::TcpListener;
+
+/// Put a short text here describing the program. It will be shown when running `cargo run -- --help`
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the server
+    Serve(Config),
+    /// Check the config without starting anything, e.g. in CI or before a deploy
+    CheckConfig(Config),
+    /// Print the version, same as `--version`
+    Version,
+    /// Print shell completions for bash, zsh, fish, elvish or powershell
+    Completions { shell: Shell },
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Config {
+    /// Address the server should bind to
+    #[clap(env, default_value = "0.0.0.0:8080")]
+    pub bind_addr: SocketAddr,
+}
+
+impl Config {
+    /// Checks that go beyond what clap can parse, `check-config` and `serve` both run them
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bind_addr.port() == 0 {
+            return Err("Port 0 would bind to a random port, pick a fixed one".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Only `serve` needs a runtime, the other commands stay plain sync code and start instantly
+fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(config.bind_addr).await?;
+        // See the `axum` recipe for actually serving something
+        println!("Listening on {}", listener.local_addr()?);
+        Ok(())
+    })
+}
+
+/// Parse the config once in main and pass it on instead of keeping it in a global,
+/// see the `state` recipe for sharing it with axum handlers
+pub fn main() {
+    let result: Result<(), Box<dyn std::error::Error>> = match Cli::parse().command {
+        Command::Serve(config) => config
+            .validate()
+            .map_err(Into::into)
+            .and_then(|()| serve(config)),
+        Command::CheckConfig(config) => config
+            .validate()
+            .map(|()| println!("{config:#?}"))
+            .map_err(Into::into),
+        Command::Version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut io::stdout(),
+            );
+            Ok(())
+        }
+    };
+    // Nonzero exit codes let scripts and CI notice the failure
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_is_valid() {
+        // Catches conflicting flags or names at test time instead of on the first run
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let cli = Cli::try_parse_from(["asdf", "check-config", "127.0.0.1:0"]).unwrap();
+        let Command::CheckConfig(config) = cli.command else {
+            panic!("Expected check-config, got {:?}", cli.command);
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Reading the time through a `Clock` so tests can control it
+//! Requires `cargo add chrono`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//! Tests require `cargo add --dev tokio -F test-util`
+//!
+//! Code calling `Utc::now()` or `Instant::now()` directly can only be tested by waiting for the
+//! real time to pass. Taking a `Clock` instead lets tests pass a `MockClock` and move it forward.
+//! Used by the `scheduler` and `resilience` recipes.
+//!
+//! `tokio::time::sleep`, `timeout` and `interval` need no clock, with `tokio::time::pause` or
+//! `#[tokio::test(start_paused = true)]` tokio's time stands still until `tokio::time::advance`
+//! moves it. When every task is waiting on a timer the paused time jumps straight to the next one,
+//! so a test sleeping for an hour finishes right away. `MockClock` follows the paused time, that
+//! way the time code reads and the time tokio sleeps by never disagree.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+
+pub trait Clock: Send + Sync + 'static {
+    /// Monotonic, for durations and deadlines
+    fn now(&self) -> Instant;
+    /// Wall clock time, for schedules and timestamps. May jump when the system time is changed.
+    fn utc(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Starts at a fixed date and moves with tokio's time, which only moves on its own when it is not
+/// paused. `advance` moves just this clock, e.g. in tests without a runtime.
+/// Clones share the time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: tokio::time::Instant,
+    start_utc: DateTime<Utc>,
+    advanced: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    pub fn new(start_utc: DateTime<Utc>) -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            start_utc,
+            advanced: Arc::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.advanced.lock().unwrap() += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed() + *self.advanced.lock().unwrap()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start.into_std() + self.elapsed()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        self.start_utc + TimeDelta::from_std(self.elapsed()).expect("Mock time stays in range")
+    }
+}
+
+/// Takes the clock as a parameter, in a real app it would be part of the state
+pub fn greeting(clock: &impl Clock) -> &'static str {
+    match clock.utc().hour() {
+        5..=11 => "Good morning",
+        12..=17 => "Good afternoon",
+        _ => "Good evening",
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let clock = SystemClock;
+    println!("{}, it is {}", greeting(&clock), clock.utc());
+    let start = clock.now();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    println!("Slept for {:?}", start.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noon() -> DateTime<Utc> {
+        "2024-06-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn advancing_moves_both_times() {
+        let clock = MockClock::new(noon());
+        let start = clock.now();
+        assert_eq!(greeting(&clock), "Good afternoon");
+        clock.advance(Duration::from_secs(8 * 3600));
+        assert_eq!(greeting(&clock), "Good evening");
+        assert!(clock.now() - start >= Duration::from_secs(8 * 3600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_paused_tokio_time() {
+        let clock = MockClock::new(noon());
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert_eq!(clock.utc(), noon() + TimeDelta::seconds(90));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_fire_without_waiting() {
+        let clock = MockClock::new(noon());
+        // Would take an hour with real time
+        let result =
+            tokio::time::timeout(Duration::from_secs(3600), std::future::pending::<()>()).await;
+        assert!(result.is_err());
+        assert_eq!(clock.utc(), noon() + TimeDelta::hours(1));
+    }
+}
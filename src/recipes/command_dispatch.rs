@@ -0,0 +1,197 @@
+//! Dispatching subcommands with shared setup
+//! Requires `cargo add clap -F derive -F env`
+//! `cargo add clap_complete`
+//! `cargo add sqlx -F runtime-tokio -F postgres`
+//! `cargo add tokio -F macros -F rt-multi-thread`
+//! `cargo add tracing tracing-subscriber`
+//!
+//! Every command declares what it needs up front, `main` sets that up once and hands it over in an `AppContext`.
+//! Commands like `completions` need nothing, so they don't fail just because the database is unreachable.
+
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use sqlx::PgPool;
+
+#[derive(Parser)]
+pub struct Config {
+    #[clap(long, env, global = true)]
+    database_url: Option<String>,
+    #[clap(long, env, global = true, default_value = "info")]
+    log_level: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print how many users there are
+    Users,
+    /// Apply pending migrations
+    Migrate,
+    /// Print shell completions
+    Completions { shell: Shell },
+}
+
+#[derive(Default)]
+pub struct Requirements {
+    pub logging: bool,
+    pub database: bool,
+}
+
+impl Command {
+    fn requirements(&self) -> Requirements {
+        match self {
+            Command::Users | Command::Migrate => Requirements {
+                logging: true,
+                database: true,
+            },
+            // Output goes straight to the shell so don't log anything either
+            Command::Completions { .. } => Requirements::default(),
+        }
+    }
+
+    async fn run(self, ctx: AppContext) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Command::Users => users(ctx).await,
+            Command::Migrate => migrate(ctx).await,
+            Command::Completions { shell } => {
+                clap_complete::generate(
+                    shell,
+                    &mut Config::command(),
+                    "asdf",
+                    &mut std::io::stdout(),
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct AppContext {
+    db: Option<PgPool>,
+}
+
+impl AppContext {
+    pub async fn setup(
+        config: &Config,
+        requirements: Requirements,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if requirements.logging {
+            // Fails if a subscriber is already set, e.g. when tests set up several commands
+            let _ = tracing_subscriber::fmt()
+                .with_env_filter(&config.log_level)
+                .try_init();
+        }
+        let db = if requirements.database {
+            let url = config
+                .database_url
+                .as_deref()
+                .ok_or("--database-url is required for this command")?;
+            Some(PgPool::connect(url).await?)
+        } else {
+            None
+        };
+        Ok(Self { db })
+    }
+
+    /// Only call this from commands that declared that they need a database
+    pub fn db(&self) -> &PgPool {
+        self.db
+            .as_ref()
+            .expect("Command uses the database without requiring it")
+    }
+}
+
+async fn users(ctx: AppContext) -> Result<(), Box<dyn std::error::Error>> {
+    let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM users")
+        .fetch_one(ctx.db())
+        .await?;
+    tracing::info!("There are {count} users");
+    Ok(())
+}
+
+async fn migrate(ctx: AppContext) -> Result<(), Box<dyn std::error::Error>> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS users (id BIGSERIAL PRIMARY KEY)")
+        .execute(ctx.db())
+        .await?;
+    tracing::info!("Migrations applied");
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::parse();
+    let requirements = config.command.requirements();
+    let ctx = match AppContext::setup(&config, requirements).await {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            eprintln!("Setup failed: {e}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = config.command.run(ctx).await {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn completions_skip_the_setup() {
+        // An unreachable database would make the setup fail if it tried to connect
+        let config = Config::try_parse_from([
+            "asdf",
+            "completions",
+            "bash",
+            "--database-url",
+            "postgres://postgres@127.0.0.1:1/postgres",
+        ])
+        .unwrap();
+        let requirements = config.command.requirements();
+        assert!(!requirements.logging);
+        assert!(!requirements.database);
+        let ctx = AppContext::setup(&config, requirements).await.unwrap();
+        assert!(ctx.db.is_none());
+        config.command.run(ctx).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn database_commands_set_up_the_database() {
+        // Built by hand as clap would take `DATABASE_URL` from the environment
+        let config = Config {
+            database_url: None,
+            log_level: "info".to_owned(),
+            command: Command::Users,
+        };
+        let requirements = config.command.requirements();
+        assert!(requirements.logging);
+        assert!(requirements.database);
+        let Err(e) = AppContext::setup(&config, requirements).await else {
+            panic!("Setup should need a database url");
+        };
+        assert_eq!(e.to_string(), "--database-url is required for this command");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn dispatches_with_a_database() {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL to point at Postgres");
+        // Its own schema so the migration doesn't touch the real tables
+        let schema = format!("command_dispatch_test_{}", std::process::id());
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {schema}"))
+            .execute(&PgPool::connect(&url).await.unwrap())
+            .await
+            .unwrap();
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{url}{separator}options=-csearch_path%3D{schema}");
+        let config = Config::try_parse_from(["asdf", "migrate", "--database-url", &url]).unwrap();
+        let ctx = AppContext::setup(&config, config.command.requirements())
+            .await
+            .unwrap();
+        assert!(ctx.db.is_some());
+        config.command.run(ctx).await.unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! The tokio channels, `select!` and a bounded work queue with a pool of workers
+//! Requires `cargo add tokio -F macros -F rt-multi-thread -F sync -F time`
+//!
+//! Which channel to pick:
+//! - `mpsc`: many senders, one receiver, e.g. jobs for a task that owns some resource
+//! - `oneshot`: a single value, e.g. the answer to a request sent over an mpsc channel
+//! - `broadcast`: every receiver gets every value, e.g. events for all websocket connections
+//! - `watch`: receivers only see the latest value, e.g. config or a shutdown flag
+//!
+//! Prefer bounded channels, a full channel makes the sender wait instead of the memory growing
+//! until the process is killed.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch, Mutex},
+    task::JoinSet,
+    time::sleep,
+};
+
+/// A request carrying the channel for its answer, the usual way to talk to a task that owns state
+pub enum Command {
+    Increment,
+    Get { reply: oneshot::Sender<u64> },
+}
+
+/// Owns the counter, nobody else needs a lock to use it. Ends once every sender is dropped.
+/// See the `actor` recipe for wrapping this in a handle with a method per command.
+pub async fn counter_task(mut commands: mpsc::Receiver<Command>) {
+    let mut count = 0;
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Increment => count += 1,
+            // The asker may have given up waiting, that's fine
+            Command::Get { reply } => drop(reply.send(count)),
+        }
+    }
+}
+
+pub async fn mpsc_and_oneshot() -> u64 {
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(counter_task(rx));
+    for _ in 0..3 {
+        tx.send(Command::Increment).await.unwrap();
+    }
+    let (reply, answer) = oneshot::channel();
+    tx.send(Command::Get { reply }).await.unwrap();
+    answer.await.unwrap()
+}
+
+/// Receivers that fall behind by more than the capacity miss values and get `Lagged` instead
+pub async fn broadcast_events() -> Vec<String> {
+    let (tx, _) = broadcast::channel(16);
+    // Only values sent after subscribing are received
+    let mut listeners = (0..2).map(|_| tx.subscribe()).collect::<Vec<_>>();
+    tx.send("deployed").unwrap();
+    let mut received = Vec::new();
+    for (i, listener) in listeners.iter_mut().enumerate() {
+        received.push(format!("{i} got {}", listener.recv().await.unwrap()));
+    }
+    received
+}
+
+/// Ticks until the watched flag turns true, `select!` polls both and runs the branch ready first
+pub async fn work_until_stopped(mut stop: watch::Receiver<bool>) -> u32 {
+    let mut ticks = 0;
+    loop {
+        tokio::select! {
+            // Stopping wins if both are ready
+            biased;
+            // Also ends when the sender is gone, nobody could stop us anymore
+            changed = stop.changed() => {
+                if changed.is_err() || *stop.borrow() {
+                    return ticks;
+                }
+            }
+            // The branch that loses is dropped, so everything in it has to be fine with stopping
+            // at any await, e.g. don't hold half written state across it
+            _ = sleep(Duration::from_millis(10)) => ticks += 1,
+        }
+    }
+}
+
+/// A producer feeding jobs to a fixed number of workers. The queue holds at most `capacity` jobs,
+/// after that the producer waits for the workers to catch up.
+pub async fn work_queue(jobs: Vec<u64>, workers: usize, capacity: usize) -> Vec<u64> {
+    let (tx, rx) = mpsc::channel(capacity);
+    // mpsc has a single receiver, the workers take turns through the lock
+    let rx = Arc::new(Mutex::new(rx));
+    let mut pool = JoinSet::new();
+    for _ in 0..workers {
+        let rx = rx.clone();
+        pool.spawn(async move {
+            let mut done = Vec::new();
+            loop {
+                // The lock is only held while waiting for the next job, not while working on it
+                let job = rx.lock().await.recv().await;
+                let Some(job) = job else {
+                    break;
+                };
+                sleep(Duration::from_millis(job)).await;
+                done.push(job * 2);
+            }
+            done
+        });
+    }
+    let producer = tokio::spawn(async move {
+        for job in jobs {
+            tx.send(job).await.unwrap();
+        }
+        // Dropping the sender here ends the workers' loops once the queue is empty
+    });
+    producer.await.unwrap();
+    let mut results = Vec::new();
+    while let Some(done) = pool.join_next().await {
+        results.extend(done.unwrap());
+    }
+    results
+}
+
+#[tokio::main]
+pub async fn main() {
+    println!("Counter: {}", mpsc_and_oneshot().await);
+    println!("Broadcast: {:?}", broadcast_events().await);
+
+    let (stop, stopped) = watch::channel(false);
+    let worker = tokio::spawn(work_until_stopped(stopped));
+    sleep(Duration::from_millis(55)).await;
+    stop.send(true).unwrap();
+    println!("Worked for {} ticks", worker.await.unwrap());
+
+    let results = work_queue((1..=20).collect(), 4, 5).await;
+    println!("Processed {} jobs: {results:?}", results.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_over_oneshot() {
+        assert_eq!(mpsc_and_oneshot().await, 3);
+    }
+
+    #[tokio::test]
+    async fn every_subscriber_gets_the_event() {
+        assert_eq!(
+            broadcast_events().await,
+            ["0 got deployed", "1 got deployed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_when_told_or_when_the_sender_is_gone() {
+        let (stop, stopped) = watch::channel(false);
+        let worker = tokio::spawn(work_until_stopped(stopped));
+        stop.send(true).unwrap();
+        worker.await.unwrap();
+
+        let (stop, stopped) = watch::channel(false);
+        drop(stop);
+        assert_eq!(work_until_stopped(stopped).await, 0);
+    }
+
+    #[tokio::test]
+    async fn workers_process_every_job_once() {
+        let mut results = work_queue((1..=10).collect(), 3, 2).await;
+        results.sort_unstable();
+        assert_eq!(results, (1..=10).map(|job| job * 2).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,52 @@
+//! Inspecting running tokio tasks with tokio-console next to the usual log output
+//! Requires `cargo add tracing`
+//! `cargo add tracing-subscriber -F env-filter`
+//! `cargo add console-subscriber --optional`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F time`
+//!
+//! Run it with `RUSTFLAGS="--cfg tokio_unstable" cargo run --features console-recipe,tokio-console`
+//! and `tokio-console` (`cargo install --locked tokio-console`) in a second terminal. Tokio only
+//! emits the task instrumentation when built with `tokio_unstable`, without it the console stays empty.
+//! The feature keeps release builds from paying for it, `cargo run -- init` keeps it as
+//! `tokio-console = ["dep:console-subscriber", "tokio/tracing"]`. Declare it the same way in a
+//! project of your own.
+//! See the `logging` recipe for json output.
+
+use std::time::Duration;
+
+use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// The console needs the trace level events of tokio while the log output should stay at
+/// `RUST_LOG`. A global `EnvFilter` would throw those events away before the console sees them,
+/// so the filter is attached to the fmt layer only and the console layer brings its own.
+pub fn init() {
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")));
+    let registry = tracing_subscriber::registry().with(fmt);
+    // Listens on 127.0.0.1:6669, `TOKIO_CONSOLE_BIND` changes that
+    #[cfg(feature = "tokio-console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+}
+
+#[tokio::main]
+pub async fn main() {
+    init();
+    for worker in 0..3 {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(100 * (worker + 1))).await;
+            }
+        });
+    }
+    // Blocking inside a task shows up in the console as a task with a huge busy time
+    tokio::spawn(async {
+        loop {
+            std::thread::sleep(Duration::from_millis(50));
+            tokio::task::yield_now().await;
+        }
+    });
+    info!("Running, connect with tokio-console");
+    tokio::signal::ctrl_c().await.unwrap();
+}
@@ -0,0 +1,246 @@
+//! Timestamps in models, query parameters and the database with chrono
+//! Requires `cargo add axum thiserror tracing-subscriber`
+//! `cargo add clap -F derive -F env`
+//! `cargo add chrono -F serde`
+//! `cargo add serde -F derive`
+//! `cargo add sqlx -F runtime-tokio -F postgres -F chrono`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Builds on the `database` recipe for the connection pool.
+//! Builds on the `error` recipe for the error responses.
+//!
+//! Every timestamp is a `DateTime<Utc>`, stored as `TIMESTAMPTZ` and sent as RFC 3339,
+//! e.g. `2024-06-01T12:00:00Z`. Converting to the user's timezone is the job of whoever displays it.
+//! `TIMESTAMP` without a timezone and `NaiveDateTime` lose which timezone was meant, the same value
+//! then means different instants depending on the server's settings.
+//! `psql "$DATABASE_URL" -c "CREATE TABLE events (id BIGSERIAL PRIMARY KEY, title TEXT NOT NULL, starts_at TIMESTAMPTZ NOT NULL, created_at TIMESTAMPTZ NOT NULL DEFAULT now())"`
+
+use axum::{
+    extract::{rejection::JsonRejection, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::net::TcpListener;
+
+use crate::recipes::{
+    database::{connect, DatabaseConfig},
+    error::AppError,
+};
+
+/// chrono's serde support reads and writes RFC 3339
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Event {
+    pub id: i64,
+    pub title: String,
+    pub starts_at: DateTime<Utc>,
+    /// Set by the database so it doesn't depend on the clock of whichever server inserted it
+    pub created_at: DateTime<Utc>,
+}
+
+/// Offsets other than `Z` are accepted and converted, `2024-06-01T14:00:00+02:00` is stored as 12:00 UTC
+#[derive(Debug, Deserialize)]
+pub struct NewEvent {
+    pub title: String,
+    pub starts_at: DateTime<Utc>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DateError {
+    #[error("Invalid {name}: {value:?} is neither a date like 2024-06-01 nor a timestamp like 2024-06-01T12:00:00Z")]
+    Invalid { name: &'static str, value: String },
+    #[error("{name} is missing a timezone, add Z for UTC or an offset like +02:00")]
+    MissingTimezone { name: &'static str },
+    #[error("from has to be before to")]
+    EmptyRange,
+}
+
+impl From<DateError> for AppError {
+    fn from(e: DateError) -> Self {
+        AppError::BadRequest(e.to_string())
+    }
+}
+
+/// Accepts a full RFC 3339 timestamp or a plain date meaning midnight UTC
+pub fn parse_timestamp(name: &'static str, value: &str) -> Result<DateTime<Utc>, DateError> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN).and_utc());
+    }
+    // Guessing the timezone would silently shift the time by hours
+    if value.parse::<chrono::NaiveDateTime>().is_ok() {
+        return Err(DateError::MissingTimezone { name });
+    }
+    Err(DateError::Invalid {
+        name,
+        value: value.to_owned(),
+    })
+}
+
+/// Strings instead of `DateTime` so a bad value gets our error message instead of serde's
+#[derive(Debug, Deserialize)]
+pub struct RangeParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Both ends are optional, `to` is exclusive
+pub struct Range {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<RangeParams> for Range {
+    type Error = DateError;
+
+    fn try_from(params: RangeParams) -> Result<Self, Self::Error> {
+        let from = params
+            .from
+            .map(|from| parse_timestamp("from", &from))
+            .transpose()?;
+        let to = params.to.map(|to| parse_timestamp("to", &to)).transpose()?;
+        if from.zip(to).is_some_and(|(from, to)| from >= to) {
+            return Err(DateError::EmptyRange);
+        }
+        Ok(Range { from, to })
+    }
+}
+
+/// `GET /events?from=2024-06-01&to=2024-07-01T00:00:00Z`
+async fn list_events(
+    State(pool): State<PgPool>,
+    Query(params): Query<RangeParams>,
+) -> Result<Json<Vec<Event>>, AppError> {
+    let range = Range::try_from(params)?;
+    let events = sqlx::query_as(
+        "SELECT id, title, starts_at, created_at FROM events
+         WHERE ($1::timestamptz IS NULL OR starts_at >= $1)
+           AND ($2::timestamptz IS NULL OR starts_at < $2)
+         ORDER BY starts_at",
+    )
+    .bind(range.from)
+    .bind(range.to)
+    .fetch_all(&pool)
+    .await?;
+    Ok(Json(events))
+}
+
+/// Taking the rejection turns axum's default 422 for an unparsable body into a 400 like the rest
+async fn create_event(
+    State(pool): State<PgPool>,
+    event: Result<Json<NewEvent>, JsonRejection>,
+) -> Result<(StatusCode, Json<Event>), AppError> {
+    let Json(event) = event.map_err(|rejection| AppError::BadRequest(rejection.body_text()))?;
+    let event = sqlx::query_as(
+        "INSERT INTO events (title, starts_at) VALUES ($1, $2)
+         RETURNING id, title, starts_at, created_at",
+    )
+    .bind(event.title)
+    .bind(event.starts_at)
+    .fetch_one(&pool)
+    .await?;
+    Ok((StatusCode::CREATED, Json(event)))
+}
+
+pub fn app(pool: PgPool) -> Router {
+    Router::new()
+        .route("/events", get(list_events).post(create_event))
+        .with_state(pool)
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let pool = connect(&DatabaseConfig::parse()).await.unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(pool)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use sqlx::postgres::PgPoolOptions;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn noon() -> DateTime<Utc> {
+        "2024-06-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_timestamps_and_dates() {
+        assert_eq!(
+            parse_timestamp("from", "2024-06-01T12:00:00Z").unwrap(),
+            noon()
+        );
+        assert_eq!(
+            parse_timestamp("from", "2024-06-01T14:00:00+02:00").unwrap(),
+            noon()
+        );
+        assert_eq!(
+            parse_timestamp("from", "2024-06-01").unwrap(),
+            noon() - chrono::TimeDelta::hours(12)
+        );
+    }
+
+    #[test]
+    fn explains_what_is_wrong() {
+        assert!(matches!(
+            parse_timestamp("from", "2024-06-01T12:00:00"),
+            Err(DateError::MissingTimezone { name: "from" })
+        ));
+        assert!(matches!(
+            parse_timestamp("to", "yesterday"),
+            Err(DateError::Invalid { name: "to", .. })
+        ));
+        let params = RangeParams {
+            from: Some("2024-06-02".to_owned()),
+            to: Some("2024-06-01".to_owned()),
+        };
+        assert!(matches!(
+            Range::try_from(params),
+            Err(DateError::EmptyRange)
+        ));
+    }
+
+    #[test]
+    fn serializes_as_rfc_3339() {
+        let event = Event {
+            id: 1,
+            title: "Launch".to_owned(),
+            starts_at: noon(),
+            created_at: noon(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["starts_at"], "2024-06-01T12:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn invalid_ranges_are_bad_requests() {
+        // Never connects, the range is rejected before the query
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+            .unwrap();
+        let req = Request::get("/events?from=2024-06-01T12:00:00")
+            .body(Body::empty())
+            .unwrap();
+        let res = app(pool).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "bad_request");
+        assert_eq!(
+            body["error"],
+            "from is missing a timezone, add Z for UTC or an offset like +02:00"
+        );
+    }
+}
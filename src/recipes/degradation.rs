@@ -0,0 +1,199 @@
+//! Serving fallback responses when a dependency is disabled or down
+//! Requires `cargo add axum tracing`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev tower -F util`
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// Runtime toggles for dependencies. Flags that were never set count as enabled.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureFlags(Arc<RwLock<HashMap<String, bool>>>);
+
+impl FeatureFlags {
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.0.read().unwrap().get(flag).copied().unwrap_or(true)
+    }
+
+    pub fn set(&self, flag: &str, enabled: bool) {
+        self.0.write().unwrap().insert(flag.to_owned(), enabled);
+    }
+}
+
+/// What to respond with instead of running the real handler
+#[derive(Debug, Clone)]
+pub struct Fallback {
+    /// Name of the flag guarding the dependency
+    pub flag: &'static str,
+    /// 203 tells clients that this is not the response of the origin, 200 works for clients that can't handle it
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    pub body: &'static str,
+}
+
+impl Fallback {
+    fn response(&self) -> Response {
+        (
+            self.status,
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(self.content_type),
+                ),
+                // Caches must not keep serving the fallback once the dependency is back
+                (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+                (
+                    header::HeaderName::from_static("x-degraded-mode"),
+                    HeaderValue::from_static(self.flag),
+                ),
+            ],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+/// Middleware serving the fallback if its flag is disabled or the real handler failed with a server error
+pub async fn degrade(
+    State((flags, fallback)): State<(FeatureFlags, Fallback)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !flags.is_enabled(fallback.flag) {
+        return fallback.response();
+    }
+    let res = next.run(req).await;
+    if res.status().is_server_error() {
+        warn!(flag = fallback.flag, status = %res.status(), "Serving fallback response");
+        return fallback.response();
+    }
+    res
+}
+
+#[tokio::main]
+pub async fn main() {
+    let flags = FeatureFlags::default();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(flags)).await.unwrap();
+}
+
+pub fn app(flags: FeatureFlags) -> Router {
+    let recommendations_fallback = Fallback {
+        flag: "recommendations",
+        status: StatusCode::NON_AUTHORITATIVE_INFORMATION,
+        content_type: "application/json",
+        body: r#"{"items":[]}"#,
+    };
+    Router::new()
+        .route(
+            "/recommendations",
+            get(recommendations).layer(middleware::from_fn_with_state(
+                (flags.clone(), recommendations_fallback),
+                degrade,
+            )),
+        )
+        // In a real app you want to protect this route
+        .route("/admin/flags/:flag", put(set_flag))
+        .with_state(flags)
+}
+
+async fn recommendations() -> Result<Json<Vec<String>>, StatusCode> {
+    // Pretend this asks some recommendation service that is currently down
+    Err(StatusCode::BAD_GATEWAY)
+}
+
+/// `curl -X PUT localhost:8080/admin/flags/recommendations -H 'content-type: application/json' -d false`
+async fn set_flag(
+    State(flags): State<FeatureFlags>,
+    Path(flag): Path<String>,
+    Json(enabled): Json<bool>,
+) -> StatusCode {
+    flags.set(&flag, enabled);
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn get_request(uri: &str) -> Request {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    async fn body_string(res: Response) -> String {
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failing_dependency_serves_fallback() {
+        let res = app(FeatureFlags::default())
+            .oneshot(get_request("/recommendations"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NON_AUTHORITATIVE_INFORMATION);
+        assert_eq!(res.headers()["x-degraded-mode"], "recommendations");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-store");
+        assert_eq!(body_string(res).await, r#"{"items":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn disabled_flag_skips_the_handler() {
+        let fallback = Fallback {
+            flag: "greeting",
+            status: StatusCode::OK,
+            content_type: "text/plain",
+            body: "Hi",
+        };
+        let flags = FeatureFlags::default();
+        let app = Router::new().route(
+            "/",
+            get(|| async { "Hello, world!" }).layer(middleware::from_fn_with_state(
+                (flags.clone(), fallback),
+                degrade,
+            )),
+        );
+
+        let res = app.clone().oneshot(get_request("/")).await.unwrap();
+        assert!(!res.headers().contains_key("x-degraded-mode"));
+        assert_eq!(body_string(res).await, "Hello, world!");
+
+        flags.set("greeting", false);
+        let res = app.oneshot(get_request("/")).await.unwrap();
+        assert_eq!(res.headers()["x-degraded-mode"], "greeting");
+        assert_eq!(body_string(res).await, "Hi");
+    }
+
+    #[tokio::test]
+    async fn flags_are_toggled_at_runtime() {
+        let flags = FeatureFlags::default();
+        let res = app(flags.clone())
+            .oneshot(
+                Request::put("/admin/flags/recommendations")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from("false"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!flags.is_enabled("recommendations"));
+        assert!(flags.is_enabled("never-set"));
+    }
+}
@@ -0,0 +1,264 @@
+//! Deregistering from service discovery before draining on shutdown
+//! Requires `cargo add axum reqwest tracing`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net -F time`
+//! Tests require `cargo add --dev wiremock serde_json`
+//! `cargo add --dev tokio -F test-util`
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use reqwest::Client;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Deregistering should never hold up shutdown for longer than this
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Anything that can tell the rest of the system to stop sending us traffic
+pub trait Discovery: Send + Sync {
+    fn register(&self) -> impl Future<Output = Result<(), BoxError>> + Send;
+    fn deregister(&self) -> impl Future<Output = Result<(), BoxError>> + Send;
+}
+
+/// Use this if you are not using any service discovery
+pub struct NoopDiscovery;
+
+impl Discovery for NoopDiscovery {
+    async fn register(&self) -> Result<(), BoxError> {
+        Ok(())
+    }
+
+    async fn deregister(&self) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+/// Registers the service with the local consul agent
+pub struct ConsulDiscovery {
+    pub client: Client,
+    /// Address of the consul agent e.g. `http://localhost:8500`
+    pub agent: String,
+    pub service: ConsulService,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConsulService {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+}
+
+impl Discovery for ConsulDiscovery {
+    async fn register(&self) -> Result<(), BoxError> {
+        self.client
+            .put(format!("{}/v1/agent/service/register", self.agent))
+            .json(&self.service)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn deregister(&self) -> Result<(), BoxError> {
+        self.client
+            .put(format!(
+                "{}/v1/agent/service/deregister/{}",
+                self.agent, self.service.id
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let discovery = ConsulDiscovery {
+        client: Client::new(),
+        agent: "http://localhost:8500".into(),
+        service: ConsulService {
+            id: "my-service-1".into(),
+            name: "my-service".into(),
+            address: "127.0.0.1".into(),
+            port: 8080,
+        },
+    };
+    discovery.register().await.unwrap();
+
+    let ready = Arc::new(AtomicBool::new(true));
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, world!" }))
+        .route("/readyz", get(readyz))
+        .with_state(ready.clone());
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(deregister_then_drain(
+            shutdown_signal(),
+            discovery,
+            ready,
+            Duration::from_secs(5),
+        ))
+        .await
+        .unwrap();
+}
+
+/// Load balancers that poll us instead of asking the discovery service stop sending traffic once this fails
+async fn readyz(State(ready): State<Arc<AtomicBool>>) -> StatusCode {
+    if ready.load(Ordering::Relaxed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Hand this to `with_graceful_shutdown`. Axum only starts draining once this future completes
+/// so we keep serving requests while the deregistration propagates to everyone routing to us.
+pub async fn deregister_then_drain(
+    signal: impl Future<Output = ()>,
+    discovery: impl Discovery,
+    ready: Arc<AtomicBool>,
+    propagation_delay: Duration,
+) {
+    signal.await;
+    info!("Shutdown signal received, deregistering from service discovery");
+    ready.store(false, Ordering::Relaxed);
+    // A broken discovery service must not keep us from shutting down
+    match tokio::time::timeout(DEREGISTER_TIMEOUT, discovery.deregister()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Failed to deregister from service discovery: {e}"),
+        Err(_) => warn!("Deregistering from service discovery timed out"),
+    }
+    tokio::time::sleep(propagation_delay).await;
+    info!("Draining connections");
+}
+
+/// Completes on ctrl_c or SIGTERM which is what docker and kubernetes send
+pub async fn shutdown_signal() {
+    let ctrl_c = async { tokio::signal::ctrl_c().await.unwrap() };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::time::Instant;
+    use wiremock::{
+        matchers::{body_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn registers_with_consul() {
+        let agent = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/v1/agent/service/register"))
+            .and(body_json(json!({
+                "ID": "my-service-1",
+                "Name": "my-service",
+                "Address": "127.0.0.1",
+                "Port": 8080,
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&agent)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/v1/agent/service/deregister/my-service-1"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&agent)
+            .await;
+
+        let discovery = ConsulDiscovery {
+            client: Client::new(),
+            agent: agent.uri(),
+            service: ConsulService {
+                id: "my-service-1".into(),
+                name: "my-service".into(),
+                address: "127.0.0.1".into(),
+                port: 8080,
+            },
+        };
+        discovery.register().await.unwrap();
+        discovery.deregister().await.unwrap();
+    }
+
+    /// Never answers, like a discovery service that is down without refusing connections
+    struct HangingDiscovery;
+
+    impl Discovery for HangingDiscovery {
+        async fn register(&self) -> Result<(), BoxError> {
+            Ok(())
+        }
+
+        async fn deregister(&self) -> Result<(), BoxError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_being_ready_before_draining() {
+        let ready = Arc::new(AtomicBool::new(true));
+        let start = Instant::now();
+        deregister_then_drain(
+            async {},
+            NoopDiscovery,
+            ready.clone(),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(!ready.load(Ordering::Relaxed));
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hanging_discovery_does_not_block_shutdown() {
+        let ready = Arc::new(AtomicBool::new(true));
+        let start = Instant::now();
+        deregister_then_drain(
+            async {},
+            HangingDiscovery,
+            ready.clone(),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(start.elapsed(), DEREGISTER_TIMEOUT + Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn readyz_follows_the_flag() {
+        let ready = Arc::new(AtomicBool::new(true));
+        assert_eq!(readyz(State(ready.clone())).await, StatusCode::OK);
+        ready.store(false, Ordering::Relaxed);
+        assert_eq!(readyz(State(ready)).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
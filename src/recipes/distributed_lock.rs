@@ -0,0 +1,283 @@
+//! Distributed locks so only one instance runs a scheduled job
+//! Requires `cargo add redis -F tokio-comp -F connection-manager`
+//! `cargo add thiserror tracing tracing-subscriber`
+//! `cargo add uuid -F v4`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time -F sync`
+//!
+//! Every lock has a ttl so a crashed holder can't block the job forever. Pick a ttl longer
+//! than the job takes, otherwise a second instance may start while the first one is still running.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use redis::{aio::ConnectionManager, Script};
+use tokio::{runtime::Handle, time::Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("Redis failed: {0}")]
+    Redis(#[from] redis::RedisError),
+}
+
+/// Call [`LockGuard::release`] when done. Dropping it releases the lock too, but only
+/// in the background and only inside a tokio runtime, otherwise the lock expires after its ttl.
+pub struct LockGuard {
+    held: Option<Held>,
+}
+
+enum Held {
+    Redis {
+        conn: ConnectionManager,
+        key: String,
+        token: String,
+    },
+    InMemory {
+        held: Arc<InMemoryLocks>,
+        key: String,
+        token: u64,
+    },
+}
+
+impl Held {
+    /// Returns false if the lock expired and was taken by someone else in the meantime
+    async fn release(self) -> Result<bool, LockError> {
+        match self {
+            Held::Redis {
+                mut conn,
+                key,
+                token,
+            } => {
+                let released: i64 = Script::new(RELEASE)
+                    .key(&key)
+                    .arg(token)
+                    .invoke_async(&mut conn)
+                    .await?;
+                Ok(released == 1)
+            }
+            Held::InMemory { held, key, token } => Ok(release_in_memory(&held, &key, token)),
+        }
+    }
+}
+
+type InMemoryLocks = Mutex<HashMap<String, (u64, Instant)>>;
+
+/// Needs no runtime, so it can run in `drop`
+fn release_in_memory(held: &InMemoryLocks, key: &str, token: u64) -> bool {
+    let mut held = held.lock().unwrap();
+    if held.get(key).is_some_and(|&(current, _)| current == token) {
+        held.remove(key);
+        true
+    } else {
+        false
+    }
+}
+
+impl LockGuard {
+    /// Returns false if the lock had already expired and was taken by someone else, whose lock
+    /// is left alone
+    pub async fn release(mut self) -> Result<bool, LockError> {
+        match self.held.take() {
+            Some(held) => held.release().await,
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let held = match self.held.take() {
+            Some(Held::InMemory { held, key, token }) => {
+                release_in_memory(&held, &key, token);
+                return;
+            }
+            Some(held) => held,
+            None => return,
+        };
+        // tokio::spawn would panic outside a runtime, e.g. when the runtime is shutting down
+        match Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    // If this fails the ttl cleans up after us
+                    if let Err(e) = held.release().await {
+                        warn!("Failed to release lock: {e}");
+                    }
+                });
+            }
+            Err(_) => warn!("Dropped a lock outside a runtime, it is released by its ttl"),
+        }
+    }
+}
+
+pub trait DistributedLock: Send + Sync + 'static {
+    /// Returns `None` if someone else currently holds the lock
+    fn try_acquire(
+        &self,
+        key: &str,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Option<LockGuard>, LockError>> + Send;
+}
+
+#[derive(Clone)]
+pub struct RedisLock {
+    conn: ConnectionManager,
+}
+
+/// Only delete the key if it still holds our token. If our lock expired and someone else
+/// took it in the meantime a plain DEL would release their lock.
+const RELEASE: &str = r#"
+    if redis.call("GET", KEYS[1]) == ARGV[1] then
+        return redis.call("DEL", KEYS[1])
+    else
+        return 0
+    end
+"#;
+
+impl RedisLock {
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let conn = redis::Client::open(url)?.get_connection_manager().await?;
+        Ok(Self { conn })
+    }
+}
+
+impl DistributedLock for RedisLock {
+    async fn try_acquire(&self, key: &str, ttl: Duration) -> Result<Option<LockGuard>, LockError> {
+        let mut conn = self.conn.clone();
+        let token = uuid::Uuid::new_v4().to_string();
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut conn)
+            .await?;
+        Ok(acquired.map(|_| LockGuard {
+            held: Some(Held::Redis {
+                conn,
+                key: key.to_owned(),
+                token,
+            }),
+        }))
+    }
+}
+
+/// For tests and single instance deployments
+#[derive(Clone, Default)]
+pub struct InMemoryLock {
+    held: Arc<InMemoryLocks>,
+    next_token: Arc<AtomicU64>,
+}
+
+impl InMemoryLock {
+    fn acquire(&self, key: &str, ttl: Duration) -> Option<LockGuard> {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut held = self.held.lock().unwrap();
+        if held.get(key).is_some_and(|&(_, expires)| expires > now) {
+            return None;
+        }
+        held.insert(key.to_owned(), (token, now + ttl));
+        Some(LockGuard {
+            held: Some(Held::InMemory {
+                held: self.held.clone(),
+                key: key.to_owned(),
+                token,
+            }),
+        })
+    }
+}
+
+impl DistributedLock for InMemoryLock {
+    async fn try_acquire(&self, key: &str, ttl: Duration) -> Result<Option<LockGuard>, LockError> {
+        Ok(self.acquire(key, ttl))
+    }
+}
+
+async fn send_digest_emails() {
+    info!("Sending digest emails");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+}
+
+/// Every instance runs this loop but only the one getting the lock does the work
+pub async fn schedule(lock: impl DistributedLock, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let guard = match lock.try_acquire("jobs:digest-emails", every * 2).await {
+            Ok(Some(guard)) => guard,
+            Ok(None) => {
+                info!("Digest emails are handled by another instance");
+                continue;
+            }
+            // Skipping one run is better than two instances sending the same emails
+            Err(e) => {
+                warn!("Failed to acquire the digest emails lock: {e}");
+                continue;
+            }
+        };
+        send_digest_emails().await;
+        if let Err(e) = guard.release().await {
+            warn!("Failed to release the digest emails lock: {e}");
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let lock = RedisLock::connect("redis://127.0.0.1/").await.unwrap();
+    schedule(lock, Duration::from_secs(60)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn only_one_holder_at_a_time() {
+        let lock = InMemoryLock::default();
+        let guard = lock.try_acquire("job", TTL).await.unwrap().unwrap();
+        assert!(lock.try_acquire("job", TTL).await.unwrap().is_none());
+        // Other keys are independent
+        assert!(lock.try_acquire("other", TTL).await.unwrap().is_some());
+
+        assert!(guard.release().await.unwrap());
+        let guard = lock.try_acquire("job", TTL).await.unwrap().unwrap();
+        drop(guard);
+        assert!(lock.try_acquire("job", TTL).await.unwrap().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_locks_can_be_taken_over() {
+        let lock = InMemoryLock::default();
+        // Stands in for a process that died while holding the lock
+        let crashed = lock.try_acquire("job", TTL).await.unwrap().unwrap();
+        tokio::time::sleep(TTL - Duration::from_millis(1)).await;
+        assert!(lock.try_acquire("job", TTL).await.unwrap().is_none());
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let current = lock.try_acquire("job", TTL).await.unwrap().unwrap();
+
+        // The old holder must not release the lock of the new one
+        assert!(!crashed.release().await.unwrap());
+        assert!(lock.try_acquire("job", TTL).await.unwrap().is_none());
+        assert!(current.release().await.unwrap());
+    }
+
+    #[test]
+    fn dropping_outside_a_runtime_does_not_panic() {
+        let lock = InMemoryLock::default();
+        let guard = lock.acquire("job", TTL).unwrap();
+        drop(guard);
+        assert!(lock.acquire("job", TTL).is_some());
+    }
+}
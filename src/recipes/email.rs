@@ -0,0 +1,371 @@
+//! Sending transactional email from handlers through a background queue
+//! Requires `cargo add axum thiserror tracing tracing-subscriber`
+//! `cargo add url uuid -F uuid/v4`
+//! `cargo add lettre -F tokio1 -F tokio1-native-tls`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F signal -F sync -F time`
+//! Tests require `cargo add --dev tower -F util`
+//! Builds on the `config` recipe for the SMTP settings and the `email_template` recipe for rendering.
+//!
+//! Talking to an SMTP server takes hundreds of milliseconds and fails now and then. Handlers only
+//! render the email and put it into a queue, a single task sends them and retries failures.
+//! The queue lives in memory, emails still in it are lost when the process is killed. Where that
+//! matters store them in the database in the same transaction, see the `outbox` recipe.
+//! The tokens of the verification links are kept in memory as well, a restart invalidates the
+//! links sent before it.
+//! Run a local SMTP server that shows every email it receives with
+//! `docker run -p 1025:1025 -p 8025:8025 axllent/mailpit` and `--smtp-url smtp://localhost:1025`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use lettre::{
+    message::Mailbox, transport::stub::AsyncStubTransport, Address, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+use tracing::{error, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::recipes::{
+    config::Config,
+    email_template::{render_email, EmailError, Welcome, WelcomeContext},
+};
+
+const SEND_ATTEMPTS: u32 = 3;
+
+/// How long a verification link works
+const VERIFY_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub enum Mailer {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    /// Used without `smtp_url`, so development doesn't need a mail server
+    Log,
+    /// Keeps the emails instead of sending them, for tests
+    Stub(AsyncStubTransport),
+}
+
+impl Mailer {
+    pub fn from_config(config: &Config) -> Result<Self, EmailError> {
+        match &config.smtp_url {
+            // The url carries host, port, credentials and whether to use TLS
+            Some(url) => Ok(Mailer::Smtp(
+                AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+            )),
+            None => Ok(Mailer::Log),
+        }
+    }
+
+    async fn send(&self, message: &Message) -> Result<(), EmailError> {
+        match self {
+            Mailer::Smtp(transport) => transport.send(message.clone()).await.map(drop)?,
+            Mailer::Log => info!(
+                "Not sending email to {:?}, no smtp_url is configured:\n{}",
+                message.envelope().to(),
+                String::from_utf8_lossy(&message.formatted())
+            ),
+            Mailer::Stub(transport) => transport.send(message.clone()).await?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueueError {
+    #[error("Too many emails are waiting to be sent")]
+    Full,
+    #[error("The email queue was shut down")]
+    Closed,
+}
+
+/// Cheap to clone, every handler gets one through the state
+#[derive(Clone)]
+pub struct EmailQueue {
+    messages: mpsc::Sender<Message>,
+}
+
+impl EmailQueue {
+    /// Bounded so a broken mail server can't make the queue eat all memory
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self { messages: tx }, rx)
+    }
+
+    /// Never waits, a full queue is reported to the caller instead of slowing down the request
+    pub fn enqueue(&self, message: Message) -> Result<(), QueueError> {
+        self.messages.try_send(message).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => QueueError::Full,
+            mpsc::error::TrySendError::Closed(_) => QueueError::Closed,
+        })
+    }
+}
+
+/// Sends one email after another until every `EmailQueue` is dropped and the queue is empty
+pub fn spawn_sender(mailer: Mailer, mut messages: mpsc::Receiver<Message>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(message) = messages.recv().await {
+            for attempt in 1..=SEND_ATTEMPTS {
+                match mailer.send(&message).await {
+                    Ok(()) => break,
+                    Err(e) if attempt < SEND_ATTEMPTS => {
+                        warn!("Sending email failed, attempt {attempt} of {SEND_ATTEMPTS}: {e}");
+                        tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+                    }
+                    Err(e) => error!("Giving up sending email: {e}"),
+                }
+            }
+        }
+    })
+}
+
+struct Pending {
+    email: Address,
+    expires: Instant,
+}
+
+/// The tokens of the verification links sent out
+#[derive(Clone, Default)]
+pub struct Verifications(Arc<Mutex<HashMap<String, Pending>>>);
+
+impl Verifications {
+    /// The token is random so links can't be guessed from the address
+    pub fn issue(&self, email: Address) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        let now = Instant::now();
+        let mut pending = self.0.lock().unwrap();
+        pending.retain(|_, pending| pending.expires > now);
+        pending.insert(
+            token.clone(),
+            Pending {
+                email,
+                expires: now + VERIFY_FOR,
+            },
+        );
+        token
+    }
+
+    /// Each token works once
+    pub fn verify(&self, token: &str) -> Option<Address> {
+        let pending = self.0.lock().unwrap().remove(token)?;
+        (pending.expires > Instant::now()).then_some(pending.email)
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub queue: EmailQueue,
+    pub from: Mailbox,
+    pub verifications: Verifications,
+    /// Where the verify route is reachable from the outside, e.g. `https://example.com/verify`
+    pub verify_url: Url,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Signup {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Verify {
+    pub token: String,
+}
+
+/// The response doesn't wait for the email, 202 says it was accepted and will happen later
+async fn signup(
+    State(state): State<AppState>,
+    Json(signup): Json<Signup>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let email: Address = signup.email.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            "email is not a valid address".to_owned(),
+        )
+    })?;
+    let mut link = state.verify_url.clone();
+    link.query_pairs_mut()
+        .append_pair("token", &state.verifications.issue(email.clone()));
+    let context = WelcomeContext {
+        verification_link: link.into(),
+        name: signup.name.clone(),
+    };
+    let to = Mailbox::new(Some(signup.name), email);
+    let message = render_email(Welcome, &context, state.from.clone(), to).map_err(|e| {
+        error!("{e}");
+        (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+    })?;
+    state.queue.enqueue(message).map_err(|e| {
+        error!("{e}");
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Try again later".to_owned(),
+        )
+    })?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn verify(
+    State(state): State<AppState>,
+    Query(verify): Query<Verify>,
+) -> Result<String, (StatusCode, &'static str)> {
+    match state.verifications.verify(&verify.token) {
+        Some(email) => Ok(format!("Verified {email}")),
+        None => Err((StatusCode::NOT_FOUND, "The link is invalid or expired")),
+    }
+}
+
+pub fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/signup", post(signup))
+        .route("/verify", get(verify))
+        .with_state(state)
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let (queue, messages) = EmailQueue::new(1000);
+    let sender = spawn_sender(Mailer::from_config(&config).unwrap(), messages);
+    let state = AppState {
+        queue,
+        from: config.mail_from.parse().expect("mail_from was validated"),
+        verifications: Verifications::default(),
+        verify_url: "https://example.com/verify".parse().unwrap(),
+    };
+
+    let listener = TcpListener::bind(config.bind_addr).await.unwrap();
+    axum::serve(listener, app(state))
+        .with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+        .await
+        .unwrap();
+    // The router and with it the last queue handle is dropped, the sender finishes what is left
+    sender.await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        extract::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn message(to: &str) -> Message {
+        Message::builder()
+            .from("noreply@example.com".parse().unwrap())
+            .to(to.parse().unwrap())
+            .subject("Hello")
+            .body(String::from("Hi"))
+            .unwrap()
+    }
+
+    fn state() -> (AppState, mpsc::Receiver<Message>) {
+        let (queue, messages) = EmailQueue::new(10);
+        let state = AppState {
+            queue,
+            from: "noreply@example.com".parse().unwrap(),
+            verifications: Verifications::default(),
+            verify_url: "https://example.com/verify".parse().unwrap(),
+        };
+        (state, messages)
+    }
+
+    async fn send(app: Router, req: Request) -> (StatusCode, String) {
+        let res = app.oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn full_queue_is_reported_instead_of_waiting() {
+        let (queue, _messages) = EmailQueue::new(1);
+        queue.enqueue(message("jane@example.com")).unwrap();
+        assert!(matches!(
+            queue.enqueue(message("jane@example.com")),
+            Err(QueueError::Full)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sender_drains_the_queue_after_the_last_handle_is_gone() {
+        let (queue, messages) = EmailQueue::new(10);
+        queue.enqueue(message("jane@example.com")).unwrap();
+        queue.enqueue(message("john@example.com")).unwrap();
+        let transport = AsyncStubTransport::new_ok();
+        let sender = spawn_sender(Mailer::Stub(transport.clone()), messages);
+        drop(queue);
+        sender.await.unwrap();
+
+        let to: Vec<_> = transport
+            .messages()
+            .await
+            .into_iter()
+            .map(|(envelope, _)| envelope.to()[0].to_string())
+            .collect();
+        assert_eq!(to, ["jane@example.com", "john@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn signup_sends_a_verification_link() {
+        let (state, mut messages) = state();
+        let req = Request::post("/signup")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"name": "Jane", "email": "jane+test@example.com"}"#,
+            ))
+            .unwrap();
+        let (status, _) = send(app(state.clone()), req).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let message = messages.recv().await.unwrap();
+        let token = {
+            let pending = state.verifications.0.lock().unwrap();
+            assert_eq!(pending.len(), 1);
+            pending.keys().next().unwrap().clone()
+        };
+        let link = format!("https://example.com/verify?token={token}");
+        // Undoes the quoted printable encoding of the body
+        let body = String::from_utf8(message.formatted())
+            .unwrap()
+            .replace("=\r\n", "")
+            .replace("=3D", "=");
+        assert!(body.contains(&link));
+
+        let verify = || Request::get(format!("/verify?token={token}")).body(Body::empty());
+        let res = send(app(state.clone()), verify().unwrap()).await;
+        assert_eq!(
+            res,
+            (StatusCode::OK, "Verified jane+test@example.com".to_owned())
+        );
+        // Links only work once
+        let (status, _) = send(app(state), verify().unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_are_rejected() {
+        let (state, _messages) = state();
+        let req = Request::get("/verify?token=guessed")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(app(state), req).await.0, StatusCode::NOT_FOUND);
+    }
+}
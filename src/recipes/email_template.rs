@@ -0,0 +1,171 @@
+//! Typed email templates with askama and lettre
+//! Requires `cargo add askama thiserror`
+//! `cargo add lettre -F tokio1 -F tokio1-native-tls`
+//! `cargo add tokio -F macros -F rt-multi-thread`
+//! Tests require `cargo add --dev insta`
+//! For bigger templates put them into `templates/` and use `#[template(path = "welcome.html")]` instead of `source`
+
+use askama::Template;
+use lettre::{
+    message::{Mailbox, MultiPart},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmailError {
+    #[error("Failed to render email template: {0}")]
+    Render(#[from] askama::Error),
+    #[error("Failed to build email: {0}")]
+    Build(#[from] lettre::error::Error),
+    #[error("Failed to send email: {0}")]
+    Send(#[from] lettre::transport::smtp::Error),
+    #[error("Failed to send email: {0}")]
+    Stub(#[from] lettre::transport::stub::Error),
+}
+
+/// An email consists of a subject and a html and plain text version of the same content
+/// which are rendered from the same typed context
+pub trait EmailTemplate {
+    type Context;
+
+    fn subject(context: &Self::Context) -> String;
+    fn html(context: &Self::Context) -> askama::Result<String>;
+    fn text(context: &Self::Context) -> askama::Result<String>;
+}
+
+pub fn render_email<T: EmailTemplate>(
+    _template: T,
+    context: &T::Context,
+    from: Mailbox,
+    to: Mailbox,
+) -> Result<Message, EmailError> {
+    Ok(Message::builder()
+        .from(from)
+        .to(to)
+        .subject(T::subject(context))
+        // Mail clients that can't display html will fall back to the plain text part
+        .multipart(MultiPart::alternative_plain_html(
+            T::text(context)?,
+            T::html(context)?,
+        ))?)
+}
+
+pub struct Welcome;
+
+pub struct WelcomeContext {
+    pub name: String,
+    pub verification_link: String,
+}
+
+/// The `html` extension makes askama escape every value so a user named `<script>` can't inject markup
+#[derive(Template)]
+#[template(
+    ext = "html",
+    source = r#"<p>Hello {{ ctx.name }},</p>
+<p>please <a href="{{ ctx.verification_link }}">verify your email address</a>.</p>"#
+)]
+struct WelcomeHtml<'a> {
+    ctx: &'a WelcomeContext,
+}
+
+/// Plain text is not interpreted by the mail client so nothing gets escaped here
+#[derive(Template)]
+#[template(
+    ext = "txt",
+    source = "Hello {{ ctx.name }},\n\nplease verify your email address: {{ ctx.verification_link }}"
+)]
+struct WelcomeText<'a> {
+    ctx: &'a WelcomeContext,
+}
+
+impl EmailTemplate for Welcome {
+    type Context = WelcomeContext;
+
+    fn subject(context: &Self::Context) -> String {
+        format!("Welcome {}", context.name)
+    }
+
+    fn html(ctx: &Self::Context) -> askama::Result<String> {
+        WelcomeHtml { ctx }.render()
+    }
+
+    fn text(ctx: &Self::Context) -> askama::Result<String> {
+        WelcomeText { ctx }.render()
+    }
+}
+
+pub async fn send_welcome(
+    mailer: &AsyncSmtpTransport<Tokio1Executor>,
+    to: Mailbox,
+    name: String,
+) -> Result<(), EmailError> {
+    let context = WelcomeContext {
+        verification_link: format!("https://example.com/verify?email={}", to.email),
+        name,
+    };
+    let from = "Example <noreply@example.com>".parse().unwrap();
+    mailer
+        .send(render_email(Welcome, &context, from, to)?)
+        .await?;
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() {
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("localhost")
+        .port(1025)
+        .build();
+    send_welcome(
+        &mailer,
+        "Jane <jane@example.com>".parse().unwrap(),
+        "Jane".into(),
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> WelcomeContext {
+        WelcomeContext {
+            name: "<b>Jane</b>".to_owned(),
+            verification_link: "https://example.com/verify?email=jane@example.com&token=\"x\""
+                .to_owned(),
+        }
+    }
+
+    #[test]
+    fn escapes_html() {
+        insta::assert_snapshot!(Welcome::html(&context()).unwrap(), @r#"
+        <p>Hello &#60;b&#62;Jane&#60;/b&#62;,</p>
+        <p>please <a href="https://example.com/verify?email=jane@example.com&#38;token=&#34;x&#34;">verify your email address</a>.</p>
+        "#);
+    }
+
+    #[test]
+    fn leaves_text_as_is() {
+        insta::assert_snapshot!(Welcome::text(&context()).unwrap(), @r#"
+        Hello <b>Jane</b>,
+
+        please verify your email address: https://example.com/verify?email=jane@example.com&token="x"
+        "#);
+    }
+
+    #[test]
+    fn sends_html_and_text_alternatives() {
+        let message = render_email(
+            Welcome,
+            &context(),
+            "Example <noreply@example.com>".parse().unwrap(),
+            "Jane <jane@example.com>".parse().unwrap(),
+        )
+        .unwrap();
+        let message = String::from_utf8(message.formatted()).unwrap();
+        assert!(message.contains("Subject: Welcome <b>Jane</b>\r\n"));
+        assert!(message.contains("Content-Type: multipart/alternative;"));
+        assert!(message.contains("Content-Type: text/plain; charset=utf-8"));
+        assert!(message.contains("Content-Type: text/html; charset=utf-8"));
+    }
+}
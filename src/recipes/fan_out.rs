@@ -0,0 +1,136 @@
+//! Fetching many urls concurrently with a limit instead of spawning a task per request
+//! Requires `cargo add futures reqwest`
+//! `cargo add tokio -F macros -F rt-multi-thread -F sync -F time`
+//! Tests require `cargo add --dev wiremock`
+//!
+//! `tokio::spawn` for every url starts all requests at once, which gets you rate limited or runs
+//! out of sockets with a long list. `buffer_unordered` only keeps `limit` requests of this batch in
+//! flight and hands back results as they finish. The semaphore bounds requests across all batches
+//! running at the same time, e.g. several handlers each fanning out to the same upstream.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt};
+use reqwest::{Client, StatusCode};
+use tokio::sync::Semaphore;
+
+#[derive(Debug)]
+pub struct Fetched {
+    pub url: String,
+    /// Only the time of the request itself, waiting for a permit is not included
+    pub elapsed: Duration,
+    pub result: Result<StatusCode, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub succeeded: Vec<Fetched>,
+    pub failed: Vec<Fetched>,
+}
+
+pub async fn fetch_all(
+    client: &Client,
+    urls: Vec<String>,
+    limit: usize,
+    permits: &Semaphore,
+) -> Report {
+    let fetches = urls.into_iter().map(|url| async move {
+        let _permit = permits.acquire().await.expect("Semaphore is never closed");
+        let start = Instant::now();
+        let result = match client.get(&url).send().await {
+            Ok(res) if res.status().is_success() => Ok(res.status()),
+            Ok(res) => Err(format!("Status {}", res.status())),
+            Err(e) => Err(e.to_string()),
+        };
+        Fetched {
+            url,
+            elapsed: start.elapsed(),
+            result,
+        }
+    });
+    // The futures are lazy, nothing is sent before the stream polls them
+    stream::iter(fetches)
+        .buffer_unordered(limit)
+        .fold(Report::default(), |mut report, fetched| async move {
+            match fetched.result {
+                Ok(_) => report.succeeded.push(fetched),
+                Err(_) => report.failed.push(fetched),
+            }
+            report
+        })
+        .await
+}
+
+#[tokio::main]
+pub async fn main() {
+    let client = Client::builder()
+        // A single hanging url would otherwise hold up the whole batch
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    // Shared by everything calling the same upstream
+    let permits = Arc::new(Semaphore::new(20));
+    let urls = (0..50)
+        .map(|i| format!("https://httpbin.org/delay/{}", i % 3))
+        .collect();
+    let report = fetch_all(&client, urls, 10, &permits).await;
+    for fetched in report.succeeded.iter().chain(&report.failed) {
+        println!("{:?} {} {:?}", fetched.elapsed, fetched.url, fetched.result);
+    }
+    println!(
+        "{} succeeded, {} failed",
+        report.succeeded.len(),
+        report.failed.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sorts_results_into_successes_and_failures() {
+        let server = MockServer::start().await;
+        Mock::given(path("/ok"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(3)
+            .mount(&server)
+            .await;
+        Mock::given(path("/broken"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut urls = vec![format!("{}/ok", server.uri()); 3];
+        urls.push(format!("{}/broken", server.uri()));
+        let report = fetch_all(&Client::new(), urls, 2, &Semaphore::new(2)).await;
+        assert_eq!(report.succeeded.len(), 3);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(
+            report.failed[0].result,
+            Err("Status 500 Internal Server Error".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_requests_in_flight() {
+        let server = MockServer::start().await;
+        Mock::given(path("/slow"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(100)))
+            .mount(&server)
+            .await;
+
+        let urls = vec![format!("{}/slow", server.uri()); 4];
+        let start = Instant::now();
+        let report = fetch_all(&Client::new(), urls, 2, &Semaphore::new(10)).await;
+        assert_eq!(report.succeeded.len(), 4);
+        // Two rounds of two requests
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,199 @@
+//! Uploading files with `multipart/form-data` and downloading them again
+//! Requires `cargo add axum -F multipart`
+//! `cargo add tokio-util -F io`
+//! `cargo add uuid -F v4 -F serde`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F fs -F io-util`
+//!
+//! Upload with `curl -F file=@Cargo.toml localhost:8080/files` and download the file with the
+//! returned id from `localhost:8080/files/<id>`.
+//! Both directions stream so a file never has to fit into memory. Files are stored under a
+//! generated id, the name sent by the client is only ever used in the `Content-Disposition` header
+//! so a name like `../../etc/passwd` can't escape the upload directory.
+//! See the `storage` recipe for keeping files in S3 instead, which survives the container.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::{multipart::Field, DefaultBodyLimit, Multipart, Path, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use tokio::{fs::File, io::AsyncWriteExt, net::TcpListener};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+const UPLOAD_DIR: &str = "uploads";
+const MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredFile {
+    pub id: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+/// Stands in for your real database
+type Files = Arc<Mutex<HashMap<Uuid, StoredFile>>>;
+
+async fn upload(
+    State(files): State<Files>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<StoredFile>>, (StatusCode, String)> {
+    let bad_request = |e: axum::extract::multipart::MultipartError| (e.status(), e.body_text());
+    let mut stored = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let file = save(field).await?;
+        files.lock().unwrap().insert(file.id, file.clone());
+        stored.push(file);
+    }
+    Ok(Json(stored))
+}
+
+/// Writes to a temporary file first so a failed upload never shows up as a truncated file
+async fn save(mut field: Field<'_>) -> Result<StoredFile, (StatusCode, String)> {
+    let id = Uuid::new_v4();
+    let name = field.file_name().unwrap_or("upload").to_owned();
+    let content_type = field
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+    let path = PathBuf::from(UPLOAD_DIR).join(id.to_string());
+    let partial = path.with_extension("part");
+
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let mut out = File::create(&partial).await.map_err(internal)?;
+    let mut size = 0;
+    let result = loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err((e.status(), e.body_text())),
+        };
+        size += chunk.len() as u64;
+        if size > MAX_FILE_SIZE {
+            break Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Files can be at most {MAX_FILE_SIZE} bytes"),
+            ));
+        }
+        if let Err(e) = out.write_all(&chunk).await {
+            break Err(internal(e));
+        }
+    };
+    // Data can still sit in a buffer of the file, without a flush errors writing it are lost
+    let result = match result {
+        Ok(()) => out.flush().await.map_err(internal),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&partial, &path).await.map_err(internal)?;
+    Ok(StoredFile {
+        id,
+        name,
+        content_type,
+        size,
+    })
+}
+
+async fn download(State(files): State<Files>, Path(id): Path<Uuid>) -> Response {
+    let Some(stored) = files.lock().unwrap().get(&id).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let file = match File::open(PathBuf::from(UPLOAD_DIR).join(id.to_string())).await {
+        Ok(file) => file,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let content_type = HeaderValue::from_str(&stored.content_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    // An uploaded html file rendered inline would run its scripts on our origin, downloading it as
+    // an attachment without sniffing its type prevents that
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, HeaderValue::from(stored.size)),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition(&stored.name),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response()
+}
+
+/// `filename` is for old clients and only gets a plain ascii version of the name,
+/// `filename*` carries the real name percent encoded
+fn content_disposition(name: &str) -> HeaderValue {
+    let ascii = name
+        .chars()
+        .map(|c| match c {
+            ' ' | '.' | '-' | '_' => c,
+            c if c.is_ascii_alphanumeric() => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let encoded = name
+        .bytes()
+        .map(|b| match b {
+            b'.' | b'-' | b'_' => (b as char).to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("%{b:02X}"),
+        })
+        .collect::<String>();
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{ascii}\"; filename*=UTF-8''{encoded}"
+    ))
+    .expect("Only ascii is left in the header")
+}
+
+#[tokio::main]
+pub async fn main() {
+    tokio::fs::create_dir_all(UPLOAD_DIR).await.unwrap();
+    let app = Router::new()
+        .route(
+            "/files",
+            // The default limit of 2MB applies to the whole request, multipart overhead included
+            post(upload).layer(DefaultBodyLimit::max(MAX_FILE_SIZE as usize + 64 * 1024)),
+        )
+        .route("/files/:id", get(download))
+        .with_state(Files::default());
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_disposition_escapes_names() {
+        assert_eq!(
+            content_disposition("report 2024.pdf"),
+            "attachment; filename=\"report 2024.pdf\"; filename*=UTF-8''report%202024.pdf"
+        );
+        assert_eq!(
+            content_disposition("a\"b\r\nä.txt"),
+            "attachment; filename=\"a_b___.txt\"; filename*=UTF-8''a%22b%0D%0A%C3%A4.txt"
+        );
+    }
+}
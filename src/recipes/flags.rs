@@ -0,0 +1,203 @@
+//! Feature flags from a config file, switched on for everyone or rolled out to a percentage of users
+//! Requires `cargo add axum thiserror toml`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F fs`
+//!
+//! The flags are read from `flags.toml`:
+//! ```toml
+//! [flags]
+//! new_checkout = true
+//! fancy_search = { rollout_percent = 25 }
+//! ```
+//! A user is either in a rollout or not and stays there on every request and every instance, raising
+//! the percentage only adds users. Flags missing from the file are off, so removing a flag turns it off.
+//! See the `config` recipe for reloading the file while running and the `degradation` recipe for
+//! switching off dependencies at runtime.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, State},
+    http::request::Parts,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+const FLAGS_FILE: &str = "flags.toml";
+/// Replace it with the id of the logged in user, e.g. from the `AuthUser` of the `auth` recipe
+const USER_HEADER: &str = "x-user-id";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Flag {
+    Enabled(bool),
+    Rollout { rollout_percent: u8 },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlagsFile {
+    #[serde(default)]
+    flags: BTreeMap<String, Flag>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FlagsError {
+    #[error("Invalid flags file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("rollout_percent of {0} must be between 0 and 100")]
+    InvalidPercent(String),
+}
+
+/// Cheap to clone, every request gets the same flags
+#[derive(Debug, Clone, Default)]
+pub struct FeatureFlags(Arc<BTreeMap<String, Flag>>);
+
+impl FeatureFlags {
+    pub fn from_toml(content: &str) -> Result<Self, FlagsError> {
+        let file: FlagsFile = toml::from_str(content)?;
+        if let Some((name, _)) = file.flags.iter().find(
+            |(_, flag)| matches!(flag, Flag::Rollout { rollout_percent } if *rollout_percent > 100),
+        ) {
+            return Err(FlagsError::InvalidPercent(name.clone()));
+        }
+        Ok(Self(Arc::new(file.flags)))
+    }
+
+    /// Users without an id are only part of rollouts at 100 percent
+    pub fn is_enabled(&self, flag: &str, user: Option<&str>) -> bool {
+        match self.0.get(flag) {
+            None => false,
+            Some(Flag::Enabled(enabled)) => *enabled,
+            Some(Flag::Rollout { rollout_percent }) => match user {
+                _ if *rollout_percent >= 100 => true,
+                Some(user) => bucket(flag, user) < u32::from(*rollout_percent),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Puts every user into one of 100 buckets. The flag name is part of the hash so the first
+/// 10 percent of one rollout are not always the same users as the first 10 percent of another.
+/// FNV-1a instead of the std hasher because the result must not change between Rust versions.
+fn bucket(flag: &str, user: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in flag.bytes().chain([0]).chain(user.bytes()) {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash % 100
+}
+
+/// The flags as seen by the user making the request
+pub struct Flags {
+    flags: FeatureFlags,
+    user: Option<String>,
+}
+
+impl Flags {
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.flags.is_enabled(flag, self.user.as_deref())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Flags
+where
+    FeatureFlags: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts
+            .headers
+            .get(USER_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+        Ok(Flags {
+            flags: FeatureFlags::from_ref(state),
+            user,
+        })
+    }
+}
+
+async fn search(flags: Flags) -> &'static str {
+    if flags.is_enabled("fancy_search") {
+        "Fancy search results"
+    } else {
+        "Plain search results"
+    }
+}
+
+/// In a real app you want to protect this route, see the `admin_shutdown` recipe
+async fn list_flags(State(flags): State<FeatureFlags>) -> Json<BTreeMap<String, Flag>> {
+    Json(flags.0.as_ref().clone())
+}
+
+pub fn app(flags: FeatureFlags) -> Router {
+    Router::new()
+        .route("/search", get(search))
+        .route("/admin/flags", get(list_flags))
+        .with_state(flags)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let flags = match tokio::fs::read_to_string(FLAGS_FILE).await {
+        Ok(content) => FeatureFlags::from_toml(&content).unwrap(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => FeatureFlags::default(),
+        Err(e) => panic!("Failed to read {FLAGS_FILE}: {e}"),
+    };
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(flags)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_booleans_and_rollouts() {
+        let flags = FeatureFlags::from_toml(
+            r#"
+            [flags]
+            on = true
+            off = false
+            all = { rollout_percent = 100 }
+            none = { rollout_percent = 0 }
+            "#,
+        )
+        .unwrap();
+        assert!(flags.is_enabled("on", None));
+        assert!(!flags.is_enabled("off", Some("alice")));
+        assert!(flags.is_enabled("all", None));
+        assert!(!flags.is_enabled("none", Some("alice")));
+        assert!(!flags.is_enabled("missing", Some("alice")));
+        assert!(matches!(
+            FeatureFlags::from_toml("[flags]\nx = { rollout_percent = 101 }"),
+            Err(FlagsError::InvalidPercent(_))
+        ));
+    }
+
+    #[test]
+    fn rollouts_are_roughly_sized_and_only_grow() {
+        let flags = FeatureFlags::from_toml("[flags]\nhalf = { rollout_percent = 50 }").unwrap();
+        let users = (0..10_000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let enabled = users
+            .iter()
+            .filter(|user| flags.is_enabled("half", Some(user)))
+            .count();
+        assert!((4_500..5_500).contains(&enabled), "{enabled}");
+        // Raising the percentage keeps everyone who already had the flag
+        let more = FeatureFlags::from_toml("[flags]\nhalf = { rollout_percent = 60 }").unwrap();
+        assert!(users
+            .iter()
+            .filter(|user| flags.is_enabled("half", Some(user)))
+            .all(|user| more.is_enabled("half", Some(user))));
+    }
+}
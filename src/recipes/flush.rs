@@ -0,0 +1,194 @@
+//! Flushing buffered logs and metrics on shutdown
+//! Requires `cargo add axum futures metrics metrics-exporter-prometheus reqwest tracing tracing-appender`
+//! `cargo add tracing-subscriber -F env-filter`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net -F time -F sync`
+//! Tests require `cargo add --dev tokio -F test-util`
+
+use std::{future::Future, time::Duration};
+
+use axum::{routing::get, Router};
+use futures::future::BoxFuture;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use tokio::{net::TcpListener, sync::oneshot};
+use tracing::{info, warn};
+use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
+
+type FlushFn = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+/// Everything that buffers data and needs to be flushed before the process exits
+#[derive(Default)]
+pub struct FlushOnShutdown {
+    sinks: Vec<(&'static str, FlushFn)>,
+}
+
+impl FlushOnShutdown {
+    pub fn add<F, Fut>(&mut self, name: &'static str, flush: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.sinks.push((name, Box::new(move || Box::pin(flush()))));
+    }
+
+    /// Flushes that block like dropping a [`tracing_appender::non_blocking::WorkerGuard`] get their own thread.
+    /// Unlike `spawn_blocking` a thread that hangs forever does not keep the tokio runtime from shutting down.
+    pub fn add_blocking<F>(&mut self, name: &'static str, flush: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.add(name, move || {
+            let (tx, rx) = oneshot::channel();
+            std::thread::spawn(move || {
+                flush();
+                let _ = tx.send(());
+            });
+            async move {
+                let _ = rx.await;
+            }
+        });
+    }
+
+    /// Flushes the sinks in the order they were added, giving each up to `timeout` so a hanging
+    /// sink can't keep the ones after it from flushing. Keep the sum below the grace period of
+    /// your orchestrator. Add the log writer last so warnings about the other sinks still make it
+    /// into the logs. Returns the sinks that timed out.
+    pub async fn flush(self, timeout: Duration) -> Vec<&'static str> {
+        let mut timed_out = Vec::new();
+        for (name, flush) in self.sinks {
+            if tokio::time::timeout(timeout, flush()).await.is_err() {
+                warn!("Flushing {name} timed out after {timeout:?}");
+                timed_out.push(name);
+            }
+        }
+        timed_out
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let (writer, log_guard) =
+        tracing_appender::non_blocking(tracing_appender::rolling::never("logs", "app.log"));
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(writer)
+        .finish()
+        .init();
+    let metrics = PrometheusBuilder::new().install_recorder().unwrap();
+
+    let mut flush = FlushOnShutdown::default();
+    flush.add("metrics", move || push_metrics(metrics));
+    flush.add_blocking("logs", move || drop(log_guard));
+
+    let app = Router::new().route(
+        "/",
+        get(|| async {
+            metrics::counter!("requests").increment(1);
+            "Hello, world!"
+        }),
+    );
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+        .await
+        .unwrap();
+    info!("Server stopped, flushing buffers");
+    flush.flush(Duration::from_secs(5)).await;
+}
+
+/// Push the final state of the metrics to a prometheus pushgateway as nobody is going to scrape us anymore
+async fn push_metrics(metrics: PrometheusHandle) {
+    let result = reqwest::Client::new()
+        .put("http://localhost:9091/metrics/job/my-service")
+        .body(metrics.render())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    if let Err(e) = result {
+        warn!("Failed to push metrics: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Stands in for a file or socket that the non blocking appender writes to
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_logs_written_just_before_shutdown() {
+        let capture = Capture::default();
+        let (writer, log_guard) = tracing_appender::non_blocking(capture.clone());
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(writer)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let mut flush = FlushOnShutdown::default();
+        flush.add_blocking("logs", move || drop(log_guard));
+        for i in 0..100 {
+            info!("Last words {i}");
+        }
+        flush.flush(Duration::from_secs(5)).await;
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(logs.lines().count(), 100);
+        assert!(logs.contains("Last words 99"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hanging_flushes_time_out() {
+        let mut flush = FlushOnShutdown::default();
+        flush.add("hangs", std::future::pending);
+        // Never finishes, the sender is kept alive until the end of the test
+        let (_hang, blocked) = std::sync::mpsc::channel::<()>();
+        flush.add_blocking("blocks", move || {
+            let _ = blocked.recv();
+        });
+        let flushed = Arc::new(Mutex::new(false));
+        let logs = flushed.clone();
+        flush.add("logs", move || async move { *logs.lock().unwrap() = true });
+
+        let start = Instant::now();
+        let timed_out = flush.flush(Duration::from_secs(5)).await;
+        assert_eq!(timed_out, ["hangs", "blocks"]);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        // The sinks before it didn't use up its time
+        assert!(*flushed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn flushes_in_order() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut flush = FlushOnShutdown::default();
+        for name in ["metrics", "traces", "logs"] {
+            let order = order.clone();
+            flush.add(
+                name,
+                move || async move { order.lock().unwrap().push(name) },
+            );
+        }
+        flush.flush(Duration::from_secs(5)).await;
+        assert_eq!(*order.lock().unwrap(), ["metrics", "traces", "logs"]);
+    }
+}
@@ -0,0 +1,189 @@
+//! A GraphQL api with queries, mutations and subscriptions over websockets
+//! Requires `cargo add async-graphql async-graphql-axum axum futures`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F sync`
+//!
+//! Open `http://localhost:8080/graphql` for the GraphiQL playground, e.g. run
+//! `subscription { bookAdded { id title } }` in one tab and
+//! `mutation { addBook(title: "Dune", author: "Frank Herbert") { id } }` in another.
+//! Queries and mutations are plain POSTs to `/graphql`, subscriptions use the `graphql-transport-ws`
+//! protocol on `/ws`. Field names are camelCase in the schema, the Rust code stays snake_case.
+
+use std::sync::Mutex;
+
+use async_graphql::{
+    http::GraphiQLSource, Context, Object, Schema, SimpleObject, Subscription, ID,
+};
+use async_graphql_axum::{GraphQL, GraphQLSubscription};
+use axum::{
+    response::{Html, IntoResponse},
+    routing::get,
+    Router,
+};
+use futures::Stream;
+use tokio::{net::TcpListener, sync::broadcast};
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Book {
+    pub id: ID,
+    pub title: String,
+    pub author: String,
+}
+
+/// Stands in for a database, reachable from every resolver through the schema data
+pub struct Library {
+    books: Mutex<Vec<Book>>,
+    added: broadcast::Sender<Book>,
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        Self {
+            books: Mutex::default(),
+            added: broadcast::channel(64).0,
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn books(&self, ctx: &Context<'_>) -> Vec<Book> {
+        ctx.data_unchecked::<Library>()
+            .books
+            .lock()
+            .unwrap()
+            .clone()
+    }
+
+    /// Returns null for unknown ids
+    async fn book(&self, ctx: &Context<'_>, id: ID) -> Option<Book> {
+        let books = ctx.data_unchecked::<Library>().books.lock().unwrap();
+        books.iter().find(|book| book.id == id).cloned()
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Errors returned here end up in the `errors` list of the response next to a null field
+    async fn add_book(
+        &self,
+        ctx: &Context<'_>,
+        title: String,
+        author: String,
+    ) -> async_graphql::Result<Book> {
+        if title.trim().is_empty() {
+            return Err("The title must not be empty".into());
+        }
+        let library = ctx.data_unchecked::<Library>();
+        let book = {
+            let mut books = library.books.lock().unwrap();
+            let book = Book {
+                id: ID::from(books.len() + 1),
+                title,
+                author,
+            };
+            books.push(book.clone());
+            book
+        };
+        // Nobody subscribed is not an error
+        let _ = library.added.send(book.clone());
+        Ok(book)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    async fn book_added(&self, ctx: &Context<'_>) -> impl Stream<Item = Book> {
+        let added = ctx.data_unchecked::<Library>().added.subscribe();
+        futures::stream::unfold(added, |mut added| async move {
+            loop {
+                match added.recv().await {
+                    Ok(book) => return Some((book, added)),
+                    // A slow client misses some books instead of holding up the others
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+/// Without subscriptions pass `EmptySubscription` and leave out the `/ws` route
+pub type LibrarySchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub fn schema() -> LibrarySchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(Library::default())
+        // Bounds how expensive a single request can get, a client can't ask for everything at once
+        .limit_depth(10)
+        .limit_complexity(500)
+        .finish()
+}
+
+async fn graphiql() -> impl IntoResponse {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/ws")
+            .finish(),
+    )
+}
+
+pub fn app(schema: LibrarySchema) -> Router {
+    Router::new()
+        .route(
+            "/graphql",
+            get(graphiql).post_service(GraphQL::new(schema.clone())),
+        )
+        .route_service("/ws", GraphQLSubscription::new(schema))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(schema())).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn added_books_can_be_queried_and_are_published() {
+        let schema = schema();
+        let mut added = schema.execute_stream("subscription { bookAdded { title } }");
+        // The first poll subscribes, books added before that are not sent
+        assert!(futures::poll!(added.next()).is_pending());
+        let res = schema
+            .execute(r#"mutation { addBook(title: "Dune", author: "Frank Herbert") { id } }"#)
+            .await;
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+        let res = schema
+            .execute(r#"{ book(id: "1") { title author } }"#)
+            .await;
+        assert_eq!(
+            res.data.into_json().unwrap(),
+            serde_json::json!({ "book": { "title": "Dune", "author": "Frank Herbert" } })
+        );
+        let event = added.next().await.unwrap();
+        assert_eq!(
+            event.data.into_json().unwrap(),
+            serde_json::json!({ "bookAdded": { "title": "Dune" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_titles() {
+        let res = schema()
+            .execute(r#"mutation { addBook(title: " ", author: "Nobody") { id } }"#)
+            .await;
+        assert_eq!(res.errors[0].message, "The title must not be empty");
+    }
+}
@@ -0,0 +1,87 @@
+//! gRPC service and client using tonic
+//! Requires `cargo add tonic prost`
+//! `cargo add tokio-stream -F time`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net -F time`
+//! `cargo add --build tonic-build` and `protoc` (e.g. `apt install protobuf-compiler`)
+//!
+//! `build.rs` generates the messages, the server trait and the client from `proto/greeter.proto`.
+//! Try it with `grpcurl -plaintext -import-path proto -proto greeter.proto -d '{"name": "you"}' localhost:50051 greeter.Greeter/SayHello`
+
+use std::{pin::Pin, time::Duration};
+
+use tokio_stream::{Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status};
+
+/// The generated code, `cargo doc --features grpc-recipe` shows everything in it
+pub mod proto {
+    tonic::include_proto!("greeter");
+}
+
+use proto::{
+    greeter_client::GreeterClient,
+    greeter_server::{Greeter, GreeterServer},
+    HelloReply, HelloRequest,
+};
+
+#[derive(Debug, Default)]
+pub struct MyGreeter;
+
+#[tonic::async_trait]
+impl Greeter for MyGreeter {
+    async fn say_hello(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<HelloReply>, Status> {
+        let name = request.into_inner().name;
+        // Status carries the gRPC error code, clients match on the code and not the message
+        if name.is_empty() {
+            return Err(Status::invalid_argument("name must not be empty"));
+        }
+        Ok(Response::new(HelloReply {
+            message: format!("Hello {name}"),
+        }))
+    }
+
+    type SayHelloStreamStream = Pin<Box<dyn Stream<Item = Result<HelloReply, Status>> + Send>>;
+
+    async fn say_hello_stream(
+        &self,
+        request: Request<HelloRequest>,
+    ) -> Result<Response<Self::SayHelloStreamStream>, Status> {
+        let name = request.into_inner().name;
+        // The stream is dropped when the client disconnects so there is nothing to clean up
+        let replies = tokio_stream::iter(1..)
+            .throttle(Duration::from_secs(1))
+            .map(move |i| {
+                Ok(HelloReply {
+                    message: format!("Hello {name} #{i}"),
+                })
+            });
+        Ok(Response::new(Box::pin(replies)))
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let addr = "0.0.0.0:50051".parse().unwrap();
+    let server = tokio::spawn(
+        Server::builder()
+            .add_service(GreeterServer::new(MyGreeter))
+            .serve_with_shutdown(addr, async { tokio::signal::ctrl_c().await.unwrap() }),
+    );
+
+    // Channels reconnect by themselves and are cheap to clone, create one per upstream and share it
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut client = GreeterClient::connect("http://127.0.0.1:50051")
+        .await
+        .unwrap();
+    let reply = client
+        .say_hello(HelloRequest {
+            name: "client".to_owned(),
+        })
+        .await
+        .unwrap();
+    println!("{}", reply.into_inner().message);
+
+    server.await.unwrap().unwrap();
+}
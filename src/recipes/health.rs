@@ -0,0 +1,115 @@
+//! Liveness and readiness endpoints for Kubernetes probes
+//! Requires `cargo add axum`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F signal -F sync -F time`
+//! Builds on the `health_checks` recipe which runs the checks.
+//!
+//! `/healthz` only tells whether the process still answers, a failing liveness probe restarts the pod.
+//! It never looks at dependencies, otherwise a database outage would restart every pod at once.
+//! `/readyz` aggregates the checks the subsystems registered, a failing readiness probe only stops
+//! traffic to the pod until it recovers.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use tokio::{net::TcpListener, sync::RwLock};
+
+use crate::recipes::health_checks::{CheckResult, HealthChecks, Status};
+
+/// Cheap to clone so every subsystem can register its own check once it is set up
+#[derive(Clone)]
+pub struct HealthRegistry {
+    checks: Arc<RwLock<HealthChecks>>,
+    draining: Arc<AtomicBool>,
+}
+
+impl HealthRegistry {
+    pub fn new(cache_ttl: Duration) -> Self {
+        Self {
+            checks: Arc::new(RwLock::new(HealthChecks::new(cache_ttl))),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A check that takes longer than `timeout` counts as down
+    pub async fn register<F, Fut>(&self, name: &'static str, timeout: Duration, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = CheckResult> + Send + 'static,
+    {
+        self.checks.write().await.register(name, timeout, check);
+    }
+
+    /// Fails readiness so the load balancer stops sending requests before the server stops accepting them
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Merge these into the app, they work with any state of the app router
+    pub fn routes<S: Clone + Send + Sync + 'static>(self) -> Router<S> {
+        Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(self)
+    }
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn readyz(State(registry): State<HealthRegistry>) -> impl IntoResponse {
+    if registry.draining.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response();
+    }
+    let report = registry.checks.read().await.report().await;
+    let status = match report.status {
+        Status::Up | Status::Degraded => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report)).into_response()
+}
+
+fn up() -> CheckResult {
+    CheckResult {
+        status: Status::Up,
+        detail: None,
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let health = HealthRegistry::new(Duration::from_secs(2));
+    // e.g. `sqlx::query("SELECT 1").execute(&pool)`
+    health
+        .register("db", Duration::from_secs(1), || async { up() })
+        .await;
+    // Subsystems started later register their checks when they are connected
+    let queue_health = health.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        queue_health
+            .register("queue", Duration::from_secs(1), || async { up() })
+            .await;
+    });
+
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .merge(health.clone().routes());
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            tokio::signal::ctrl_c().await.unwrap();
+            health.start_draining();
+            // Give the readiness probe time to notice before new connections are refused
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        })
+        .await
+        .unwrap();
+}
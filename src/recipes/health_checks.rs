@@ -0,0 +1,252 @@
+//! Readiness endpoint aggregating the health of every dependency
+//! Requires `cargo add axum futures`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F sync -F time`
+//! Tests require `cargo add --dev serde_json`
+//! `cargo add --dev tower -F util`
+//! `cargo add --dev tokio -F test-util`
+//! See the `health` recipe for registering checks from several subsystems and a liveness endpoint.
+
+use std::{collections::BTreeMap, future::Future, sync::Arc, time::Duration};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use futures::future::{join_all, BoxFuture};
+use serde::Serialize;
+use tokio::{net::TcpListener, sync::Mutex, time::Instant};
+
+/// Ordered from best to worst so the overall status is simply the maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Degraded,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, CheckResult>,
+}
+
+type CheckFn = Box<dyn Fn() -> BoxFuture<'static, CheckResult> + Send + Sync>;
+
+struct Check {
+    name: &'static str,
+    timeout: Duration,
+    run: CheckFn,
+}
+
+pub struct HealthChecks {
+    checks: Vec<Check>,
+    cache_ttl: Duration,
+    /// A tokio mutex so concurrent probes wait for one refresh instead of all hitting the dependencies
+    cache: Mutex<Option<(Instant, HealthReport)>>,
+}
+
+impl HealthChecks {
+    /// Results are reused for `cache_ttl` so aggressive probing does not hammer the dependencies
+    pub fn new(cache_ttl: Duration) -> Self {
+        Self {
+            checks: Vec::new(),
+            cache_ttl,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// A check that takes longer than `timeout` counts as down
+    pub fn register<F, Fut>(&mut self, name: &'static str, timeout: Duration, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = CheckResult> + Send + 'static,
+    {
+        self.checks.push(Check {
+            name,
+            timeout,
+            run: Box::new(move || Box::pin(check())),
+        });
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        let mut cache = self.cache.lock().await;
+        if let Some((checked_at, report)) = &*cache {
+            if checked_at.elapsed() < self.cache_ttl {
+                return report.clone();
+            }
+        }
+        // All checks run at the same time so the probe takes as long as the slowest check, not the sum
+        let results = join_all(self.checks.iter().map(|check| async move {
+            let result = tokio::time::timeout(check.timeout, (check.run)())
+                .await
+                .unwrap_or_else(|_| CheckResult {
+                    status: Status::Down,
+                    detail: Some(format!("Timed out after {:?}", check.timeout)),
+                });
+            (check.name, result)
+        }))
+        .await;
+        let report = HealthReport {
+            status: results
+                .iter()
+                .map(|(_, result)| result.status)
+                .max()
+                .unwrap_or(Status::Up),
+            checks: results.into_iter().collect(),
+        };
+        *cache = Some((Instant::now(), report.clone()));
+        report
+    }
+}
+
+/// Degraded still counts as ready, only a dependency that is down takes us out of rotation
+async fn readyz(State(health): State<Arc<HealthChecks>>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report().await;
+    let status = match report.status {
+        Status::Up | Status::Degraded => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let mut health = HealthChecks::new(Duration::from_secs(2));
+    health.register("db", Duration::from_secs(1), || async {
+        // e.g. `sqlx::query("SELECT 1").execute(&pool)`
+        CheckResult {
+            status: Status::Up,
+            detail: None,
+        }
+    });
+    health.register("upstream-x", Duration::from_secs(1), || async {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        CheckResult {
+            status: Status::Up,
+            detail: None,
+        }
+    });
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(health)).await.unwrap();
+}
+
+pub fn app(health: HealthChecks) -> Router {
+    Router::new()
+        .route("/readyz", get(readyz))
+        .with_state(Arc::new(health))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{
+        body::{to_bytes, Body},
+        extract::Request,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn result(status: Status, detail: Option<&str>) -> CheckResult {
+        CheckResult {
+            status,
+            detail: detail.map(str::to_owned),
+        }
+    }
+
+    async fn readyz(health: HealthChecks) -> (StatusCode, Value) {
+        let req = Request::get("/readyz").body(Body::empty()).unwrap();
+        let res = app(health).oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn lists_every_check_and_the_worst_status() {
+        let mut health = HealthChecks::new(Duration::ZERO);
+        health.register("db", Duration::from_secs(1), || async {
+            result(Status::Up, None)
+        });
+        health.register("redis", Duration::from_secs(1), || async {
+            result(Status::Down, Some("Connection refused"))
+        });
+
+        let (status, body) = readyz(health).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({
+                "status": "down",
+                "checks": {
+                    "db": { "status": "up" },
+                    "redis": { "status": "down", "detail": "Connection refused" },
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded_is_still_ready() {
+        let mut health = HealthChecks::new(Duration::ZERO);
+        health.register("db", Duration::from_secs(1), || async {
+            result(Status::Up, None)
+        });
+        health.register("cache", Duration::from_secs(1), || async {
+            result(Status::Degraded, Some("Evicting"))
+        });
+
+        let (status, body) = readyz(health).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "degraded");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hanging_checks_time_out_on_their_own() {
+        let mut health = HealthChecks::new(Duration::ZERO);
+        health.register("db", Duration::from_secs(1), || async {
+            result(Status::Up, None)
+        });
+        health.register("upstream-x", Duration::from_secs(2), || async {
+            std::future::pending().await
+        });
+
+        let start = Instant::now();
+        let report = health.report().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(report.checks["db"].status, Status::Up);
+        assert_eq!(report.checks["upstream-x"].status, Status::Down);
+        assert_eq!(
+            report.checks["upstream-x"].detail.as_deref(),
+            Some("Timed out after 2s")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn results_are_cached() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut health = HealthChecks::new(Duration::from_secs(2));
+        health.register("db", Duration::from_secs(1), {
+            let runs = runs.clone();
+            move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+                async { result(Status::Up, None) }
+            }
+        });
+
+        health.report().await;
+        health.report().await;
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        health.report().await;
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,114 @@
+//! Server side rendered html pages with askama templates sharing a base layout
+//! Requires `cargo add askama axum`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! The templates live in `templates/` next to `Cargo.toml` and are compiled into the binary, so a
+//! missing variable or a typo in a template is a compile error instead of a broken page.
+//! `index.html` and `user.html` start with `{% extends "base.html" %}` and only fill in its blocks.
+//! See the `email_template` recipe for rendering emails with askama.
+
+use askama::Template;
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tokio::net::TcpListener;
+
+/// Stands in for a database
+const USERS: &[(&str, &str)] = &[("alice", "2021-03-14"), ("bob", "2023-11-02")];
+
+#[derive(Debug)]
+pub enum HtmlError {
+    NotFound,
+    Render(askama::Error),
+}
+
+impl From<askama::Error> for HtmlError {
+    fn from(e: askama::Error) -> Self {
+        HtmlError::Render(e)
+    }
+}
+
+impl IntoResponse for HtmlError {
+    fn into_response(self) -> Response {
+        match self {
+            HtmlError::NotFound => {
+                (StatusCode::NOT_FOUND, Html("<h1>Page not found</h1>")).into_response()
+            }
+            // The details go to the log, not to the visitor
+            HtmlError::Render(e) => {
+                eprintln!("Failed to render template: {e}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Html("<h1>Something went wrong</h1>"),
+                )
+                    .into_response()
+            }
+        }
+    }
+}
+
+/// Rendering into a `String` first means a failing template never sends half a page with a 200
+pub fn render(template: &impl Template) -> Result<Html<String>, HtmlError> {
+    Ok(Html(template.render()?))
+}
+
+/// Every value is html escaped because of the file extension, `{{ user }}` can't inject markup
+#[derive(Template)]
+#[template(path = "index.html")]
+pub struct IndexPage<'a> {
+    pub users: Vec<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "user.html")]
+pub struct UserPage<'a> {
+    pub name: &'a str,
+    pub joined: &'a str,
+}
+
+async fn index() -> Result<Html<String>, HtmlError> {
+    render(&IndexPage {
+        users: USERS.iter().map(|(name, _)| *name).collect(),
+    })
+}
+
+async fn user(Path(name): Path<String>) -> Result<Html<String>, HtmlError> {
+    let (name, joined) = USERS
+        .iter()
+        .find(|(user, _)| *user == name)
+        .ok_or(HtmlError::NotFound)?;
+    render(&UserPage { name, joined })
+}
+
+pub fn app() -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/users/:name", get(user))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app()).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_into_the_layout_and_escapes_values() {
+        let page = render(&IndexPage {
+            users: vec!["<script>"],
+        })
+        .unwrap()
+        .0;
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("<title>Home - Example</title>"));
+        assert!(!page.contains("<script>"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Http client
+//! Requires `cargo add reqwest -F json`
+//! Requires `cargo add tokio -F time`
+//! Tests require `cargo add --dev wiremock`
+//!
+//! `HttpClient` adds what a bare `Client` lacks in production: a timeout for every request and
+//! retries of transient failures with backoff. Only idempotent requests are retried, see the
+//! `idempotent_retry` recipe for retrying POSTs and the `api_client` recipe for wrapping an api in
+//! a typed client.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use reqwest::{Client, Method, RequestBuilder, Response};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Applies to every attempt on its own
+    pub timeout: Duration,
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Limits retries to a share of all requests. When an upstream is down every request failing
+/// would otherwise be sent `max_attempts` times and multiply the load on it.
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// In tenths of a retry so every request can deposit a fraction of one
+    balance: AtomicU32,
+    max: u32,
+}
+
+impl RetryBudget {
+    /// Every request earns a tenth of a retry, at most `max_retries` can be saved up
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            balance: AtomicU32::new(max_retries * 10),
+            max: max_retries * 10,
+        }
+    }
+
+    fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                Some((b + 1).min(self.max))
+            });
+    }
+
+    fn withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| b.checked_sub(10))
+            .is_ok()
+    }
+}
+
+/// Cheap to clone, all clones share the connection pool and the retry budget
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client,
+    policy: RetryPolicy,
+    budget: Arc<RetryBudget>,
+}
+
+impl HttpClient {
+    pub fn new(client: Client, policy: RetryPolicy, budget: RetryBudget) -> Self {
+        Self {
+            client,
+            policy,
+            budget: Arc::new(budget),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    /// Returns the last response when all attempts got a server error so the caller can look at it
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let request = request.timeout(self.policy.timeout).build()?;
+        // Streaming bodies can't be cloned and are sent only once
+        let retryable = request.method().is_idempotent() && request.try_clone().is_some();
+        self.budget.deposit();
+        if !retryable {
+            return self.client.execute(request).await;
+        }
+        let mut attempt = 1;
+        loop {
+            let attempt_request = request.try_clone().expect("Checked to be cloneable above");
+            let result = self.client.execute(attempt_request).await;
+            let transient = match &result {
+                Ok(res) => res.status().is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !transient || attempt >= self.policy.max_attempts || !self.budget.withdraw() {
+                return result;
+            }
+            tokio::time::sleep(self.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Exponential backoff with full jitter so clients that failed together don't retry together
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .policy
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.policy.max_delay);
+        // RandomState is seeded randomly, good enough for jitter without depending on rand
+        let random = RandomState::new().build_hasher().finish();
+        backoff.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    // This client should not be created for every request, clone it instead as it holds the
+    // connection pool
+    let client = HttpClient::new(Client::new(), RetryPolicy::default(), RetryBudget::new(10));
+    let res = client
+        .send(client.get("https://httpbin.org/get"))
+        .await // This error usually happens when the url can't be reached or resolved to an ip
+        .unwrap();
+    println!("{}", res.status());
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn test_client() -> HttpClient {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        HttpClient::new(Client::new(), policy, RetryBudget::new(10))
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let server = MockServer::start().await;
+        // Mocks mounted first take precedence until they ran out
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = test_client();
+        let res = client.send(client.get(&server.uri())).await.unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors_or_posts() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = test_client();
+        let res = client.send(client.get(&server.uri())).await.unwrap();
+        assert_eq!(res.status(), 404);
+        let res = client.send(client.post(&server.uri())).await.unwrap();
+        assert_eq!(res.status(), 503);
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let budget = RetryBudget::new(1);
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+        for _ in 0..10 {
+            budget.deposit();
+        }
+        assert!(budget.withdraw());
+    }
+}
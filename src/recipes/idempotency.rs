@@ -0,0 +1,448 @@
+//! Middleware replaying the stored response when a client retries a request with the same `Idempotency-Key`
+//! Requires `cargo add axum serde_json sha2 thiserror tracing tracing-subscriber`
+//! `cargo add hex -F serde`
+//! `cargo add redis -F tokio-comp -F connection-manager`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! A client that got no response can't know whether a payment went through. Sending the same key
+//! with the retry lets the server answer with the response of the first attempt instead of
+//! charging again. See the `idempotent_retry` recipe for the client side.
+//! Try it with `curl -i localhost:8080/payments -H 'Idempotency-Key: 1' -H 'Content-Type: application/json' -d '{"amount": 5}'`
+//! twice, the second response has `Idempotent-Replayed: true`.
+//!
+//! The first request claims the key, a retry arriving while it still runs gets a 409. Responses
+//! are kept for a day, except server errors which free the key for another attempt. Reusing a key
+//! for a different request is a 422. Keys should be scoped to the caller, e.g. by prefixing the
+//! user id, so one client can't replay the responses of another.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Request, State},
+    http::{header::HeaderName, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpListener, time::Instant};
+use tracing::{error, warn};
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+pub static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Tells the client this is the stored response of an earlier request
+pub static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+/// How long a retry gets the stored response
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longer than any request takes, a key claimed by a crashed instance is free again after this
+const CLAIM_TTL: Duration = Duration::from_secs(60);
+/// The body is read into memory to compare it with the first request
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Record {
+    /// The first request is still running
+    InProgress { fingerprint: String },
+    Completed {
+        fingerprint: String,
+        status: u16,
+        headers: Vec<(String, String)>,
+        #[serde(with = "hex")]
+        body: Vec<u8>,
+    },
+}
+
+impl Record {
+    fn fingerprint(&self) -> &str {
+        match self {
+            Record::InProgress { fingerprint } | Record::Completed { fingerprint, .. } => {
+                fingerprint
+            }
+        }
+    }
+}
+
+pub trait IdempotencyStore: Clone + Send + Sync + 'static {
+    /// Stores `record` if the key is free and returns `None`, otherwise returns what is stored
+    fn insert_new(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Option<Record>, BoxError>> + Send;
+    fn set(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<(), BoxError>> + Send;
+    fn remove(&self, key: &str) -> impl Future<Output = Result<(), BoxError>> + Send;
+}
+
+#[derive(Clone)]
+pub struct RedisStore {
+    conn: ConnectionManager,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let conn = redis::Client::open(url)?.get_connection_manager().await?;
+        Ok(Self { conn })
+    }
+}
+
+impl IdempotencyStore for RedisStore {
+    async fn insert_new(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> Result<Option<Record>, BoxError> {
+        let mut conn = self.conn.clone();
+        // Setting and reading in one command means two retries can't both claim the key.
+        // NX together with GET needs Redis 7.
+        let existing: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(serde_json::to_string(record)?)
+            .arg("NX")
+            .arg("GET")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut conn)
+            .await?;
+        Ok(existing
+            .map(|json| serde_json::from_str(&json))
+            .transpose()?)
+    }
+
+    async fn set(&self, key: &str, record: &Record, ttl: Duration) -> Result<(), BoxError> {
+        let mut conn = self.conn.clone();
+        conn.set_ex::<_, _, ()>(key, serde_json::to_string(record)?, ttl.as_secs())
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), BoxError> {
+        let mut conn = self.conn.clone();
+        conn.del::<_, ()>(key).await?;
+        Ok(())
+    }
+}
+
+/// For tests and single instance deployments
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    records: Arc<Mutex<HashMap<String, (Record, Instant)>>>,
+}
+
+impl IdempotencyStore for InMemoryStore {
+    async fn insert_new(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> Result<Option<Record>, BoxError> {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        if let Some((existing, expires)) = records.get(key) {
+            if *expires > now {
+                return Ok(Some(existing.clone()));
+            }
+        }
+        records.insert(key.to_owned(), (record.clone(), now + ttl));
+        Ok(None)
+    }
+
+    async fn set(&self, key: &str, record: &Record, ttl: Duration) -> Result<(), BoxError> {
+        let expires = Instant::now() + ttl;
+        let mut records = self.records.lock().unwrap();
+        records.insert(key.to_owned(), (record.clone(), expires));
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), BoxError> {
+        self.records.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("Idempotency-Key has to be between 1 and 255 characters")]
+    InvalidKey,
+    #[error("Request bodies can be at most {MAX_BODY} bytes")]
+    TooLarge,
+    #[error("A request with this Idempotency-Key is still being processed, retry later")]
+    InProgress,
+    #[error("This Idempotency-Key was already used for a different request")]
+    Mismatch,
+    #[error("Idempotency store failed: {0}")]
+    Store(BoxError),
+}
+
+impl IntoResponse for IdempotencyError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            IdempotencyError::InvalidKey => StatusCode::BAD_REQUEST,
+            IdempotencyError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            IdempotencyError::InProgress => StatusCode::CONFLICT,
+            IdempotencyError::Mismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            // Without the store a retry can't be recognized, failing is better than charging twice
+            IdempotencyError::Store(e) => {
+                error!("Idempotency store failed: {e}");
+                return (StatusCode::SERVICE_UNAVAILABLE, "Try again later").into_response();
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Add with `middleware::from_fn_with_state(store, idempotency::<RedisStore>)`.
+/// Requests without the header and safe methods like GET pass through untouched.
+pub async fn idempotency<S: IdempotencyStore>(
+    State(store): State<S>,
+    request: Request,
+    next: Next,
+) -> Response {
+    handle(store, request, next)
+        .await
+        .unwrap_or_else(IntoResponse::into_response)
+}
+
+async fn handle<S: IdempotencyStore>(
+    store: S,
+    request: Request,
+    next: Next,
+) -> Result<Response, IdempotencyError> {
+    let key = match request.headers().get(&IDEMPOTENCY_KEY) {
+        Some(key) if !request.method().is_safe() => key,
+        _ => return Ok(next.run(request).await),
+    };
+    let key = match key.to_str() {
+        Ok(key) if (1..=255).contains(&key.len()) => format!("idempotency:v1:{key}"),
+        _ => return Err(IdempotencyError::InvalidKey),
+    };
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, MAX_BODY)
+        .await
+        .map_err(|_| IdempotencyError::TooLarge)?;
+    let fingerprint = fingerprint(&parts.method, &parts.uri, &body);
+
+    let claim = Record::InProgress {
+        fingerprint: fingerprint.clone(),
+    };
+    let stored = store
+        .insert_new(&key, &claim, CLAIM_TTL)
+        .await
+        .map_err(IdempotencyError::Store)?;
+    if let Some(stored) = stored {
+        if stored.fingerprint() != fingerprint {
+            return Err(IdempotencyError::Mismatch);
+        }
+        return match stored {
+            Record::InProgress { .. } => Err(IdempotencyError::InProgress),
+            Record::Completed {
+                status,
+                headers,
+                body,
+                ..
+            } => Ok(replay(status, headers, body)),
+        };
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Reading the response for {key} failed: {e}");
+            release(&store, &key).await;
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    if parts.status.is_server_error() {
+        // The request may not have happened, a retry with the same key runs it again
+        release(&store, &key).await;
+    } else {
+        let record = Record::Completed {
+            fingerprint,
+            status: parts.status.as_u16(),
+            headers: stored_headers(&parts.headers),
+            body: body.to_vec(),
+        };
+        // The request already happened, failing the response now would make the client retry it
+        if let Err(e) = store.set(&key, &record, TTL).await {
+            warn!("Storing the response for {key} failed: {e}");
+        }
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// If this fails the claim expires after `CLAIM_TTL`
+async fn release(store: &impl IdempotencyStore, key: &str) {
+    if let Err(e) = store.remove(key).await {
+        warn!("Releasing {key} failed: {e}");
+    }
+}
+
+/// Identifies the request so a key reused for something else is noticed
+fn fingerprint(method: &Method, uri: &Uri, body: &Bytes) -> String {
+    let mut hash = Sha256::new();
+    hash.update(method.as_str());
+    hash.update(b" ");
+    hash.update(uri.to_string());
+    hash.update(b"\n");
+    hash.update(body);
+    hex::encode(hash.finalize())
+}
+
+fn stored_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+        .collect()
+}
+
+fn replay(status: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> Response {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response.headers_mut().insert(
+        IDEMPOTENT_REPLAYED.clone(),
+        HeaderValue::from_static("true"),
+    );
+    response
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPayment {
+    pub amount: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Payment {
+    pub id: u64,
+    pub amount: u64,
+}
+
+/// Stands in for your payment provider
+async fn create_payment(
+    State(payments): State<Arc<AtomicU64>>,
+    Json(payment): Json<NewPayment>,
+) -> (StatusCode, Json<Payment>) {
+    let id = payments.fetch_add(1, Ordering::Relaxed) + 1;
+    let payment = Payment {
+        id,
+        amount: payment.amount,
+    };
+    (StatusCode::CREATED, Json(payment))
+}
+
+pub fn app<S: IdempotencyStore>(store: S) -> Router {
+    Router::new()
+        .route("/payments", post(create_payment))
+        .route_layer(middleware::from_fn_with_state(store, idempotency::<S>))
+        .with_state(Arc::new(AtomicU64::new(0)))
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let store = RedisStore::connect("redis://127.0.0.1/").await.unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(store)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn payment(key: Option<&str>, amount: u64) -> Request {
+        let mut request =
+            Request::post("/payments").header(header::CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(&IDEMPOTENCY_KEY, key);
+        }
+        request
+            .body(Body::from(format!(r#"{{"amount":{amount}}}"#)))
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> Bytes {
+        to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_get_the_stored_response() {
+        let app = app(InMemoryStore::default());
+
+        let first = app.clone().oneshot(payment(Some("a"), 5)).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(first.headers().get(&IDEMPOTENT_REPLAYED).is_none());
+        let retry = app.clone().oneshot(payment(Some("a"), 5)).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()[&IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(body(first).await, body(retry).await);
+
+        // A new key is a new payment
+        let other = app.oneshot(payment(Some("b"), 5)).await.unwrap();
+        assert_eq!(&body(other).await[..], br#"{"id":2,"amount":5}"#);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_key_reused_for_a_different_request() {
+        let app = app(InMemoryStore::default());
+        app.clone().oneshot(payment(Some("a"), 5)).await.unwrap();
+        let reused = app.oneshot(payment(Some("a"), 6)).await.unwrap();
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_key_pass_through() {
+        let app = app(InMemoryStore::default());
+        app.clone().oneshot(payment(None, 5)).await.unwrap();
+        let second = app.oneshot(payment(None, 5)).await.unwrap();
+        assert_eq!(&body(second).await[..], br#"{"id":2,"amount":5}"#);
+    }
+
+    #[tokio::test]
+    async fn a_running_request_blocks_its_retries() {
+        let store = InMemoryStore::default();
+        let running = Record::InProgress {
+            fingerprint: fingerprint(
+                &Method::POST,
+                &Uri::from_static("/payments"),
+                &Bytes::from_static(br#"{"amount":5}"#),
+            ),
+        };
+        store
+            .insert_new("idempotency:v1:a", &running, CLAIM_TTL)
+            .await
+            .unwrap();
+        let retry = app(store).oneshot(payment(Some("a"), 5)).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+    }
+}
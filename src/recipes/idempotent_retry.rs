@@ -0,0 +1,257 @@
+//! Retrying outbound POSTs safely with idempotency keys
+//! Requires `cargo add reqwest -F json`
+//! `cargo add serde -F derive`
+//! `cargo add uuid -F v4`
+//! `cargo add serde_json thiserror`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//!
+//! The key is generated once per logical operation and sent with every attempt, so the upstream
+//! can tell a retry from a new request and doesn't e.g. charge a customer twice.
+//! See the `idempotency` recipe for the server side.
+
+use std::time::Duration;
+
+use reqwest::{header::HeaderValue, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Upper bound for the wait between two attempts, however many attempts are configured
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdempotentError {
+    /// The upstream already saw this key with a different body
+    #[error("Idempotency key {key} was already used for a different request")]
+    Conflict { key: String },
+    #[error("Upstream responded with {0}")]
+    Status(StatusCode),
+    #[error("Failed to serialize the body: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Idempotency key {0:?} is not a valid header value")]
+    InvalidKey(String),
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+#[derive(Clone)]
+pub struct IdempotentClient {
+    client: reqwest::Client,
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl IdempotentClient {
+    pub fn new(client: reqwest::Client, max_attempts: u32, base_delay: Duration) -> Self {
+        Self {
+            client,
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        }
+    }
+
+    /// Generates a new key, so call this once per operation and not per attempt
+    pub async fn post<B: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<R, IdempotentError> {
+        let key = uuid::Uuid::new_v4().to_string();
+        self.post_with_key(url, body, &key).await
+    }
+
+    /// For callers that persist the key so retries survive a restart
+    pub async fn post_with_key<B: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &B,
+        key: &str,
+    ) -> Result<R, IdempotentError> {
+        // Serialize once so every attempt sends the exact same bytes
+        let body = serde_json::to_vec(body)?;
+        let header =
+            HeaderValue::from_str(key).map_err(|_| IdempotentError::InvalidKey(key.to_owned()))?;
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .client
+                .post(url)
+                .header("idempotency-key", header.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await;
+            let retry = match result {
+                Ok(res) if res.status().is_success() => return Ok(res.json().await?),
+                Ok(res) if res.status() == StatusCode::CONFLICT => {
+                    return Err(IdempotentError::Conflict {
+                        key: key.to_owned(),
+                    })
+                }
+                Ok(res) if is_transient(res.status()) => IdempotentError::Status(res.status()),
+                Ok(res) => return Err(IdempotentError::Status(res.status())),
+                // Errors before a response was received are safe to retry with the same key
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => e.into(),
+                Err(e) => return Err(e.into()),
+            };
+            if attempt >= self.max_attempts {
+                return Err(retry);
+            }
+            tokio::time::sleep(self.retry_delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Doubles with every attempt up to `MAX_DELAY`, saturating instead of overflowing
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt - 1).unwrap_or(u32::MAX);
+        self.base_delay.saturating_mul(factor).min(MAX_DELAY)
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[derive(Serialize)]
+struct CreateCharge {
+    amount: u64,
+    currency: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+struct Charge {
+    id: String,
+}
+
+#[tokio::main]
+pub async fn main() {
+    let client = IdempotentClient::new(reqwest::Client::new(), 4, Duration::from_millis(200));
+    let charge: Charge = client
+        .post(
+            "http://localhost:8080/charges",
+            &CreateCharge {
+                amount: 1000,
+                currency: "eur",
+            },
+        )
+        .await
+        .unwrap();
+    println!("Created charge {}", charge.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{header_exists, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn client() -> IdempotentClient {
+        IdempotentClient::new(reqwest::Client::new(), 3, Duration::from_millis(1))
+    }
+
+    fn create_charge() -> CreateCharge {
+        CreateCharge {
+            amount: 1000,
+            currency: "eur",
+        }
+    }
+
+    async fn keys(server: &MockServer) -> Vec<String> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                request.headers["idempotency-key"]
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn retries_with_the_same_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/charges"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/charges"))
+            .and(header_exists("idempotency-key"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(serde_json::json!({"id": "ch_1"})),
+            )
+            .mount(&server)
+            .await;
+
+        let client = client();
+        let url = format!("{}/charges", server.uri());
+        let charge: Charge = client.post(&url, &create_charge()).await.unwrap();
+        assert_eq!(charge.id, "ch_1");
+        let first = keys(&server).await;
+        assert_eq!(first.len(), 3);
+        assert!(first.iter().all(|key| *key == first[0]));
+
+        // A new operation gets a new key
+        let _: Charge = client.post(&url, &create_charge()).await.unwrap();
+        let keys = keys(&server).await;
+        assert_eq!(keys.len(), 4);
+        assert_ne!(keys[3], first[0]);
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_not_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(409))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let result = client()
+            .post_with_key::<_, Charge>(&server.uri(), &create_charge(), "key-1")
+            .await;
+        assert!(matches!(result, Err(IdempotentError::Conflict { key }) if key == "key-1"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let result = client()
+            .post::<_, Charge>(&server.uri(), &create_charge())
+            .await;
+        assert!(matches!(
+            result,
+            Err(IdempotentError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_keys_are_an_error() {
+        let result = client()
+            .post_with_key::<_, Charge>("http://localhost:1", &create_charge(), "line\nbreak")
+            .await;
+        assert!(matches!(result, Err(IdempotentError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let client =
+            IdempotentClient::new(reqwest::Client::new(), u32::MAX, Duration::from_secs(1));
+        assert_eq!(client.retry_delay(1), Duration::from_secs(1));
+        assert_eq!(client.retry_delay(3), Duration::from_secs(4));
+        assert_eq!(client.retry_delay(40), MAX_DELAY);
+        assert_eq!(client.retry_delay(u32::MAX), MAX_DELAY);
+    }
+}
@@ -0,0 +1,235 @@
+//! Newtypes for ids so a user id can't be passed where an order id is expected
+//! Requires `cargo add axum`
+//! `cargo add sqlx -F postgres -F runtime-tokio -F uuid`
+//! `cargo add uuid -F v4 -F serde`
+//! `cargo add ulid -F serde -F uuid`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! `fn transfer(from: Uuid, to: Uuid)` compiles with the arguments swapped,
+//! `fn transfer(from: UserId, to: AccountId)` doesn't. The newtypes serialize, bind in queries and
+//! extract from paths exactly like the wrapped type, so they cost nothing but the declaration.
+//!
+//! Random v4 uuids are spread over the whole index which makes inserts into large tables slow and
+//! listing by creation order impossible. ULIDs start with the time they were created in
+//! milliseconds, so they sort by creation and new rows land at the end of the index. They are
+//! stored in the same `uuid` column and printed as 26 characters, e.g. `01J0Z3NDEKTSV4RRFFQ69G5FAV`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, PgPool, Postgres,
+};
+use tokio::net::TcpListener;
+use ulid::Ulid;
+use uuid::Uuid;
+
+/// `transparent` makes serde and sqlx treat it as the `Uuid` inside, axum's `Path` uses serde
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(transparent)]
+#[sqlx(transparent)]
+pub struct UserId(Uuid);
+
+impl UserId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl Default for UserId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// Sorts by creation time, `Ord` compares the timestamp first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OrderId(Ulid);
+
+impl OrderId {
+    pub fn new() -> Self {
+        Self(Ulid::new())
+    }
+}
+
+impl Default for OrderId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for OrderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for OrderId {
+    type Err = ulid::DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+// sqlx has no support for ulids, they are stored as the uuid with the same 128 bits
+impl sqlx::Type<Postgres> for OrderId {
+    fn type_info() -> PgTypeInfo {
+        <Uuid as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl Encode<'_, Postgres> for OrderId {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <Uuid as Encode<Postgres>>::encode_by_ref(&Uuid::from(self.0), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for OrderId {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self(<Uuid as Decode<Postgres>>::decode(value)?.into()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Order {
+    pub id: OrderId,
+    pub user_id: UserId,
+    pub amount: i64,
+}
+
+/// The ids bind and decode like any other column
+pub async fn orders_of(pool: &PgPool, user: UserId) -> Result<Vec<Order>, sqlx::Error> {
+    // Ordering by the id is ordering by creation time
+    sqlx::query_as("SELECT id, user_id, amount FROM orders WHERE user_id = $1 ORDER BY id")
+        .bind(user)
+        .fetch_all(pool)
+        .await
+}
+
+/// Stands in for your real database
+type Orders = Arc<Mutex<HashMap<UserId, Vec<Order>>>>;
+
+/// An id that doesn't parse is rejected by `Path` with a 400 before the handler runs
+async fn list_orders(
+    State(orders): State<Orders>,
+    Path(user): Path<UserId>,
+) -> Result<Json<Vec<Order>>, StatusCode> {
+    let orders = orders.lock().unwrap();
+    let orders = orders.get(&user).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(orders.clone()))
+}
+
+async fn get_order(
+    State(orders): State<Orders>,
+    Path((user, order)): Path<(UserId, OrderId)>,
+) -> Result<Json<Order>, StatusCode> {
+    let orders = orders.lock().unwrap();
+    orders
+        .get(&user)
+        .and_then(|orders| orders.iter().find(|o| o.id == order))
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub fn app(orders: Orders) -> Router {
+    Router::new()
+        .route("/users/:user/orders", get(list_orders))
+        .route("/users/:user/orders/:order", get(get_order))
+        .with_state(orders)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let user = UserId::new();
+    let orders = (1..=3)
+        .map(|amount| Order {
+            id: OrderId::new(),
+            user_id: user,
+            amount,
+        })
+        .collect();
+    let orders = Arc::new(Mutex::new(HashMap::from([(user, orders)])));
+    println!("Try localhost:8080/users/{user}/orders");
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(orders)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn serializes_as_the_inner_id() {
+        let id: UserId = "67e55044-10b1-426f-9247-bb680e5fe0c8".parse().unwrap();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, r#""67e55044-10b1-426f-9247-bb680e5fe0c8""#);
+        assert_eq!(serde_json::from_str::<UserId>(&json).unwrap(), id);
+    }
+
+    #[test]
+    fn ulids_sort_by_creation() {
+        let first = OrderId::new();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = OrderId::new();
+        assert!(first < second);
+        assert_eq!(second.to_string().parse::<OrderId>().unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn extracts_ids_from_the_path() {
+        let user = UserId::new();
+        let order = Order {
+            id: OrderId::new(),
+            user_id: user,
+            amount: 5,
+        };
+        let uri = format!("/users/{user}/orders/{}", order.id);
+        let orders = Arc::new(Mutex::new(HashMap::from([(user, vec![order])])));
+        let get = |uri: String| Request::get(uri).body(Body::empty()).unwrap();
+
+        let res = app(orders.clone()).oneshot(get(uri)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app(orders)
+            .oneshot(get("/users/not-a-uuid/orders".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,502 @@
+//! Background jobs stored in Postgres with retries and a dead letter state
+//! Requires `cargo add serde_json tokio-util tracing tracing-subscriber`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add sqlx -F runtime-tokio -F postgres`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F time`
+//! Builds on the `database` recipe for the pool and the `workers` recipe to restart workers that fail.
+//!
+//! Jobs are enqueued with the connection of the transaction doing the change they belong to, so a
+//! rolled back request never leaves a job behind. Workers poll with `FOR UPDATE SKIP LOCKED`, any
+//! number of them can run on any number of instances without taking the same job.
+//! A claimed job is leased instead of locked for the whole run, a worker that crashes halfway
+//! doesn't hold a transaction open and its job runs again once the lease is over. Delivery is at
+//! least once, so handlers have to be idempotent.
+//! Failed jobs are retried with exponential backoff, a handler that panics counts as a failed
+//! attempt. After the last attempt they stay in the table with `failed_at` set, look at them with
+//! `dead_jobs` and run them again with `requeue_dead`.
+//! The table is created by the migration in `migrations/jobs`, run it with `MIGRATOR` or copy it
+//! into the migrations of your service.
+
+use std::{any::Any, future::Future, panic::AssertUnwindSafe, time::Duration};
+
+use clap::Parser;
+use futures::FutureExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{migrate::Migrator, PgConnection, PgPool};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::recipes::{
+    database::{connect, DatabaseConfig},
+    workers::{Backoff, BoxError, Supervisor},
+};
+
+/// Reads the migrations at compile time, touch this file or `cargo clean` if a new one is not picked up
+pub static MIGRATOR: Migrator = sqlx::migrate!("migrations/jobs");
+
+/// How long an idle worker waits before looking for new jobs again
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_BASE: Duration = Duration::from_secs(10);
+const RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// The payload of a job. Every type has its own queue so a worker only sees jobs it can parse.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    const QUEUE: &'static str;
+    const MAX_ATTEMPTS: i32 = 5;
+    /// A run taking longer is cancelled and counts as failed. The lease is a minute longer, so
+    /// no other worker starts the job while this one still runs it.
+    const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+}
+
+/// Call this with the transaction doing the change the job belongs to
+pub async fn enqueue<J: Job>(
+    conn: &mut PgConnection,
+    job: &J,
+    delay: Duration,
+) -> Result<i64, BoxError> {
+    let (id,): (i64,) = sqlx::query_as(
+        "INSERT INTO jobs (queue, payload, max_attempts, run_at)
+         VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+         RETURNING id",
+    )
+    .bind(J::QUEUE)
+    .bind(serde_json::to_value(job)?)
+    .bind(J::MAX_ATTEMPTS)
+    .bind(delay.as_secs_f64())
+    .fetch_one(conn)
+    .await?;
+    Ok(id)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Claimed {
+    id: i64,
+    payload: serde_json::Value,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+/// Takes the job that is due the longest and leases it
+async fn claim(
+    pool: &PgPool,
+    queue: &str,
+    lease: Duration,
+) -> Result<Option<Claimed>, sqlx::Error> {
+    // A worker that died during the last attempt never buried its job, do it once the lease is over
+    sqlx::query(
+        "UPDATE jobs SET locked_until = NULL, failed_at = now(),
+             last_error = 'The lease of the last attempt ran out'
+         WHERE queue = $1 AND failed_at IS NULL AND attempts >= max_attempts
+           AND locked_until < now()",
+    )
+    .bind(queue)
+    .execute(pool)
+    .await?;
+    sqlx::query_as(
+        "UPDATE jobs SET attempts = attempts + 1, locked_until = now() + make_interval(secs => $2)
+         WHERE id = (
+             SELECT id FROM jobs
+             WHERE queue = $1 AND failed_at IS NULL AND run_at <= now()
+               AND attempts < max_attempts
+               AND (locked_until IS NULL OR locked_until < now())
+             ORDER BY run_at
+             LIMIT 1
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING id, payload, attempts, max_attempts",
+    )
+    .bind(queue)
+    .bind(lease.as_secs_f64())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Doubles with every attempt, starting at `RETRY_BASE`
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    (RETRY_BASE * 2u32.pow(exponent)).min(RETRY_MAX)
+}
+
+/// Runs jobs of `J`'s queue one after another until `shutdown` is cancelled.
+/// Returns database errors so the supervisor restarts it with a backoff, a job that fails is
+/// retried or buried instead.
+pub async fn work<J, F, Fut>(
+    pool: PgPool,
+    shutdown: CancellationToken,
+    handle: F,
+) -> Result<(), BoxError>
+where
+    J: Job,
+    F: Fn(J) -> Fut,
+    Fut: Future<Output = Result<(), BoxError>>,
+{
+    let lease = J::TIMEOUT + Duration::from_secs(60);
+    while !shutdown.is_cancelled() {
+        let Some(job) = claim(&pool, J::QUEUE, lease).await? else {
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+            continue;
+        };
+        // A job that started is finished even on shutdown, stopping halfway would run it twice
+        let result = match serde_json::from_value::<J>(job.payload) {
+            // A panic would take the worker down before the job is retried or buried
+            Ok(payload) => {
+                let run = AssertUnwindSafe(handle(payload)).catch_unwind();
+                match tokio::time::timeout(J::TIMEOUT, run).await {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(panic)) => Err(format!("Panicked: {}", panic_message(&*panic)).into()),
+                    Err(_) => Err(format!("Timed out after {:?}", J::TIMEOUT).into()),
+                }
+            }
+            // Retrying can't fix a payload that doesn't parse
+            Err(e) => {
+                warn!(queue = J::QUEUE, job.id, "Burying malformed job: {e}");
+                bury(&pool, job.id, &e.to_string()).await?;
+                continue;
+            }
+        };
+        match result {
+            Ok(()) => {
+                sqlx::query("DELETE FROM jobs WHERE id = $1")
+                    .bind(job.id)
+                    .execute(&pool)
+                    .await?;
+            }
+            Err(e) if job.attempts >= job.max_attempts => {
+                warn!(
+                    queue = J::QUEUE,
+                    job.id, "Giving up after {} attempts: {e}", job.attempts
+                );
+                bury(&pool, job.id, &e.to_string()).await?;
+            }
+            Err(e) => {
+                let delay = retry_delay(job.attempts);
+                warn!(
+                    queue = J::QUEUE,
+                    job.id, "Attempt {} failed, retrying in {delay:?}: {e}", job.attempts
+                );
+                sqlx::query(
+                    "UPDATE jobs SET locked_until = NULL, last_error = $2,
+                         run_at = now() + make_interval(secs => $3)
+                     WHERE id = $1",
+                )
+                .bind(job.id)
+                .bind(e.to_string())
+                .bind(delay.as_secs_f64())
+                .execute(&pool)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `panic!("literal")` carries a `&str`, `panic!("{x}")` a `String`, same as in the `panics` recipe
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// Moves the job to the dead letters, it is never claimed again on its own
+async fn bury(pool: &PgPool, id: i64, error: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE jobs SET locked_until = NULL, last_error = $2, failed_at = now() WHERE id = $1",
+    )
+    .bind(id)
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DeadJob {
+    pub id: i64,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
+pub async fn dead_jobs(pool: &PgPool, queue: &str) -> Result<Vec<DeadJob>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, payload, attempts, last_error FROM jobs
+         WHERE queue = $1 AND failed_at IS NOT NULL
+         ORDER BY failed_at",
+    )
+    .bind(queue)
+    .fetch_all(pool)
+    .await
+}
+
+/// Gives every dead job of the queue a fresh set of attempts, e.g. after fixing the bug that killed them
+pub async fn requeue_dead(pool: &PgPool, queue: &str) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE jobs SET failed_at = NULL, attempts = 0, run_at = now()
+         WHERE queue = $1 AND failed_at IS NOT NULL",
+    )
+    .bind(queue)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendWelcomeEmail {
+    pub user_id: i64,
+    pub email: String,
+}
+
+impl Job for SendWelcomeEmail {
+    const QUEUE: &'static str = "send_welcome_email";
+}
+
+async fn send_welcome_email(job: SendWelcomeEmail) -> Result<(), BoxError> {
+    info!(job.user_id, "Sending welcome email to {}", job.email);
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let pool = connect(&DatabaseConfig::parse()).await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    let supervisor = Supervisor::new(shutdown.clone(), Backoff::default());
+    // Spawn more workers for the same queue to run its jobs concurrently
+    let worker_pool = pool.clone();
+    supervisor.spawn("send-welcome-email", move |shutdown| {
+        work(worker_pool.clone(), shutdown, send_welcome_email)
+    });
+
+    let mut tx = pool.begin().await.unwrap();
+    let job = SendWelcomeEmail {
+        user_id: 1,
+        email: "jane@example.com".to_owned(),
+    };
+    enqueue(&mut tx, &job, Duration::ZERO).await.unwrap();
+    tx.commit().await.unwrap();
+
+    tokio::signal::ctrl_c().await.unwrap();
+    shutdown.cancel();
+    supervisor.join().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use sqlx::postgres::PgConnectOptions;
+
+    use super::*;
+
+    /// Every test gets its own schema with the migration applied, so they can run side by side
+    async fn test_pool() -> PgPool {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL to point at Postgres");
+        let schema = format!(
+            "jobs_test_{}_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        );
+        let admin = PgPool::connect(&url).await.unwrap();
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&admin)
+            .await
+            .unwrap();
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", &schema)]);
+        let pool = PgPool::connect_with(options).await.unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+        pool
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Flaky;
+
+    impl Job for Flaky {
+        const QUEUE: &'static str = "flaky";
+        const MAX_ATTEMPTS: i32 = 2;
+    }
+
+    async fn add(pool: &PgPool) -> i64 {
+        let mut conn = pool.acquire().await.unwrap();
+        enqueue(&mut conn, &Flaky, Duration::ZERO).await.unwrap()
+    }
+
+    /// Runs `work` for a single job, the handler stops the worker once it ran
+    async fn work_once<Fut>(pool: &PgPool, handle: fn(Flaky) -> Fut) -> Result<(), BoxError>
+    where
+        Fut: Future<Output = Result<(), BoxError>>,
+    {
+        let shutdown = CancellationToken::new();
+        let stop = shutdown.clone();
+        work(pool.clone(), shutdown, move |job| {
+            stop.cancel();
+            handle(job)
+        })
+        .await
+    }
+
+    async fn fails(_: Flaky) -> Result<(), BoxError> {
+        Err("boom".into())
+    }
+
+    async fn panics(_: Flaky) -> Result<(), BoxError> {
+        panic!("kaboom")
+    }
+
+    #[derive(Debug, sqlx::FromRow)]
+    struct Row {
+        attempts: i32,
+        last_error: Option<String>,
+        failed: bool,
+        locked: bool,
+        delayed: bool,
+    }
+
+    async fn row(pool: &PgPool, id: i64) -> Row {
+        sqlx::query_as(
+            "SELECT attempts, last_error, failed_at IS NOT NULL AS failed,
+                 locked_until IS NOT NULL AS locked, run_at > now() AS delayed
+             FROM jobs WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn claims_skip_locked_and_leased_jobs() {
+        let pool = test_pool().await;
+        let (first, second, third) = (add(&pool).await, add(&pool).await, add(&pool).await);
+        let lease = Duration::from_secs(60);
+
+        // Another worker is in the middle of claiming the first job
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("SELECT id FROM jobs WHERE id = $1 FOR UPDATE")
+            .bind(first)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let claimed = claim(&pool, Flaky::QUEUE, lease).await.unwrap().unwrap();
+        assert_eq!(claimed.id, second);
+        tx.rollback().await.unwrap();
+
+        let claimed = claim(&pool, Flaky::QUEUE, lease).await.unwrap().unwrap();
+        assert_eq!(claimed.id, first);
+        let claimed = claim(&pool, Flaky::QUEUE, lease).await.unwrap().unwrap();
+        assert_eq!(claimed.id, third);
+        assert!(claim(&pool, Flaky::QUEUE, lease).await.unwrap().is_none());
+        assert!(claim(&pool, "other", lease).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn failed_jobs_are_retried_later() {
+        let pool = test_pool().await;
+        let id = add(&pool).await;
+        work_once(&pool, fails).await.unwrap();
+
+        let row = row(&pool, id).await;
+        assert_eq!(row.attempts, 1);
+        assert_eq!(row.last_error.as_deref(), Some("boom"));
+        assert!(!row.failed);
+        assert!(!row.locked);
+        assert!(row.delayed);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn jobs_are_buried_after_the_last_attempt_and_can_be_requeued() {
+        let pool = test_pool().await;
+        let id = add(&pool).await;
+        work_once(&pool, fails).await.unwrap();
+        sqlx::query("UPDATE jobs SET run_at = now()")
+            .execute(&pool)
+            .await
+            .unwrap();
+        work_once(&pool, fails).await.unwrap();
+
+        let row = row(&pool, id).await;
+        assert_eq!(row.attempts, 2);
+        assert!(row.failed);
+        let dead = dead_jobs(&pool, Flaky::QUEUE).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].id, id);
+        assert!(claim(&pool, Flaky::QUEUE, Duration::from_secs(60))
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(requeue_dead(&pool, Flaky::QUEUE).await.unwrap(), 1);
+        assert!(dead_jobs(&pool, Flaky::QUEUE).await.unwrap().is_empty());
+        let claimed = claim(&pool, Flaky::QUEUE, Duration::from_secs(60))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((claimed.id, claimed.attempts), (id, 1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn panics_count_as_failed_attempts() {
+        let pool = test_pool().await;
+        let id = add(&pool).await;
+        // The worker survives the panic
+        work_once(&pool, panics).await.unwrap();
+
+        let row = row(&pool, id).await;
+        assert_eq!(row.attempts, 1);
+        assert_eq!(row.last_error.as_deref(), Some("Panicked: kaboom"));
+        assert!(!row.locked);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn expired_leases_are_claimed_again_until_the_budget_is_spent() {
+        let pool = test_pool().await;
+        let id = add(&pool).await;
+        let lease = Duration::from_millis(100);
+
+        // Every claim stands in for a worker that died while running the job
+        for attempt in 1..=2 {
+            let claimed = claim(&pool, Flaky::QUEUE, lease).await.unwrap().unwrap();
+            assert_eq!((claimed.id, claimed.attempts), (id, attempt));
+            assert!(claim(&pool, Flaky::QUEUE, lease).await.unwrap().is_none());
+            tokio::time::sleep(lease * 2).await;
+        }
+
+        assert!(claim(&pool, Flaky::QUEUE, lease).await.unwrap().is_none());
+        let row = row(&pool, id).await;
+        assert!(row.failed);
+        assert_eq!(
+            row.last_error.as_deref(),
+            Some("The lease of the last attempt ran out")
+        );
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(retry_delay(1), RETRY_BASE);
+        assert_eq!(retry_delay(2), RETRY_BASE * 2);
+        assert_eq!(retry_delay(4), RETRY_BASE * 8);
+        assert_eq!(retry_delay(100), RETRY_MAX);
+    }
+}
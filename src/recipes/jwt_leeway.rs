@@ -0,0 +1,192 @@
+//! Tolerating clock skew when verifying JWTs
+//! Requires `cargo add axum jsonwebtoken -F jsonwebtoken/rust_crypto`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, StatusCode},
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+/// Clocks of healthy servers are seconds apart. Anything more means the clocks are broken
+/// and accepting tokens that far off would effectively extend their lifetime.
+pub const MAX_LEEWAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    /// Secret used to verify tokens
+    #[clap(long, env)]
+    pub jwt_secret: String,
+    /// Seconds of clock skew tolerated for `exp` and `nbf`
+    #[clap(long, env, default_value = "30", value_parser = parse_leeway)]
+    pub jwt_leeway: Duration,
+}
+
+fn parse_leeway(secs: &str) -> Result<Duration, String> {
+    let leeway = Duration::from_secs(secs.parse().map_err(|e| format!("{e}"))?);
+    if leeway > MAX_LEEWAY {
+        return Err(format!(
+            "Leeway must be at most {} seconds",
+            MAX_LEEWAY.as_secs()
+        ));
+    }
+    Ok(leeway)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    /// Seconds since the unix epoch
+    pub exp: u64,
+    pub nbf: u64,
+}
+
+#[derive(Clone)]
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: Arc<Validation>,
+}
+
+impl JwtVerifier {
+    /// The leeway is applied to both sides: a token is accepted from `nbf - leeway` until `exp + leeway`
+    pub fn new(secret: &[u8], leeway: Duration) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = leeway.min(MAX_LEEWAY).as_secs();
+        // nbf is not checked by default
+        validation.validate_nbf = true;
+        validation.set_required_spec_claims(&["exp", "nbf", "sub"]);
+        Self {
+            key: DecodingKey::from_secret(secret),
+            validation: Arc::new(validation),
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        jsonwebtoken::decode(token, &self.key, &self.validation).map(|data| data.claims)
+    }
+}
+
+/// Extracts and verifies the bearer token of a request
+pub struct Authenticated(pub Claims);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Authenticated
+where
+    JwtVerifier: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        JwtVerifier::from_ref(state)
+            .verify(token)
+            .map(Authenticated)
+            .map_err(|_| StatusCode::UNAUTHORIZED)
+    }
+}
+
+async fn me(Authenticated(claims): Authenticated) -> String {
+    format!("Hello {}", claims.sub)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::parse();
+    let verifier = JwtVerifier::new(config.jwt_secret.as_bytes(), config.jwt_leeway);
+    let app = Router::new().route("/me", get(me)).with_state(verifier);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{errors::ErrorKind, get_current_timestamp, EncodingKey, Header};
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+    const LEEWAY: u64 = 30;
+
+    /// `nbf` and `exp` are relative to now, the other one is far enough away to not matter
+    fn token(nbf: i64, exp: i64) -> String {
+        let now = get_current_timestamp() as i64;
+        let claims = Claims {
+            sub: "jane".to_owned(),
+            nbf: (now + nbf) as u64,
+            exp: (now + exp) as u64,
+        };
+        jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn verify(token: &str) -> Result<Claims, ErrorKind> {
+        JwtVerifier::new(SECRET, Duration::from_secs(LEEWAY))
+            .verify(token)
+            .map_err(|e| e.into_kind())
+    }
+
+    #[test]
+    fn accepts_tokens_from_a_clock_running_ahead_within_the_leeway() {
+        let nbf = LEEWAY as i64 - 5;
+        assert_eq!(verify(&token(nbf, 3600)).unwrap().sub, "jane");
+    }
+
+    #[test]
+    fn rejects_tokens_not_yet_valid_beyond_the_leeway() {
+        let nbf = LEEWAY as i64 + 5;
+        assert_eq!(
+            verify(&token(nbf, 3600)).unwrap_err(),
+            ErrorKind::ImmatureSignature
+        );
+    }
+
+    #[test]
+    fn accepts_tokens_expired_within_the_leeway() {
+        let exp = -(LEEWAY as i64) + 5;
+        assert_eq!(verify(&token(-3600, exp)).unwrap().sub, "jane");
+    }
+
+    #[test]
+    fn rejects_tokens_expired_beyond_the_leeway() {
+        let exp = -(LEEWAY as i64) - 5;
+        assert_eq!(
+            verify(&token(-3600, exp)).unwrap_err(),
+            ErrorKind::ExpiredSignature
+        );
+        assert_eq!(
+            verify(&token(-7200, -3600)).unwrap_err(),
+            ErrorKind::ExpiredSignature
+        );
+    }
+
+    #[test]
+    fn leeway_is_bounded() {
+        assert!(parse_leeway("300").is_ok());
+        assert!(parse_leeway("301").is_err());
+
+        let verifier = JwtVerifier::new(SECRET, Duration::from_secs(24 * 3600));
+        let exp = -(MAX_LEEWAY.as_secs() as i64) - 5;
+        let err = verifier.verify(&token(-3600, exp)).unwrap_err();
+        assert_eq!(err.into_kind(), ErrorKind::ExpiredSignature);
+    }
+}
@@ -0,0 +1,216 @@
+//! Pushing database changes to browsers with Postgres LISTEN/NOTIFY
+//! Requires `cargo add axum -F ws`
+//! `cargo add futures tracing tracing-subscriber`
+//! `cargo add clap -F derive -F env`
+//! `cargo add sqlx -F runtime-tokio -F postgres`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F sync -F time`
+//! Builds on the `database` recipe and announces changes to its todos.
+//!
+//! One connection listens for the whole process and a broadcast channel hands every notification
+//! to the clients, so a thousand clients don't need a thousand database connections. No message
+//! broker is needed as long as the changes happen in Postgres anyway.
+//! Postgres only delivers notifications to connections listening right now, whatever was sent while
+//! the connection was down is gone. After reconnecting every client gets a `resync` and reloads what
+//! it shows, the same as when it connects for the first time.
+//! A payload can be at most 8000 bytes, send the id of what changed and let clients fetch it.
+//! `NOTIFY` inside a transaction is only sent on commit, and not at all on rollback.
+//! ```sql
+//! CREATE FUNCTION notify_todos() RETURNS trigger AS $$
+//! BEGIN
+//!     PERFORM pg_notify('todos', json_build_object('op', TG_OP, 'id', COALESCE(NEW.id, OLD.id))::text);
+//!     RETURN NULL;
+//! END;
+//! $$ LANGUAGE plpgsql;
+//! CREATE TRIGGER todos_notify AFTER INSERT OR UPDATE OR DELETE ON todos
+//!     FOR EACH ROW EXECUTE FUNCTION notify_todos();
+//! ```
+//! Watch with `curl -N localhost:8080/changes` or `websocat ws://localhost:8080/changes/ws`, then
+//! `psql "$DATABASE_URL" -c "INSERT INTO todos (title) VALUES ('milk')"`.
+
+use std::{convert::Infallible, pin::pin, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use futures::{stream, Stream, StreamExt};
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::{
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{info, warn};
+
+use crate::recipes::database::{connect, DatabaseConfig};
+
+pub const CHANNEL: &str = "todos";
+/// How many changes a slow client can fall behind before it has to resync
+const CAPACITY: usize = 1024;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The payload as sent by `pg_notify`
+    Notification(String),
+    /// Changes may have been missed, clients have to reload what they show
+    Resync,
+}
+
+impl Change {
+    fn to_sse(&self) -> Event {
+        match self {
+            Change::Notification(payload) => Event::default().event("change").data(payload),
+            Change::Resync => Event::default().event("resync").data(""),
+        }
+    }
+}
+
+type Changes = broadcast::Sender<Change>;
+
+/// Runs forever, reconnecting with a growing delay while the database can't be reached
+pub async fn listen(pool: PgPool, changes: Changes) {
+    let mut backoff = MIN_BACKOFF;
+    let mut listened_before = false;
+    loop {
+        let mut listener = match start_listening(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Listening on {CHANNEL} failed, retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+        info!("Listening on {CHANNEL}");
+        backoff = MIN_BACKOFF;
+        // Sent only once listening again, so every change after it reaches the clients
+        if listened_before {
+            let _ = changes.send(Change::Resync);
+        }
+        listened_before = true;
+        loop {
+            // `recv` would reconnect by itself and hide that notifications were lost
+            match listener.try_recv().await {
+                Ok(Some(notification)) => {
+                    // Fails only when no client is connected which is fine
+                    let payload = notification.payload().to_owned();
+                    let _ = changes.send(Change::Notification(payload));
+                }
+                Ok(None) => {
+                    warn!("Lost the connection listening on {CHANNEL}");
+                    break;
+                }
+                Err(e) => {
+                    warn!("Listening on {CHANNEL} failed: {e}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps one connection of the pool busy for as long as it listens
+async fn start_listening(pool: &PgPool) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    Ok(listener)
+}
+
+/// A client falling behind gets a `Resync` instead of being disconnected
+pub fn subscribe(changes: &Changes) -> impl Stream<Item = Change> {
+    stream::unfold(changes.subscribe(), |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(change) => Some((change, receiver)),
+            Err(RecvError::Lagged(_)) => Some((Change::Resync, receiver)),
+            Err(RecvError::Closed) => None,
+        }
+    })
+}
+
+async fn sse(State(changes): State<Changes>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = subscribe(&changes).map(|change| Ok(change.to_sse()));
+    // Comments sent while nothing happens keep proxies from closing the idle connection
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn ws(ws: WebSocketUpgrade, State(changes): State<Changes>) -> Response {
+    ws.on_upgrade(move |socket| forward(socket, changes))
+}
+
+async fn forward(mut socket: WebSocket, changes: Changes) {
+    let mut changes = pin!(subscribe(&changes));
+    loop {
+        tokio::select! {
+            change = changes.next() => {
+                let text = match change {
+                    Some(Change::Notification(payload)) => payload,
+                    Some(Change::Resync) => r#"{"op":"RESYNC"}"#.to_owned(),
+                    None => break,
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // Clients only ever close, without reading a closed socket would linger until the
+            // next change
+            message = socket.recv() => {
+                if let Some(Ok(Message::Close(_)) | Err(_)) | None = message {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub fn app(changes: Changes) -> Router {
+    Router::new()
+        .route("/changes", get(sse))
+        .route("/changes/ws", get(ws))
+        .with_state(changes)
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let pool = connect(&DatabaseConfig::parse()).await.unwrap();
+    let changes = broadcast::channel(CAPACITY).0;
+    tokio::spawn(listen(pool, changes.clone()));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(changes)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lagging_clients_resync() {
+        let changes = broadcast::channel(2).0;
+        let received = subscribe(&changes);
+        for payload in ["1", "2", "3"] {
+            changes
+                .send(Change::Notification(payload.to_owned()))
+                .unwrap();
+        }
+        drop(changes);
+        let received: Vec<_> = received.collect().await;
+        assert_eq!(
+            received,
+            [
+                Change::Resync,
+                Change::Notification("2".to_owned()),
+                Change::Notification("3".to_owned()),
+            ]
+        );
+    }
+}
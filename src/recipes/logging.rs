@@ -0,0 +1,124 @@
+//! Logging using tracing
+//! Requires `cargo add tracing tracing-appender`
+//! And `cargo add tracing-subscriber -F env-filter -F json`
+//!
+//! `LOG_FORMAT=json` switches to one JSON object per line for log aggregators, the default is
+//! human readable. With the `config` recipe use its `log_format` setting instead of the env var.
+//! `LOG_DIR=logs` additionally writes the logs to a file in that directory which is rotated daily,
+//! for hosts where nothing collects stdout. Old files beyond `MAX_LOG_FILES` are deleted.
+
+use std::{path::Path, str::FromStr};
+
+use tracing::{debug, error, info, info_span, warn, Subscriber};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{InitError, RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+/// Two weeks of daily files
+const MAX_LOG_FILES: usize = 14;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {s:?}, expected pretty or json")),
+        }
+    }
+}
+
+/// The log level is configurable via the RUST_LOG env var and applies to stdout and the file alike.
+/// Keep the returned guard alive until main returns, dropping it flushes the lines still buffered
+/// for the file. Writing to the file happens on a background thread, so a slow disk does not
+/// hold up the code that logs.
+pub fn init(format: LogFormat, log_dir: Option<&Path>) -> Result<Option<WorkerGuard>, InitError> {
+    let (file, guard) = match log_dir {
+        Some(dir) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(env!("CARGO_PKG_NAME"))
+                .filename_suffix("log")
+                .max_log_files(MAX_LOG_FILES)
+                .build(dir)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (Some(fmt_layer(format, writer, false)), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt_layer(format, std::io::stdout, true))
+        .with(file)
+        .init();
+    Ok(guard)
+}
+
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    // Color codes only make sense on a terminal, in a file they are noise
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            // Adds the fields of the current span and all its parents to every line
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+pub fn main() {
+    let format = std::env::var("LOG_FORMAT")
+        .map(|format| format.parse().unwrap())
+        .unwrap_or_default();
+    let log_dir = std::env::var_os("LOG_DIR");
+    let _guard = init(format, log_dir.as_deref().map(Path::new)).unwrap();
+
+    // Every event inside this span carries the service name. Tasks spawned with tokio::spawn don't
+    // inherit the current span, pass it along with `.instrument(tracing::Span::current())`.
+    let _service = info_span!("service", service = env!("CARGO_PKG_NAME")).entered();
+    log_wherever_you_want();
+}
+
+fn log_wherever_you_want() {
+    let x = 3;
+    // There macros support all kinds of fancy formatting but the simplest way is to use them like the format! macro
+    debug!("Debug {}", x);
+    // This will give the log message context which will be printed before the message
+    info!(x, "Info");
+    // This crate is capable of a *lot* more than these 4 macros but 90% of the time this is all you need
+    warn!("Warn");
+    error!("Error")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("pretty".parse(), Ok(LogFormat::Pretty));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! Publishing and consuming events with NATS JetStream
+//! Requires `cargo add async-nats futures serde_json tokio-util tracing tracing-subscriber`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F time`
+//! Builds on the `workers` recipe to restart the consumer when it fails.
+//! Start a server with `docker run -p 4222:4222 nats -js`
+//!
+//! Delivery is at least once: a message is only acked after it was handled, so a crash in between
+//! means it is delivered again. Make the handlers idempotent, e.g. by storing processed event ids.
+
+use std::{future::Future, time::Duration};
+
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, PullConsumer},
+    AckKind,
+};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::recipes::workers::{Backoff, BoxError, Supervisor};
+
+const STREAM: &str = "ORDERS";
+const CONSUMER: &str = "order-emails";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderPlaced {
+    pub order_id: u64,
+    pub email: String,
+}
+
+/// Creates the stream on first use, it stores every message sent to a subject below `orders.`
+pub async fn orders_stream(js: &jetstream::Context) -> Result<jetstream::stream::Stream, BoxError> {
+    let stream = js
+        .get_or_create_stream(jetstream::stream::Config {
+            name: STREAM.to_owned(),
+            subjects: vec!["orders.>".to_owned()],
+            ..Default::default()
+        })
+        .await?;
+    Ok(stream)
+}
+
+/// Returns once the server confirmed it stored the event, so it won't get lost after this
+pub async fn publish_event<T: Serialize>(
+    js: &jetstream::Context,
+    subject: &str,
+    event: &T,
+) -> Result<(), BoxError> {
+    let payload = serde_json::to_vec(event)?;
+    // The first await sends the message, the second one waits for the ack of the server
+    js.publish(subject.to_owned(), payload.into())
+        .await?
+        .await?;
+    Ok(())
+}
+
+/// Handles messages until the token is cancelled.
+/// On shutdown the message being handled is finished and acked while messages that were already
+/// fetched but not handled are redelivered once their ack wait runs out.
+pub async fn consume<T, F, Fut>(
+    consumer: PullConsumer,
+    shutdown: CancellationToken,
+    handle: F,
+) -> Result<(), BoxError>
+where
+    T: DeserializeOwned,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), BoxError>>,
+{
+    let mut messages = consumer.messages().await?;
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            message = messages.next() => message,
+        };
+        let Some(message) = message else {
+            return Err("Message stream ended".into());
+        };
+        let message = message?;
+        match serde_json::from_slice::<T>(&message.payload) {
+            // Retrying can't fix a message that doesn't parse so don't redeliver it
+            Err(e) => {
+                warn!(subject = %message.subject, "Dropping malformed message: {e}");
+                message.ack_with(AckKind::Term).await?;
+            }
+            Ok(event) => match handle(event).await {
+                Ok(()) => message.ack().await?,
+                Err(e) => {
+                    warn!(subject = %message.subject, "Failed to handle message: {e}");
+                    message
+                        .ack_with(AckKind::Nak(Some(Duration::from_secs(10))))
+                        .await?;
+                }
+            },
+        }
+    }
+}
+
+async fn send_confirmation(order: OrderPlaced) -> Result<(), BoxError> {
+    info!(order.order_id, "Sending confirmation to {}", order.email);
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let client = async_nats::connect("nats://127.0.0.1:4222").await.unwrap();
+    let js = jetstream::new(client);
+    let stream = orders_stream(&js).await.unwrap();
+    // Durable consumers remember their position so restarts continue where they left off
+    let consumer: PullConsumer = stream
+        .get_or_create_consumer(
+            CONSUMER,
+            pull::Config {
+                durable_name: Some(CONSUMER.to_owned()),
+                // Longer than handling a message takes, otherwise it is redelivered while still being handled
+                ack_wait: Duration::from_secs(30),
+                max_deliver: 5,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    let shutdown = CancellationToken::new();
+    let supervisor = Supervisor::new(shutdown.clone(), Backoff::default());
+    supervisor.spawn("order-emails", move |shutdown| {
+        consume(consumer.clone(), shutdown, send_confirmation)
+    });
+
+    publish_event(
+        &js,
+        "orders.placed",
+        &OrderPlaced {
+            order_id: 1,
+            email: "customer@example.com".to_owned(),
+        },
+    )
+    .await
+    .unwrap();
+
+    tokio::signal::ctrl_c().await.unwrap();
+    shutdown.cancel();
+    supervisor.join().await;
+}
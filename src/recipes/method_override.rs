@@ -0,0 +1,198 @@
+//! Method override for clients that can only send GET and POST
+//! Requires `cargo add axum serde_urlencoded`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router, ServiceExt,
+};
+use tokio::net::TcpListener;
+use tower::Layer;
+
+static METHOD_OVERRIDE: HeaderName = HeaderName::from_static("x-http-method-override");
+
+/// Forms are buffered to look for a `_method` field. Larger ones and bodies of unknown size, like
+/// uploads, are passed through untouched and can only be overridden with the header.
+const FORM_LIMIT: u64 = 64 * 1024;
+
+#[tokio::main]
+pub async fn main() {
+    let router = Router::new().route(
+        "/items/:id",
+        post(|| async { "created" }).delete(|| async { "deleted" }),
+    );
+    // Middleware added with `Router::layer` runs after routing which is too late to change the method.
+    // Wrapping the whole router makes it run before.
+    let app = middleware::from_fn(method_override).layer(router);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+        .await
+        .unwrap();
+}
+
+/// Only POST can be overridden. Turning a GET into a DELETE would let anything that follows links
+/// or prefetches delete things and caches would happily cache the result.
+pub async fn method_override(req: Request, next: Next) -> Response {
+    let (mut parts, mut body) = req.into_parts();
+    let mut requested = parts
+        .headers
+        .remove(&METHOD_OVERRIDE)
+        .map(|value| value.as_bytes().to_vec());
+
+    let small = body
+        .size_hint()
+        .upper()
+        .is_some_and(|len| len <= FORM_LIMIT);
+    if requested.is_none() && parts.method == Method::POST && is_form(&parts.headers) && small {
+        let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Failed to read the form").into_response(),
+        };
+        requested = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&bytes)
+            .ok()
+            .and_then(|fields| fields.into_iter().find(|(key, _)| key == "_method"))
+            .map(|(_, value)| value.into_bytes());
+        // The handler still wants to read the form so we put the body back
+        body = Body::from(bytes);
+    }
+
+    if let Some(requested) = requested {
+        if parts.method != Method::POST {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Only POST requests can override their method",
+            )
+                .into_response();
+        }
+        parts.method = match requested.to_ascii_uppercase().as_slice() {
+            b"PUT" => Method::PUT,
+            b"PATCH" => Method::PATCH,
+            b"DELETE" => Method::DELETE,
+            _ => return (StatusCode::BAD_REQUEST, "Unsupported method override").into_response(),
+        };
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Ignores parameters like the charset
+fn is_form(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| ct.split(';').next())
+        .is_some_and(|mime| {
+            mime.trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, routing::get};
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    /// Sends the request through the middleware wrapping a router that answers with the method
+    async fn send(req: Request) -> (StatusCode, String) {
+        let router = Router::new().route(
+            "/items/:id",
+            get(|| async { "GET" })
+                .post(|| async { "POST" })
+                .put(|| async { "PUT" })
+                // Reads the form to check that it is still there
+                .delete(|body: String| async move { format!("DELETE {body}") }),
+        );
+        let res = middleware::from_fn(method_override)
+            .layer(router)
+            .oneshot(req)
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn header_overrides_post() {
+        let req = Request::post("/items/1")
+            .header(&METHOD_OVERRIDE, "put")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(req).await, (StatusCode::OK, "PUT".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn form_field_overrides_post() {
+        let req = Request::post("/items/1")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("_method=DELETE&reason=duplicate"))
+            .unwrap();
+        assert_eq!(
+            send(req).await,
+            (
+                StatusCode::OK,
+                "DELETE _method=DELETE&reason=duplicate".to_owned()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn get_is_never_overridden() {
+        let req = Request::get("/items/1")
+            .header(&METHOD_OVERRIDE, "DELETE")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(req).await.0, StatusCode::BAD_REQUEST);
+
+        // Without an override the request goes through unchanged
+        let req = Request::get("/items/1").body(Body::empty()).unwrap();
+        assert_eq!(send(req).await, (StatusCode::OK, "GET".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_methods() {
+        let req = Request::post("/items/1")
+            .header(&METHOD_OVERRIDE, "CONNECT")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(req).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn form_field_overrides_post_with_a_charset() {
+        let req = Request::post("/items/1")
+            .header(
+                header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded; charset=UTF-8",
+            )
+            .body(Body::from("_method=DELETE"))
+            .unwrap();
+        assert_eq!(
+            send(req).await,
+            (StatusCode::OK, "DELETE _method=DELETE".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn other_bodies_are_passed_through() {
+        // Looks like a form but isn't one
+        let req = Request::post("/items/1")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("_method=DELETE"))
+            .unwrap();
+        assert_eq!(send(req).await, (StatusCode::OK, "POST".to_owned()));
+
+        let large = format!("_method=DELETE&data={}", "x".repeat(FORM_LIMIT as usize));
+        let req = Request::post("/items/1")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(large))
+            .unwrap();
+        assert_eq!(send(req).await, (StatusCode::OK, "POST".to_owned()));
+    }
+}
@@ -0,0 +1,175 @@
+//! Prometheus metrics for every request plus a `/metrics` endpoint
+//! Requires `cargo add axum metrics metrics-exporter-prometheus`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F time`
+//!
+//! The middleware records `http_requests_total`, `http_request_duration_seconds` and
+//! `http_requests_in_flight` labeled by method, route and (except for in flight) status.
+//! Record your own metrics anywhere with the `metrics` macros, they end up on the same endpoint.
+
+use std::{
+    future::ready,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::{self, Next},
+    response::Response,
+    routing::get,
+    Router,
+};
+use metrics::Gauge;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tokio::net::TcpListener;
+
+const REQUEST_DURATION: &str = "http_request_duration_seconds";
+
+/// Without buckets durations are exported as summaries which can't be aggregated across instances
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn builder() -> PrometheusBuilder {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.to_owned()), DURATION_BUCKETS)
+        .unwrap()
+}
+
+/// Can only be called once per process as it installs the global recorder
+pub fn install_recorder() -> PrometheusHandle {
+    let handle = builder().install_recorder().unwrap();
+    // Histograms are only drained when rendering, so without scrapes they would grow forever
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+    handle
+}
+
+/// Decrements on drop so requests whose client went away are not counted forever
+struct InFlight(Gauge);
+
+impl InFlight {
+    fn new(gauge: Gauge) -> Self {
+        gauge.increment(1);
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.decrement(1);
+    }
+}
+
+/// Add this with `route_layer` so it only runs for matched routes.
+/// Requests to unknown paths would otherwise create a new label value each.
+pub async fn track_metrics(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let method = req.method().to_string();
+
+    let in_flight = InFlight::new(metrics::gauge!(
+        "http_requests_in_flight",
+        "method" => method.clone(),
+        "route" => route.clone()
+    ));
+    let start = Instant::now();
+    let res = next.run(req).await;
+    drop(in_flight);
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", res.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!(REQUEST_DURATION, &labels).record(start.elapsed().as_secs_f64());
+    res
+}
+
+pub fn app(handle: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/", get(|| async { "Hello, world!" }))
+        .route("/users/:id", get(|| async { "A user" }))
+        .route_layer(middleware::from_fn(track_metrics))
+        // Added after the layer so scrapes don't show up in the request metrics
+        .route("/metrics", get(move || ready(handle.render())))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let handle = install_recorder();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(handle)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Requests the paths and returns the scrape that follows them
+    async fn scrape_after(paths: &[&str]) -> String {
+        // Local to the test's thread, the global recorder can only be installed once
+        let recorder = builder().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let app = app(recorder.handle());
+        for path in paths {
+            let req = Request::get(*path).body(Body::empty()).unwrap();
+            app.clone().oneshot(req).await.unwrap();
+        }
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_by_route() {
+        let scrape = scrape_after(&["/users/1", "/users/2", "/"]).await;
+        assert!(
+            scrape
+                .contains(r#"http_requests_total{method="GET",route="/users/:id",status="200"} 2"#),
+            "{scrape}"
+        );
+        assert!(
+            scrape.contains(r#"http_requests_total{method="GET",route="/",status="200"} 1"#),
+            "{scrape}"
+        );
+        assert!(
+            scrape.contains(
+                r#"http_request_duration_seconds_bucket{method="GET",route="/users/:id",status="200",le="0.005"} 2"#
+            ),
+            "{scrape}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_scrapes_are_not_recorded() {
+        let scrape = scrape_after(&["/nope", "/metrics"]).await;
+        assert!(!scrape.contains("http_requests_total"), "{scrape}");
+    }
+
+    #[tokio::test]
+    async fn finished_requests_are_no_longer_in_flight() {
+        let scrape = scrape_after(&["/users/1"]).await;
+        assert!(
+            scrape.contains(r#"http_requests_in_flight{method="GET",route="/users/:id"} 0"#),
+            "{scrape}"
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! The layers every route should have: request ids, logging, CORS, compression, timeouts and body limits
+//! Requires `cargo add axum tracing tracing-subscriber`
+//! `cargo add clap -F derive -F env`
+//! `cargo add tower -F util`
+//! `cargo add tower-http -F trace -F request-id -F cors -F compression-gzip -F compression-br -F timeout -F limit`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Builds on the `axum` recipe and wraps its router.
+//!
+//! Every request gets an `x-request-id`, either the one sent by a proxy in front of us or a new one.
+//! It is part of the span of the request so every log line of a request can be found by it,
+//! and it is returned to the client so a bug report can reference it.
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, MatchedPath},
+    http::{header, HeaderName, HeaderValue, Method, Request, Response, StatusCode},
+    Router,
+};
+use clap::Parser;
+use tokio::net::TcpListener;
+use tower::ServiceBuilder;
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
+use tracing::{info, info_span, Span};
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, Parser)]
+pub struct LayersConfig {
+    /// Origins allowed to call the API from a browser, e.g. `https://app.example.com`.
+    /// Comma separated, none means only same origin requests work.
+    #[clap(long, env, value_delimiter = ',', value_parser = parse_origin)]
+    pub cors_allowed_origins: Vec<HeaderValue>,
+    /// Requests taking longer get a 408 response
+    #[clap(long, env, default_value_t = 30)]
+    pub request_timeout_secs: u64,
+    /// Larger request bodies get a 413 response
+    #[clap(long, env, default_value_t = 2 * 1024 * 1024)]
+    pub max_body_bytes: usize,
+}
+
+/// Browsers send the origin without a trailing slash or path so anything else would never match
+fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    if !(origin.starts_with("https://") || origin.starts_with("http://")) || origin.ends_with('/') {
+        return Err(format!(
+            "Origins look like https://example.com, got {origin:?}"
+        ));
+    }
+    HeaderValue::from_str(origin).map_err(|e| e.to_string())
+}
+
+/// The full stack, use this instead of [`with_request_logging`] for a public service.
+/// [`ServiceBuilder`] runs the layers top to bottom, so the order is:
+/// - Request id and logging first so even requests rejected by the later layers are logged
+/// - CORS before anything that can reject a request, otherwise the browser hides the actual error
+///   behind a CORS error because the rejection lacks the CORS headers
+/// - Compression so error responses are compressed as well
+/// - Timeout and body limit last as they only concern the handler
+pub fn default_layers(router: Router, config: &LayersConfig) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::list(config.cors_allowed_origins.clone()))
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        // Lets browsers skip the preflight request for an hour
+        .max_age(Duration::from_secs(60 * 60));
+    router
+        // Otherwise the 2MB default of axum's extractors would still apply to larger limits
+        .layer(DefaultBodyLimit::disable())
+        .layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::new(REQUEST_ID, MakeRequestUuid))
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(make_span)
+                        .on_request(())
+                        .on_response(on_response),
+                )
+                .layer(PropagateRequestIdLayer::new(REQUEST_ID))
+                .layer(cors)
+                .layer(CompressionLayer::new())
+                .layer(TimeoutLayer::with_status_code(
+                    StatusCode::REQUEST_TIMEOUT,
+                    Duration::from_secs(config.request_timeout_secs),
+                ))
+                .layer(RequestBodyLimitLayer::new(config.max_body_bytes)),
+        )
+}
+
+/// Applies the whole stack to a router. Layers added to the router afterwards run outside of it
+/// and are not logged with a request id.
+pub fn with_request_logging(router: Router) -> Router {
+    // ServiceBuilder runs the layers top to bottom so the id is set before the span is created
+    router.layer(
+        ServiceBuilder::new()
+            .layer(SetRequestIdLayer::new(REQUEST_ID, MakeRequestUuid))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(make_span)
+                    .on_request(())
+                    .on_response(on_response),
+            )
+            .layer(PropagateRequestIdLayer::new(REQUEST_ID)),
+    )
+}
+
+fn make_span(req: &Request<Body>) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    // The route keeps ids out of the path field so logs can be grouped by endpoint
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    info_span!(
+        "request",
+        method = %req.method(),
+        path = req.uri().path(),
+        route,
+        request_id,
+        // Filled in once the response is there
+        status = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+    )
+}
+
+/// Generic over the body as layers like compression between it and the handler change its type
+fn on_response<B>(res: &Response<B>, latency: Duration, span: &Span) {
+    span.record("status", res.status().as_u16());
+    span.record("latency_ms", latency.as_millis() as u64);
+    info!("Finished request");
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let config = LayersConfig::parse();
+    let app = default_layers(crate::recipes::axum_server::app(), &config);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app() -> Router {
+        let router = Router::new()
+            .route(
+                "/",
+                get(|| async { "Hello, world!".repeat(100) })
+                    .post(|body: String| async move { body }),
+            )
+            .route(
+                "/slow",
+                get(|| async { tokio::time::sleep(Duration::from_secs(60)).await }),
+            );
+        let config = LayersConfig {
+            cors_allowed_origins: vec![HeaderValue::from_static("https://app.example.com")],
+            request_timeout_secs: 1,
+            max_body_bytes: 16,
+        };
+        default_layers(router, &config)
+    }
+
+    async fn send(req: Request<Body>) -> Response<Body> {
+        app().oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_response_has_a_request_id() {
+        let res = send(Request::get("/").body(Body::empty()).unwrap()).await;
+        let id = res.headers()[&REQUEST_ID].to_str().unwrap();
+        assert_eq!(id.len(), 36, "{id}");
+
+        // The id of a proxy in front of us is kept
+        let req = Request::get("/")
+            .header(&REQUEST_ID, "from-the-proxy")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(req).await.headers()[&REQUEST_ID], "from-the-proxy");
+    }
+
+    #[tokio::test]
+    async fn only_allowed_origins_pass_the_preflight() {
+        let preflight = |origin: &'static str| {
+            Request::options("/")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = send(preflight("https://app.example.com")).await;
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        let res = send(preflight("https://evil.example.com")).await;
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn responses_are_compressed_when_accepted() {
+        let req = Request::get("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = send(req).await;
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.len() < "Hello, world!".len() * 100);
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_rejected() {
+        let req = Request::post("/").body(Body::from("x".repeat(17))).unwrap();
+        assert_eq!(send(req).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let req = Request::post("/").body(Body::from("x".repeat(16))).unwrap();
+        assert_eq!(send(req).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_requests_time_out() {
+        let res = send(Request::get("/slow").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        // The layers outside of the timeout still apply to its response
+        assert!(res.headers().contains_key(&REQUEST_ID));
+    }
+}
@@ -0,0 +1,143 @@
+//! Subcommands to run the server or apply and roll back migrations without starting it
+//! Requires `cargo add axum`
+//! `cargo add clap -F derive -F env`
+//! `cargo add sqlx -F runtime-tokio -F sqlite -F migrate`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Builds on the `sqlite` recipe for the migrations and the server.
+//!
+//! `cargo run -- migrate` applies pending migrations, `cargo run -- migrate --revert 1` rolls back the
+//! last one and `cargo run -- serve` starts the server. Deployments run `migrate` as a separate step
+//! before rolling out the new version, `serve` refuses to start while migrations are pending.
+
+use std::{collections::HashSet, net::SocketAddr};
+
+use clap::{Parser, Subcommand};
+use sqlx::{migrate::Migrate, SqlitePool};
+use tokio::net::TcpListener;
+
+use crate::recipes::sqlite::{app, connect, MIGRATOR};
+
+type BoxError = Box<dyn std::error::Error>;
+
+#[derive(Debug, Parser)]
+pub struct Cli {
+    /// Flags marked global can be given before or after the subcommand
+    #[clap(long, env, global = true, default_value = "sqlite://data.db")]
+    pub database_url: String,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the server
+    Serve {
+        #[clap(long, env, default_value = "0.0.0.0:8080")]
+        bind_addr: SocketAddr,
+    },
+    /// Apply all pending migrations
+    Migrate {
+        /// Roll back this many of the applied migrations instead, newest first
+        #[clap(long, value_name = "N")]
+        revert: Option<usize>,
+    },
+}
+
+/// Versions of the applied migrations, oldest first
+async fn applied_versions(pool: &SqlitePool) -> Result<Vec<i64>, BoxError> {
+    let mut conn = pool.acquire().await?;
+    // A fresh database does not have the table yet
+    conn.ensure_migrations_table().await?;
+    let mut versions = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect::<Vec<_>>();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+/// Everything newer than the returned version is reverted, 0 reverts all of them
+fn revert_target(applied: &[i64], n: usize) -> i64 {
+    match applied.len().checked_sub(n + 1) {
+        Some(index) => applied[index],
+        None => 0,
+    }
+}
+
+async fn migrate(pool: &SqlitePool, revert: Option<usize>) -> Result<(), BoxError> {
+    let Some(n) = revert else {
+        MIGRATOR.run(pool).await?;
+        println!("Applied all pending migrations");
+        return Ok(());
+    };
+    let applied = applied_versions(pool).await?;
+    let target = revert_target(&applied, n);
+    // Runs the `.down.sql` of every migration newer than the target
+    MIGRATOR.undo(pool, target).await?;
+    println!(
+        "Reverted {} migrations",
+        applied.iter().filter(|&&v| v > target).count()
+    );
+    Ok(())
+}
+
+async fn serve(pool: SqlitePool, bind_addr: SocketAddr) -> Result<(), BoxError> {
+    let applied = applied_versions(&pool)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let pending = MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration() && !applied.contains(&m.version))
+        .count();
+    if pending > 0 {
+        return Err(format!("{pending} migrations are pending, run `migrate` first").into());
+    }
+    let listener = TcpListener::bind(bind_addr).await?;
+    println!("Listening on {bind_addr}");
+    axum::serve(listener, app(pool)).await?;
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() {
+    let cli = Cli::parse();
+    // Each subcommand is an async fn, main only sets up what all of them share and reports errors
+    let result = match connect(&cli.database_url).await {
+        Ok(pool) => match cli.command {
+            Command::Serve { bind_addr } => serve(pool, bind_addr).await,
+            Command::Migrate { revert } => migrate(&pool, revert).await,
+        },
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverts_the_newest_migrations() {
+        let applied = [1, 2, 3];
+        assert_eq!(revert_target(&applied, 0), 3);
+        assert_eq!(revert_target(&applied, 1), 2);
+        assert_eq!(revert_target(&applied, 2), 1);
+        assert_eq!(revert_target(&applied, 3), 0);
+        assert_eq!(revert_target(&applied, 10), 0);
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let cli = Cli::try_parse_from(["app", "migrate", "--revert", "2"]).unwrap();
+        assert!(matches!(cli.command, Command::Migrate { revert: Some(2) }));
+        let cli =
+            Cli::try_parse_from(["app", "serve", "--database-url", "sqlite://test.db"]).unwrap();
+        assert_eq!(cli.database_url, "sqlite://test.db");
+    }
+}
@@ -0,0 +1,429 @@
+//! Every recipe is its own module which is only compiled when its cargo feature is enabled, see Cargo.toml
+//! Each one exposes a `main` function which is registered in [`RECIPES`] so `main.rs` can run it
+
+#[cfg(feature = "accept-language-recipe")]
+pub mod accept_language;
+#[cfg(feature = "accounts-recipe")]
+pub mod accounts;
+#[cfg(feature = "actor-recipe")]
+pub mod actor;
+#[cfg(feature = "admin-shutdown-recipe")]
+pub mod admin_shutdown;
+#[cfg(feature = "api-client-recipe")]
+pub mod api_client;
+#[cfg(feature = "assets-recipe")]
+pub mod assets;
+#[cfg(feature = "auth-recipe")]
+pub mod auth;
+#[cfg(feature = "axum-recipe")]
+pub mod axum_server;
+#[cfg(feature = "bench-recipe")]
+pub mod bench;
+#[cfg(feature = "cache-recipe")]
+pub mod cache;
+#[cfg(feature = "clap-recipe")]
+pub mod clap_config;
+#[cfg(feature = "clock-recipe")]
+pub mod clock;
+#[cfg(feature = "command-dispatch-recipe")]
+pub mod command_dispatch;
+#[cfg(feature = "concurrency-recipe")]
+pub mod concurrency;
+#[cfg(feature = "config-recipe")]
+pub mod config;
+#[cfg(feature = "config-sources-recipe")]
+pub mod config_sources;
+#[cfg(feature = "console-recipe")]
+pub mod console;
+#[cfg(feature = "csv-export-recipe")]
+pub mod csv_export;
+#[cfg(feature = "database-recipe")]
+pub mod database;
+#[cfg(feature = "dates-recipe")]
+pub mod dates;
+#[cfg(feature = "degradation-recipe")]
+pub mod degradation;
+#[cfg(feature = "discovery-recipe")]
+pub mod discovery;
+#[cfg(feature = "distributed-lock-recipe")]
+pub mod distributed_lock;
+#[cfg(feature = "email-recipe")]
+pub mod email;
+#[cfg(feature = "email-template-recipe")]
+pub mod email_template;
+#[cfg(feature = "error-recipe")]
+pub mod error;
+#[cfg(feature = "fan-out-recipe")]
+pub mod fan_out;
+#[cfg(feature = "files-recipe")]
+pub mod files;
+#[cfg(feature = "flags-recipe")]
+pub mod flags;
+#[cfg(feature = "flush-recipe")]
+pub mod flush;
+#[cfg(feature = "graphql-recipe")]
+pub mod graphql;
+#[cfg(feature = "grpc-recipe")]
+pub mod grpc;
+#[cfg(feature = "health-recipe")]
+pub mod health;
+#[cfg(feature = "health-checks-recipe")]
+pub mod health_checks;
+#[cfg(feature = "html-recipe")]
+pub mod html;
+#[cfg(feature = "reqwest-recipe")]
+pub mod http_client;
+#[cfg(feature = "idempotency-recipe")]
+pub mod idempotency;
+#[cfg(feature = "idempotent-retry-recipe")]
+pub mod idempotent_retry;
+#[cfg(feature = "ids-recipe")]
+pub mod ids;
+#[cfg(feature = "jobs-recipe")]
+pub mod jobs;
+#[cfg(feature = "jwt-leeway-recipe")]
+pub mod jwt_leeway;
+#[cfg(feature = "listen-notify-recipe")]
+pub mod listen_notify;
+#[cfg(feature = "tracing-recipe")]
+pub mod logging;
+#[cfg(feature = "messaging-recipe")]
+pub mod messaging;
+#[cfg(feature = "method-override-recipe")]
+pub mod method_override;
+#[cfg(feature = "metrics-recipe")]
+pub mod metrics;
+#[cfg(feature = "middleware-recipe")]
+pub mod middleware;
+#[cfg(feature = "migrate-recipe")]
+pub mod migrate;
+#[cfg(feature = "mtls-recipe")]
+pub mod mtls;
+#[cfg(feature = "multipart-mixed-recipe")]
+pub mod multipart_mixed;
+#[cfg(feature = "ndjson-ingest-recipe")]
+pub mod ndjson_ingest;
+#[cfg(feature = "oauth-recipe")]
+pub mod oauth;
+#[cfg(feature = "offload-recipe")]
+pub mod offload;
+#[cfg(feature = "openapi-recipe")]
+pub mod openapi;
+#[cfg(feature = "openapi-validation-recipe")]
+pub mod openapi_validation;
+#[cfg(feature = "outbound-rate-limit-recipe")]
+pub mod outbound_rate_limit;
+#[cfg(feature = "outbox-recipe")]
+pub mod outbox;
+#[cfg(feature = "pagination-recipe")]
+pub mod pagination;
+#[cfg(feature = "panics-recipe")]
+pub mod panics;
+#[cfg(feature = "patch-recipe")]
+pub mod patch;
+#[cfg(feature = "precompressed-assets-recipe")]
+pub mod precompressed_assets;
+#[cfg(feature = "problem-details-recipe")]
+pub mod problem_details;
+#[cfg(feature = "proptest-recipe")]
+pub mod property_tests;
+#[cfg(feature = "proxy-protocol-recipe")]
+pub mod proxy_protocol;
+#[cfg(feature = "rate-limit-recipe")]
+pub mod rate_limit;
+#[cfg(feature = "raw-body-recipe")]
+pub mod raw_body;
+#[cfg(feature = "read-replica-recipe")]
+pub mod read_replica;
+#[cfg(feature = "replay-recipe")]
+pub mod replay;
+#[cfg(feature = "resilience-recipe")]
+pub mod resilience;
+#[cfg(feature = "scheduler-recipe")]
+pub mod scheduler;
+#[cfg(feature = "sessions-recipe")]
+pub mod sessions;
+#[cfg(feature = "shutdown-recipe")]
+pub mod shutdown;
+#[cfg(feature = "sqlite-recipe")]
+pub mod sqlite;
+#[cfg(feature = "sqlx-cancellation-recipe")]
+pub mod sqlx_cancellation;
+#[cfg(feature = "sse-recipe")]
+pub mod sse;
+#[cfg(feature = "state-recipe")]
+pub mod state;
+#[cfg(feature = "storage-recipe")]
+pub mod storage;
+#[cfg(feature = "streaming-client-recipe")]
+pub mod streaming_client;
+#[cfg(feature = "swr-cache-recipe")]
+pub mod swr_cache;
+#[cfg(feature = "tcp-recipe")]
+pub mod tcp;
+#[cfg(feature = "tls-recipe")]
+pub mod tls;
+#[cfg(feature = "upstreams-recipe")]
+pub mod upstreams;
+#[cfg(feature = "validation-recipe")]
+pub mod validation;
+#[cfg(feature = "web-service")]
+pub mod web_service;
+#[cfg(feature = "webhook-recipe")]
+pub mod webhook;
+#[cfg(feature = "websocket-recipe")]
+pub mod websocket;
+#[cfg(feature = "websocket-heartbeat-recipe")]
+pub mod websocket_heartbeat;
+#[cfg(feature = "workers-recipe")]
+pub mod workers;
+
+/// All recipes enabled via cargo features as `(name, feature, main)`
+/// The name is what has to be passed as `RECIPE=<name>` when more than one recipe is enabled
+/// The feature tells the recipes asked for apart from the ones they build on
+pub const RECIPES: &[(&str, &str, fn())] = &[
+    #[cfg(feature = "accept-language-recipe")]
+    (
+        "accept_language",
+        "accept-language-recipe",
+        accept_language::main,
+    ),
+    #[cfg(feature = "accounts-recipe")]
+    ("accounts", "accounts-recipe", accounts::main),
+    #[cfg(feature = "actor-recipe")]
+    ("actor", "actor-recipe", actor::main),
+    #[cfg(feature = "admin-shutdown-recipe")]
+    (
+        "admin_shutdown",
+        "admin-shutdown-recipe",
+        admin_shutdown::main,
+    ),
+    #[cfg(feature = "api-client-recipe")]
+    ("api_client", "api-client-recipe", api_client::main),
+    #[cfg(feature = "assets-recipe")]
+    ("assets", "assets-recipe", assets::main),
+    #[cfg(feature = "auth-recipe")]
+    ("auth", "auth-recipe", auth::main),
+    #[cfg(feature = "axum-recipe")]
+    ("axum_server", "axum-recipe", axum_server::main),
+    #[cfg(feature = "bench-recipe")]
+    ("bench", "bench-recipe", bench::main),
+    #[cfg(feature = "cache-recipe")]
+    ("cache", "cache-recipe", cache::main),
+    #[cfg(feature = "clap-recipe")]
+    ("clap_config", "clap-recipe", clap_config::main),
+    #[cfg(feature = "clock-recipe")]
+    ("clock", "clock-recipe", clock::main),
+    #[cfg(feature = "command-dispatch-recipe")]
+    (
+        "command_dispatch",
+        "command-dispatch-recipe",
+        command_dispatch::main,
+    ),
+    #[cfg(feature = "concurrency-recipe")]
+    ("concurrency", "concurrency-recipe", concurrency::main),
+    #[cfg(feature = "config-recipe")]
+    ("config", "config-recipe", config::main),
+    #[cfg(feature = "config-sources-recipe")]
+    (
+        "config_sources",
+        "config-sources-recipe",
+        config_sources::main,
+    ),
+    #[cfg(feature = "console-recipe")]
+    ("console", "console-recipe", console::main),
+    #[cfg(feature = "csv-export-recipe")]
+    ("csv_export", "csv-export-recipe", csv_export::main),
+    #[cfg(feature = "database-recipe")]
+    ("database", "database-recipe", database::main),
+    #[cfg(feature = "dates-recipe")]
+    ("dates", "dates-recipe", dates::main),
+    #[cfg(feature = "degradation-recipe")]
+    ("degradation", "degradation-recipe", degradation::main),
+    #[cfg(feature = "discovery-recipe")]
+    ("discovery", "discovery-recipe", discovery::main),
+    #[cfg(feature = "distributed-lock-recipe")]
+    (
+        "distributed_lock",
+        "distributed-lock-recipe",
+        distributed_lock::main,
+    ),
+    #[cfg(feature = "email-recipe")]
+    ("email", "email-recipe", email::main),
+    #[cfg(feature = "email-template-recipe")]
+    (
+        "email_template",
+        "email-template-recipe",
+        email_template::main,
+    ),
+    #[cfg(feature = "error-recipe")]
+    ("error", "error-recipe", error::main),
+    #[cfg(feature = "fan-out-recipe")]
+    ("fan_out", "fan-out-recipe", fan_out::main),
+    #[cfg(feature = "files-recipe")]
+    ("files", "files-recipe", files::main),
+    #[cfg(feature = "flags-recipe")]
+    ("flags", "flags-recipe", flags::main),
+    #[cfg(feature = "flush-recipe")]
+    ("flush", "flush-recipe", flush::main),
+    #[cfg(feature = "graphql-recipe")]
+    ("graphql", "graphql-recipe", graphql::main),
+    #[cfg(feature = "grpc-recipe")]
+    ("grpc", "grpc-recipe", grpc::main),
+    #[cfg(feature = "health-recipe")]
+    ("health", "health-recipe", health::main),
+    #[cfg(feature = "health-checks-recipe")]
+    ("health_checks", "health-checks-recipe", health_checks::main),
+    #[cfg(feature = "html-recipe")]
+    ("html", "html-recipe", html::main),
+    #[cfg(feature = "reqwest-recipe")]
+    ("http_client", "reqwest-recipe", http_client::main),
+    #[cfg(feature = "idempotency-recipe")]
+    ("idempotency", "idempotency-recipe", idempotency::main),
+    #[cfg(feature = "idempotent-retry-recipe")]
+    (
+        "idempotent_retry",
+        "idempotent-retry-recipe",
+        idempotent_retry::main,
+    ),
+    #[cfg(feature = "ids-recipe")]
+    ("ids", "ids-recipe", ids::main),
+    #[cfg(feature = "jobs-recipe")]
+    ("jobs", "jobs-recipe", jobs::main),
+    #[cfg(feature = "jwt-leeway-recipe")]
+    ("jwt_leeway", "jwt-leeway-recipe", jwt_leeway::main),
+    #[cfg(feature = "listen-notify-recipe")]
+    ("listen_notify", "listen-notify-recipe", listen_notify::main),
+    #[cfg(feature = "tracing-recipe")]
+    ("logging", "tracing-recipe", logging::main),
+    #[cfg(feature = "messaging-recipe")]
+    ("messaging", "messaging-recipe", messaging::main),
+    #[cfg(feature = "method-override-recipe")]
+    (
+        "method_override",
+        "method-override-recipe",
+        method_override::main,
+    ),
+    #[cfg(feature = "metrics-recipe")]
+    ("metrics", "metrics-recipe", metrics::main),
+    #[cfg(feature = "middleware-recipe")]
+    ("middleware", "middleware-recipe", middleware::main),
+    #[cfg(feature = "migrate-recipe")]
+    ("migrate", "migrate-recipe", migrate::main),
+    #[cfg(feature = "mtls-recipe")]
+    ("mtls", "mtls-recipe", mtls::main),
+    #[cfg(feature = "multipart-mixed-recipe")]
+    (
+        "multipart_mixed",
+        "multipart-mixed-recipe",
+        multipart_mixed::main,
+    ),
+    #[cfg(feature = "ndjson-ingest-recipe")]
+    ("ndjson_ingest", "ndjson-ingest-recipe", ndjson_ingest::main),
+    #[cfg(feature = "oauth-recipe")]
+    ("oauth", "oauth-recipe", oauth::main),
+    #[cfg(feature = "offload-recipe")]
+    ("offload", "offload-recipe", offload::main),
+    #[cfg(feature = "openapi-recipe")]
+    ("openapi", "openapi-recipe", openapi::main),
+    #[cfg(feature = "openapi-validation-recipe")]
+    (
+        "openapi_validation",
+        "openapi-validation-recipe",
+        openapi_validation::main,
+    ),
+    #[cfg(feature = "outbound-rate-limit-recipe")]
+    (
+        "outbound_rate_limit",
+        "outbound-rate-limit-recipe",
+        outbound_rate_limit::main,
+    ),
+    #[cfg(feature = "outbox-recipe")]
+    ("outbox", "outbox-recipe", outbox::main),
+    #[cfg(feature = "pagination-recipe")]
+    ("pagination", "pagination-recipe", pagination::main),
+    #[cfg(feature = "panics-recipe")]
+    ("panics", "panics-recipe", panics::main),
+    #[cfg(feature = "patch-recipe")]
+    ("patch", "patch-recipe", patch::main),
+    #[cfg(feature = "precompressed-assets-recipe")]
+    (
+        "precompressed_assets",
+        "precompressed-assets-recipe",
+        precompressed_assets::main,
+    ),
+    #[cfg(feature = "problem-details-recipe")]
+    (
+        "problem_details",
+        "problem-details-recipe",
+        problem_details::main,
+    ),
+    #[cfg(feature = "proptest-recipe")]
+    ("property_tests", "proptest-recipe", property_tests::main),
+    #[cfg(feature = "proxy-protocol-recipe")]
+    (
+        "proxy_protocol",
+        "proxy-protocol-recipe",
+        proxy_protocol::main,
+    ),
+    #[cfg(feature = "rate-limit-recipe")]
+    ("rate_limit", "rate-limit-recipe", rate_limit::main),
+    #[cfg(feature = "raw-body-recipe")]
+    ("raw_body", "raw-body-recipe", raw_body::main),
+    #[cfg(feature = "read-replica-recipe")]
+    ("read_replica", "read-replica-recipe", read_replica::main),
+    #[cfg(feature = "replay-recipe")]
+    ("replay", "replay-recipe", replay::main),
+    #[cfg(feature = "resilience-recipe")]
+    ("resilience", "resilience-recipe", resilience::main),
+    #[cfg(feature = "scheduler-recipe")]
+    ("scheduler", "scheduler-recipe", scheduler::main),
+    #[cfg(feature = "sessions-recipe")]
+    ("sessions", "sessions-recipe", sessions::main),
+    #[cfg(feature = "shutdown-recipe")]
+    ("shutdown", "shutdown-recipe", shutdown::main),
+    #[cfg(feature = "sqlite-recipe")]
+    ("sqlite", "sqlite-recipe", sqlite::main),
+    #[cfg(feature = "sqlx-cancellation-recipe")]
+    (
+        "sqlx_cancellation",
+        "sqlx-cancellation-recipe",
+        sqlx_cancellation::main,
+    ),
+    #[cfg(feature = "sse-recipe")]
+    ("sse", "sse-recipe", sse::main),
+    #[cfg(feature = "state-recipe")]
+    ("state", "state-recipe", state::main),
+    #[cfg(feature = "storage-recipe")]
+    ("storage", "storage-recipe", storage::main),
+    #[cfg(feature = "streaming-client-recipe")]
+    (
+        "streaming_client",
+        "streaming-client-recipe",
+        streaming_client::main,
+    ),
+    #[cfg(feature = "swr-cache-recipe")]
+    ("swr_cache", "swr-cache-recipe", swr_cache::main),
+    #[cfg(feature = "tcp-recipe")]
+    ("tcp", "tcp-recipe", tcp::main),
+    #[cfg(feature = "tls-recipe")]
+    ("tls", "tls-recipe", tls::main),
+    #[cfg(feature = "upstreams-recipe")]
+    ("upstreams", "upstreams-recipe", upstreams::main),
+    #[cfg(feature = "validation-recipe")]
+    ("validation", "validation-recipe", validation::main),
+    #[cfg(feature = "web-service")]
+    ("web_service", "web-service", web_service::main),
+    #[cfg(feature = "webhook-recipe")]
+    ("webhook", "webhook-recipe", webhook::main),
+    #[cfg(feature = "websocket-recipe")]
+    ("websocket", "websocket-recipe", websocket::main),
+    #[cfg(feature = "websocket-heartbeat-recipe")]
+    (
+        "websocket_heartbeat",
+        "websocket-heartbeat-recipe",
+        websocket_heartbeat::main,
+    ),
+    #[cfg(feature = "workers-recipe")]
+    ("workers", "workers-recipe", workers::main),
+];
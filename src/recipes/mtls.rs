@@ -0,0 +1,331 @@
+//! Https server with optional mutual TLS (client certificate authentication)
+//! Requires `cargo add axum tracing x509-parser`
+//! `cargo add rustls --no-default-features -F ring -F std -F logging -F tls12`
+//! `cargo add tokio-rustls --no-default-features -F ring -F logging -F tls12`
+//! `cargo add hyper -F server -F http1 -F http2`
+//! `cargo add hyper-util -F server-auto -F tokio`
+//! `cargo add tower -F util`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev rcgen@0.13`
+
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    async_trait, extract::FromRequestParts, http::request::Parts, http::StatusCode, routing::get,
+    Router,
+};
+use hyper_util::{rt::TokioExecutor, rt::TokioIo, server::conn::auto};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{info, warn};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Connections without a certificate signed by the client CA are rejected during the handshake
+    Required,
+    /// Connections without a certificate are accepted but show up as [`ClientIdentity::Anonymous`].
+    /// Certificates that are presented still have to be valid.
+    Optional,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA used to verify client certificates. Without it client certificates are not requested at all.
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+}
+
+/// Who is on the other end of the connection as established during the TLS handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientIdentity {
+    Verified {
+        common_name: Option<String>,
+        /// DNS names and URIs from the subject alternative name extension
+        san: Vec<String>,
+    },
+    Anonymous,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIdentity {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // The accept loop inserts this for every request so if it's missing the router was not served by it
+        parts
+            .extensions
+            .get::<ClientIdentity>()
+            .cloned()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+async fn whoami(identity: ClientIdentity) -> Result<String, StatusCode> {
+    match identity {
+        ClientIdentity::Verified { common_name, san } => Ok(format!(
+            "Hello {} ({})",
+            common_name.unwrap_or_default(),
+            san.join(", ")
+        )),
+        ClientIdentity::Anonymous => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = TlsConfig {
+        cert: "certs/server.crt".into(),
+        key: "certs/server.key".into(),
+        client_ca: Some("certs/ca.crt".into()),
+        client_auth: ClientAuth::Optional,
+    };
+    let app = Router::new().route("/whoami", get(whoami));
+    let listener = TcpListener::bind("0.0.0.0:8443").await.unwrap();
+    serve(listener, server_config(&config).unwrap(), app).await;
+}
+
+pub fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, BoxError> {
+    let provider = Arc::new(ring::default_provider());
+    let certs = CertificateDer::pem_file_iter(&config.cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)?;
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let server_config = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca)? {
+                roots.add(cert?)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider);
+            let verifier = match config.client_auth {
+                ClientAuth::Required => verifier.build()?,
+                ClientAuth::Optional => verifier.allow_unauthenticated().build()?,
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_single_cert(certs, key)?;
+    Ok(Arc::new(server_config))
+}
+
+/// `axum::serve` does not support TLS so we accept connections ourselves and hand them to hyper
+pub async fn serve(listener: TcpListener, config: Arc<ServerConfig>, app: Router) {
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        // Handshakes can be slow so they must not block accepting the next connection
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // This is where missing or untrusted client certificates end up
+                    warn!(%addr, "TLS handshake failed: {e}");
+                    return;
+                }
+            };
+            let identity = client_identity(stream.get_ref().1.peer_certificates());
+            info!(%addr, ?identity, "Accepted TLS connection");
+            let service = hyper::service::service_fn(move |mut req| {
+                req.extensions_mut().insert(identity.clone());
+                app.clone().oneshot(req)
+            });
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                warn!(%addr, "Error serving connection: {e}");
+            }
+        });
+    }
+}
+
+/// rustls already verified the chain so we only need to read the names from the leaf certificate
+fn client_identity(peer_certs: Option<&[CertificateDer<'_>]>) -> ClientIdentity {
+    let Some(Ok((_, cert))) = peer_certs
+        .and_then(|certs| certs.first())
+        .map(|cert| X509Certificate::from_der(cert))
+    else {
+        return ClientIdentity::Anonymous;
+    };
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(ToOwned::to_owned);
+    let san = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    ClientIdentity::Verified { common_name, san }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair, SanType,
+    };
+    use rustls::{pki_types::ServerName, ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    struct Pki {
+        ca: CertifiedKey,
+        server: CertifiedKey,
+        client: CertifiedKey,
+    }
+
+    fn pki() -> Pki {
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key_pair = KeyPair::generate().unwrap();
+        let ca = CertifiedKey {
+            cert: params.self_signed(&key_pair).unwrap(),
+            key_pair,
+        };
+        let signed = |params: CertificateParams| {
+            let key_pair = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+            CertifiedKey { cert, key_pair }
+        };
+        let mut params = CertificateParams::new(["localhost".to_owned()]).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server = signed(params);
+        let mut params = CertificateParams::new(["alice.example.com".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.subject_alt_names.push(SanType::URI(
+            "spiffe://example.com/alice".try_into().unwrap(),
+        ));
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = signed(params);
+        Pki { ca, server, client }
+    }
+
+    /// Writes the files `server_config` reads to a directory of their own
+    fn tls_config(pki: &Pki, dir: &Path, client_auth: ClientAuth) -> TlsConfig {
+        fs::create_dir_all(dir).unwrap();
+        let write = |name: &str, pem: String| {
+            fs::write(dir.join(name), pem).unwrap();
+            dir.join(name)
+        };
+        TlsConfig {
+            cert: write("server.crt", pki.server.cert.pem()),
+            key: write("server.key", pki.server.key_pair.serialize_pem()),
+            client_ca: Some(write("ca.crt", pki.ca.cert.pem())),
+            client_auth,
+        }
+    }
+
+    /// Serves `whoami` and requests it with or without the client certificate
+    async fn request(
+        pki: &Pki,
+        client_auth: ClientAuth,
+        with_cert: bool,
+    ) -> std::io::Result<String> {
+        let dir = std::env::temp_dir().join(format!(
+            "mtls-{}-{client_auth:?}-{with_cert}",
+            std::process::id()
+        ));
+        let config = server_config(&tls_config(pki, &dir, client_auth)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/whoami", get(whoami));
+        tokio::spawn(serve(listener, config, app));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.cert.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let client_config = if with_cert {
+            let key = PrivateKeyDer::try_from(pki.client.key_pair.serialize_der()).unwrap();
+            builder
+                .with_client_auth_cert(vec![pki.client.cert.der().clone()], key)
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+        stream
+            .write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn identifies_clients_by_certificate() {
+        let pki = pki();
+        let response = request(&pki, ClientAuth::Required, true).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("Hello alice (alice.example.com, spiffe://example.com/alice)"));
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_without_certificate() {
+        let pki = pki();
+        // With TLS 1.3 the client finishes the handshake first, the rejection arrives on read
+        let response = request(&pki, ClientAuth::Required, false).await;
+        assert!(response.is_err(), "{response:?}");
+
+        let response = request(&pki, ClientAuth::Optional, false).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized"));
+    }
+
+    #[test]
+    fn reads_names_from_the_leaf_certificate() {
+        let pki = pki();
+        let identity = client_identity(Some(&[pki.client.cert.der().clone()]));
+        assert_eq!(
+            identity,
+            ClientIdentity::Verified {
+                common_name: Some("alice".to_owned()),
+                san: vec![
+                    "alice.example.com".to_owned(),
+                    "spiffe://example.com/alice".to_owned()
+                ],
+            }
+        );
+        assert_eq!(client_identity(None), ClientIdentity::Anonymous);
+    }
+}
@@ -0,0 +1,184 @@
+//! Streaming several resources in one `multipart/mixed` response
+//! Requires `cargo add axum bytes futures`
+//! `cargo add uuid -F v4`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+
+use std::io;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream, Stream, StreamExt};
+use tokio::net::TcpListener;
+
+pub struct Part {
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Part {
+    pub fn new(content_type: &'static str, body: impl Into<Bytes>) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Self {
+            headers,
+            body: body.into(),
+        }
+    }
+}
+
+/// Parts are written as they come out of the stream so they don't have to fit into memory together
+pub fn multipart_mixed(parts: impl Stream<Item = Part> + Send + 'static) -> Response {
+    // 128 random bits practically never show up in the content. If they do we abort
+    // the response rather than sending something the client would split up in the wrong places.
+    let boundary = format!("part-{}", uuid::Uuid::new_v4().simple());
+    let content_type = format!("multipart/mixed; boundary={boundary}");
+    let delimiter = Bytes::from(format!("--{boundary}"));
+    let closing = Bytes::from(format!("--{boundary}--\r\n"));
+
+    let body = parts
+        .map(move |part| encode_part(&delimiter, part))
+        .chain(stream::once(async move { Ok(closing) }));
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+fn encode_part(delimiter: &[u8], part: Part) -> io::Result<Bytes> {
+    if part
+        .body
+        .windows(delimiter.len())
+        .any(|window| window == delimiter)
+    {
+        return Err(io::Error::other("Part contains the multipart boundary"));
+    }
+    let mut buf = BytesMut::with_capacity(part.body.len() + 128);
+    buf.put_slice(delimiter);
+    buf.put_slice(b"\r\n");
+    for (name, value) in &part.headers {
+        buf.put_slice(name.as_str().as_bytes());
+        buf.put_slice(b": ");
+        buf.put_slice(value.as_bytes());
+        buf.put_slice(b"\r\n");
+    }
+    // An empty line separates the headers from the body
+    buf.put_slice(b"\r\n");
+    buf.put_slice(&part.body);
+    // The line break before the next delimiter belongs to the delimiter and not to the body
+    buf.put_slice(b"\r\n");
+    Ok(buf.freeze())
+}
+
+async fn bundle() -> Response {
+    let parts = stream::iter([
+        Part::new("application/json", r#"{"id":1,"name":"Jane"}"#),
+        Part::new("text/plain; charset=utf-8", "Hello, world!"),
+    ]);
+    multipart_mixed(parts)
+}
+
+pub fn app() -> Router {
+    Router::new().route("/bundle", get(bundle))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app()).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Splits the body at the boundary from the content type, like a client would
+    fn parse(response_content_type: &str, body: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let boundary = response_content_type
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap();
+        let body = std::str::from_utf8(body).unwrap();
+        let body = body
+            .strip_suffix(&format!("\r\n--{boundary}--\r\n"))
+            .expect("Missing closing boundary");
+        let body = body.strip_prefix(&format!("--{boundary}\r\n")).unwrap();
+        body.split(&format!("\r\n--{boundary}\r\n"))
+            .map(|part| {
+                let (headers, body) = part.split_once("\r\n\r\n").unwrap();
+                let content_type = headers.strip_prefix("content-type: ").unwrap();
+                (content_type.to_owned(), body.as_bytes().to_vec())
+            })
+            .collect()
+    }
+
+    async fn into_parts(response: Response) -> Vec<(String, Vec<u8>)> {
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        parse(&content_type, &body)
+    }
+
+    #[tokio::test]
+    async fn parses_back_into_the_parts() {
+        let response = app()
+            .oneshot(Request::get("/bundle").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            into_parts(response).await,
+            [
+                (
+                    "application/json".to_owned(),
+                    br#"{"id":1,"name":"Jane"}"#.to_vec()
+                ),
+                (
+                    "text/plain; charset=utf-8".to_owned(),
+                    b"Hello, world!".to_vec()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_line_breaks_and_dashes_in_bodies() {
+        let parts = stream::iter([
+            Part::new("text/plain", "--\r\n--part-\r\n\r\n"),
+            Part::new("text/plain", ""),
+        ]);
+        assert_eq!(
+            into_parts(multipart_mixed(parts)).await,
+            [
+                ("text/plain".to_owned(), b"--\r\n--part-\r\n\r\n".to_vec()),
+                ("text/plain".to_owned(), Vec::new()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aborts_on_a_part_containing_the_boundary() {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let response = multipart_mixed(rx);
+        let boundary = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap()
+            .to_owned();
+        tx.unbounded_send(Part::new("text/plain", "fine")).unwrap();
+        tx.unbounded_send(Part::new("text/plain", format!("a\r\n--{boundary}\r\nb")))
+            .unwrap();
+        drop(tx);
+        assert!(to_bytes(response.into_body(), usize::MAX).await.is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! Streaming ingest of (optionally gzip compressed) NDJSON
+//! Requires `cargo add axum futures serde_json`
+//! `cargo add async-compression -F tokio -F gzip`
+//! `cargo add serde -F derive`
+//! `cargo add tokio-util -F io`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F io-util`
+//! Tests require `cargo add --dev serde_json`
+//! `cargo add --dev tower -F util`
+//!
+//! Chunked transfer encoding is already taken care of by hyper, the handler just sees a stream of bytes.
+//! Records are processed one line at a time so memory usage does not depend on the size of the upload.
+//! Try it with `gzip -c records.ndjson | curl -H 'content-encoding: gzip' --data-binary @- localhost:8080/ingest`
+
+use std::io;
+
+use async_compression::tokio::bufread::GzipDecoder;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::TcpListener,
+};
+use tokio_util::io::StreamReader;
+
+/// Limit of the decompressed data. A few kilobytes of gzip can decompress to gigabytes.
+const MAX_DECOMPRESSED_SIZE: u64 = 100 * 1024 * 1024;
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IngestSummary {
+    pub processed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+async fn ingest(headers: HeaderMap, body: Body) -> (StatusCode, Json<IngestSummary>) {
+    let stream = body.into_data_stream().map_err(io::Error::other);
+    let reader = StreamReader::new(stream);
+    let gzip = headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding == "gzip");
+    let result = if gzip {
+        process(BufReader::new(GzipDecoder::new(reader)), handle_record).await
+    } else {
+        process(reader, handle_record).await
+    };
+    match result {
+        Ok(processed) => (
+            StatusCode::OK,
+            Json(IngestSummary {
+                processed,
+                error: None,
+            }),
+        ),
+        // Everything before the error was processed already so tell the client where to resume
+        Err((processed, e)) => (
+            StatusCode::BAD_REQUEST,
+            Json(IngestSummary {
+                processed,
+                error: Some(e.to_string()),
+            }),
+        ),
+    }
+}
+
+async fn handle_record(record: Record) {
+    println!("{} {}", record.id, record.value);
+}
+
+/// Returns the number of processed records or how many were processed before the error
+pub async fn process<F, Fut>(
+    reader: impl AsyncBufRead + Unpin,
+    mut handle: F,
+) -> Result<u64, (u64, io::Error)>
+where
+    F: FnMut(Record) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    // Reading one byte more than allowed tells us apart an upload of exactly the limit and one that is too large
+    let mut reader = BufReader::new(reader.take(MAX_DECOMPRESSED_SIZE + 1));
+    let mut processed = 0;
+    let mut line = String::new();
+    let mut read = 0;
+    loop {
+        line.clear();
+        // A truncated gzip stream shows up here as an UnexpectedEof error
+        let n = match (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_line(&mut line)
+            .await
+        {
+            Ok(n) => n,
+            Err(e) => return Err((processed, e)),
+        };
+        if n == 0 {
+            return Ok(processed);
+        }
+        read += n as u64;
+        if read > MAX_DECOMPRESSED_SIZE {
+            return Err((processed, io::Error::other("Decompressed body too large")));
+        }
+        if !line.ends_with('\n') && n == MAX_LINE_LENGTH {
+            return Err((processed, io::Error::other("Line too long")));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            (
+                processed,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid record {}: {e}", processed + 1),
+                ),
+            )
+        })?;
+        handle(record).await;
+        processed += 1;
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app()).await.unwrap();
+}
+
+pub fn app() -> Router {
+    Router::new().route("/ingest", post(ingest))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use axum::{
+        body::{to_bytes, Bytes},
+        extract::Request,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn records(count: u64) -> Vec<u8> {
+        (1..=count)
+            .map(|id| format!("{{\"id\": {id}, \"value\": \"record {id}\"}}\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    async fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        GzipEncoder::new(data)
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        compressed
+    }
+
+    /// Sends the body in small chunks like a chunked upload would arrive
+    async fn post_ingest(body: Vec<u8>, gzip: bool) -> (StatusCode, Value) {
+        let chunks: Vec<_> = body
+            .chunks(100)
+            .map(|chunk| Ok::<_, io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let mut req = Request::post("/ingest");
+        if gzip {
+            req = req.header(header::CONTENT_ENCODING, "gzip");
+        }
+        let req = req
+            .body(Body::from_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn processes_gzip_compressed_chunks() {
+        let body = gzip(&records(1000)).await;
+        let (status, summary) = post_ingest(body, true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(summary, json!({ "processed": 1000 }));
+    }
+
+    #[tokio::test]
+    async fn truncated_gzip_reports_progress() {
+        let mut body = gzip(&records(1000)).await;
+        body.truncate(body.len() / 2);
+        let (status, summary) = post_ingest(body, true).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let processed = summary["processed"].as_u64().unwrap();
+        assert!(processed > 0 && processed < 1000, "{summary}");
+        assert_eq!(summary["error"], "unexpected end of file");
+    }
+
+    #[tokio::test]
+    async fn accepts_a_last_line_without_newline() {
+        let mut body = records(2);
+        body.pop();
+        let (status, summary) = post_ingest(body, false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(summary, json!({ "processed": 2 }));
+    }
+
+    #[tokio::test]
+    async fn partial_last_line_is_an_error() {
+        let mut body = records(2);
+        body.truncate(body.len() - 10);
+        let (status, summary) = post_ingest(body, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(summary["processed"], 1);
+        assert!(summary["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid record 2: EOF while parsing"));
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_stop_the_ingest() {
+        let mut body = records(1);
+        body.extend_from_slice(b"\n{\"id\": \"two\"}\n");
+        body.extend_from_slice(&records(1));
+        let (status, summary) = post_ingest(body, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(summary["processed"], 1);
+        assert!(summary["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid record 2: invalid type"));
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_lines() {
+        let body = vec![b'x'; MAX_LINE_LENGTH + 1];
+        let (status, summary) = post_ingest(body, false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(summary, json!({ "processed": 0, "error": "Line too long" }));
+    }
+}
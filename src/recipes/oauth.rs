@@ -0,0 +1,382 @@
+//! Logging in with an OAuth2/OpenID Connect provider using the authorization code flow
+//! Requires `cargo add axum oauth2 reqwest tower-sessions@0.13`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Builds on the `sessions` recipe which keeps the login state and the tokens.
+//!
+//! `/login` redirects to the provider which sends the browser back to `/callback` with a code.
+//! The code is exchanged for tokens directly with the provider, so they never pass through the browser.
+//! The `state` parameter ties the callback to the login started in this session (CSRF) and PKCE ties
+//! the code to it, so a code intercepted on the way back is useless to anyone else.
+//! The endpoints come from the config to work with any provider, the `openidconnect` crate can
+//! discover them and verify ID tokens instead of asking the userinfo endpoint.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use oauth2::{
+    basic::BasicClient, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
+    EndpointNotSet, EndpointSet, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    TokenResponse, TokenUrl,
+};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tower_sessions::Session;
+
+use crate::recipes::sessions::{session_layer, SessionConfig};
+
+const STATE_KEY: &str = "oauth_state";
+const VERIFIER_KEY: &str = "oauth_pkce_verifier";
+const USER_KEY: &str = "user";
+const ACCESS_TOKEN_KEY: &str = "access_token";
+
+#[derive(Debug, Parser)]
+pub struct OAuthConfig {
+    #[clap(long, env)]
+    pub oauth_client_id: String,
+    #[clap(long, env, hide_env_values = true)]
+    pub oauth_client_secret: String,
+    /// e.g. `https://accounts.google.com/o/oauth2/v2/auth`
+    #[clap(long, env)]
+    pub oauth_auth_url: String,
+    /// e.g. `https://oauth2.googleapis.com/token`
+    #[clap(long, env)]
+    pub oauth_token_url: String,
+    /// e.g. `https://openidconnect.googleapis.com/v1/userinfo`
+    #[clap(long, env)]
+    pub oauth_userinfo_url: String,
+    /// Has to be registered with the provider exactly like this
+    #[clap(long, env, default_value = "http://localhost:8080/callback")]
+    pub oauth_redirect_url: String,
+    #[command(flatten)]
+    pub session: SessionConfig,
+}
+
+type OAuthClient =
+    BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+
+#[derive(Clone)]
+pub struct AppState {
+    client: OAuthClient,
+    http: reqwest::Client,
+    userinfo_url: String,
+}
+
+impl AppState {
+    pub fn new(config: &OAuthConfig) -> Result<Self, oauth2::url::ParseError> {
+        let client = BasicClient::new(ClientId::new(config.oauth_client_id.clone()))
+            .set_client_secret(ClientSecret::new(config.oauth_client_secret.clone()))
+            .set_auth_uri(AuthUrl::new(config.oauth_auth_url.clone())?)
+            .set_token_uri(TokenUrl::new(config.oauth_token_url.clone())?)
+            .set_redirect_uri(RedirectUrl::new(config.oauth_redirect_url.clone())?);
+        let http = reqwest::Client::builder()
+            // A token endpoint redirecting somewhere else could leak the code and secret there
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Client config is valid");
+        Ok(Self {
+            client,
+            http,
+            userinfo_url: config.oauth_userinfo_url.clone(),
+        })
+    }
+}
+
+async fn login(State(state): State<AppState>, session: Session) -> Result<Redirect, StatusCode> {
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, csrf) = state
+        .client
+        .authorize_url(CsrfToken::new_random)
+        .add_scope(Scope::new("openid".to_owned()))
+        .add_scope(Scope::new("email".to_owned()))
+        .set_pkce_challenge(challenge)
+        .url();
+    session
+        .insert(STATE_KEY, csrf.secret())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    session
+        .insert(VERIFIER_KEY, verifier.secret())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set instead of the code when the user declined or the provider failed
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+}
+
+async fn callback(
+    State(state): State<AppState>,
+    session: Session,
+    Query(callback): Query<Callback>,
+) -> Result<Redirect, Response> {
+    let internal = |e: &dyn std::fmt::Display| {
+        eprintln!("Login failed: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    };
+    let bad_request = |message: &str| (StatusCode::BAD_REQUEST, message.to_owned()).into_response();
+    if let Some(error) = callback.error {
+        return Err(bad_request(&format!("Login failed: {error}")));
+    }
+    // Removed right away so every login attempt can only be completed once
+    let expected_state: Option<String> =
+        session.remove(STATE_KEY).await.map_err(|e| internal(&e))?;
+    let verifier: Option<String> = session
+        .remove(VERIFIER_KEY)
+        .await
+        .map_err(|e| internal(&e))?;
+    let (Some(expected_state), Some(verifier)) = (expected_state, verifier) else {
+        return Err(bad_request("No login in progress, start again at /login"));
+    };
+    if callback.state.as_deref() != Some(expected_state.as_str()) {
+        return Err(bad_request(
+            "Login state does not match, start again at /login",
+        ));
+    }
+    let code = callback.code.ok_or_else(|| bad_request("Missing code"))?;
+
+    let token = state
+        .client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(PkceCodeVerifier::new(verifier))
+        .request_async(&state.http)
+        .await
+        .map_err(|e| internal(&e))?;
+    let user: UserInfo = state
+        .http
+        .get(&state.userinfo_url)
+        .bearer_auth(token.access_token().secret())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| internal(&e))?
+        .json()
+        .await
+        .map_err(|e| internal(&e))?;
+
+    // A new session id once logged in, see the `sessions` recipe
+    session.cycle_id().await.map_err(|e| internal(&e))?;
+    let name = user.email.unwrap_or(user.sub);
+    session
+        .insert(USER_KEY, name)
+        .await
+        .map_err(|e| internal(&e))?;
+    // Kept on the server for calling the provider's APIs on behalf of the user later
+    session
+        .insert(ACCESS_TOKEN_KEY, token.access_token().secret())
+        .await
+        .map_err(|e| internal(&e))?;
+    Ok(Redirect::to("/"))
+}
+
+async fn home(session: Session) -> String {
+    match session.get::<String>(USER_KEY).await.ok().flatten() {
+        Some(user) => format!("Logged in as {user}"),
+        None => "Not logged in, go to /login".to_owned(),
+    }
+}
+
+pub fn app(config: &OAuthConfig) -> Result<Router, oauth2::url::ParseError> {
+    Ok(Router::new()
+        .route("/", get(home))
+        .route("/login", get(login))
+        .route("/callback", get(callback))
+        .with_state(AppState::new(config)?)
+        // The session cookie is SameSite=Lax so the browser still sends it when the provider redirects back
+        .layer(session_layer(&config.session)))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = OAuthConfig::parse();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(&config).unwrap()).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use oauth2::url::{form_urlencoded, Url};
+    use tower::ServiceExt;
+    use wiremock::{
+        matchers::{header as has_header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn config(provider: &MockServer) -> OAuthConfig {
+        OAuthConfig {
+            oauth_client_id: "client".to_owned(),
+            oauth_client_secret: "secret".to_owned(),
+            oauth_auth_url: format!("{}/authorize", provider.uri()),
+            oauth_token_url: format!("{}/token", provider.uri()),
+            oauth_userinfo_url: format!("{}/userinfo", provider.uri()),
+            oauth_redirect_url: "http://localhost:8080/callback".to_owned(),
+            session: SessionConfig {
+                cookie_secure: false,
+                session_idle_minutes: 60,
+            },
+        }
+    }
+
+    async fn visit(app: &Router, uri: &str, cookie: &str) -> Response {
+        let req = Request::get(uri)
+            .header(header::COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    async fn text(res: Response) -> String {
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    /// The session cookie and the query of the redirect to the provider
+    async fn start_login(app: &Router) -> (String, HashMap<String, String>) {
+        let res = visit(app, "/login", "").await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_owned();
+        let location = Url::parse(res.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+        (cookie, location.query_pairs().into_owned().collect())
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_the_code_and_the_pkce_verifier() {
+        let provider = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "access",
+                "token_type": "bearer",
+            })))
+            .expect(1)
+            .mount(&provider)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/userinfo"))
+            .and(has_header("authorization", "Bearer access"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "sub": "1",
+                "email": "ada@example.com",
+            })))
+            .expect(1)
+            .mount(&provider)
+            .await;
+        let app = app(&config(&provider)).unwrap();
+
+        let (cookie, query) = start_login(&app).await;
+        assert_eq!(query["client_id"], "client");
+        assert_eq!(query["code_challenge_method"], "S256");
+        let callback = format!("/callback?code=the-code&state={}", query["state"]);
+        let res = visit(&app, &callback, &cookie).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        // The id is cycled on login
+        let logged_in = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        let logged_in = logged_in.split(';').next().unwrap();
+        assert_ne!(logged_in, cookie);
+        assert_eq!(
+            text(visit(&app, "/", logged_in).await).await,
+            "Logged in as ada@example.com"
+        );
+
+        // The verifier sent with the code belongs to the challenge sent with the login
+        let token_request = &provider.received_requests().await.unwrap()[0];
+        let form: HashMap<String, String> = form_urlencoded::parse(&token_request.body)
+            .into_owned()
+            .collect();
+        assert_eq!(form["code"], "the-code");
+        let verifier = PkceCodeVerifier::new(form["code_verifier"].clone());
+        let challenge = PkceCodeChallenge::from_code_verifier_sha256(&verifier);
+        assert_eq!(challenge.as_str(), query["code_challenge"]);
+    }
+
+    #[tokio::test]
+    async fn callbacks_without_a_login_are_rejected() {
+        let provider = MockServer::start().await;
+        let app = app(&config(&provider)).unwrap();
+        let res = visit(&app, "/callback?code=the-code&state=guessed", "").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            text(res).await,
+            "No login in progress, start again at /login"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_mismatched_state_ends_the_login() {
+        let provider = MockServer::start().await;
+        let app = app(&config(&provider)).unwrap();
+        let (cookie, query) = start_login(&app).await;
+
+        let res = visit(&app, "/callback?code=the-code&state=forged", &cookie).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            text(res).await,
+            "Login state does not match, start again at /login"
+        );
+        // The attempt is used up, the right state doesn't help anymore
+        let callback = format!("/callback?code=the-code&state={}", query["state"]);
+        let res = visit(&app, &callback, &cookie).await;
+        assert_eq!(
+            text(res).await,
+            "No login in progress, start again at /login"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_codes_do_not_log_in() {
+        let provider = MockServer::start().await;
+        // What providers answer when the verifier doesn't match the challenge
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": "invalid_grant",
+            })))
+            .mount(&provider)
+            .await;
+        let app = app(&config(&provider)).unwrap();
+        let (cookie, query) = start_login(&app).await;
+
+        let callback = format!("/callback?code=stolen&state={}", query["state"]);
+        let res = visit(&app, &callback, &cookie).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            text(visit(&app, "/", &cookie).await).await,
+            "Not logged in, go to /login"
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_from_the_provider_are_shown() {
+        let provider = MockServer::start().await;
+        let app = app(&config(&provider)).unwrap();
+        let res = visit(&app, "/callback?error=access_denied", "").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(text(res).await, "Login failed: access_denied");
+    }
+}
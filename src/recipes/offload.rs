@@ -0,0 +1,202 @@
+//! Moving cpu heavy work off the async worker threads with `spawn_blocking` and rayon
+//! Requires `cargo add axum hex rayon sha2`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F sync -F time`
+//! Compare the strategies in release mode: `cargo run --release --features offload-recipe`
+//!
+//! A tokio worker thread runs many tasks by switching between them at every `.await`. Code that
+//! computes for a long time without awaiting keeps every other task on that thread waiting, so
+//! a single slow request makes unrelated ones slow as well. As a rule of thumb anything taking
+//! more than about 100 microseconds between awaits should not run on the worker threads.
+//!
+//! - `spawn_blocking`: runs a closure on tokio's blocking pool which grows up to 512 threads.
+//!   Meant for blocking io and the occasional heavy call, e.g. hashing a password.
+//! - rayon: a pool with one thread per core. Better for lots of cpu bound work, more threads than
+//!   cores only add switching, and `par_iter` splits a single job over all cores.
+//! - `block_in_place`: turns the current worker into a blocking thread without moving the work.
+//!   Only for code that can't be moved, e.g. because it borrows, and panics on the current thread
+//!   runtime.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, extract::Query, http::StatusCode, routing::post, Router};
+use rayon::prelude::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinSet};
+
+/// Stands in for password hashing, compression, parsing a large document and the like
+pub fn hash_rounds(data: &[u8], rounds: u32) -> String {
+    let mut hash = Sha256::digest(data);
+    for _ in 1..rounds {
+        hash = Sha256::digest(hash);
+    }
+    hex::encode(hash)
+}
+
+/// Shrinks a grayscale image by averaging `factor` x `factor` blocks, the rows are computed in
+/// parallel on the rayon pool
+pub fn downscale(pixels: &[u8], width: usize, factor: usize) -> Vec<u8> {
+    let height = pixels.len() / width;
+    let (new_width, new_height) = (width / factor, height / factor);
+    (0..new_height)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (0..new_width).map(move |x| {
+                let sum: usize = (0..factor)
+                    .flat_map(|dy| (0..factor).map(move |dx| (dy, dx)))
+                    .map(|(dy, dx)| pixels[(y * factor + dy) * width + x * factor + dx] as usize)
+                    .sum();
+                (sum / (factor * factor)) as u8
+            })
+        })
+        .collect()
+}
+
+/// Runs `work` on the rayon pool and waits for it without blocking the worker thread
+pub async fn rayon_spawn<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    let (tx, rx) = oneshot::channel();
+    rayon::spawn(move || {
+        // The caller may have been cancelled, nobody is waiting for the result then
+        let _ = tx.send(work());
+    });
+    rx.await.expect("The work panicked")
+}
+
+async fn hash(body: Bytes) -> String {
+    // Dropping the handle when the request is cancelled does not stop the closure, it runs to
+    // completion anyway
+    tokio::task::spawn_blocking(move || hash_rounds(&body, 100_000))
+        .await
+        .expect("Hashing does not panic")
+}
+
+#[derive(Debug, Deserialize)]
+struct Thumbnail {
+    width: usize,
+    factor: usize,
+}
+
+async fn thumbnail(
+    Query(params): Query<Thumbnail>,
+    body: Bytes,
+) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    if params.width == 0 || params.factor == 0 || !body.len().is_multiple_of(params.width) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Body is not a grayscale image of that width",
+        ));
+    }
+    Ok(rayon_spawn(move || downscale(&body, params.width, params.factor)).await)
+}
+
+pub fn app() -> Router {
+    Router::new()
+        .route("/hash", post(hash))
+        .route("/thumbnail", post(thumbnail))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Strategy {
+    /// What not to do, shown for comparison
+    Inline,
+    BlockInPlace,
+    SpawnBlocking,
+    Rayon,
+}
+
+/// Runs `jobs` hashes concurrently with the strategy while a ticker checks every millisecond how
+/// late it is woken up. Returns the total time and the worst delay the ticker saw, the delay is
+/// what every other request on the server would feel.
+pub async fn compare(strategy: Strategy, jobs: usize, rounds: u32) -> (Duration, Duration) {
+    let worst = Arc::new(AtomicU64::new(0));
+    let ticker = tokio::spawn({
+        let worst = worst.clone();
+        async move {
+            loop {
+                let start = Instant::now();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                let late = start.elapsed().saturating_sub(Duration::from_millis(1));
+                worst.fetch_max(late.as_micros() as u64, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut set = JoinSet::new();
+    for i in 0..jobs {
+        let data = i.to_le_bytes();
+        set.spawn(async move {
+            match strategy {
+                Strategy::Inline => hash_rounds(&data, rounds),
+                Strategy::BlockInPlace => {
+                    tokio::task::block_in_place(|| hash_rounds(&data, rounds))
+                }
+                Strategy::SpawnBlocking => {
+                    tokio::task::spawn_blocking(move || hash_rounds(&data, rounds))
+                        .await
+                        .unwrap()
+                }
+                Strategy::Rayon => rayon_spawn(move || hash_rounds(&data, rounds)).await,
+            }
+        });
+    }
+    set.join_all().await;
+    let elapsed = start.elapsed();
+    ticker.abort();
+    (
+        elapsed,
+        Duration::from_micros(worst.load(Ordering::Relaxed)),
+    )
+}
+
+pub fn main() {
+    // Two workers make the effect easy to see, with more jobs than workers all of them get blocked
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        for strategy in [
+            Strategy::Inline,
+            Strategy::BlockInPlace,
+            Strategy::SpawnBlocking,
+            Strategy::Rayon,
+        ] {
+            let (elapsed, worst) = compare(strategy, 16, 200_000).await;
+            println!("{strategy:?}: took {elapsed:?}, other tasks waited up to {worst:?}");
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
+        println!("Listening on {}", listener.local_addr().unwrap());
+        axum::serve(listener, app()).await.unwrap();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downscales_by_averaging_blocks() {
+        #[rustfmt::skip]
+        let pixels = [
+            0, 2, 10, 10,
+            4, 6, 20, 20,
+        ];
+        assert_eq!(downscale(&pixels, 4, 2), [3, 15]);
+    }
+
+    #[tokio::test]
+    async fn offloaded_results_match_inline_ones() {
+        let expected = hash_rounds(b"data", 10);
+        assert_eq!(rayon_spawn(|| hash_rounds(b"data", 10)).await, expected);
+    }
+}
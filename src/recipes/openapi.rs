@@ -0,0 +1,107 @@
+//! OpenAPI spec and Swagger UI generated from the handlers of the axum recipe
+//! Requires `cargo add axum utoipa utoipa-axum@0.1`
+//! `cargo add utoipa-swagger-ui@8 -F axum -F vendored`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! Browse the docs at `localhost:8080/docs`, the spec itself is at `/api-docs/openapi.json`.
+//! Routes are registered through `OpenApiRouter` which adds every handler to the router and its
+//! `#[utoipa::path]` to the spec at the same time, so a route can't be served without being documented.
+//! Only what is in the attribute ends up in the spec though, it is not checked against the handler.
+//! See the `openapi_validation` recipe for validating requests against a spec.
+
+use axum::{extract::Path, http::StatusCode, Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use utoipa::{OpenApi, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_swagger_ui::SwaggerUi;
+
+/// Everything that is not tied to a route goes here
+#[derive(OpenApi)]
+#[openapi(info(title = "Example API", description = "The handlers of the axum recipe"))]
+struct ApiDoc;
+
+/// Doc comments of the type and its fields become descriptions in the schema
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct MyJson {
+    #[schema(example = "foo")]
+    foo: String,
+    /// Unicode code points
+    #[schema(example = json!([98, 97, 114]))]
+    bar: Vec<u32>,
+}
+
+pub fn router_and_spec() -> (Router, utoipa::openapi::OpenApi) {
+    OpenApiRouter::with_openapi(ApiDoc::openapi())
+        // Handlers passed together have to share the same path
+        .routes(routes!(return_json, decode_json))
+        .routes(routes!(greet))
+        .split_for_parts()
+}
+
+pub fn app() -> Router {
+    let (router, spec) = router_and_spec();
+    router.merge(SwaggerUi::new("/docs").url("/api-docs/openapi.json", spec))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app()).await.unwrap();
+}
+
+/// Greets whoever is in the path
+#[utoipa::path(
+    get,
+    path = "/hello/{name}",
+    params(("name" = String, Path, description = "Who to greet")),
+    responses((status = 200, description = "The greeting", body = String))
+)]
+async fn greet(Path(name): Path<String>) -> String {
+    format!("Hello {name}")
+}
+
+/// Returns an example of `MyJson`
+#[utoipa::path(get, path = "/", responses((status = 200, body = MyJson)))]
+async fn return_json() -> Json<MyJson> {
+    Json(MyJson {
+        foo: "foo".to_string(),
+        bar: vec![98, 97, 114],
+    })
+}
+
+/// Decodes `bar` into a string
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body = MyJson,
+    responses(
+        (status = 200, description = "`foo` followed by the decoded `bar`", body = String),
+        (status = 400, description = "`bar` contains a number that is not a code point")
+    )
+)]
+async fn decode_json(Json(my_json): Json<MyJson>) -> Result<String, StatusCode> {
+    let decoded = my_json
+        .bar
+        .into_iter()
+        .map(char::try_from)
+        .collect::<Result<String, _>>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(format!("{} {}", my_json.foo, decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_contains_every_route() {
+        let (_, spec) = router_and_spec();
+        let mut paths = spec.paths.paths.keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, ["/", "/hello/{name}"]);
+        let schemas = spec.components.unwrap().schemas;
+        assert!(schemas.contains_key("MyJson"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Validating request bodies against the schemas of an OpenAPI spec
+//! Requires `cargo add axum serde_json`
+//! `cargo add jsonschema --no-default-features`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev tower -F util`
+//!
+//! Typed extractors already reject bodies with missing fields or wrong types, so validating those again costs
+//! time for nothing. Add the validation only to routes where the spec says more than the types do
+//! (`minLength`, `pattern`, `maximum`, ...) or where the handler takes an untyped `Json<Value>`.
+//! This expects OpenAPI 3.1 whose schemas are plain JSON Schema. 3.0 specific keywords like `nullable` are ignored.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use jsonschema::{Draft, Validator};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// A schema compiled once at startup. Clone it into as many routes as you like.
+#[derive(Clone)]
+pub struct SchemaValidator(Arc<Validator>);
+
+impl SchemaValidator {
+    /// `name` is the name of a schema in `components.schemas` of the spec
+    pub fn from_spec(spec: &Value, name: &str) -> Result<Self, String> {
+        if spec
+            .pointer(&format!("/components/schemas/{name}"))
+            .is_none()
+        {
+            return Err(format!("Schema {name} does not exist in the spec"));
+        }
+        // Using the whole spec as the schema keeps `$ref`s between components working.
+        // The keywords of the spec itself (`openapi`, `paths`, ...) are unknown to JSON Schema and ignored.
+        let mut schema = spec.clone();
+        schema["$ref"] = json!(format!("#/components/schemas/{name}"));
+        jsonschema::options()
+            .with_draft(Draft::Draft202012)
+            .build(&schema)
+            .map(|validator| Self(Arc::new(validator)))
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaViolation {
+    /// JSON pointer to the offending value in the request body
+    pub path: String,
+    pub message: String,
+}
+
+pub async fn validate_body(
+    State(validator): State<SchemaValidator>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let instance: Value = match serde_json::from_slice(&bytes) {
+        Ok(instance) => instance,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid json: {e}")).into_response(),
+    };
+    let violations: Vec<_> = validator
+        .0
+        .iter_errors(&instance)
+        .map(|e| SchemaViolation {
+            path: e.instance_path().to_string(),
+            message: e.to_string(),
+        })
+        .collect();
+    if !violations.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Request body does not match the schema", "violations": violations })),
+        )
+            .into_response();
+    }
+    // The handler has to parse the body again so we hand it the bytes we already read
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+const SPEC: &str = r##"{
+    "openapi": "3.1.0",
+    "info": { "title": "Example", "version": "1.0.0" },
+    "paths": {},
+    "components": {
+        "schemas": {
+            "NewUser": {
+                "type": "object",
+                "required": ["name", "email"],
+                "properties": {
+                    "name": { "type": "string", "minLength": 1, "maxLength": 100 },
+                    "email": { "$ref": "#/components/schemas/Email" }
+                }
+            },
+            "Email": { "type": "string", "pattern": "^[^@]+@[^@]+$" }
+        }
+    }
+}"##;
+
+#[tokio::main]
+pub async fn main() {
+    let spec: Value = serde_json::from_str(SPEC).unwrap();
+    let new_user = SchemaValidator::from_spec(&spec, "NewUser").unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(new_user)).await.unwrap();
+}
+
+pub fn app(new_user: SchemaValidator) -> Router {
+    Router::new().route(
+        "/users",
+        // Opt in per route with the schema that belongs to it
+        post(|Json(user): Json<Value>| async move { Json(user) })
+            .layer(middleware::from_fn_with_state(new_user, validate_body)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn new_user() -> SchemaValidator {
+        let spec: Value = serde_json::from_str(SPEC).unwrap();
+        SchemaValidator::from_spec(&spec, "NewUser").unwrap()
+    }
+
+    async fn post_user(body: &str) -> (StatusCode, Value) {
+        let req = Request::post("/users")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let res = app(new_user()).oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_violating_the_schema() {
+        let (status, body) = post_user(r#"{"name": "", "email": "nope"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Request body does not match the schema");
+        let mut paths: Vec<_> = body["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["path"].as_str().unwrap())
+            .collect();
+        paths.sort();
+        // The email schema is only reachable through a `$ref`
+        assert_eq!(paths, ["/email", "/name"]);
+    }
+
+    #[tokio::test]
+    async fn passes_valid_bodies_to_the_handler() {
+        let user = json!({ "name": "Jane", "email": "jane@example.com" });
+        let (status, body) = post_user(&user.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, user);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_json() {
+        let (status, _) = post_user("{").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn unknown_schemas_fail_at_startup() {
+        let spec: Value = serde_json::from_str(SPEC).unwrap();
+        assert!(SchemaValidator::from_spec(&spec, "Nope").is_err());
+    }
+}
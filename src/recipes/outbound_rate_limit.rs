@@ -0,0 +1,256 @@
+//! Self throttling outbound calls to rate limited third party APIs
+//! Requires `cargo add governor httpdate reqwest`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//! Tests require `cargo add --dev tokio -F test-util`
+//! See the `rate_limit` recipe for limiting the requests of our own clients.
+
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use governor::{
+    clock::Clock,
+    middleware::NoOpMiddleware,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use reqwest::{header, Client, Request, Response, StatusCode};
+use tokio::time::Instant;
+
+/// Upper bound for a `Retry-After` pause, a misbehaving upstream can't stall a host for days
+pub const MAX_PAUSE: Duration = Duration::from_secs(60 * 60);
+
+/// governor's default clock ignores tokio's, with this one tests can pause time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    type Instant = std::time::Instant;
+
+    fn now(&self) -> Self::Instant {
+        Instant::now().into_std()
+    }
+}
+
+struct HostLimiter {
+    limiter: RateLimiter<NotKeyed, InMemoryState, TokioClock, NoOpMiddleware<std::time::Instant>>,
+    /// Set when the upstream told us to back off with `Retry-After`
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl HostLimiter {
+    fn pause(&self, retry_after: Duration) {
+        let now = Instant::now();
+        let max = now + MAX_PAUSE;
+        // Huge values like `u64::MAX` seconds would overflow the instant
+        let until = now
+            .checked_add(retry_after)
+            .map_or(max, |until| until.min(max));
+        let mut paused_until = self.paused_until.lock().unwrap();
+        // Concurrent responses must not shorten a longer pause
+        *paused_until = Some(paused_until.map_or(until, |current| current.max(until)));
+    }
+}
+
+/// Clone this into every task calling the API. All clones share the limiters so the rate
+/// applies to all calls to a host no matter where they come from.
+#[derive(Clone)]
+pub struct RateLimitedClient {
+    client: Client,
+    quota: Quota,
+    hosts: Arc<Mutex<HashMap<String, Arc<HostLimiter>>>>,
+}
+
+impl RateLimitedClient {
+    /// A burst size of one spaces calls evenly instead of sending `per_second` calls at once
+    pub fn new(client: Client, per_second: NonZeroU32) -> Self {
+        Self {
+            client,
+            quota: Quota::per_second(per_second).allow_burst(NonZeroU32::MIN),
+            hosts: Default::default(),
+        }
+    }
+
+    fn limiter(&self, host: &str) -> Arc<HostLimiter> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_insert_with(|| {
+                Arc::new(HostLimiter {
+                    limiter: RateLimiter::direct_with_clock(self.quota, TokioClock),
+                    paused_until: Mutex::new(None),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits until a call to `host` may be sent
+    async fn acquire(&self, host: &str) -> Arc<HostLimiter> {
+        let limiter = self.limiter(host);
+        let paused_until = *limiter.paused_until.lock().unwrap();
+        if let Some(until) = paused_until {
+            tokio::time::sleep_until(until).await;
+        }
+        // Instead of `until_ready` which sleeps with its own timer
+        while let Err(not_until) = limiter.limiter.check() {
+            tokio::time::sleep(not_until.wait_time_from(TokioClock.now())).await;
+        }
+        limiter
+    }
+
+    /// Waits until the request is allowed to be sent instead of failing
+    pub async fn execute(&self, req: Request) -> reqwest::Result<Response> {
+        let host = req.url().authority().to_owned();
+        let limiter = self.acquire(&host).await;
+
+        let res = self.client.execute(req).await?;
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            if let Some(retry_after) = retry_after(res.headers()) {
+                limiter.pause(retry_after);
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// `Retry-After` is either a number of seconds or a http date
+pub fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[tokio::main]
+pub async fn main() {
+    let client = RateLimitedClient::new(Client::new(), NonZeroU32::new(2).unwrap());
+    let tasks: Vec<_> = (0..10)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let req = Request::new(
+                    reqwest::Method::GET,
+                    "https://httpbin.org/get".parse().unwrap(),
+                );
+                let res = client.execute(req).await;
+                println!("{i}: {:?}", res.map(|res| res.status()));
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns when each of `calls` concurrent calls to `host` was let through
+    async fn send_times(
+        client: &RateLimitedClient,
+        host: &'static str,
+        calls: usize,
+    ) -> Vec<Duration> {
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..calls)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    client.acquire(host).await;
+                    start.elapsed()
+                })
+            })
+            .collect();
+        let mut times = Vec::new();
+        for task in tasks {
+            times.push(task.await.unwrap());
+        }
+        times.sort();
+        times
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_bursts_to_the_rate() {
+        let client = RateLimitedClient::new(Client::new(), NonZeroU32::new(4).unwrap());
+        let times = send_times(&client, "api.example.com", 5).await;
+        let ms = |ms| Duration::from_millis(ms);
+        assert_eq!(times, [ms(0), ms(250), ms(500), ms(750), ms(1000)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hosts_have_their_own_limit() {
+        let client = RateLimitedClient::new(Client::new(), NonZeroU32::new(1).unwrap());
+        send_times(&client, "a.example.com", 1).await;
+        let times = send_times(&client, "b.example.com", 1).await;
+        assert_eq!(times, [Duration::ZERO]);
+        // The first call used up the second of a
+        let times = send_times(&client, "a.example.com", 1).await;
+        assert_eq!(times, [Duration::from_secs(1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_out_retry_after() {
+        let client = RateLimitedClient::new(Client::new(), NonZeroU32::new(1).unwrap());
+        let until = Instant::now() + Duration::from_secs(10);
+        *client
+            .limiter("api.example.com")
+            .paused_until
+            .lock()
+            .unwrap() = Some(until);
+        let times = send_times(&client, "api.example.com", 2).await;
+        assert_eq!(times, [Duration::from_secs(10), Duration::from_secs(11)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn huge_retry_after_is_capped() {
+        let client = RateLimitedClient::new(Client::new(), NonZeroU32::new(1).unwrap());
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "18446744073709551615".parse().unwrap());
+        let limiter = client.limiter("api.example.com");
+        limiter.pause(retry_after(&headers).unwrap());
+        let times = send_times(&client, "api.example.com", 1).await;
+        assert_eq!(times, [MAX_PAUSE]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shorter_pauses_keep_the_longer_one() {
+        let client = RateLimitedClient::new(Client::new(), NonZeroU32::new(1).unwrap());
+        let limiter = client.limiter("api.example.com");
+        limiter.pause(Duration::from_secs(10));
+        limiter.pause(Duration::from_secs(2));
+        let times = send_times(&client, "api.example.com", 1).await;
+        assert_eq!(times, [Duration::from_secs(10)]);
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn retry_after_date_in_the_past() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_missing_or_invalid() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(header::RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+}
@@ -0,0 +1,350 @@
+//! Reliable event publishing with the transactional outbox pattern
+//! Requires `cargo add async-nats serde_json tokio-util tracing-subscriber`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add sqlx -F runtime-tokio -F postgres -F uuid`
+//! `cargo add uuid -F v4 -F serde`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F time`
+//! Builds on the `database` recipe for the pool, the `messaging` recipe for publishing to NATS and
+//! the `workers` recipe to restart the relay when it fails.
+//! Most tests need Postgres and are ignored by default, run them with
+//! `DATABASE_URL=postgres://... cargo test --features outbox-recipe -- --ignored`.
+//!
+//! Events are written in the same transaction as the change they describe, so either both or neither are persisted.
+//! A relay publishes them later which means they are delivered at least once: a crash between publishing and
+//! marking the event as sent publishes it again. JetStream drops messages with an id it has already
+//! seen within its duplicate window, two minutes by default, which catches most of these. Consumers
+//! still have to deduplicate using the event id, see the `messaging` recipe for consuming.
+//! ```sql
+//! CREATE TABLE outbox (
+//!     id UUID PRIMARY KEY,
+//!     seq BIGSERIAL NOT NULL,
+//!     topic TEXT NOT NULL,
+//!     payload JSONB NOT NULL,
+//!     sent_at TIMESTAMPTZ
+//! );
+//! CREATE INDEX outbox_unsent ON outbox (seq) WHERE sent_at IS NULL;
+//! ```
+
+use std::{future::Future, time::Duration};
+
+use async_nats::{header::NATS_MESSAGE_ID, jetstream, HeaderMap};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::recipes::{
+    database::{connect, DatabaseConfig},
+    messaging::orders_stream,
+    workers::{Backoff, BoxError, Supervisor},
+};
+
+const BATCH_SIZE: usize = 100;
+
+/// What the relay hands to the broker
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OutboxEvent {
+    /// Send this along with the event so consumers can deduplicate
+    pub id: Uuid,
+    pub topic: String,
+    pub payload: serde_json::Value,
+}
+
+/// Whatever message broker you are using
+pub trait Publisher: Send + Sync {
+    fn publish(&self, event: &OutboxEvent) -> impl Future<Output = Result<(), BoxError>> + Send;
+}
+
+/// Call this with the transaction doing the business change
+pub async fn enqueue(
+    tx: &mut PgConnection,
+    topic: &str,
+    event: &impl Serialize,
+) -> Result<Uuid, BoxError> {
+    let id = Uuid::new_v4();
+    sqlx::query("INSERT INTO outbox (id, topic, payload) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(topic)
+        .bind(serde_json::to_value(event)?)
+        .execute(tx)
+        .await?;
+    Ok(id)
+}
+
+#[derive(Debug, Serialize)]
+struct OrderCreated {
+    order_id: Uuid,
+    amount: i64,
+}
+
+pub async fn create_order(pool: &PgPool, amount: i64) -> Result<Uuid, BoxError> {
+    let mut tx = pool.begin().await?;
+    let order_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO orders (id, amount) VALUES ($1, $2)")
+        .bind(order_id)
+        .bind(amount)
+        .execute(&mut *tx)
+        .await?;
+    enqueue(
+        &mut tx,
+        "orders.created",
+        &OrderCreated { order_id, amount },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(order_id)
+}
+
+/// Publishes one batch of unsent events and returns how many were sent.
+/// Events published before a failure are still marked as sent, the error is returned afterwards.
+pub async fn relay_batch(pool: &PgPool, publisher: &impl Publisher) -> Result<usize, BoxError> {
+    let mut tx = pool.begin().await?;
+    // SKIP LOCKED lets multiple relays run side by side without publishing the same events
+    let events: Vec<OutboxEvent> = sqlx::query_as(
+        "SELECT id, topic, payload FROM outbox WHERE sent_at IS NULL ORDER BY seq LIMIT $1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(BATCH_SIZE as i64)
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut sent = Vec::with_capacity(events.len());
+    let mut failed = None;
+    for event in &events {
+        // Stop at the first failure to keep the order and retry the rest with the next batch
+        if let Err(e) = publisher.publish(event).await {
+            failed = Some(format!("Failed to publish event {}: {e}", event.id));
+            break;
+        }
+        sent.push(event.id);
+    }
+    sqlx::query("UPDATE outbox SET sent_at = now() WHERE id = ANY($1)")
+        .bind(&sent)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    match failed {
+        Some(e) => Err(e.into()),
+        None => Ok(sent.len()),
+    }
+}
+
+/// Runs until `shutdown` is cancelled. Errors are returned instead of retried so the supervisor
+/// restarts the relay with a growing delay while the database or the broker are down.
+pub async fn relay(
+    pool: PgPool,
+    publisher: impl Publisher,
+    interval: Duration,
+    shutdown: CancellationToken,
+) -> Result<(), BoxError> {
+    while !shutdown.is_cancelled() {
+        // A full batch means there may be more so don't wait
+        if relay_batch(&pool, &publisher).await? == BATCH_SIZE {
+            continue;
+        }
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Publishes to the JetStream stream of the `messaging` recipe
+#[derive(Clone)]
+pub struct JetStreamPublisher(pub jetstream::Context);
+
+impl Publisher for JetStreamPublisher {
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), BoxError> {
+        let headers = dedup_headers(event);
+        let payload = serde_json::to_vec(&event.payload)?;
+        // The first await sends the message, the second one waits for the ack of the server
+        self.0
+            .publish_with_headers(event.topic.clone(), headers, payload.into())
+            .await?
+            .await?;
+        Ok(())
+    }
+}
+
+/// What JetStream deduplicates by, a relay publishing the event twice sends the same id
+fn dedup_headers(event: &OutboxEvent) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(NATS_MESSAGE_ID, event.id.to_string().as_str());
+    headers
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let pool = connect(&DatabaseConfig::parse()).await.unwrap();
+    let client = async_nats::connect("nats://127.0.0.1:4222").await.unwrap();
+    let js = jetstream::new(client);
+    orders_stream(&js).await.unwrap();
+
+    let shutdown = CancellationToken::new();
+    let supervisor = Supervisor::new(shutdown.clone(), Backoff::default());
+    let publisher = JetStreamPublisher(js);
+    let relay_pool = pool.clone();
+    supervisor.spawn("outbox-relay", move |shutdown| {
+        relay(
+            relay_pool.clone(),
+            publisher.clone(),
+            Duration::from_secs(1),
+            shutdown,
+        )
+    });
+
+    create_order(&pool, 42).await.unwrap();
+    tokio::signal::ctrl_c().await.unwrap();
+    shutdown.cancel();
+    supervisor.join().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Mutex};
+
+    use sqlx::postgres::PgConnectOptions;
+
+    use super::*;
+
+    /// Every test gets its own schema so they can run side by side
+    async fn test_pool() -> PgPool {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL to point at Postgres");
+        let schema = format!("outbox_test_{}", Uuid::new_v4().simple());
+        let admin = PgPool::connect(&url).await.unwrap();
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&admin)
+            .await
+            .unwrap();
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", &schema)]);
+        let pool = PgPool::connect_with(options).await.unwrap();
+        sqlx::raw_sql(
+            "CREATE TABLE orders (id UUID PRIMARY KEY, amount BIGINT NOT NULL);
+             CREATE TABLE outbox (
+                 id UUID PRIMARY KEY,
+                 seq BIGSERIAL NOT NULL,
+                 topic TEXT NOT NULL,
+                 payload JSONB NOT NULL,
+                 sent_at TIMESTAMPTZ
+             );",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    /// Fails every publish after the first `succeed` ones
+    struct Recording {
+        published: Mutex<Vec<OutboxEvent>>,
+        succeed: usize,
+    }
+
+    impl Recording {
+        fn new(succeed: usize) -> Self {
+            Self {
+                published: Mutex::new(Vec::new()),
+                succeed,
+            }
+        }
+
+        fn topics(&self) -> Vec<String> {
+            let published = self.published.lock().unwrap();
+            published.iter().map(|e| e.topic.clone()).collect()
+        }
+    }
+
+    impl Publisher for Recording {
+        async fn publish(&self, event: &OutboxEvent) -> Result<(), BoxError> {
+            let mut published = self.published.lock().unwrap();
+            if published.len() == self.succeed {
+                return Err("broker is down".into());
+            }
+            published.push(event.clone());
+            Ok(())
+        }
+    }
+
+    async fn unsent(pool: &PgPool) -> Vec<String> {
+        sqlx::query_scalar("SELECT topic FROM outbox WHERE sent_at IS NULL ORDER BY seq")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn events_are_written_with_the_change() {
+        let pool = test_pool().await;
+        let order_id = create_order(&pool, 42).await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        enqueue(&mut tx, "orders.cancelled", &order_id)
+            .await
+            .unwrap();
+        tx.rollback().await.unwrap();
+
+        assert_eq!(unsent(&pool).await, ["orders.created"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn relays_each_event_once() {
+        let pool = test_pool().await;
+        let order_id = create_order(&pool, 42).await.unwrap();
+        let publisher = Recording::new(usize::MAX);
+
+        assert_eq!(relay_batch(&pool, &publisher).await.unwrap(), 1);
+        assert_eq!(relay_batch(&pool, &publisher).await.unwrap(), 0);
+
+        let published = publisher.published.into_inner().unwrap();
+        let (id,): (Uuid,) = sqlx::query_as("SELECT id FROM outbox")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].id, id);
+        assert_eq!(published[0].topic, "orders.created");
+        assert_eq!(published[0].payload["order_id"], order_id.to_string());
+        assert!(unsent(&pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn only_published_events_are_marked_sent() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        for topic in ["first", "second", "third"] {
+            enqueue(&mut tx, topic, &topic).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let failing = Recording::new(1);
+        assert!(relay_batch(&pool, &failing).await.is_err());
+        assert_eq!(failing.topics(), ["first"]);
+        assert_eq!(unsent(&pool).await, ["second", "third"]);
+
+        // The next batch picks up where the failed one stopped, in order
+        let working = Recording::new(usize::MAX);
+        assert_eq!(relay_batch(&pool, &working).await.unwrap(), 2);
+        assert_eq!(working.topics(), ["second", "third"]);
+    }
+
+    #[test]
+    fn jetstream_deduplicates_by_event_id() {
+        let event = OutboxEvent {
+            id: Uuid::new_v4(),
+            topic: "orders.created".to_owned(),
+            payload: serde_json::json!({}),
+        };
+        let headers = dedup_headers(&event);
+        assert_eq!(
+            headers.get(NATS_MESSAGE_ID).unwrap().as_str(),
+            event.id.to_string()
+        );
+    }
+}
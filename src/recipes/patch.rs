@@ -0,0 +1,267 @@
+//! PATCH requests distinguishing between absent fields and fields explicitly set to null
+//! Requires `cargo add axum serde_json`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::patch,
+    Json, Router,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+/// A field of a patch. Fields using this need `#[serde(default)]` so a missing field becomes `Absent`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Patch<T> {
+    /// Leave the current value as it is
+    #[default]
+    Absent,
+    /// Clear the current value
+    Null,
+    /// Replace the current value
+    Value(T),
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Patch<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // This is only called if the field is present so null is the only way to get None
+        Ok(match Option::<T>::deserialize(deserializer)? {
+            Some(value) => Patch::Value(value),
+            None => Patch::Null,
+        })
+    }
+}
+
+impl<T> Patch<T> {
+    /// Applies the patch to an optional field
+    pub fn apply_to(self, target: &mut Option<T>) {
+        match self {
+            Patch::Absent => {}
+            Patch::Null => *target = None,
+            Patch::Value(value) => *target = Some(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub name: String,
+    pub nickname: Option<String>,
+    pub address: Option<Address>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Address {
+    pub city: String,
+    pub zip: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserPatch {
+    #[serde(default)]
+    pub name: Patch<String>,
+    #[serde(default)]
+    pub nickname: Patch<String>,
+    /// Nested objects are merged like in JSON merge patch (RFC 7396):
+    /// `{"address": {"zip": null}}` only clears the zip code while `{"address": null}` clears the whole address
+    #[serde(default)]
+    pub address: Patch<AddressPatch>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddressPatch {
+    #[serde(default)]
+    pub city: Patch<String>,
+    #[serde(default)]
+    pub zip: Patch<String>,
+}
+
+impl UserPatch {
+    pub fn apply(self, user: &mut User) -> Result<(), &'static str> {
+        match self.name {
+            Patch::Absent => {}
+            Patch::Null => return Err("name can't be null"),
+            Patch::Value(name) => user.name = name,
+        }
+        self.nickname.apply_to(&mut user.nickname);
+        match self.address {
+            Patch::Absent => {}
+            Patch::Null => user.address = None,
+            Patch::Value(patch) => match &mut user.address {
+                Some(address) => patch.apply(address)?,
+                // There is nothing to merge into so the patch has to contain a complete address
+                None => user.address = Some(patch.into_address()?),
+            },
+        }
+        Ok(())
+    }
+}
+
+impl AddressPatch {
+    fn apply(self, address: &mut Address) -> Result<(), &'static str> {
+        match self.city {
+            Patch::Absent => {}
+            Patch::Null => return Err("address.city can't be null"),
+            Patch::Value(city) => address.city = city,
+        }
+        self.zip.apply_to(&mut address.zip);
+        Ok(())
+    }
+
+    fn into_address(self) -> Result<Address, &'static str> {
+        let Patch::Value(city) = self.city else {
+            return Err("address.city is required");
+        };
+        let mut zip = None;
+        self.zip.apply_to(&mut zip);
+        Ok(Address { city, zip })
+    }
+}
+
+/// If you don't want typed patches: RFC 7396 JSON merge patch on untyped values
+pub fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("Checked above");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+type Users = Arc<Mutex<HashMap<u32, User>>>;
+
+async fn patch_user(
+    State(users): State<Users>,
+    Path(id): Path<u32>,
+    Json(patch): Json<UserPatch>,
+) -> Result<Json<User>, (StatusCode, &'static str)> {
+    let mut users = users.lock().unwrap();
+    let user = users
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown user"))?;
+    // Apply to a copy so an invalid patch does not leave the user half updated
+    let mut patched = user.clone();
+    patch
+        .apply(&mut patched)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    *user = patched;
+    Ok(Json(user.clone()))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let users = Users::default();
+    users.lock().unwrap().insert(
+        1,
+        User {
+            name: "Jane".into(),
+            nickname: Some("J".into()),
+            address: None,
+        },
+    );
+    let app = Router::new()
+        .route("/users/:id", patch(patch_user))
+        .with_state(users);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn jane() -> User {
+        User {
+            name: "Jane".into(),
+            nickname: Some("J".into()),
+            address: Some(Address {
+                city: "Berlin".into(),
+                zip: Some("10115".into()),
+            }),
+        }
+    }
+
+    fn patched(patch: Value) -> Result<Value, &'static str> {
+        let mut user = jane();
+        let patch: UserPatch = serde_json::from_value(patch).unwrap();
+        patch.apply(&mut user)?;
+        Ok(serde_json::to_value(user).unwrap())
+    }
+
+    #[test]
+    fn absent_fields_are_unchanged() {
+        assert_eq!(
+            patched(json!({})).unwrap(),
+            serde_json::to_value(jane()).unwrap()
+        );
+    }
+
+    #[test]
+    fn null_clears_and_values_update() {
+        let user = patched(json!({"name": "Janet", "nickname": null})).unwrap();
+        assert_eq!(user["name"], "Janet");
+        assert_eq!(user["nickname"], Value::Null);
+        assert_eq!(user["address"]["city"], "Berlin");
+    }
+
+    #[test]
+    fn nested_objects_are_merged() {
+        let user = patched(json!({"address": {"zip": null}})).unwrap();
+        assert_eq!(user["address"], json!({"city": "Berlin", "zip": null}));
+
+        let user = patched(json!({"address": null})).unwrap();
+        assert_eq!(user["address"], Value::Null);
+    }
+
+    #[test]
+    fn required_fields_can_not_be_cleared() {
+        assert_eq!(patched(json!({"name": null})), Err("name can't be null"));
+        assert_eq!(
+            patched(json!({"address": {"city": null}})),
+            Err("address.city can't be null")
+        );
+    }
+
+    #[test]
+    fn new_nested_objects_must_be_complete() {
+        let mut user = jane();
+        user.address = None;
+        let patch: UserPatch = serde_json::from_value(json!({"address": {"zip": "1"}})).unwrap();
+        assert_eq!(patch.apply(&mut user), Err("address.city is required"));
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut target = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        merge_patch(&mut target, json!({"a": "z", "c": {"f": null}}));
+        assert_eq!(target, json!({"a": "z", "c": {"d": "e"}}));
+    }
+
+    #[test]
+    fn non_object_patch_replaces_target() {
+        let mut target = json!({"a": "b"});
+        merge_patch(&mut target, json!(["c"]));
+        assert_eq!(target, json!(["c"]));
+    }
+}
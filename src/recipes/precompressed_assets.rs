@@ -0,0 +1,109 @@
+//! Serving pre-compressed static assets for a single page app
+//! Requires `cargo add axum`
+//! `cargo add tower-http -F fs`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! Build the assets with `index.js.br` and `index.js.gz` next to `index.js`. ServeDir picks
+//! the best variant the client accepts and falls back to the raw file if there is none.
+//! It also sends `Vary: Accept-Encoding` on every response once precompression is
+//! configured, uncompressed ones included, so caches don't hand a client an encoding it can't read.
+
+use axum::Router;
+use tokio::net::TcpListener;
+use tower_http::services::{ServeDir, ServeFile};
+
+pub fn assets(dir: &str) -> ServeDir<ServeFile> {
+    // Unknown paths are client side routes so they get the index page
+    let index = ServeFile::new(format!("{dir}/index.html"))
+        .precompressed_br()
+        .precompressed_gzip();
+    ServeDir::new(dir)
+        .precompressed_br()
+        .precompressed_gzip()
+        .fallback(index)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let app = Router::new()
+        // .nest("/api", api_routes())
+        .fallback_service(assets("dist"));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// The contents don't have to be compressed for real, ServeDir only looks at the file names.
+    /// Written once as the tests run in parallel.
+    fn dist() -> &'static str {
+        static DIST: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        DIST.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("precompressed-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            for (name, content) in [
+                ("index.html", "index"),
+                ("app.js", "raw"),
+                ("app.js.br", "brotli"),
+                ("app.js.gz", "gzip"),
+            ] {
+                std::fs::write(dir.join(name), content).unwrap();
+            }
+            dir.to_str().unwrap().to_owned()
+        })
+    }
+
+    async fn get(path: &str, accept_encoding: Option<&str>) -> (Option<String>, String) {
+        let mut request = Request::get(path);
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+        }
+        let response = assets(dist())
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        let encoding = response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap().to_owned());
+        let body = to_bytes(Body::new(response.into_body()), usize::MAX)
+            .await
+            .unwrap();
+        (encoding, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_the_encoding_the_client_accepts() {
+        assert_eq!(
+            get("/app.js", Some("gzip, br")).await,
+            (Some("br".to_owned()), "brotli".to_owned())
+        );
+        assert_eq!(
+            get("/app.js", Some("gzip")).await,
+            (Some("gzip".to_owned()), "gzip".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_the_raw_file_otherwise() {
+        assert_eq!(get("/app.js", None).await, (None, "raw".to_owned()));
+        assert_eq!(
+            get("/app.js", Some("deflate")).await,
+            (None, "raw".to_owned())
+        );
+        // Only index.html exists, there is no compressed variant to pick
+        assert_eq!(
+            get("/settings", Some("br")).await,
+            (None, "index".to_owned())
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Problem details (RFC 9457) error responses
+//! Requires `cargo add axum`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev tower -F util`
+//! Builds on the `error` recipe and renders its `AppError` as problem details instead.
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::recipes::error::{AppError, ErrorBody, FieldError};
+
+/// See <https://www.rfc-editor.org/rfc/rfc9457>
+#[derive(Debug, Clone, Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub type_uri: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Extension member listing what was wrong with the request
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// Which format error responses use. Pick one for your whole API.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorFormat {
+    /// `{"error": "...", "code": "..."}` with `application/json` as the `error` recipe renders it
+    Json,
+    /// [`ProblemDetails`] with `application/problem+json`
+    Problem,
+}
+
+impl ProblemDetails {
+    fn new(status: StatusCode, body: ErrorBody) -> Self {
+        let (type_uri, title) = match body.code {
+            "validation" => (
+                "https://example.com/problems/validation",
+                "Validation failed",
+            ),
+            // about:blank means the status code says everything there is to say
+            _ => ("about:blank", status.canonical_reason().unwrap_or_default()),
+        };
+        ProblemDetails {
+            type_uri,
+            title,
+            status: status.as_u16(),
+            detail: body.error,
+            instance: None,
+            errors: body.errors,
+        }
+    }
+}
+
+/// Handlers don't know which request they are answering, so [`AppError`] leaves its [`ErrorBody`]
+/// on the response and this fills in the `instance` before rendering it in the configured format
+pub async fn render_errors(
+    State(format): State<ErrorFormat>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // Prefer the request id if there is one as it can be used to find the request in the logs
+    let instance = match req
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+    {
+        Some(id) => format!("urn:request-id:{id}"),
+        None => req.uri().path().to_owned(),
+    };
+    let mut res = next.run(req).await;
+    let Some(body) = res.extensions_mut().remove::<ErrorBody>() else {
+        return res;
+    };
+    match format {
+        ErrorFormat::Json => res,
+        ErrorFormat::Problem => {
+            let mut problem = ProblemDetails::new(res.status(), body);
+            problem.instance = Some(instance);
+            (
+                res.status(),
+                // This overrides the content type set by Json
+                [(header::CONTENT_TYPE, "application/problem+json")],
+                Json(problem),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(ErrorFormat::Problem))
+        .await
+        .unwrap();
+}
+
+pub fn app(format: ErrorFormat) -> Router {
+    Router::new()
+        .route("/users/:id", get(get_user))
+        .route("/users", post(create_user))
+        .layer(middleware::from_fn_with_state(format, render_errors))
+}
+
+async fn get_user(Path(_id): Path<u32>) -> Result<String, AppError> {
+    Err(AppError::NotFound("User"))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewUser {
+    name: String,
+}
+
+async fn create_user(Json(user): Json<NewUser>) -> Result<StatusCode, AppError> {
+    if user.name.is_empty() {
+        return Err(AppError::Validation(vec![FieldError {
+            field: "name".into(),
+            message: "must not be empty".into(),
+        }]));
+    }
+    Ok(StatusCode::CREATED)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn send(format: ErrorFormat, req: Request) -> (Response, Value) {
+        let res = app(format).oneshot(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let bytes = to_bytes(body, usize::MAX).await.unwrap();
+        let json = serde_json::from_slice(&bytes).unwrap();
+        (Response::from_parts(parts, Body::empty()), json)
+    }
+
+    fn create_user(name: &str) -> Request {
+        Request::post("/users")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-request-id", "abc")
+            .body(Body::from(json!({ "name": name }).to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn validation_errors_list_the_fields() {
+        let (res, body) = send(ErrorFormat::Problem, create_user("")).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(
+            body,
+            json!({
+                "type": "https://example.com/problems/validation",
+                "title": "Validation failed",
+                "status": 422,
+                "detail": "Request validation failed",
+                "instance": "urn:request-id:abc",
+                "errors": [{ "field": "name", "message": "must not be empty" }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn not_found_is_about_blank() {
+        let req = Request::get("/users/7").body(Body::empty()).unwrap();
+        let (res, body) = send(ErrorFormat::Problem, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        // Without a request id the path identifies the occurrence
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "User not found",
+                "instance": "/users/7",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn json_format_is_left_alone() {
+        let (res, body) = send(ErrorFormat::Json, create_user("")).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body,
+            json!({
+                "error": "Request validation failed",
+                "code": "validation",
+                "errors": [{ "field": "name", "message": "must not be empty" }],
+            })
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Property based tests with proptest, checking a property for hundreds of generated inputs
+//! Requires `cargo add proptest serde_json`
+//! Builds on the `axum` recipe and tests its `MyJson` and `decode_chars`.
+//!
+//! A unit test checks the examples someone thought of, a property test states what has to hold
+//! for every input and lets proptest search for one where it doesn't. When it finds one it shrinks
+//! it to the simplest input that still fails, so the failure is easy to read. Failing seeds are
+//! saved in `proptest-regressions/` next to the source, commit them so every run checks them again.
+//!
+//! Serde types are a good start: whatever is serialized has to deserialize to the same value.
+
+use proptest::{
+    collection::vec,
+    prelude::*,
+    strategy::ValueTree,
+    test_runner::{TestCaseError, TestError, TestRunner},
+};
+
+use crate::recipes::axum_server::{decode_chars, MyJson};
+
+/// Codes `decode_chars` accepts, generated from chars so surrogates and too large values are
+/// never produced
+pub fn char_code() -> impl Strategy<Value = u32> {
+    any::<char>().prop_map(u32::from)
+}
+
+/// The regex generates the string, the other fields combine strategies of their own
+pub fn my_json() -> impl Strategy<Value = MyJson> {
+    ("[a-zA-Z0-9 ]{0,16}", vec(char_code(), 0..32)).prop_map(|(text, codes)| MyJson {
+        foo: text,
+        bar: codes,
+    })
+}
+
+/// Shows shrinking without a test failing: claims every `u32` is a valid char, which proptest
+/// disproves and then shrinks the list to a single code
+pub fn find_invalid_code() -> Vec<u32> {
+    let mut runner = TestRunner::default();
+    let result = runner.run(&vec(any::<u32>(), 1..32), |codes| {
+        decode_chars(codes)
+            .map(drop)
+            .map_err(|e| TestCaseError::fail(e.to_string()))
+    });
+    match result {
+        Err(TestError::Fail(_, minimal)) => minimal,
+        other => panic!("Expected the property to fail, got {other:?}"),
+    }
+}
+
+pub fn main() {
+    let mut runner = TestRunner::default();
+    for _ in 0..3 {
+        let value = my_json().new_tree(&mut runner).unwrap().current();
+        println!("Generated {}", serde_json::to_string(&value).unwrap());
+    }
+    println!("Shrunk to {:?}", find_invalid_code());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn json_roundtrips(value in my_json()) {
+            let json = serde_json::to_string(&value).unwrap();
+            prop_assert_eq!(serde_json::from_str::<MyJson>(&json).unwrap(), value);
+        }
+
+        #[test]
+        fn decodes_one_char_per_code(codes in vec(char_code(), 0..64)) {
+            let decoded = decode_chars(codes.clone()).unwrap();
+            prop_assert_eq!(decoded.chars().map(u32::from).collect::<Vec<_>>(), codes);
+        }
+    }
+
+    #[test]
+    fn shrinks_to_a_single_invalid_code() {
+        let minimal = find_invalid_code();
+        assert_eq!(minimal.len(), 1);
+        assert!(char::from_u32(minimal[0]).is_none());
+    }
+}
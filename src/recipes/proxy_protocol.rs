@@ -0,0 +1,233 @@
+//! Recovering the client address from a PROXY protocol (v1 and v2) header sent by a tcp load balancer
+//! Requires `cargo add axum bytes proxy-protocol thiserror tracing`
+//! `cargo add clap -F derive -F env`
+//! `cargo add hyper -F server -F http1 -F http2`
+//! `cargo add hyper-util -F server-auto -F tokio`
+//! `cargo add tower -F util`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F io-util -F time`
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{extract::ConnectInfo, routing::get, Router};
+use bytes::BytesMut;
+use clap::{Parser, ValueEnum};
+use hyper_util::{rt::TokioExecutor, rt::TokioIo, server::conn::auto};
+use proxy_protocol::{version1, version2, ProxyHeader};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
+use tower::ServiceExt;
+use tracing::warn;
+
+/// Header of the binary protocol
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header
+const V1_MAX_LEN: usize = 107;
+/// Clients that connect but never send the header must not tie up a task forever
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// There is no optional mode on purpose: if clients can connect without going through the load balancer
+/// they can also send a PROXY header with whatever address they like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProxyProtocol {
+    Disabled,
+    /// Connections without a valid header are closed
+    Required,
+}
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    #[clap(long, env, default_value = "0.0.0.0:8080")]
+    pub bind_addr: SocketAddr,
+    #[clap(long, env, value_enum, default_value = "disabled")]
+    pub proxy_protocol: ProxyProtocol,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHeaderError {
+    #[error("Failed to read PROXY header: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection did not start with a PROXY header")]
+    Missing,
+    #[error("Invalid PROXY header: {0}")]
+    Invalid(#[from] proxy_protocol::ParseError),
+    #[error("Timed out waiting for PROXY header")]
+    Timeout,
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::parse();
+    let app = Router::new().route(
+        "/",
+        get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { format!("Hello {addr}") }),
+    );
+    let listener = TcpListener::bind(config.bind_addr).await.unwrap();
+    serve(listener, config.proxy_protocol, app).await;
+}
+
+/// Like `axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())`
+/// but with the address of the client instead of the load balancer
+pub async fn serve(listener: TcpListener, mode: ProxyProtocol, app: Router) {
+    loop {
+        let Ok((mut stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        let app = app.clone();
+        tokio::spawn(async move {
+            let client = match mode {
+                ProxyProtocol::Disabled => peer,
+                ProxyProtocol::Required => {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_proxy_header(&mut stream)).await
+                    {
+                        Ok(Ok(header)) => client_addr(&header).unwrap_or(peer),
+                        Ok(Err(e)) => {
+                            warn!(%peer, "Rejecting connection: {e}");
+                            return;
+                        }
+                        Err(_) => {
+                            warn!(%peer, "Rejecting connection: {}", ProxyHeaderError::Timeout);
+                            return;
+                        }
+                    }
+                }
+            };
+            let service = hyper::service::service_fn(move |mut req| {
+                req.extensions_mut().insert(ConnectInfo(client));
+                app.clone().oneshot(req)
+            });
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                warn!(%client, "Error serving connection: {e}");
+            }
+        });
+    }
+}
+
+/// Reads exactly the header so the rest of the stream can be handed to hyper untouched
+pub async fn read_proxy_header(stream: &mut TcpStream) -> Result<ProxyHeader, ProxyHeaderError> {
+    // Both versions are at least 15 bytes long
+    let mut buf = BytesMut::zeroed(5);
+    stream.read_exact(&mut buf).await?;
+    if &buf[..] == b"PROXY" {
+        // The text format ends with \r\n
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                return Err(ProxyHeaderError::Missing);
+            }
+            buf.extend_from_slice(&[stream.read_u8().await?]);
+        }
+    } else {
+        // 12 bytes signature, version/command, family, 2 bytes length of the addresses
+        buf.resize(16, 0);
+        stream.read_exact(&mut buf[5..]).await?;
+        if buf[..12] != V2_SIGNATURE {
+            return Err(ProxyHeaderError::Missing);
+        }
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        buf.resize(16 + len, 0);
+        stream.read_exact(&mut buf[16..]).await?;
+    }
+    Ok(proxy_protocol::parse(&mut buf.freeze())?)
+}
+
+/// `None` if the header does not carry an address, e.g. for health checks of the load balancer itself
+pub fn client_addr(header: &ProxyHeader) -> Option<SocketAddr> {
+    match header {
+        ProxyHeader::Version1 {
+            addresses: version1::ProxyAddresses::Ipv4 { source, .. },
+        } => Some(SocketAddr::V4(*source)),
+        ProxyHeader::Version1 {
+            addresses: version1::ProxyAddresses::Ipv6 { source, .. },
+        } => Some(SocketAddr::V6(*source)),
+        ProxyHeader::Version2 {
+            command: version2::ProxyCommand::Proxy,
+            addresses,
+            ..
+        } => match addresses {
+            version2::ProxyAddresses::Ipv4 { source, .. } => Some(SocketAddr::V4(*source)),
+            version2::ProxyAddresses::Ipv6 { source, .. } => Some(SocketAddr::V6(*source)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// Starts the server and returns its address
+    async fn start(mode: ProxyProtocol) -> SocketAddr {
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.to_string() }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, mode, app));
+        addr
+    }
+
+    /// Sends the header followed by a request and returns the whole response
+    async fn request(server: SocketAddr, header: &[u8]) -> String {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream.write_all(header).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        // The server closing the connection right away reads as an empty response
+        let _ = stream.read_to_string(&mut res).await;
+        res
+    }
+
+    fn v2_header() -> BytesMut {
+        proxy_protocol::encode(ProxyHeader::Version2 {
+            command: version2::ProxyCommand::Proxy,
+            transport_protocol: version2::ProxyTransportProtocol::Stream,
+            addresses: version2::ProxyAddresses::Ipv4 {
+                source: "203.0.113.7:4321".parse().unwrap(),
+                destination: SocketAddrV4::new([10, 0, 0, 1].into(), 8080),
+            },
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn recovers_the_client_address_from_v2() {
+        let server = start(ProxyProtocol::Required).await;
+        let res = request(server, &v2_header()).await;
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+        assert!(res.ends_with("\r\n\r\n203.0.113.7:4321"), "{res}");
+    }
+
+    #[tokio::test]
+    async fn recovers_the_client_address_from_v1() {
+        let server = start(ProxyProtocol::Required).await;
+        let header = b"PROXY TCP6 2001:db8::7 2001:db8::1 4321 8080\r\n";
+        let res = request(server, header).await;
+        assert!(res.ends_with("\r\n\r\n[2001:db8::7]:4321"), "{res}");
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_a_header() {
+        let server = start(ProxyProtocol::Required).await;
+        assert_eq!(request(server, b"").await, "");
+    }
+
+    #[tokio::test]
+    async fn uses_the_peer_address_when_disabled() {
+        let server = start(ProxyProtocol::Disabled).await;
+        let res = request(server, b"").await;
+        assert!(res.contains("\r\n\r\n127.0.0.1:"), "{res}");
+    }
+}
@@ -0,0 +1,159 @@
+//! Limiting how many requests each client can make
+//! Requires `cargo add axum governor`
+//! `cargo add clap -F derive -F env`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F time`
+//!
+//! Every client ip gets a token bucket refilled at `rate_limit_per_second` and holding up to
+//! `rate_limit_burst` tokens. Requests without a token get a 429 with `Retry-After` so well behaved
+//! clients know when to come back. The buckets live in memory, so with several instances behind a
+//! load balancer each one enforces the limit on its own.
+//! See the `outbound_rate_limit` recipe for staying within the limits of other APIs.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Quota, RateLimiter,
+};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Parser)]
+pub struct RateLimitConfig {
+    #[clap(long, env, default_value = "5")]
+    pub rate_limit_per_second: NonZeroU32,
+    /// How many requests a client can send at once after being idle
+    #[clap(long, env, default_value = "20")]
+    pub rate_limit_burst: NonZeroU32,
+    /// Only enable this behind a proxy that sets the header, otherwise clients pick their own key
+    #[clap(long, env)]
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Clone)]
+pub struct RateLimit {
+    limiter: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimit {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let quota =
+            Quota::per_second(config.rate_limit_per_second).allow_burst(config.rate_limit_burst);
+        let limiter = Arc::new(RateLimiter::keyed(quota));
+        // A bucket that filled up again is the same as no bucket, dropping those keeps the
+        // memory bounded by the number of recently active clients
+        let cleanup = Arc::downgrade(&limiter);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let Some(limiter) = cleanup.upgrade() else {
+                    break;
+                };
+                limiter.retain_recent();
+            }
+        });
+        Self {
+            limiter,
+            trust_forwarded_for: config.trust_forwarded_for,
+        }
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        // The proxy appends the address it saw, so the last entry is the one it vouches for
+        let forwarded = self
+            .trust_forwarded_for
+            .then(|| headers.get("x-forwarded-for")?.to_str().ok())
+            .flatten()
+            .and_then(|list| list.rsplit(',').next()?.trim().parse().ok());
+        forwarded.unwrap_or(peer.ip())
+    }
+}
+
+/// Add this with `layer` instead of `route_layer` so requests to unknown paths count as well
+pub async fn rate_limit(
+    State(limit): State<RateLimit>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let ip = limit.client_ip(req.headers(), peer);
+    match limit.limiter.check_key(&ip) {
+        Ok(()) => next.run(req).await,
+        Err(not_until) => {
+            let wait = not_until.wait_time_from(DefaultClock::default().now());
+            // Rounded up, a client retrying a little too early would be rejected again
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "Too many requests",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let limit = RateLimit::new(&RateLimitConfig::parse());
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, world!" }))
+        .layer(middleware::from_fn_with_state(limit, rate_limit));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    // Without connect info the middleware can't see the client address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(trust_forwarded_for: bool) -> RateLimit {
+        RateLimit {
+            limiter: Arc::new(RateLimiter::keyed(Quota::per_second(NonZeroU32::MIN))),
+            trust_forwarded_for,
+        }
+    }
+
+    #[test]
+    fn uses_forwarded_for_only_when_trusted() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 1234));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        assert_eq!(limit(false).client_ip(&headers, peer), peer.ip());
+        assert_eq!(
+            limit(true).client_ip(&headers, peer),
+            IpAddr::from([2, 2, 2, 2])
+        );
+    }
+
+    #[test]
+    fn limits_each_client_on_its_own() {
+        let limit = limit(false);
+        let a = IpAddr::from([1, 1, 1, 1]);
+        let b = IpAddr::from([2, 2, 2, 2]);
+        assert!(limit.limiter.check_key(&a).is_ok());
+        assert!(limit.limiter.check_key(&a).is_err());
+        assert!(limit.limiter.check_key(&b).is_ok());
+    }
+}
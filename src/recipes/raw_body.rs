@@ -0,0 +1,146 @@
+//! Verifying a signature over the raw body and still parsing it with `Json`
+//! Requires `cargo add axum bytes hex hmac sha2`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev tower -F util`
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Json, Router,
+};
+use bytes::Bytes;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::net::TcpListener;
+
+/// Only signed requests are buffered so this does not limit uploads on other routes
+const SIGNED_BODY_LIMIT: usize = 1024 * 1024;
+
+/// The body exactly as it was received, for handlers that need it in addition to the parsed one
+#[derive(Debug, Clone)]
+pub struct RawBody(pub Bytes);
+
+#[derive(Clone)]
+pub struct SigningKey(pub Arc<[u8]>);
+
+/// Expects `X-Signature: sha256=<hex encoded hmac of the body>`
+pub async fn verify_signature(State(key): State<SigningKey>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let Some(signature) = parts
+        .headers
+        .get("x-signature")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("sha256="))
+        .and_then(|value| hex::decode(value).ok())
+    else {
+        return (StatusCode::UNAUTHORIZED, "Missing signature").into_response();
+    };
+    let bytes = match axum::body::to_bytes(body, SIGNED_BODY_LIMIT).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.0).expect("HMAC accepts keys of any length");
+    mac.update(&bytes);
+    // verify_slice compares in constant time so the signature can't be guessed byte by byte
+    if mac.verify_slice(&signature).is_err() {
+        return (StatusCode::UNAUTHORIZED, "Invalid signature").into_response();
+    }
+    // Bytes is reference counted so keeping a copy around for the handler is cheap
+    parts.extensions.insert(RawBody(bytes.clone()));
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    kind: String,
+}
+
+/// `Json` consumes the body we put back while `RawBody` gives access to the exact bytes that were signed
+async fn webhook(Extension(RawBody(raw)): Extension<RawBody>, Json(event): Json<Event>) -> String {
+    format!("Received {} ({} bytes)", event.kind, raw.len())
+}
+
+#[tokio::main]
+pub async fn main() {
+    let key = SigningKey(Arc::from(&b"secret"[..]));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(key)).await.unwrap();
+}
+
+pub fn app(key: SigningKey) -> Router {
+    Router::new()
+        // Only this route pays for buffering the body
+        .route(
+            "/webhook",
+            post(webhook).layer(middleware::from_fn_with_state(key, verify_signature)),
+        )
+        .route("/upload", post(|body: Body| async move { drop(body) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    fn sign(body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY).unwrap();
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    async fn post_webhook(body: &[u8], signature: &str) -> (StatusCode, String) {
+        let req = Request::post("/webhook")
+            .header("content-type", "application/json")
+            .header("x-signature", signature)
+            .body(Body::from(body.to_vec()))
+            .unwrap();
+        let res = app(SigningKey(Arc::from(KEY))).oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn verifies_and_parses_the_same_body() {
+        let body = br#"{"kind": "ping"}"#;
+        let (status, text) = post_webhook(body, &sign(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(text, "Received ping (16 bytes)");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        let body = br#"{"kind": "ping"}"#;
+        let (status, text) = post_webhook(body, &sign(b"something else")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(text, "Invalid signature");
+
+        let (status, text) = post_webhook(body, "md5=abc").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(text, "Missing signature");
+    }
+
+    #[tokio::test]
+    async fn limits_signed_bodies_only() {
+        let body = vec![b' '; SIGNED_BODY_LIMIT + 1];
+        let (status, _) = post_webhook(&body, &sign(&body)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::post("/upload").body(Body::from(body)).unwrap();
+        let res = app(SigningKey(Arc::from(KEY))).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,196 @@
+//! Routing reads to replicas and writes to the primary database
+//! Requires `cargo add sqlx -F runtime-tokio -F postgres`
+//! `cargo add clap -F derive -F env`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//! `cargo add tracing tracing-subscriber`
+//!
+//! Replicas are checked in the background. Reads rotate over the healthy ones and go to
+//! the primary when none are left, so a replica outage slows things down instead of failing reads.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use clap::Parser;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+#[derive(Parser)]
+pub struct DatabaseConfig {
+    #[clap(long, env)]
+    pub database_url: String,
+    /// Comma separated list of replica urls
+    #[clap(long, env, value_delimiter = ',')]
+    pub database_replica_urls: Vec<String>,
+}
+
+struct Replica {
+    pool: PgPool,
+    healthy: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Database {
+    primary: PgPool,
+    replicas: Arc<[Replica]>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Database {
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        let primary = PgPoolOptions::new().connect(&config.database_url).await?;
+        // Replicas connect lazily so one being down at startup doesn't keep us from starting
+        let replicas = config
+            .database_replica_urls
+            .iter()
+            .map(|url| {
+                Ok(Replica {
+                    pool: PgPoolOptions::new()
+                        .acquire_timeout(Duration::from_secs(2))
+                        .connect_lazy(url)?,
+                    healthy: AtomicBool::new(true),
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()?;
+        Ok(Self {
+            primary,
+            replicas,
+            next: Default::default(),
+        })
+    }
+
+    pub fn write(&self) -> &PgPool {
+        &self.primary
+    }
+
+    /// Data read here may lag slightly behind what was just written to the primary
+    pub fn read(&self) -> &PgPool {
+        let healthy: Vec<bool> = self
+            .replicas
+            .iter()
+            .map(|replica| replica.healthy.load(Ordering::Relaxed))
+            .collect();
+        match pick_replica(&healthy, self.next.fetch_add(1, Ordering::Relaxed)) {
+            Some(i) => &self.replicas[i].pool,
+            None => {
+                if !self.replicas.is_empty() {
+                    tracing::warn!("No healthy replica, reading from the primary");
+                }
+                &self.primary
+            }
+        }
+    }
+
+    /// Checks every replica periodically and takes unreachable ones out of the rotation
+    pub fn spawn_health_checks(&self, interval: Duration) {
+        let replicas = self.replicas.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                check_replicas(&replicas).await;
+            }
+        });
+    }
+}
+
+async fn check_replicas(replicas: &[Replica]) {
+    for (i, replica) in replicas.iter().enumerate() {
+        let up = sqlx::query("SELECT 1").execute(&replica.pool).await.is_ok();
+        if replica.healthy.swap(up, Ordering::Relaxed) != up {
+            tracing::warn!(replica = i, up, "Replica health changed");
+        }
+    }
+}
+
+/// Round robin over the healthy replicas starting at `counter`
+fn pick_replica(healthy: &[bool], counter: usize) -> Option<usize> {
+    let len = healthy.len();
+    (0..len)
+        .map(|offset| (counter + offset) % len)
+        .find(|&i| healthy[i])
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let db = Database::connect(&DatabaseConfig::parse()).await.unwrap();
+    db.spawn_health_checks(Duration::from_secs(5));
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS notes (body TEXT NOT NULL)")
+        .execute(db.write())
+        .await
+        .unwrap();
+    let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM notes")
+        .fetch_one(db.read())
+        .await
+        .unwrap();
+    println!("{count} notes");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nothing listens on port 1, so every query fails with connection refused
+    const DOWN: &str = "postgres://postgres@127.0.0.1:1/postgres";
+
+    fn database(replicas: &[&str]) -> Database {
+        // sqlx retries refused connections until the acquire timeout
+        let lazy = |url| {
+            PgPoolOptions::new()
+                .acquire_timeout(Duration::from_millis(200))
+                .connect_lazy(url)
+                .unwrap()
+        };
+        Database {
+            primary: lazy(DOWN),
+            replicas: replicas
+                .iter()
+                .map(|url| Replica {
+                    pool: lazy(url),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            next: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_pick_a_replica() {
+        let db = database(&[DOWN, DOWN]);
+        let first = db.read();
+        let second = db.read();
+        assert!(std::ptr::eq(first, &db.replicas[0].pool));
+        assert!(std::ptr::eq(second, &db.replicas[1].pool));
+        assert!(std::ptr::eq(db.write(), &db.primary));
+    }
+
+    #[tokio::test]
+    async fn reads_fall_back_to_the_primary_when_replicas_are_down() {
+        let db = database(&[DOWN, DOWN]);
+        check_replicas(&db.replicas).await;
+        assert!(db
+            .replicas
+            .iter()
+            .all(|r| !r.healthy.load(Ordering::Relaxed)));
+        assert!(std::ptr::eq(db.read(), &db.primary));
+        assert!(std::ptr::eq(db.read(), &db.primary));
+    }
+
+    #[test]
+    fn rotates_over_healthy_replicas() {
+        let healthy = [true, false, true];
+        let picked: Vec<_> = (0..4).map(|i| pick_replica(&healthy, i)).collect();
+        assert_eq!(picked, [Some(0), Some(2), Some(2), Some(0)]);
+    }
+
+    #[test]
+    fn none_without_healthy_replicas() {
+        assert_eq!(pick_replica(&[false, false], 0), None);
+        assert_eq!(pick_replica(&[], 0), None);
+    }
+}
@@ -0,0 +1,387 @@
+//! Capturing requests to a file and replaying them locally
+//! Requires `cargo add axum futures reqwest serde_json tracing`
+//! `cargo add clap -F derive`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F fs -F io-util -F sync`
+//! Tests require `cargo add --dev tower -F util`
+//! Captured requests can be replayed with `cargo run -- replay captured.ndjson --target http://localhost:8080`
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::{self, Next},
+    response::Response,
+    routing::get,
+    Router,
+};
+use clap::{Parser, Subcommand};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tracing::warn;
+
+/// Replaces the values of redacted headers, replay leaves these headers out
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Parser)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Reissue captured requests against a server
+    Replay {
+        file: PathBuf,
+        #[clap(long, default_value = "http://localhost:8080")]
+        target: String,
+    },
+}
+
+/// One line of the capture file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    /// Path including the query string
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    /// Missing if the body was not valid utf-8, too large or redacted
+    pub body: Option<String>,
+}
+
+pub struct CaptureConfig {
+    /// Can be flipped at runtime, e.g. from an admin endpoint
+    pub enabled: AtomicBool,
+    /// Capture only every nth request
+    pub sample_every: u64,
+    /// Stop capturing after this many requests so we never fill up the disk
+    pub max_requests: u64,
+    /// Bodies larger than this are not captured, the request still goes through
+    pub max_body_size: usize,
+    /// Values of these headers are replaced by [`REDACTED`]
+    pub redact_headers: Vec<HeaderName>,
+    /// Bodies of requests to these paths are never captured, e.g. `/login`
+    pub redact_body_paths: Vec<String>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            sample_every: 10,
+            max_requests: 1000,
+            max_body_size: 64 * 1024,
+            redact_headers: vec![
+                axum::http::header::AUTHORIZATION,
+                axum::http::header::COOKIE,
+                axum::http::header::PROXY_AUTHORIZATION,
+            ],
+            redact_body_paths: vec!["/login".into()],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Capture {
+    config: Arc<CaptureConfig>,
+    seen: Arc<AtomicU64>,
+    captured: Arc<AtomicU64>,
+    tx: mpsc::Sender<CapturedRequest>,
+}
+
+impl Capture {
+    /// Spawns a task appending captured requests to `file`
+    pub async fn to_file(config: CaptureConfig, file: PathBuf) -> std::io::Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .await?;
+        let (capture, mut rx) = Self::new(config);
+        tokio::spawn(async move {
+            while let Some(captured) = rx.recv().await {
+                let mut line = serde_json::to_vec(&captured).expect("Serializing can't fail");
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).await {
+                    warn!("Failed to write captured request: {e}");
+                }
+            }
+        });
+        Ok(capture)
+    }
+
+    fn new(config: CaptureConfig) -> (Self, mpsc::Receiver<CapturedRequest>) {
+        // When the writer can't keep up we rather drop captures than slow down requests
+        let (tx, rx) = mpsc::channel(100);
+        let capture = Self {
+            config: Arc::new(config),
+            seen: Default::default(),
+            captured: Default::default(),
+            tx,
+        };
+        (capture, rx)
+    }
+
+    fn should_capture(&self) -> bool {
+        self.config.enabled.load(Ordering::Relaxed)
+            && self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.config.sample_every)
+            && self.captured.fetch_add(1, Ordering::Relaxed) < self.config.max_requests
+    }
+}
+
+pub async fn capture_requests(
+    State(capture): State<Capture>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !capture.should_capture() {
+        return next.run(req).await;
+    }
+    let config = &capture.config;
+    let (parts, body) = req.into_parts();
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if config.redact_headers.contains(name) {
+                REDACTED.to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+    let (body, captured_body) = if config
+        .redact_body_paths
+        .iter()
+        .any(|p| p == parts.uri.path())
+    {
+        (body, None)
+    } else {
+        let (body, bytes) = read_up_to(body, config.max_body_size).await;
+        (
+            body,
+            bytes.and_then(|bytes| String::from_utf8(bytes.into()).ok()),
+        )
+    };
+    let captured = CapturedRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        headers,
+        body: captured_body,
+    };
+    if capture.tx.try_send(captured).is_err() {
+        warn!("Dropping captured request, writer is not keeping up");
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Returns the body if it is at most `limit` bytes long. Otherwise what was read is put back in
+/// front of the rest, the handler gets the whole body either way.
+async fn read_up_to(body: Body, limit: usize) -> (Body, Option<Bytes>) {
+    let mut body = body.into_data_stream();
+    let mut read = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let stop = match &chunk {
+            Ok(chunk) => {
+                size += chunk.len();
+                size > limit
+            }
+            // The handler gets the error when it reads the body
+            Err(_) => true,
+        };
+        read.push(chunk);
+        if stop {
+            return (Body::from_stream(stream::iter(read).chain(body)), None);
+        }
+    }
+    // Every chunk is `Ok` here, errors returned above
+    let bytes: Bytes = read.into_iter().flatten().flatten().collect();
+    (Body::from(bytes.clone()), Some(bytes))
+}
+
+#[tokio::main]
+pub async fn main() {
+    match Cli::parse().command {
+        Some(Command::Replay { file, target }) => replay(file, &target).await.unwrap(),
+        None => serve().await,
+    }
+}
+
+async fn serve() {
+    let config = CaptureConfig {
+        enabled: AtomicBool::new(std::env::var("CAPTURE_REQUESTS").is_ok()),
+        ..Default::default()
+    };
+    let capture = Capture::to_file(config, "captured.ndjson".into())
+        .await
+        .unwrap();
+    let app = Router::new()
+        .route(
+            "/",
+            get(|| async { "Hello, world!" }).post(|body: String| async move { body }),
+        )
+        .layer(middleware::from_fn_with_state(capture, capture_requests));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+pub async fn replay(file: PathBuf, target: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::new();
+    let mut lines = BufReader::new(tokio::fs::File::open(file).await?).lines();
+    while let Some(line) = lines.next_line().await? {
+        let captured: CapturedRequest = serde_json::from_str(&line)?;
+        let mut req = client.request(
+            captured.method.parse()?,
+            format!("{target}{}", captured.uri),
+        );
+        for (name, value) in &captured.headers {
+            // These describe the original connection and are set by reqwest for the new one
+            if matches!(
+                name.as_str(),
+                "host" | "content-length" | "transfer-encoding"
+            ) {
+                continue;
+            }
+            // Sending `Authorization: [redacted]` would fail differently than a missing header
+            if value == REDACTED {
+                continue;
+            }
+            req = req.header(name, HeaderValue::from_str(value)?);
+        }
+        if let Some(body) = captured.body {
+            req = req.body(body);
+        }
+        let res = req.send().await?;
+        println!("{} {} -> {}", captured.method, captured.uri, res.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::any};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn capture_all(max_body_size: usize) -> (Capture, mpsc::Receiver<CapturedRequest>) {
+        Capture::new(CaptureConfig {
+            enabled: AtomicBool::new(true),
+            sample_every: 1,
+            max_body_size,
+            ..Default::default()
+        })
+    }
+
+    fn echo_app(capture: Capture) -> Router {
+        Router::new()
+            .route("/*path", any(|body: String| async move { body }))
+            .layer(middleware::from_fn_with_state(capture, capture_requests))
+    }
+
+    async fn body_string(res: Response) -> String {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn post(uri: &str, body: impl Into<Body>) -> Request {
+        Request::post(uri)
+            .header("authorization", "Bearer secret")
+            .header("content-type", "text/plain")
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn captures_and_replays_requests() {
+        let (capture, mut captured) = capture_all(1024);
+        let res = echo_app(capture)
+            .oneshot(post("/orders?dry_run=true", "two apples"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let captured = captured.recv().await.unwrap();
+        assert_eq!(captured.method, "POST");
+        assert_eq!(captured.uri, "/orders?dry_run=true");
+        assert_eq!(captured.body.as_deref(), Some("two apples"));
+        assert!(captured
+            .headers
+            .contains(&("authorization".to_owned(), REDACTED.to_owned())));
+
+        let file = std::env::temp_dir().join(format!("replay-{}.ndjson", std::process::id()));
+        let mut line = serde_json::to_vec(&captured).unwrap();
+        line.push(b'\n');
+        tokio::fs::write(&file, line).await.unwrap();
+
+        // The server the requests are replayed against captures them again
+        let (capture, mut replayed) = capture_all(1024);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, echo_app(capture)).await });
+        replay(file.clone(), &target).await.unwrap();
+        tokio::fs::remove_file(file).await.unwrap();
+
+        let replayed = replayed.recv().await.unwrap();
+        assert_eq!(replayed.method, "POST");
+        assert_eq!(replayed.uri, "/orders?dry_run=true");
+        assert_eq!(replayed.body.as_deref(), Some("two apples"));
+        let header = |name: &str| {
+            replayed
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("content-type"), Some("text/plain"));
+        assert_eq!(header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn large_bodies_pass_through_uncaptured() {
+        let (capture, mut captured) = capture_all(4);
+        let chunks = ["too ", "large ", "to capture"].map(Ok::<_, std::io::Error>);
+        let body = Body::from_stream(stream::iter(chunks));
+        let res = echo_app(capture)
+            .oneshot(post("/upload", body))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_string(res).await, "too large to capture");
+        assert_eq!(captured.recv().await.unwrap().body, None);
+    }
+
+    #[tokio::test]
+    async fn bodies_of_redacted_paths_are_not_captured() {
+        let (capture, mut captured) = capture_all(1024);
+        let res = echo_app(capture)
+            .oneshot(post("/login", "hunter2"))
+            .await
+            .unwrap();
+
+        assert_eq!(body_string(res).await, "hunter2");
+        assert_eq!(captured.recv().await.unwrap().body, None);
+    }
+}
@@ -0,0 +1,359 @@
+//! A circuit breaker that stops calling an upstream while most calls to it fail
+//! Requires `cargo add metrics reqwest thiserror`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//! Builds on the `reqwest` recipe, every call through the breaker is retried there first.
+//! Builds on the `clock` recipe so tests can move time forward instead of sleeping.
+//!
+//! Closed: calls go through and their outcomes are kept for the last `window` calls. Once at least
+//! `min_calls` were made and the share of failures reaches `failure_rate` the breaker opens.
+//! Open: calls fail right away without touching the upstream, giving it time to recover and
+//! keeping callers from waiting on timeouts. After `open_for` the breaker is half open.
+//! Half open: up to `probes` calls go through. One success closes the breaker, one failure opens it again.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::{Client, RequestBuilder, Response};
+
+use crate::recipes::{
+    clock::{Clock, SystemClock},
+    http_client::{HttpClient, RetryBudget, RetryPolicy},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    pub window: usize,
+    pub min_calls: usize,
+    /// Between 0 and 1
+    pub failure_rate: f64,
+    pub open_for: Duration,
+    pub probes: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_calls: 10,
+            failure_rate: 0.5,
+            open_for: Duration::from_secs(30),
+            probes: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum Inner {
+    /// `true` for every failed call
+    Closed {
+        outcomes: VecDeque<bool>,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        in_flight: u32,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Circuit breaker is open")]
+pub struct CircuitOpen;
+
+type Hook = Box<dyn Fn(State, State) + Send + Sync>;
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    clock: Box<dyn Clock>,
+    inner: Mutex<Inner>,
+    on_change: Option<Hook>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig, clock: impl Clock) -> Self {
+        Self {
+            config,
+            clock: Box::new(clock),
+            inner: Mutex::new(Inner::Closed {
+                outcomes: VecDeque::new(),
+            }),
+            on_change: None,
+        }
+    }
+
+    /// Called with the old and the new state, e.g. to export metrics or log
+    pub fn on_state_change(mut self, hook: impl Fn(State, State) + Send + Sync + 'static) -> Self {
+        self.on_change = Some(Box::new(hook));
+        self
+    }
+
+    pub fn state(&self) -> State {
+        state_of(&self.inner.lock().unwrap())
+    }
+
+    /// Every successful call has to be followed by exactly one `record`
+    pub fn acquire(&self) -> Result<(), CircuitOpen> {
+        let mut inner = self.inner.lock().unwrap();
+        match &mut *inner {
+            Inner::Closed { .. } => Ok(()),
+            Inner::Open { until } if self.clock.now() < *until => Err(CircuitOpen),
+            Inner::Open { .. } => {
+                let change = transition(&mut inner, Inner::HalfOpen { in_flight: 1 });
+                drop(inner);
+                self.notify(change);
+                Ok(())
+            }
+            Inner::HalfOpen { in_flight } if *in_flight < self.config.probes => {
+                *in_flight += 1;
+                Ok(())
+            }
+            Inner::HalfOpen { .. } => Err(CircuitOpen),
+        }
+    }
+
+    pub fn record(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let next = match &mut *inner {
+            Inner::Closed { outcomes } => {
+                outcomes.push_back(!success);
+                if outcomes.len() > self.config.window {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|failed| **failed).count();
+                let tripped = outcomes.len() >= self.config.min_calls
+                    && failures as f64 >= outcomes.len() as f64 * self.config.failure_rate;
+                if !tripped {
+                    return;
+                }
+                self.open()
+            }
+            Inner::HalfOpen { .. } if success => Inner::Closed {
+                outcomes: VecDeque::new(),
+            },
+            Inner::HalfOpen { .. } => self.open(),
+            // A call that started before the breaker opened, it doesn't change anything anymore
+            Inner::Open { .. } => return,
+        };
+        let change = transition(&mut inner, next);
+        drop(inner);
+        self.notify(change);
+    }
+
+    fn open(&self) -> Inner {
+        Inner::Open {
+            until: self.clock.now() + self.config.open_for,
+        }
+    }
+
+    /// Only called once the lock is released so the hook can use the breaker itself
+    fn notify(&self, (from, to): (State, State)) {
+        if let Some(hook) = &self.on_change {
+            hook(from, to);
+        }
+    }
+}
+
+/// Returns the old and the new state for [`CircuitBreaker::notify`]
+fn transition(inner: &mut Inner, next: Inner) -> (State, State) {
+    let from = state_of(inner);
+    *inner = next;
+    (from, state_of(inner))
+}
+
+fn state_of(inner: &Inner) -> State {
+    match inner {
+        Inner::Closed { .. } => State::Closed,
+        Inner::Open { .. } => State::Open,
+        Inner::HalfOpen { .. } => State::HalfOpen,
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BreakerError {
+    #[error(transparent)]
+    Open(#[from] CircuitOpen),
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+/// One breaker per upstream, a broken upstream should not stop calls to the healthy ones
+#[derive(Clone)]
+pub struct GuardedClient {
+    http: HttpClient,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl GuardedClient {
+    pub fn new(http: HttpClient, breaker: CircuitBreaker) -> Self {
+        Self {
+            http,
+            breaker: Arc::new(breaker),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.http.get(url)
+    }
+
+    /// Server errors count as failures of the upstream, client errors are the caller's fault
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, BreakerError> {
+        self.breaker.acquire()?;
+        let mut call = Call {
+            breaker: &self.breaker,
+            finished: false,
+        };
+        let result = self.http.send(request).await;
+        call.finished = true;
+        self.breaker.record(match &result {
+            Ok(res) => !res.status().is_server_error(),
+            Err(_) => false,
+        });
+        Ok(result?)
+    }
+}
+
+/// Counts a call that was dropped before it finished as failed, otherwise a cancelled probe
+/// would keep the breaker half open forever
+struct Call<'a> {
+    breaker: &'a CircuitBreaker,
+    finished: bool,
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker.record(false);
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let breaker =
+        CircuitBreaker::new(BreakerConfig::default(), SystemClock).on_state_change(|from, to| {
+            println!("Circuit breaker went from {from:?} to {to:?}");
+            metrics::counter!("circuit_breaker_transitions_total", "to" => format!("{to:?}"))
+                .increment(1);
+        });
+    let http = HttpClient::new(Client::new(), RetryPolicy::default(), RetryBudget::new(10));
+    let client = GuardedClient::new(http, breaker);
+    for _ in 0..15 {
+        match client
+            .send(client.get("https://httpbin.org/status/503"))
+            .await
+        {
+            Ok(res) => println!("{}", res.status()),
+            Err(e) => println!("{e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Weak;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::recipes::clock::MockClock;
+
+    fn breaker() -> (CircuitBreaker, MockClock, Arc<Mutex<Vec<State>>>) {
+        let clock = MockClock::new(Utc::now());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let config = BreakerConfig {
+            window: 4,
+            min_calls: 4,
+            failure_rate: 0.5,
+            open_for: Duration::from_secs(10),
+            probes: 1,
+        };
+        let recorded = changes.clone();
+        let breaker = CircuitBreaker::new(config, clock.clone())
+            .on_state_change(move |_, to| recorded.lock().unwrap().push(to));
+        (breaker, clock, changes)
+    }
+
+    fn call(breaker: &CircuitBreaker, success: bool) -> Result<(), CircuitOpen> {
+        breaker.acquire()?;
+        breaker.record(success);
+        Ok(())
+    }
+
+    #[test]
+    fn opens_at_the_failure_rate() {
+        let (breaker, _, _) = breaker();
+        call(&breaker, true).unwrap();
+        call(&breaker, true).unwrap();
+        call(&breaker, true).unwrap();
+        call(&breaker, false).unwrap();
+        assert_eq!(breaker.state(), State::Closed);
+        // The oldest success drops out of the window, 2 of 4 calls failed
+        call(&breaker, false).unwrap();
+        assert_eq!(breaker.state(), State::Open);
+        assert!(call(&breaker, true).is_err());
+    }
+
+    #[test]
+    fn half_open_probe_decides() {
+        let (breaker, clock, changes) = breaker();
+        for _ in 0..4 {
+            call(&breaker, false).unwrap();
+        }
+        clock.advance(Duration::from_secs(10));
+        breaker.acquire().unwrap();
+        // Only one probe at a time
+        assert!(breaker.acquire().is_err());
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Open);
+
+        clock.advance(Duration::from_secs(10));
+        call(&breaker, true).unwrap();
+        assert_eq!(breaker.state(), State::Closed);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                State::Open,
+                State::HalfOpen,
+                State::Open,
+                State::HalfOpen,
+                State::Closed
+            ]
+        );
+    }
+
+    #[test]
+    fn hooks_can_use_the_breaker() {
+        let clock = MockClock::new(Utc::now());
+        let config = BreakerConfig {
+            min_calls: 1,
+            ..Default::default()
+        };
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let breaker = Arc::new_cyclic(|breaker: &Weak<CircuitBreaker>| {
+            let breaker = breaker.clone();
+            CircuitBreaker::new(config, clock.clone()).on_state_change(move |_, _| {
+                // Would deadlock if the hook was called with the lock held
+                let state = breaker.upgrade().unwrap().state();
+                recorded.lock().unwrap().push(state);
+            })
+        });
+        call(&breaker, false).unwrap();
+        clock.advance(config.open_for);
+        call(&breaker, true).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [State::Open, State::HalfOpen, State::Closed]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Running jobs on cron schedules read from a config file
+//! Requires `cargo add chrono cron toml tracing tracing-subscriber`
+//! `cargo add serde -F derive`
+//! `cargo add tokio-util -F rt`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F time`
+//!
+//! The jobs are functions in the code, the config only decides when each one runs:
+//! ```toml
+//! [[jobs]]
+//! name = "cleanup"
+//! # sec min hour day-of-month month day-of-week
+//! schedule = "0 */5 * * * *"
+//! ```
+//! A job never overlaps with itself, a run that takes longer than the interval skips the runs it missed.
+//! On shutdown no new runs are started and running ones are waited for.
+//! Every instance of the service runs every job, see the `distributed_lock` recipe to run them only once.
+//! Builds on the `clock` recipe, tests run the schedules with paused time instead of waiting.
+
+use std::{future::Future, str::FromStr, sync::Arc};
+
+use cron::Schedule;
+use serde::Deserialize;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, info_span, Instrument};
+
+use crate::recipes::clock::{Clock, SystemClock};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+const DEFAULT_CONFIG: &str = r#"
+[[jobs]]
+name = "cleanup"
+schedule = "*/10 * * * * *"
+
+[[jobs]]
+name = "report"
+schedule = "0 * * * * *"
+"#;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub jobs: Vec<JobConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    pub name: String,
+    pub schedule: String,
+}
+
+pub struct Scheduler {
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+    clock: Arc<dyn Clock>,
+}
+
+impl Scheduler {
+    pub fn new(shutdown: CancellationToken, clock: impl Clock) -> Self {
+        Self {
+            shutdown,
+            tracker: TaskTracker::new(),
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// `job` is borrowed across every run, so it has to be `Sync` to move between threads
+    pub fn spawn<F, Fut>(&self, name: String, schedule: Schedule, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), BoxError>> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let clock = self.clock.clone();
+        self.tracker.spawn(async move {
+            // Computed after every run so runs missed while the job was still busy are skipped
+            while let Some(next) = schedule.after(&clock.utc()).next() {
+                let wait = (next - clock.utc()).to_std().unwrap_or_default();
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(wait) => {}
+                }
+                let span = info_span!("job", name = %name, scheduled = %next);
+                async {
+                    let started = clock.now();
+                    match job().await {
+                        Ok(()) => info!(elapsed = ?started.elapsed(), "Finished"),
+                        Err(e) => error!(elapsed = ?started.elapsed(), "Failed: {e}"),
+                    }
+                }
+                .instrument(span)
+                .await;
+            }
+        });
+    }
+
+    /// Waits for the running jobs after the shutdown token was cancelled
+    pub async fn join(self) {
+        self.shutdown.cancelled().await;
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+/// Reports every broken schedule at once, a job nobody knows about is an error too
+fn parse_schedules(
+    config: &SchedulerConfig,
+    known: &[&str],
+) -> Result<Vec<(String, Schedule)>, Vec<String>> {
+    let mut schedules = Vec::new();
+    let mut errors = Vec::new();
+    for job in &config.jobs {
+        if !known.contains(&job.name.as_str()) {
+            errors.push(format!("Unknown job {:?}", job.name));
+            continue;
+        }
+        match Schedule::from_str(&job.schedule) {
+            Ok(schedule) => schedules.push((job.name.clone(), schedule)),
+            Err(e) => errors.push(format!("Invalid schedule of {}: {e}", job.name)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(schedules)
+    } else {
+        Err(errors)
+    }
+}
+
+async fn cleanup() -> Result<(), BoxError> {
+    info!("Deleting expired sessions");
+    Ok(())
+}
+
+async fn report() -> Result<(), BoxError> {
+    Err("Mail server unreachable".into())
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let config =
+        std::fs::read_to_string("scheduler.toml").unwrap_or_else(|_| DEFAULT_CONFIG.to_owned());
+    let config: SchedulerConfig = toml::from_str(&config).unwrap();
+    let schedules = parse_schedules(&config, &["cleanup", "report"]).unwrap_or_else(|errors| {
+        eprintln!("Invalid scheduler config:\n  - {}", errors.join("\n  - "));
+        std::process::exit(1);
+    });
+
+    let shutdown = CancellationToken::new();
+    let scheduler = Scheduler::new(shutdown.clone(), SystemClock);
+    for (name, schedule) in schedules {
+        match name.as_str() {
+            "cleanup" => scheduler.spawn(name, schedule, cleanup),
+            "report" => scheduler.spawn(name, schedule, report),
+            _ => unreachable!("Only known jobs are parsed"),
+        }
+    }
+
+    tokio::signal::ctrl_c().await.unwrap();
+    shutdown.cancel();
+    scheduler.join().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::recipes::clock::MockClock;
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_on_schedule_without_waiting() {
+        let clock = MockClock::new("2024-06-01T12:00:00Z".parse().unwrap());
+        let shutdown = CancellationToken::new();
+        let scheduler = Scheduler::new(shutdown.clone(), clock);
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        scheduler.spawn(
+            "count".to_owned(),
+            Schedule::from_str("*/10 * * * * *").unwrap(),
+            move || {
+                counted.fetch_add(1, Ordering::Relaxed);
+                async { Ok::<_, BoxError>(()) }
+            },
+        );
+        // The paused time jumps from one run to the next, this takes no real time
+        tokio::time::sleep(Duration::from_secs(35)).await;
+        shutdown.cancel();
+        scheduler.join().await;
+        // At 12:00:10, 12:00:20 and 12:00:30
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_runs_keep_the_schedule() {
+        let clock = MockClock::new("2024-06-01T12:00:00Z".parse().unwrap());
+        let shutdown = CancellationToken::new();
+        let scheduler = Scheduler::new(shutdown.clone(), clock);
+        let runs = Arc::new(AtomicU32::new(0));
+        let counted = runs.clone();
+        scheduler.spawn(
+            "fail".to_owned(),
+            Schedule::from_str("0 * * * * *").unwrap(),
+            move || {
+                counted.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), BoxError>("unreachable".into()) }
+            },
+        );
+        tokio::time::sleep(Duration::from_secs(150)).await;
+        shutdown.cancel();
+        scheduler.join().await;
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn reports_every_invalid_job() {
+        let config: SchedulerConfig = toml::from_str(
+            r#"
+            [[jobs]]
+            name = "cleanup"
+            schedule = "not cron"
+
+            [[jobs]]
+            name = "typo"
+            schedule = "0 * * * * *"
+
+            [[jobs]]
+            name = "report"
+            schedule = "0 * * * * *"
+            "#,
+        )
+        .unwrap();
+        let errors = parse_schedules(&config, &["cleanup", "report"]).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[1].contains("typo"));
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let config: SchedulerConfig = toml::from_str(DEFAULT_CONFIG).unwrap();
+        assert_eq!(
+            parse_schedules(&config, &["cleanup", "report"])
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
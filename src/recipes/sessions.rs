@@ -0,0 +1,217 @@
+//! Cookie based sessions for browser facing apps
+//! Requires `cargo add axum tower-sessions@0.13`
+//! `cargo add clap -F derive -F env`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! The cookie only holds a random session id, the data stays on the server.
+//! `MemoryStore` loses all sessions on restart and isn't shared between instances, swap it for one
+//! of the database backed stores like `tower-sessions-sqlx-store` in production.
+//! Log in with `curl -c jar -b jar -X POST localhost:8080/login -H 'content-type: application/json' -d '{"username": "admin", "password": "admin"}'`
+//! and keep passing `-c jar -b jar` so curl sends the cookie like a browser would, e.g. to `/counter`.
+//! See the `auth` recipe for APIs called by other programs instead of browsers and the `oauth` recipe
+//! for logging in through another provider.
+
+use axum::{
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use clap::{ArgAction, Parser};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tower_sessions::{
+    cookie::{time::Duration, SameSite},
+    Expiry, MemoryStore, Session, SessionManagerLayer,
+};
+
+const USER_KEY: &str = "user";
+const COUNTER_KEY: &str = "counter";
+
+#[derive(Debug, Parser)]
+pub struct SessionConfig {
+    /// Only send the cookie over https. Turn it off for local development over http.
+    #[clap(long, env, default_value_t = true, action = ArgAction::Set)]
+    pub cookie_secure: bool,
+    /// Sessions without a request for this long are logged out
+    #[clap(long, env, default_value_t = 60)]
+    pub session_idle_minutes: i64,
+}
+
+pub fn session_layer(config: &SessionConfig) -> SessionManagerLayer<MemoryStore> {
+    SessionManagerLayer::new(MemoryStore::default())
+        .with_name("id")
+        .with_secure(config.cookie_secure)
+        // Scripts can't read the cookie, so an XSS bug can't steal the session
+        .with_http_only(true)
+        // The cookie is not sent with requests started by other sites, which stops most CSRF
+        .with_same_site(SameSite::Lax)
+        .with_expiry(Expiry::OnInactivity(Duration::minutes(
+            config.session_idle_minutes,
+        )))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+/// Replace the hardcoded user with a lookup of a password hash in your database
+async fn login(session: Session, Json(login): Json<Login>) -> Result<&'static str, StatusCode> {
+    if login.username != "admin" || login.password != "admin" {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    // A new id on login means an id an attacker planted before can't be used to ride the session
+    session
+        .cycle_id()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    session
+        .insert(USER_KEY, login.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok("Logged in")
+}
+
+async fn counter(session: Session) -> Result<String, StatusCode> {
+    let user: Option<String> = session
+        .get(USER_KEY)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = user.ok_or(StatusCode::UNAUTHORIZED)?;
+    let count = session
+        .get::<u64>(COUNTER_KEY)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .unwrap_or_default()
+        + 1;
+    session
+        .insert(COUNTER_KEY, count)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(format!("{user} visited this page {count} times"))
+}
+
+/// Deletes the session in the store as well, only removing the cookie would leave it usable
+async fn logout(session: Session) -> Result<&'static str, StatusCode> {
+    session
+        .flush()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok("Logged out")
+}
+
+pub fn app(config: &SessionConfig) -> Router {
+    Router::new()
+        .route("/login", post(login))
+        .route("/counter", get(counter))
+        .route("/logout", post(logout))
+        .layer(session_layer(config))
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = SessionConfig::parse();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(&config)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+        response::Response,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            cookie_secure: true,
+            session_idle_minutes: 60,
+        }
+    }
+
+    /// `cookie` is the `name=value` pair of a previous response
+    async fn send(app: &Router, method: &str, uri: &str, cookie: Option<&str>) -> Response {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        let body = if uri == "/login" {
+            req = req.header(header::CONTENT_TYPE, "application/json");
+            r#"{"username": "admin", "password": "admin"}"#
+        } else {
+            ""
+        };
+        app.clone()
+            .oneshot(req.body(Body::from(body)).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn set_cookie(res: &Response) -> String {
+        let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        cookie.split(';').next().unwrap().to_owned()
+    }
+
+    async fn text(res: Response) -> String {
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_session_cookie_is_locked_down() {
+        let app = app(&config());
+        let res = send(&app, "POST", "/login", None).await;
+        let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with("id="), "{cookie}");
+        for attribute in ["HttpOnly", "SameSite=Lax", "Secure", "Max-Age=3600"] {
+            assert!(cookie.contains(attribute), "{cookie}");
+        }
+    }
+
+    #[tokio::test]
+    async fn the_counter_needs_a_login() {
+        let app = app(&config());
+        let res = send(&app, "GET", "/counter", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let cookie = set_cookie(&send(&app, "POST", "/login", None).await);
+        for count in 1..=2 {
+            let res = send(&app, "GET", "/counter", Some(&cookie)).await;
+            assert_eq!(
+                text(res).await,
+                format!("admin visited this page {count} times")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn logging_in_cycles_the_id() {
+        let app = app(&config());
+        let planted = set_cookie(&send(&app, "POST", "/login", None).await);
+        let cookie = set_cookie(&send(&app, "POST", "/login", Some(&planted)).await);
+        assert_ne!(cookie, planted);
+
+        let res = send(&app, "GET", "/counter", Some(&planted)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = send(&app, "GET", "/counter", Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn logging_out_deletes_the_session() {
+        let app = app(&config());
+        let cookie = set_cookie(&send(&app, "POST", "/login", None).await);
+        let res = send(&app, "POST", "/logout", Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Even a client that keeps the cookie is logged out
+        let res = send(&app, "GET", "/counter", Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
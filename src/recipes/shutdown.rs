@@ -0,0 +1,186 @@
+//! One graceful shutdown story for servers, clients and background workers
+//! Requires `cargo add axum reqwest tracing tracing-subscriber`
+//! `cargo add tokio-util -F rt`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net -F time`
+//!
+//! SIGINT or SIGTERM cancels a single [`CancellationToken`] which every part of the program watches.
+//! Tasks are spawned through the controller so it knows what to wait for before the process exits.
+//! Whatever is still running after the timeout is abandoned so a stuck task can't block a deploy.
+
+use std::{future::Future, time::Duration};
+
+use axum::{routing::get, Router};
+use tokio::{net::TcpListener, task::JoinHandle, time::error::Elapsed};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+#[derive(Clone, Default)]
+pub struct ShutdownController {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl ShutdownController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hand this to everything that should stop on shutdown. Cancelling it starts the shutdown.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Spawns a task that is waited for on shutdown. It has to watch the token to ever finish.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(task)
+    }
+
+    /// Cancels the token on the first SIGINT (ctrl_c) or SIGTERM (docker stop, kubernetes)
+    pub fn listen_for_signals(&self) {
+        let token = self.token.clone();
+        tokio::spawn(async move {
+            let ctrl_c = async { tokio::signal::ctrl_c().await.unwrap() };
+            #[cfg(unix)]
+            let terminate = async {
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                    .unwrap()
+                    .recv()
+                    .await;
+            };
+            #[cfg(not(unix))]
+            let terminate = std::future::pending::<()>();
+            tokio::select! {
+                _ = ctrl_c => info!("Received SIGINT"),
+                _ = terminate => info!("Received SIGTERM"),
+                // Shutdown was triggered some other way
+                _ = token.cancelled() => return,
+            }
+            token.cancel();
+        });
+    }
+
+    /// Waits for the shutdown to start and then for all spawned tasks to finish
+    pub async fn wait(self, timeout: Duration) -> Result<(), Elapsed> {
+        self.token.cancelled().await;
+        info!("Shutting down, waiting up to {timeout:?} for tasks to finish");
+        // No new tasks can be tracked after this so `wait` can't miss any
+        self.tracker.close();
+        tokio::time::timeout(timeout, self.tracker.wait()).await
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let shutdown = ShutdownController::new();
+    shutdown.listen_for_signals();
+
+    shutdown.spawn(serve(shutdown.token()));
+    shutdown.spawn(poll_upstream(shutdown.token()));
+    shutdown.spawn(worker(shutdown.token()));
+
+    if shutdown.wait(Duration::from_secs(30)).await.is_err() {
+        warn!("Tasks did not finish in time, exiting anyway");
+    }
+}
+
+/// Servers stop accepting connections and finish the requests in flight
+async fn serve(shutdown: CancellationToken) {
+    let app = Router::new().route("/", get(|| async { "Hello, world!" }));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+        .unwrap();
+    info!("Server stopped");
+}
+
+/// Clients drop requests in flight, they would be retried on the next start anyway
+async fn poll_upstream(shutdown: CancellationToken) {
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        let request = client.get("https://httpbin.org/get").send();
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            res = request => match res {
+                Ok(res) => info!("Upstream responded with {}", res.status()),
+                Err(e) => warn!("Upstream request failed: {e}"),
+            },
+        }
+    }
+    info!("Client stopped");
+}
+
+/// Workers only check the token between jobs so a job is never left half done
+async fn worker(shutdown: CancellationToken) {
+    while !shutdown.is_cancelled() {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+        // Stands in for a job that must not be interrupted
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        info!("Job done");
+    }
+    info!("Worker stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_the_token_stops_every_task() {
+        let shutdown = ShutdownController::new();
+        let stopped = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let (token, stopped) = (shutdown.token(), stopped.clone());
+            shutdown.spawn(async move {
+                token.cancelled().await;
+                // Cleaning up still gets to finish
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                stopped.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        shutdown.token().cancel();
+        shutdown.wait(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(stopped.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_shutdown_to_start() {
+        let shutdown = ShutdownController::new();
+        let token = shutdown.token();
+        shutdown.spawn(async move { token.cancelled().await });
+        let wait = tokio::spawn(shutdown.clone().wait(Duration::from_secs(5)));
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(!wait.is_finished());
+        shutdown.token().cancel();
+        wait.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_tasks_are_abandoned_after_the_timeout() {
+        let shutdown = ShutdownController::new();
+        shutdown.spawn(std::future::pending::<()>());
+        shutdown.token().cancel();
+        let start = tokio::time::Instant::now();
+        assert!(shutdown.wait(Duration::from_secs(5)).await.is_err());
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+}
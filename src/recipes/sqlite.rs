@@ -0,0 +1,165 @@
+//! SQLite database with migrations embedded into the binary and applied on startup
+//! Requires `cargo add axum`
+//! `cargo add sqlx -F runtime-tokio -F sqlite -F migrate -F macros`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! For small tools that don't need a database server, the data lives in a single file.
+//! Migrations are in `migrations/sqlite`, one `<timestamp>_<name>.up.sql` and `.down.sql` pair each.
+//! Add one with `sqlx migrate add -r --source migrations/sqlite <name>` (`cargo install sqlx-cli`).
+//! Applied migrations are recorded in the `_sqlx_migrations` table and never run twice, so change
+//! the schema with a new migration instead of editing an applied one.
+
+use std::{str::FromStr, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    SqlitePool,
+};
+use tokio::net::TcpListener;
+
+/// Reads the migrations at compile time, touch this file or `cargo clean` if a new one is not picked up
+pub static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+pub async fn connect(url: &str) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(url)?
+        .create_if_missing(true)
+        // Readers don't block the writer and the other way around
+        .journal_mode(SqliteJournalMode::Wal)
+        // Only one connection can write at a time, the others wait this long instead of failing
+        .busy_timeout(Duration::from_secs(5))
+        // Off by default in SQLite for backwards compatibility
+        .foreign_keys(true);
+    SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect_with(options)
+        .await
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Note {
+    pub id: i64,
+    pub title: String,
+    pub body: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewNote {
+    pub title: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+pub async fn insert_note(pool: &SqlitePool, note: &NewNote) -> Result<Note, sqlx::Error> {
+    sqlx::query_as("INSERT INTO notes (title, body) VALUES (?, ?) RETURNING *")
+        .bind(&note.title)
+        .bind(&note.body)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn find_note(pool: &SqlitePool, id: i64) -> Result<Option<Note>, sqlx::Error> {
+    sqlx::query_as("SELECT * FROM notes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+async fn list_notes(State(pool): State<SqlitePool>) -> Result<Json<Vec<Note>>, StatusCode> {
+    sqlx::query_as("SELECT * FROM notes ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn create_note(
+    State(pool): State<SqlitePool>,
+    Json(note): Json<NewNote>,
+) -> Result<(StatusCode, Json<Note>), StatusCode> {
+    let note = insert_note(&pool, &note).await.map_err(internal_error)?;
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+async fn get_note(
+    State(pool): State<SqlitePool>,
+    Path(id): Path<i64>,
+) -> Result<Json<Note>, StatusCode> {
+    find_note(&pool, id)
+        .await
+        .map_err(internal_error)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn delete_note(State(pool): State<SqlitePool>, Path(id): Path<i64>) -> StatusCode {
+    match sqlx::query("DELETE FROM notes WHERE id = ?")
+        .bind(id)
+        .execute(&pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => internal_error(e),
+    }
+}
+
+fn internal_error(e: sqlx::Error) -> StatusCode {
+    eprintln!("Database error: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[tokio::main]
+pub async fn main() {
+    let url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://data.db".to_owned());
+    let pool = connect(&url).await.unwrap();
+    // Running them here means a new binary can't start against an old schema
+    MIGRATOR.run(&pool).await.unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(pool)).await.unwrap();
+}
+
+pub fn app(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/notes", get(list_notes).post(create_note))
+        .route("/notes/:id", get(get_note).delete(delete_note))
+        .with_state(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn migrates_and_stores_notes() {
+        // Every connection to `:memory:` gets its own empty database, so only use one
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+
+        let note = insert_note(
+            &pool,
+            &NewNote {
+                title: "Groceries".to_owned(),
+                body: String::new(),
+            },
+        )
+        .await
+        .unwrap();
+        let found = find_note(&pool, note.id).await.unwrap().unwrap();
+        assert_eq!(found.title, "Groceries");
+        assert!(find_note(&pool, note.id + 1).await.unwrap().is_none());
+    }
+}
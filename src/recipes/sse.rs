@@ -0,0 +1,233 @@
+//! Server-sent events that clients can resume after reconnecting
+//! Requires `cargo add axum futures`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F sync -F time`
+//!
+//! Watch the stream with `curl -N localhost:8080/events` and publish with
+//! `curl -X POST localhost:8080/events -d hello`.
+//! SSE only sends from the server to the client but works over plain HTTP, through proxies and
+//! reconnects by itself. Use the `websocket` recipe when clients need to send as well.
+//! See the `listen_notify` recipe for announcing changes made in Postgres.
+//! Browsers reconnect with the id of the last event they saw in `Last-Event-ID`, the events they
+//! missed in between are replayed from a bounded history.
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use futures::{stream, Stream, StreamExt};
+use tokio::{net::TcpListener, sync::broadcast};
+
+/// How many events a client can miss while reconnecting before it has to start over
+const HISTORY: usize = 1024;
+/// Sent to clients so they don't hammer the server with reconnects
+const RETRY: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone)]
+struct StoredEvent {
+    id: u64,
+    data: String,
+}
+
+impl StoredEvent {
+    fn to_sse(&self) -> Event {
+        Event::default().id(self.id.to_string()).data(&self.data)
+    }
+}
+
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<StoredEvent>,
+    /// Also guards the id counter so events are stored and sent in the order of their ids
+    history: Arc<Mutex<VecDeque<StoredEvent>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(HISTORY).0,
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY))),
+        }
+    }
+
+    pub fn publish(&self, data: impl Into<String>) {
+        let mut history = self.history.lock().unwrap();
+        let id = history.back().map_or(1, |last| last.id + 1);
+        let event = StoredEvent {
+            id,
+            data: data.into(),
+        };
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // Fails only when nobody is listening which is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Replays what happened after `last_id`, then follows new events
+    fn subscribe(&self, last_id: Option<u64>) -> impl Stream<Item = Event> {
+        // Subscribing while holding the lock means no event is missed or sent twice between
+        // the replay and the live events
+        let history = self.history.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let mut replay = Vec::new();
+        if let Some(last_id) = last_id {
+            // The history does not go back far enough, the client has to reload its state
+            if history.front().is_some_and(|first| first.id > last_id + 1) {
+                replay.push(Event::default().event("reset").data(""));
+            }
+            replay.extend(
+                history
+                    .iter()
+                    .filter(|e| e.id > last_id)
+                    .map(StoredEvent::to_sse),
+            );
+        }
+        drop(history);
+
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((event.to_sse(), receiver)),
+                // A client too slow to keep up is disconnected, it reconnects with its
+                // `Last-Event-ID` and catches up from the history
+                Err(_) => None,
+            }
+        });
+        stream::iter(replay).chain(live)
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn stream_events(
+    State(events): State<Events>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+    let retry = stream::once(async { Event::default().retry(RETRY) });
+    let stream = retry.chain(events.subscribe(last_id)).map(Ok);
+    // Comments sent while nothing happens keep proxies from closing the idle connection
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn publish(State(events): State<Events>, body: String) {
+    events.publish(body);
+}
+
+#[tokio::main]
+pub async fn main() {
+    let events = Events::new();
+    let ticker = events.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            ticker.publish("tick");
+        }
+    });
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(events)).await.unwrap();
+}
+
+pub fn app(events: Events) -> Router {
+    Router::new()
+        .route("/events", get(stream_events).post(publish))
+        .with_state(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, BodyDataStream},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn connect(events: &Events, last_id: Option<u64>) -> BodyDataStream {
+        let mut req = Request::get("/events");
+        if let Some(last_id) = last_id {
+            req = req.header("last-event-id", last_id.to_string());
+        }
+        let res = app(events.clone())
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        res.into_body().into_data_stream()
+    }
+
+    /// Reads whole events until `count` of them arrived
+    async fn read_events(body: &mut BodyDataStream, count: usize) -> Vec<String> {
+        let mut text = String::new();
+        while text.matches("\n\n").count() < count {
+            let chunk = body.next().await.unwrap().unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        text.split_terminator("\n\n")
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn new_clients_only_get_new_events() {
+        let events = Events::new();
+        events.publish("before");
+        let mut body = connect(&events, None).await;
+        events.publish("after");
+        assert_eq!(
+            read_events(&mut body, 2).await,
+            ["retry:3000", "id: 2\ndata: after"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnecting_clients_get_what_they_missed() {
+        let events = Events::new();
+        for data in ["a", "b", "c"] {
+            events.publish(data);
+        }
+        let mut body = connect(&events, Some(1)).await;
+        events.publish("d");
+        assert_eq!(
+            read_events(&mut body, 4).await,
+            [
+                "retry:3000",
+                "id: 2\ndata: b",
+                "id: 3\ndata: c",
+                "id: 4\ndata: d"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_too_far_behind_start_over() {
+        let events = Events::new();
+        for i in 0..HISTORY + 2 {
+            events.publish(i.to_string());
+        }
+        let mut body = connect(&events, Some(1)).await;
+        let replayed = read_events(&mut body, 3).await;
+        assert_eq!(replayed[1], "event: reset\ndata: ");
+        // The oldest event still in the history
+        assert_eq!(replayed[2], "id: 3\ndata: 2");
+    }
+}
@@ -0,0 +1,154 @@
+//! One `AppState` holding everything the handlers share, passed to them by axum instead of globals
+//! Requires `cargo add axum metrics-exporter-prometheus reqwest`
+//! `cargo add sqlx -F runtime-tokio -F postgres`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F time`
+//! Builds on the `config`, `metrics` and `reqwest` recipes.
+//!
+//! Everything the builder is not given is created from the config. Tests give it their own pool or
+//! metrics handle and a config made up on the spot, which a global read once per process can't do.
+//! Handlers take the whole state or only the part they need thanks to the `FromRef` impls.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{FromRef, State},
+    middleware,
+    routing::get,
+    Router,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::net::TcpListener;
+
+use crate::recipes::{
+    config::Config,
+    http_client::{HttpClient, RetryBudget, RetryPolicy},
+    metrics::{install_recorder, track_metrics},
+};
+
+/// Cloned for every request, so everything in here is an `Arc` or a handle to one
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub db: PgPool,
+    pub http: HttpClient,
+    pub metrics: PrometheusHandle,
+}
+
+impl AppState {
+    pub fn builder(config: Config) -> AppStateBuilder {
+        AppStateBuilder {
+            config,
+            db: None,
+            http: None,
+            metrics: None,
+        }
+    }
+}
+
+pub struct AppStateBuilder {
+    config: Config,
+    db: Option<PgPool>,
+    http: Option<HttpClient>,
+    metrics: Option<PrometheusHandle>,
+}
+
+impl AppStateBuilder {
+    pub fn db(mut self, db: PgPool) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    pub fn http(mut self, http: HttpClient) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    /// Without a handle the global recorder is installed, which only works once per process.
+    /// Tests pass `PrometheusBuilder::new().build_recorder().handle()` instead.
+    pub fn metrics(mut self, metrics: PrometheusHandle) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub async fn build(self) -> Result<AppState, sqlx::Error> {
+        let config = self.config;
+        let db = match self.db {
+            Some(db) => db,
+            None => {
+                PgPoolOptions::new()
+                    .acquire_timeout(config.request_timeout)
+                    .connect(&config.database_url)
+                    .await?
+            }
+        };
+        let http = self.http.unwrap_or_else(|| {
+            let policy = RetryPolicy {
+                timeout: config.request_timeout,
+                ..Default::default()
+            };
+            HttpClient::new(reqwest::Client::new(), policy, RetryBudget::new(10))
+        });
+        let metrics = self.metrics.unwrap_or_else(install_recorder);
+        Ok(AppState {
+            config: Arc::new(config),
+            db,
+            http,
+            metrics,
+        })
+    }
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for HttpClient {
+    fn from_ref(state: &AppState) -> Self {
+        state.http.clone()
+    }
+}
+
+impl FromRef<AppState> for PrometheusHandle {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+pub fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(hello))
+        .route("/db", get(db_time))
+        .route_layer(middleware::from_fn(track_metrics))
+        .route("/metrics", get(render_metrics))
+        .with_state(state)
+}
+
+async fn hello(State(state): State<AppState>) -> String {
+    format!("Hello from {}", state.config.bind_addr)
+}
+
+async fn db_time(State(db): State<PgPool>) -> Result<String, String> {
+    sqlx::query_scalar::<_, String>("SELECT now()::text")
+        .fetch_one(&db)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn render_metrics(State(metrics): State<PrometheusHandle>) -> String {
+    metrics.render()
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let bind_addr = config.bind_addr;
+    let state = AppState::builder(config).build().await.unwrap();
+    let listener = TcpListener::bind(bind_addr).await.unwrap();
+    axum::serve(listener, app(state)).await.unwrap();
+}
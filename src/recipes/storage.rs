@@ -0,0 +1,416 @@
+//! Storing files in S3 or any S3 compatible object storage like MinIO or Cloudflare R2
+//! Requires `cargo add axum -F multipart`
+//! `cargo add clap -F derive -F env`
+//! `cargo add futures`
+//! `cargo add object_store -F aws`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! `cargo add tracing tracing-subscriber`
+//! `cargo add uuid -F v4`
+//!
+//! Files on the container's disk are gone with the container and can't be shared between replicas.
+//! Upload with `curl -F file=@Cargo.toml localhost:8080/objects`, list with
+//! `localhost:8080/objects?limit=10` and get a download url from `localhost:8080/objects/<key>`.
+//! Keys are relative to the uploads prefix, the rest of the bucket can't be listed or downloaded.
+//! Downloads don't go through the service at all, the client fetches the file from the storage with
+//! a presigned url which is only valid for a few minutes. See the `files` recipe for the same on
+//! the local disk.
+//! Run MinIO locally with `docker run -p 9000:9000 -e MINIO_ROOT_USER=minio -e MINIO_ROOT_PASSWORD=minio123 minio/minio server /data`,
+//! create a bucket in its console and use `--s3-endpoint http://localhost:9000`.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{multipart::Field, DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{Method, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use clap::Parser;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path as ObjectPath,
+    signer::Signer,
+    Attribute, Attributes, ObjectStore, PutMultipartOptions, WriteMultipart,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::error;
+use uuid::Uuid;
+
+/// Every upload is stored below this prefix
+const PREFIX: &str = "uploads";
+const MAX_FILE_SIZE: u64 = 500 * 1024 * 1024;
+/// Each part is 5MB, this bounds the memory an upload takes while the parts are sent
+const MAX_PARTS_IN_FLIGHT: usize = 4;
+/// Long enough to start the download, short enough that a leaked url is useless soon
+const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(15 * 60);
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Parser)]
+pub struct StorageConfig {
+    /// Leave out for AWS, e.g. `http://localhost:9000` for MinIO
+    #[clap(long, env)]
+    pub s3_endpoint: Option<String>,
+    #[clap(long, env)]
+    pub s3_bucket: String,
+    /// Most S3 compatible storages ignore the region but it is part of the signature
+    #[clap(long, env, default_value = "us-east-1")]
+    pub s3_region: String,
+    #[clap(long, env)]
+    pub s3_access_key_id: String,
+    #[clap(long, env, hide_env_values = true)]
+    pub s3_secret_access_key: String,
+}
+
+/// Only builds the client, nothing is sent until the first request
+pub fn connect(config: &StorageConfig) -> object_store::Result<AmazonS3> {
+    let mut builder = AmazonS3Builder::new()
+        .with_bucket_name(&config.s3_bucket)
+        .with_region(&config.s3_region)
+        .with_access_key_id(&config.s3_access_key_id)
+        .with_secret_access_key(&config.s3_secret_access_key);
+    if let Some(endpoint) = &config.s3_endpoint {
+        // Local storages usually run without TLS. They are addressed as `endpoint/bucket/key`,
+        // which is the default, instead of AWS's `bucket.endpoint/key`.
+        builder = builder
+            .with_endpoint(endpoint)
+            .with_allow_http(endpoint.starts_with("http://"));
+    }
+    builder.build()
+}
+
+/// The concrete type instead of `dyn ObjectStore` as presigning is only implemented by some stores
+type Store = Arc<AmazonS3>;
+
+/// Client supplied keys and prefixes are below [`PREFIX`], `..` could otherwise leave it
+fn object_path(key: &str) -> Result<ObjectPath, (StatusCode, String)> {
+    if key.starts_with('/') || key.split('/').any(|segment| segment == "..") {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid key {key:?}")));
+    }
+    ObjectPath::parse(format!("{PREFIX}/{key}"))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// The key as the client passes it back, without [`PREFIX`]
+fn client_key(path: &ObjectPath) -> String {
+    let path = path.as_ref();
+    path.strip_prefix(PREFIX)
+        .and_then(|key| key.strip_prefix('/'))
+        .unwrap_or(path)
+        .to_owned()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Uploaded {
+    pub key: String,
+    pub size: u64,
+}
+
+async fn upload(
+    State(store): State<Store>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<Uploaded>>, (StatusCode, String)> {
+    let bad_request = |e: axum::extract::multipart::MultipartError| (e.status(), e.body_text());
+    let mut uploaded = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        uploaded.push(save(store.as_ref(), field).await?);
+    }
+    Ok(Json(uploaded))
+}
+
+/// Streams the field as a multipart upload, the object only appears once every part arrived
+async fn save(
+    store: &dyn ObjectStore,
+    mut field: Field<'_>,
+) -> Result<Uploaded, (StatusCode, String)> {
+    let key = ObjectPath::from(format!("{PREFIX}/{}", Uuid::new_v4()));
+    let content_type = field
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+    // Stored with the object and sent back by the storage on download
+    let opts = PutMultipartOptions {
+        attributes: Attributes::from_iter([(Attribute::ContentType, content_type)]),
+        ..Default::default()
+    };
+    let upload = store
+        .put_multipart_opts(&key, opts)
+        .await
+        .map_err(storage_error)?;
+    let mut writer = WriteMultipart::new(upload);
+    let mut size = 0;
+    let result = loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err((e.status(), e.body_text())),
+        };
+        size += chunk.len() as u64;
+        if size > MAX_FILE_SIZE {
+            break Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Files can be at most {MAX_FILE_SIZE} bytes"),
+            ));
+        }
+        // Waits while the storage is slower than the client instead of buffering the whole file
+        if let Err(e) = writer.wait_for_capacity(MAX_PARTS_IN_FLIGHT).await {
+            break Err(storage_error(e));
+        }
+        writer.write(&chunk);
+    };
+    match result {
+        Ok(()) => {
+            writer.finish().await.map_err(storage_error)?;
+        }
+        Err(e) => {
+            // The storage keeps the parts of unfinished uploads, and bills for them, until aborted
+            let _ = writer.abort().await;
+            return Err(e);
+        }
+    }
+    Ok(Uploaded {
+        key: client_key(&key),
+        size,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadUrl {
+    pub url: String,
+    pub expires_in: u64,
+}
+
+/// Signing is done locally with the secret key, only the check that the object exists is a request
+async fn download_url(
+    State(store): State<Store>,
+    Path(key): Path<String>,
+) -> Result<Json<DownloadUrl>, (StatusCode, String)> {
+    let key = object_path(&key)?;
+    store.head(&key).await.map_err(storage_error)?;
+    let url = store
+        .signed_url(Method::GET, &key, DOWNLOAD_URL_TTL)
+        .await
+        .map_err(storage_error)?;
+    Ok(Json(DownloadUrl {
+        url: url.to_string(),
+        expires_in: DOWNLOAD_URL_TTL.as_secs(),
+    }))
+}
+
+/// `after` is the last key of the previous page, the storage lists keys in lexicographic order.
+/// Both it and `prefix` are relative to [`PREFIX`] like the keys.
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    pub prefix: Option<String>,
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectList {
+    pub objects: Vec<ObjectInfo>,
+    /// Pass as `after` to get the next page, `null` on the last one
+    pub next: Option<String>,
+}
+
+async fn list_objects(
+    State(store): State<Store>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<ObjectList>, (StatusCode, String)> {
+    list_page(store.as_ref(), params).await.map(Json)
+}
+
+/// Buckets can hold millions of objects, only one page is ever read from the storage
+async fn list_page(
+    store: &dyn ObjectStore,
+    params: ListParams,
+) -> Result<ObjectList, (StatusCode, String)> {
+    let prefix = object_path(params.prefix.as_deref().unwrap_or_default())?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let objects = match params.after {
+        Some(after) => store.list_with_offset(Some(&prefix), &object_path(&after)?),
+        None => store.list(Some(&prefix)),
+    };
+    let objects: Vec<_> = objects
+        .take(limit)
+        .try_collect()
+        .await
+        .map_err(storage_error)?;
+    // A full page might be the last one, the next request then just returns nothing
+    let next = if objects.len() == limit {
+        objects.last().map(|meta| client_key(&meta.location))
+    } else {
+        None
+    };
+    let objects = objects
+        .into_iter()
+        .map(|meta| ObjectInfo {
+            key: client_key(&meta.location),
+            size: meta.size,
+            last_modified: meta.last_modified.to_rfc3339(),
+        })
+        .collect();
+    Ok(ObjectList { objects, next })
+}
+
+fn storage_error(e: object_store::Error) -> (StatusCode, String) {
+    match e {
+        object_store::Error::NotFound { .. } => {
+            (StatusCode::NOT_FOUND, "No such object".to_owned())
+        }
+        e => {
+            error!("Storage error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        }
+    }
+}
+
+pub fn app(store: AmazonS3) -> Router {
+    Router::new()
+        .route(
+            "/objects",
+            // The default limit of 2MB applies to the whole request, multipart overhead included
+            get(list_objects)
+                .post(upload)
+                .layer(DefaultBodyLimit::max(MAX_FILE_SIZE as usize + 64 * 1024)),
+        )
+        // Keys contain slashes, the wildcard takes the rest of the path
+        .route("/objects/*key", get(download_url))
+        .with_state(Arc::new(store))
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let store = connect(&StorageConfig::parse()).unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(store)).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::FromRequest;
+    use object_store::{memory::InMemory, PutPayload};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_page_by_page() {
+        let store = InMemory::new();
+        for name in ["a", "b", "c"] {
+            let key = ObjectPath::from(format!("{PREFIX}/{name}"));
+            store
+                .put(&key, PutPayload::from_static(b"x"))
+                .await
+                .unwrap();
+        }
+        let params = |after: Option<String>| ListParams {
+            prefix: None,
+            after,
+            limit: Some(2),
+        };
+
+        let page = list_page(&store, params(None)).await.unwrap();
+        let keys: Vec<_> = page.objects.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(page.next.as_deref(), Some("b"));
+
+        let page = list_page(&store, params(page.next)).await.unwrap();
+        let keys: Vec<_> = page.objects.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, ["c"]);
+        assert_eq!(page.next, None);
+    }
+
+    #[tokio::test]
+    async fn keys_stay_below_the_prefix() {
+        let store = InMemory::new();
+        store
+            .put(
+                &ObjectPath::from("secrets/a"),
+                PutPayload::from_static(b"x"),
+            )
+            .await
+            .unwrap();
+        for prefix in ["..", "../secrets", "/secrets", "a/../../secrets"] {
+            let params = ListParams {
+                prefix: Some(prefix.to_owned()),
+                after: None,
+                limit: None,
+            };
+            let err = list_page(&store, params).await.unwrap_err();
+            assert_eq!(err.0, StatusCode::BAD_REQUEST, "{prefix}");
+        }
+        assert_eq!(object_path("a/b").unwrap().as_ref(), "uploads/a/b");
+        assert!(object_path("../secrets/a").is_err());
+    }
+
+    #[tokio::test]
+    async fn saves_fields_as_multipart_uploads() {
+        let body = "--X\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            Hello, world!\r\n\
+            --X--\r\n";
+        let req = axum::http::Request::post("/objects")
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let mut multipart = Multipart::from_request(req, &()).await.unwrap();
+        let field = multipart.next_field().await.unwrap().unwrap();
+        let store = InMemory::new();
+
+        let uploaded = save(&store, field).await.unwrap();
+        assert_eq!(uploaded.size, 13);
+        let object = store
+            .get(&object_path(&uploaded.key).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            object
+                .attributes
+                .get(&Attribute::ContentType)
+                .unwrap()
+                .as_ref(),
+            "text/plain"
+        );
+        assert_eq!(object.bytes().await.unwrap(), "Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn presigns_without_a_request() {
+        let store = connect(&StorageConfig {
+            s3_endpoint: Some("http://localhost:9000".to_owned()),
+            s3_bucket: "files".to_owned(),
+            s3_region: "us-east-1".to_owned(),
+            s3_access_key_id: "minio".to_owned(),
+            s3_secret_access_key: "minio123".to_owned(),
+        })
+        .unwrap();
+        let url = store
+            .signed_url(
+                Method::GET,
+                &ObjectPath::from("uploads/a"),
+                DOWNLOAD_URL_TTL,
+            )
+            .await
+            .unwrap();
+        assert!(url
+            .as_str()
+            .starts_with("http://localhost:9000/files/uploads/a?"));
+        assert!(url.query().unwrap().contains("X-Amz-Expires=900"));
+        assert!(url.query().unwrap().contains("X-Amz-Signature="));
+    }
+}
@@ -0,0 +1,215 @@
+//! Downloading and uploading large files with reqwest without holding them in memory
+//! Requires `cargo add futures reqwest -F stream`
+//! `cargo add thiserror tracing tracing-subscriber`
+//! `cargo add clap -F derive -F env`
+//! `cargo add tokio-util -F io -F rt`
+//! `cargo add tokio -F macros -F rt-multi-thread -F fs -F io-util -F signal`
+//! Builds on the `shutdown` recipe, ctrl-c stops a transfer in the middle.
+//!
+//! Only one chunk of the body is in memory at a time, so a file of several gigabytes needs as
+//! little memory as a small one. Downloads go to a `.part` file that is renamed once complete,
+//! an interrupted download never looks like a finished one.
+
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::{header, Body, Client};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use tracing::info;
+
+use crate::recipes::shutdown::ShutdownController;
+
+#[derive(Debug, Parser)]
+pub struct TransferConfig {
+    #[clap(long, env, default_value = "https://httpbin.org/bytes/102400")]
+    pub download_url: String,
+    #[clap(long, env, default_value = "https://httpbin.org/anything")]
+    pub upload_url: String,
+    #[clap(long, env, default_value = "download.bin")]
+    pub file: PathBuf,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransferError {
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Failed to access the file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Transfer was cancelled")]
+    Cancelled,
+}
+
+/// `progress` gets the bytes transferred so far and the total size if the server sent it
+pub async fn download(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    cancel: &CancellationToken,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<u64, TransferError> {
+    let res = client.get(url).send().await?.error_for_status()?;
+    let total = res.content_length();
+    let part = path.with_extension("part");
+    let mut file = BufWriter::new(File::create(&part).await?);
+    let mut body = res.bytes_stream();
+    let mut written = 0;
+    loop {
+        let chunk = tokio::select! {
+            // Checked first so a fast server can't keep the loop busy after cancelling
+            biased;
+            _ = cancel.cancelled() => {
+                drop(file);
+                tokio::fs::remove_file(&part).await?;
+                return Err(TransferError::Cancelled);
+            }
+            chunk = body.next() => chunk,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+        progress(written, total);
+    }
+    // Without flushing the last buffered bytes would be lost when the writer is dropped
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&part, path).await?;
+    Ok(written)
+}
+
+/// The progress reported is what was handed to the connection, not what the server received yet
+pub async fn upload(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    cancel: &CancellationToken,
+    progress: impl Fn(u64, u64) + Send + Sync + 'static,
+) -> Result<(), TransferError> {
+    let file = File::open(path).await?;
+    let total = file.metadata().await?.len();
+    let mut sent = 0;
+    let stream = ReaderStream::new(file).inspect_ok(move |chunk| {
+        sent += chunk.len() as u64;
+        progress(sent, total);
+    });
+    let request = client
+        .put(url)
+        // A streamed body has no known length, without the header it would be sent chunked
+        .header(header::CONTENT_LENGTH, total)
+        .body(Body::wrap_stream(stream))
+        .send();
+    // Dropping the request future closes the connection, which aborts the upload
+    tokio::select! {
+        res = request => {
+            res?.error_for_status()?;
+            Ok(())
+        }
+        _ = cancel.cancelled() => Err(TransferError::Cancelled),
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let config = TransferConfig::parse();
+    let shutdown = ShutdownController::new();
+    shutdown.listen_for_signals();
+    let cancel = shutdown.token();
+    let client = Client::new();
+
+    let bytes = download(
+        &client,
+        &config.download_url,
+        &config.file,
+        &cancel,
+        |done, total| match total {
+            Some(total) => info!("Downloaded {done} of {total} bytes"),
+            None => info!("Downloaded {done} bytes"),
+        },
+    )
+    .await
+    .unwrap();
+    info!("Saved {bytes} bytes to {}", config.file.display());
+
+    upload(
+        &client,
+        &config.upload_url,
+        &config.file,
+        &cancel,
+        |done, total| info!("Uploaded {done} of {total} bytes"),
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{header as header_matcher, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn downloads_and_uploads_in_chunks() {
+        let server = MockServer::start().await;
+        let data = vec![7u8; 256 * 1024];
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(data.clone()))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(header_matcher("content-length", "262144"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let path = temp_path("download");
+        let mut reports = Vec::new();
+        let client = Client::new();
+        let token = CancellationToken::new();
+        let written = download(&client, &server.uri(), &path, &token, |done, total| {
+            reports.push((done, total))
+        })
+        .await
+        .unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), data);
+        assert_eq!(reports.last(), Some(&(written, Some(written))));
+
+        upload(&client, &server.uri(), &path, &token, |_, _| {})
+            .await
+            .unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_download_leaves_no_file() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0u8; 1024]))
+            .mount(&server)
+            .await;
+
+        let path = temp_path("cancelled");
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = download(&Client::new(), &server.uri(), &path, &token, |_, _| {}).await;
+        assert!(matches!(result, Err(TransferError::Cancelled)));
+        assert!(!path.with_extension("part").exists());
+        assert!(!path.exists());
+    }
+}
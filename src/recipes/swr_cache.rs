@@ -0,0 +1,328 @@
+//! Coalescing cache misses and serving stale values while revalidating
+//! Requires `cargo add axum futures`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F time`
+//!
+//! Values are fresh for `fresh_for` and can be served stale for another `stale_for` while one
+//! background refresh runs. Concurrent misses share a single computation instead of all hitting the backend.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use axum::{extract::State, routing::get, Router};
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use tokio::{net::TcpListener, time::Instant};
+
+type Inflight<V, E> = Shared<BoxFuture<'static, Result<V, CacheError<E>>>>;
+
+/// Shared by every request waiting on the same load
+#[derive(Debug)]
+pub enum CacheError<E> {
+    Load(Arc<E>),
+    /// The load panicked, the next request starts a new one
+    Panicked,
+}
+
+impl<E> Clone for CacheError<E> {
+    fn clone(&self) -> Self {
+        match self {
+            CacheError::Load(e) => CacheError::Load(e.clone()),
+            CacheError::Panicked => CacheError::Panicked,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for CacheError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Load(e) => e.fmt(f),
+            CacheError::Panicked => f.write_str("Loading the value panicked"),
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    stale_at: Instant,
+    expires_at: Instant,
+}
+
+struct CacheState<K, V, E> {
+    entries: HashMap<K, Entry<V>>,
+    inflight: HashMap<K, Inflight<V, E>>,
+}
+
+pub struct SwrCache<K, V, E> {
+    state: Arc<Mutex<CacheState<K, V, E>>>,
+    fresh_for: Duration,
+    stale_for: Duration,
+}
+
+impl<K, V, E> Clone for SwrCache<K, V, E> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            fresh_for: self.fresh_for,
+            stale_for: self.stale_for,
+        }
+    }
+}
+
+impl<K, V, E> SwrCache<K, V, E>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    pub fn new(fresh_for: Duration, stale_for: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(CacheState {
+                entries: HashMap::new(),
+                inflight: HashMap::new(),
+            })),
+            fresh_for,
+            stale_for,
+        }
+    }
+
+    /// `load` only runs if no fresh value is cached and nobody else is already loading it
+    pub async fn get<F, Fut>(&self, key: K, load: F) -> Result<V, CacheError<E>>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, E>> + Send + 'static,
+    {
+        let inflight = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let cached = state
+                .entries
+                .get(&key)
+                .filter(|entry| entry.expires_at > now)
+                .map(|entry| (entry.value.clone(), entry.stale_at > now));
+            if let Some((value, true)) = cached {
+                return Ok(value);
+            }
+            let inflight = match state.inflight.get(&key) {
+                Some(inflight) => inflight.clone(),
+                None => self.start_load(&mut state, key, load),
+            };
+            // A stale value is served right away while the refresh continues in the background
+            if let Some((value, false)) = cached {
+                return Ok(value);
+            }
+            inflight
+        };
+        inflight.await
+    }
+
+    fn start_load<F, Fut>(&self, state: &mut CacheState<K, V, E>, key: K, load: F) -> Inflight<V, E>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, E>> + Send + 'static,
+    {
+        let cache = self.clone();
+        let task_key = key.clone();
+        // Spawned so the load finishes even if every request waiting on it is cancelled
+        let task = tokio::spawn(async move {
+            // Also removes the load if it panics, otherwise every later request would wait on it
+            let _guard = InflightGuard {
+                cache: cache.clone(),
+                key: task_key.clone(),
+            };
+            let result = load().await.map_err(|e| CacheError::Load(Arc::new(e)));
+            let mut state = cache.state.lock().unwrap();
+            // On failure the previous entry is kept and served until its hard expiry
+            if let Ok(value) = &result {
+                let now = Instant::now();
+                state.entries.insert(
+                    task_key,
+                    Entry {
+                        value: value.clone(),
+                        stale_at: now + cache.fresh_for,
+                        expires_at: now + cache.fresh_for + cache.stale_for,
+                    },
+                );
+            }
+            result
+        });
+        let inflight = async move { task.await.unwrap_or(Err(CacheError::Panicked)) }
+            .boxed()
+            .shared();
+        // The task can't remove this before it is inserted as we are still holding the lock
+        state.inflight.insert(key, inflight.clone());
+        inflight
+    }
+}
+
+struct InflightGuard<K: Hash + Eq, V, E> {
+    cache: SwrCache<K, V, E>,
+    key: K,
+}
+
+impl<K: Hash + Eq, V, E> Drop for InflightGuard<K, V, E> {
+    fn drop(&mut self) {
+        // Panicking again while unwinding would abort
+        let mut state = self
+            .cache
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.inflight.remove(&self.key);
+    }
+}
+
+async fn expensive_report() -> Result<String, std::io::Error> {
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    Ok("Report".to_owned())
+}
+
+async fn report(State(cache): State<SwrCache<&'static str, String, std::io::Error>>) -> String {
+    match cache.get("report", expensive_report).await {
+        Ok(report) => report,
+        Err(e) => format!("Failed to build report: {e}"),
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    let cache = SwrCache::new(Duration::from_secs(10), Duration::from_secs(300));
+    let app = Router::new()
+        .route("/report", get(report))
+        .with_state(cache);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::join_all;
+
+    use super::*;
+
+    const FRESH: Duration = Duration::from_secs(10);
+    const STALE: Duration = Duration::from_secs(60);
+    const LOAD: Duration = Duration::from_secs(1);
+
+    type Cache = SwrCache<&'static str, String, String>;
+
+    /// Takes `LOAD` and counts how often it ran
+    fn load(
+        calls: &Arc<AtomicUsize>,
+        result: Result<&'static str, &'static str>,
+    ) -> impl FnOnce() -> BoxFuture<'static, Result<String, String>> + Send + 'static {
+        let calls = calls.clone();
+        move || {
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(LOAD).await;
+                result.map(ToOwned::to_owned).map_err(ToOwned::to_owned)
+            }
+            .boxed()
+        }
+    }
+
+    /// Returns the values and how long the slowest request took
+    async fn concurrently(
+        cache: &Cache,
+        calls: &Arc<AtomicUsize>,
+        result: Result<&'static str, &'static str>,
+    ) -> (Vec<Result<String, String>>, Duration) {
+        let start = Instant::now();
+        let values = join_all((0..10).map(|_| cache.get("key", load(calls, result)))).await;
+        let values = values
+            .into_iter()
+            .map(|value| value.map_err(|e| e.to_string()))
+            .collect();
+        (values, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_misses_load_once() {
+        let cache = Cache::new(FRESH, STALE);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (values, took) = concurrently(&cache, &calls, Ok("v1")).await;
+        assert!(values.iter().all(|value| value.as_deref() == Ok("v1")));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(took, LOAD);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_stale_values_while_refreshing() {
+        let cache = Cache::new(FRESH, STALE);
+        let calls = Arc::new(AtomicUsize::new(0));
+        cache.get("key", load(&calls, Ok("v1"))).await.unwrap();
+
+        tokio::time::sleep(FRESH).await;
+        let (values, took) = concurrently(&cache, &calls, Ok("v2")).await;
+        assert!(values.iter().all(|value| value.as_deref() == Ok("v1")));
+        assert_eq!(took, Duration::ZERO);
+
+        // One refresh ran in the background for all of them
+        tokio::time::sleep(LOAD * 2).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let value = cache.get("key", load(&calls, Ok("v3"))).await.unwrap();
+        assert_eq!(value, "v2");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_values_block_until_loaded() {
+        let cache = Cache::new(FRESH, STALE);
+        let calls = Arc::new(AtomicUsize::new(0));
+        cache.get("key", load(&calls, Ok("v1"))).await.unwrap();
+
+        tokio::time::sleep(FRESH + STALE).await;
+        let (values, took) = concurrently(&cache, &calls, Ok("v2")).await;
+        assert!(values.iter().all(|value| value.as_deref() == Ok("v2")));
+        assert_eq!(took, LOAD);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_refreshes_keep_the_stale_value_until_it_expires() {
+        let cache = Cache::new(FRESH, STALE);
+        let calls = Arc::new(AtomicUsize::new(0));
+        cache.get("key", load(&calls, Ok("v1"))).await.unwrap();
+
+        tokio::time::sleep(FRESH).await;
+        let value = cache.get("key", load(&calls, Err("down"))).await;
+        assert_eq!(value.unwrap(), "v1");
+        tokio::time::sleep(LOAD * 2).await;
+        let value = cache.get("key", load(&calls, Err("down"))).await;
+        assert_eq!(value.unwrap(), "v1");
+
+        tokio::time::sleep(STALE).await;
+        let value = cache.get("key", load(&calls, Err("down"))).await;
+        assert_eq!(value.unwrap_err().to_string(), "down");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicking_loads_fail_the_waiters_and_are_retried() {
+        let cache = Cache::new(FRESH, STALE);
+        let values = join_all((0..10).map(|_| {
+            cache.get("key", || async {
+                tokio::time::sleep(LOAD).await;
+                panic!("Backend exploded");
+            })
+        }))
+        .await;
+        assert!(values
+            .iter()
+            .all(|value| matches!(value, Err(CacheError::Panicked))));
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let value = cache.get("key", load(&calls, Ok("v1"))).await;
+        assert_eq!(value.unwrap(), "v1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
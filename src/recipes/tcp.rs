@@ -0,0 +1,230 @@
+//! A line based TCP server and a UDP echo server for protocols that aren't HTTP
+//! Requires `cargo add futures tracing tracing-subscriber`
+//! `cargo add tokio-util -F codec -F rt`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F signal -F sync -F time`
+//!
+//! Talk to it with `nc localhost 7000`, one command per line: `PING`, `ECHO hello` or `QUIT`.
+//! `nc -u localhost 7001` echoes every datagram back.
+//!
+//! TCP is a stream of bytes, a single read can return half a line or three of them. `Framed` with
+//! a codec turns it into a stream of whole messages and a sink to send them, swap `LinesCodec` for
+//! `LengthDelimitedCodec` or your own `Decoder` for binary protocols.
+//! Every connection runs in its own task. A semaphore caps how many there are, as each one holds a
+//! file descriptor and a buffer, and idle ones are closed. On shutdown the server stops accepting,
+//! tells every client goodbye and waits a bounded time for them to go.
+
+use std::{sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    sync::Semaphore,
+};
+use tokio_util::{
+    codec::{Framed, LinesCodec, LinesCodecError},
+    sync::CancellationToken,
+    task::TaskTracker,
+};
+use tracing::{info, warn};
+
+const MAX_CONNECTIONS: usize = 1024;
+/// Without a limit a client that never sends a newline makes the buffer grow forever
+const MAX_LINE: usize = 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest payload UDP over IPv4 can carry, longer datagrams would be cut off
+const MAX_DATAGRAM: usize = 65_507;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Ping,
+    Echo(String),
+    Quit,
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+    match name.to_ascii_uppercase().as_str() {
+        "PING" => Ok(Command::Ping),
+        "ECHO" => Ok(Command::Echo(rest.to_owned())),
+        "QUIT" => Ok(Command::Quit),
+        "" => Err("empty line".to_owned()),
+        _ => Err(format!("unknown command {name}")),
+    }
+}
+
+/// Accepts connections until `shutdown` is cancelled, then waits for them to close
+pub async fn serve_tcp(listener: TcpListener, shutdown: CancellationToken) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    let tracker = TaskTracker::new();
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors, waiting gives connections time to close
+                    warn!("Accepting a connection failed: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            // Closed right away instead of leaving the client waiting without an answer
+            warn!(%addr, "Refusing connection, {MAX_CONNECTIONS} are open");
+            continue;
+        };
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            info!(%addr, "Connected");
+            if let Err(e) = handle_connection(stream, shutdown).await {
+                warn!(%addr, "Connection failed: {e}");
+            }
+            info!(%addr, "Disconnected");
+            drop(permit);
+        });
+    }
+    tracker.close();
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, tracker.wait())
+        .await
+        .is_err()
+    {
+        warn!("Connections still open after {SHUTDOWN_TIMEOUT:?}, dropping them");
+    }
+}
+
+/// Generic over the stream so tests can use an in memory pipe instead of a socket
+pub async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    shutdown: CancellationToken,
+) -> Result<(), LinesCodecError> {
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE));
+    loop {
+        let line = tokio::select! {
+            _ = shutdown.cancelled() => {
+                lines.send("BYE shutting down").await?;
+                return Ok(());
+            }
+            line = tokio::time::timeout(IDLE_TIMEOUT, lines.next()) => match line {
+                Ok(Some(line)) => line,
+                // The client closed the connection
+                Ok(None) => return Ok(()),
+                Err(_) => {
+                    lines.send("BYE idle").await?;
+                    return Ok(());
+                }
+            },
+        };
+        let line = match line {
+            Ok(line) => line,
+            // The codec skips the rest of the line, the connection can go on
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                lines
+                    .send(format!("ERR lines can be at most {MAX_LINE} bytes"))
+                    .await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match parse(&line) {
+            Ok(Command::Ping) => lines.send("PONG").await?,
+            Ok(Command::Echo(text)) => lines.send(text).await?,
+            Ok(Command::Quit) => {
+                lines.send("BYE").await?;
+                return Ok(());
+            }
+            Err(e) => lines.send(format!("ERR {e}")).await?,
+        }
+    }
+}
+
+/// UDP has no connections, every datagram stands alone and may get lost or arrive twice
+pub async fn serve_udp(socket: UdpSocket, shutdown: CancellationToken) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let received = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            received = socket.recv_from(&mut buf) => received,
+        };
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Receiving a datagram failed: {e}");
+                continue;
+            }
+        };
+        // A failed reply only concerns this client, the server goes on
+        if let Err(e) = socket.send_to(&buf[..len], peer).await {
+            warn!(%peer, "Sending a datagram failed: {e}");
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let shutdown = CancellationToken::new();
+    let listener = TcpListener::bind("0.0.0.0:7000").await.unwrap();
+    let socket = UdpSocket::bind("0.0.0.0:7001").await.unwrap();
+    let tcp = tokio::spawn(serve_tcp(listener, shutdown.clone()));
+    let udp = tokio::spawn(serve_udp(socket, shutdown.clone()));
+    tokio::signal::ctrl_c().await.unwrap();
+    shutdown.cancel();
+    let (tcp, udp) = tokio::join!(tcp, udp);
+    tcp.unwrap();
+    udp.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_line_by_line() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(handle_connection(server, CancellationToken::new()));
+        let mut client = Framed::new(client, LinesCodec::new());
+
+        for line in ["PING", "echo hello there", "JUMP", "QUIT"] {
+            client.send(line).await.unwrap();
+        }
+        let mut answers = Vec::new();
+        while let Some(line) = client.next().await {
+            answers.push(line.unwrap());
+        }
+        assert_eq!(
+            answers,
+            ["PONG", "hello there", "ERR unknown command JUMP", "BYE"]
+        );
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn says_goodbye_on_shutdown() {
+        let (client, server) = tokio::io::duplex(1024);
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(handle_connection(server, shutdown.clone()));
+        let mut client = Framed::new(client, LinesCodec::new());
+
+        client.send("PING").await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), "PONG");
+        shutdown.cancel();
+        assert_eq!(client.next().await.unwrap().unwrap(), "BYE shutting down");
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn echoes_datagrams() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(serve_udp(server, CancellationToken::new()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+}
@@ -0,0 +1,129 @@
+//! Serving the axum recipe over https, reloading the certificate on SIGHUP
+//! Requires `cargo add axum tracing tracing-subscriber`
+//! `cargo add axum-server -F tls-rustls-no-provider`
+//! `cargo add rustls --no-default-features -F ring -F std -F logging -F tls12`
+//! `cargo add clap -F derive -F env`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F signal`
+//! Builds on the `axum` recipe and serves its router, and on the `shutdown` recipe for SIGINT and SIGTERM.
+//!
+//! Create a self signed certificate for trying it out with
+//! `openssl req -x509 -newkey rsa:2048 -nodes -keyout key.pem -out cert.pem -days 30 -subj /CN=localhost`
+//! and run `cargo run -- --tls-cert cert.pem --tls-key key.pem`.
+//! Replace the files and `kill -HUP <pid>` when the certificate is renewed, new connections use the
+//! new certificate while established ones keep the old one. See the `mtls` recipe for client certificates.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use clap::Parser;
+use tracing::{error, info};
+
+use crate::recipes::shutdown::ShutdownController;
+
+#[derive(Debug, Parser)]
+pub struct TlsConfig {
+    /// Address the server should bind to
+    #[clap(long, env, default_value = "0.0.0.0:8443")]
+    pub bind_addr: SocketAddr,
+    /// PEM file with the certificate chain, the server certificate first
+    #[clap(long, env)]
+    pub tls_cert: PathBuf,
+    /// PEM file with the private key
+    #[clap(long, env)]
+    pub tls_key: PathBuf,
+}
+
+/// Keeps the old certificate when the new files are broken, a typo should not take the server down
+async fn reload(rustls: &RustlsConfig, cert: &Path, key: &Path) {
+    match rustls.reload_from_pem_file(cert, key).await {
+        Ok(()) => info!("Reloaded the tls certificate"),
+        Err(e) => error!("Failed to reload the tls certificate, keeping the old one: {e}"),
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_sighup(rustls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    while hangup.recv().await.is_some() {
+        reload(&rustls, &cert, &key).await;
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    // rustls needs to be told which crypto implementation to use when it isn't the default one
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("No other crypto provider was installed before");
+    let config = TlsConfig::parse();
+    // Fails at startup instead of on the first connection when the files are missing or invalid
+    let rustls = RustlsConfig::from_pem_file(&config.tls_cert, &config.tls_key)
+        .await
+        .unwrap();
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(
+        rustls.clone(),
+        config.tls_cert.clone(),
+        config.tls_key.clone(),
+    ));
+
+    let shutdown = ShutdownController::new();
+    shutdown.listen_for_signals();
+    let handle = Handle::new();
+    tokio::spawn({
+        let (handle, token) = (handle.clone(), shutdown.token());
+        async move {
+            token.cancelled().await;
+            handle.graceful_shutdown(Some(Duration::from_secs(10)));
+        }
+    });
+    info!("Listening on https://{}", config.bind_addr);
+    axum_server::bind_rustls(config.bind_addr, rustls)
+        .handle(handle)
+        .serve(crate::recipes::axum_server::app().into_make_service())
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use super::*;
+
+    /// A fresh self signed certificate and key in a directory of their own
+    fn write_certificate(dir: &Path) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        fs::create_dir_all(dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[tokio::test]
+    async fn reloads_renewed_certificates_and_keeps_the_old_one_on_errors() {
+        // Another test might have installed it already
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = std::env::temp_dir().join(format!("tls-test-{}", std::process::id()));
+        let (cert, key) = write_certificate(&dir.join("old"));
+        let rustls = RustlsConfig::from_pem_file(&cert, &key).await.unwrap();
+        let old = rustls.get_inner();
+
+        fs::write(&cert, "not a certificate").unwrap();
+        reload(&rustls, &cert, &key).await;
+        assert!(Arc::ptr_eq(&old, &rustls.get_inner()));
+
+        let (cert, key) = write_certificate(&dir.join("new"));
+        reload(&rustls, &cert, &key).await;
+        assert!(!Arc::ptr_eq(&old, &rustls.get_inner()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
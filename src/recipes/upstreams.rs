@@ -0,0 +1,285 @@
+//! Typed configuration of multiple named upstream services
+//! Requires `cargo add axum reqwest thiserror toml`
+//! `cargo add url -F serde`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//! Tests require `cargo add --dev wiremock`
+//! `cargo add --dev tower -F util`
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use reqwest::{Client, Method, RequestBuilder};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use url::Url;
+
+/// ```toml
+/// [upstreams.billing]
+/// base_url = "https://billing.internal/api/"
+/// timeout_secs = 5
+/// auth = { type = "bearer", token = "secret" }
+///
+/// [upstreams.inventory]
+/// base_url = "http://inventory.internal/"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamConfig {
+    /// Should end with a `/` otherwise the last path segment is replaced when joining paths
+    pub base_url: Url,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    pub auth: Option<UpstreamAuth>,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum UpstreamAuth {
+    Bearer { token: String },
+    Basic { username: String, password: String },
+}
+
+/// Hand written so secrets never end up in the logs when debug printing the config
+impl std::fmt::Debug for UpstreamAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamAuth::Bearer { .. } => f.write_str("Bearer([redacted])"),
+            UpstreamAuth::Basic { username, .. } => write!(f, "Basic({username}, [redacted])"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("Upstream {0} is not configured")]
+    Unknown(String),
+    #[error("Invalid configuration for upstream {0}: {1}")]
+    Invalid(String, reqwest::Error),
+    #[error("Invalid path {0}: {1}")]
+    Path(String, String),
+}
+
+impl IntoResponse for UpstreamError {
+    fn into_response(self) -> Response {
+        // This is a bug in our configuration, not something the client did
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    }
+}
+
+/// One configured client per upstream. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    base_url: Url,
+    client: Client,
+    auth: Option<Arc<UpstreamAuth>>,
+}
+
+impl Upstream {
+    /// `path` is relative to the base url, a leading `/` doesn't drop the base url's path.
+    /// Absolute urls replace the base url when joined, those pointing elsewhere are rejected so
+    /// the upstream's credentials are never sent to another host.
+    pub fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, UpstreamError> {
+        let url = self
+            .base_url
+            .join(path.trim_start_matches('/'))
+            .map_err(|e| UpstreamError::Path(path.to_owned(), e.to_string()))?;
+        if url.origin() != self.base_url.origin() {
+            return Err(UpstreamError::Path(
+                path.to_owned(),
+                format!("not on {}", self.base_url.origin().ascii_serialization()),
+            ));
+        }
+        let req = self.client.request(method, url);
+        // reqwest marks these headers as sensitive so they don't show up in debug output
+        Ok(match self.auth.as_deref() {
+            Some(UpstreamAuth::Bearer { token }) => req.bearer_auth(token),
+            Some(UpstreamAuth::Basic { username, password }) => {
+                req.basic_auth(username, Some(password))
+            }
+            None => req,
+        })
+    }
+
+    pub fn get(&self, path: &str) -> Result<RequestBuilder, UpstreamError> {
+        self.request(Method::GET, path)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Upstreams(Arc<HashMap<String, Upstream>>);
+
+impl Upstreams {
+    pub fn from_config(config: HashMap<String, UpstreamConfig>) -> Result<Self, UpstreamError> {
+        let mut upstreams = HashMap::with_capacity(config.len());
+        for (name, config) in config {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .build()
+                .map_err(|e| UpstreamError::Invalid(name.clone(), e))?;
+            let upstream = Upstream {
+                base_url: config.base_url,
+                client,
+                auth: config.auth.map(Arc::new),
+            };
+            upstreams.insert(name, upstream);
+        }
+        Ok(Self(Arc::new(upstreams)))
+    }
+
+    /// Call this at startup with every upstream the code uses so a missing one fails right away
+    /// instead of on the first request that needs it
+    pub fn require(&self, names: &[&str]) -> Result<(), UpstreamError> {
+        names.iter().try_for_each(|name| self.get(name).map(|_| ()))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Upstream, UpstreamError> {
+        self.0
+            .get(name)
+            .ok_or_else(|| UpstreamError::Unknown(name.to_owned()))
+    }
+}
+
+async fn invoices(State(upstreams): State<Upstreams>) -> Result<Response, UpstreamError> {
+    let res = upstreams.get("billing")?.get("/invoices")?.send().await;
+    Ok(match res {
+        Ok(res) => (res.status(), res.text().await.unwrap_or_default()).into_response(),
+        Err(_) => StatusCode::BAD_GATEWAY.into_response(),
+    })
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config: Config = toml::from_str(&std::fs::read_to_string("config.toml").unwrap()).unwrap();
+    let upstreams = Upstreams::from_config(config.upstreams).unwrap();
+    upstreams.require(&["billing"]).unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(upstreams)).await.unwrap();
+}
+
+pub fn app(upstreams: Upstreams) -> Router {
+    Router::new()
+        .route("/invoices", get(invoices))
+        .with_state(upstreams)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use tower::ServiceExt;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    /// Billing lives below `/api/` and uses a bearer token, inventory uses basic auth
+    async fn upstreams(billing: &MockServer, inventory: &MockServer) -> Upstreams {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [upstreams.billing]
+            base_url = "{}/api/"
+            auth = {{ type = "bearer", token = "secret" }}
+
+            [upstreams.inventory]
+            base_url = "{}"
+            timeout_secs = 1
+            auth = {{ type = "basic", username = "jane", password = "hunter2" }}
+            "#,
+            billing.uri(),
+            inventory.uri(),
+        ))
+        .unwrap();
+        Upstreams::from_config(config.upstreams).unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_each_upstream_with_its_own_auth() {
+        let (billing, inventory) = (MockServer::start().await, MockServer::start().await);
+        Mock::given(method("GET"))
+            .and(path("/api/invoices"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&billing)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/items"))
+            // jane:hunter2
+            .and(header("authorization", "Basic amFuZTpodW50ZXIy"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&inventory)
+            .await;
+        let upstreams = upstreams(&billing, &inventory).await;
+        upstreams.require(&["billing", "inventory"]).unwrap();
+
+        let req = Request::get("/invoices").body(Body::empty()).unwrap();
+        let res = app(upstreams.clone()).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await.unwrap(), "[]");
+
+        let inventory = upstreams.get("inventory").unwrap();
+        let res = inventory.get("items").unwrap().send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn unknown_upstreams_are_an_error() {
+        let (billing, inventory) = (MockServer::start().await, MockServer::start().await);
+        let upstreams = upstreams(&billing, &inventory).await;
+        assert!(matches!(
+            upstreams.require(&["billing", "shipping"]),
+            Err(UpstreamError::Unknown(name)) if name == "shipping"
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_paths_are_an_error() {
+        let (billing, inventory) = (MockServer::start().await, MockServer::start().await);
+        let upstreams = upstreams(&billing, &inventory).await;
+        let billing = upstreams.get("billing").unwrap();
+        assert!(matches!(
+            billing.get("http://[::1"),
+            Err(UpstreamError::Path(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn other_hosts_never_get_the_credentials() {
+        let (billing, inventory) = (MockServer::start().await, MockServer::start().await);
+        let upstreams = upstreams(&billing, &inventory).await;
+        let upstream = upstreams.get("billing").unwrap();
+        for path in [
+            "https://attacker.example/x",
+            &format!("{}/items", inventory.uri()),
+        ] {
+            assert!(
+                matches!(upstream.get(path), Err(UpstreamError::Path(..))),
+                "{path}"
+            );
+        }
+        // Absolute urls on the upstream itself are fine
+        let url = format!("{}/api/invoices", billing.uri());
+        assert!(upstream.get(&url).is_ok());
+    }
+}
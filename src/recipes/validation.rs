@@ -0,0 +1,156 @@
+//! Validating JSON request bodies beyond what their types express
+//! Requires `cargo add axum serde_json`
+//! `cargo add validator -F derive`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! Use `ValidatedJson<T>` instead of `Json<T>` and a body that deserializes but breaks a rule is
+//! rejected with 422 and every broken rule, not only the first one:
+//! `{"error": "Validation failed", "fields": [{"field": "address.zip", "code": "length", "message": "..."}]}`
+
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+pub struct ValidatedJson<T>(pub T);
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FieldError {
+    /// Path to the field with nested structs and lists separated by dots, e.g. `items.0.name`
+    pub field: String,
+    /// The name of the rule like `length` or `email`
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+pub enum ValidationRejection {
+    /// The body is no valid json or does not match the type
+    Json(JsonRejection),
+    Invalid(Vec<FieldError>),
+}
+
+impl IntoResponse for ValidationRejection {
+    fn into_response(self) -> Response {
+        match self {
+            // Keeps the status of the rejection, e.g. 415 for a missing content type
+            ValidationRejection::Json(rejection) => (
+                rejection.status(),
+                Json(json!({ "error": rejection.body_text() })),
+            )
+                .into_response(),
+            ValidationRejection::Invalid(fields) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "error": "Validation failed", "fields": fields })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ValidationRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(ValidationRejection::Json)?;
+        value
+            .validate()
+            .map_err(|errors| ValidationRejection::Invalid(field_errors(&errors)))?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+/// Flattens the nested errors into a list sorted by field so responses are stable
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut out = Vec::new();
+    collect(errors, "", &mut out);
+    out.sort_by(|a, b| a.field.cmp(&b.field).then_with(|| a.code.cmp(&b.code)));
+    out
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let path = format!("{prefix}{field}");
+        match kind {
+            ValidationErrorsKind::Field(errors) => out.extend(errors.iter().map(|e| FieldError {
+                field: path.clone(),
+                code: e.code.to_string(),
+                message: e.message.as_ref().map(|m| m.to_string()),
+            })),
+            ValidationErrorsKind::Struct(errors) => collect(errors, &format!("{path}."), out),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect(errors, &format!("{path}.{index}."), out);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateUser {
+    #[validate(length(min = 1, max = 64, message = "Must be between 1 and 64 characters"))]
+    pub name: String,
+    #[validate(email(message = "Must be an email address"))]
+    pub email: String,
+    #[validate(range(min = 18, message = "Must be at least 18"))]
+    pub age: u8,
+    /// Nested structs are only validated when asked to
+    #[validate(nested)]
+    pub address: Address,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct Address {
+    #[validate(length(equal = 5, message = "Must have 5 digits"))]
+    pub zip: String,
+}
+
+async fn create_user(ValidatedJson(user): ValidatedJson<CreateUser>) -> String {
+    format!("Created {}", user.name)
+}
+
+#[tokio::main]
+pub async fn main() {
+    let app = Router::new().route("/users", post(create_user));
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_every_broken_rule() {
+        let user = CreateUser {
+            name: String::new(),
+            email: "alice@example.com".to_owned(),
+            age: 17,
+            address: Address {
+                zip: "123".to_owned(),
+            },
+        };
+        let errors = field_errors(&user.validate().unwrap_err());
+        let fields = errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>();
+        assert_eq!(fields, ["address.zip", "age", "name"]);
+        assert_eq!(errors[1].code, "range");
+        assert_eq!(errors[1].message.as_deref(), Some("Must be at least 18"));
+    }
+}
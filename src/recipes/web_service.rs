@@ -0,0 +1,107 @@
+//! A ready to run web service combining the axum, clap, tracing, metrics and graceful shutdown recipes
+//! Run it with `cargo run --features web-service`
+//! It only needs the dependencies of the recipes it is made of so databases and friends are not pulled in
+//! Tests require `cargo add --dev tower -F util`
+//! Builds on the `metrics` recipe for the request metrics and on the `shutdown` recipe for the signals.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{extract::State, middleware, routing::get, Router};
+use clap::Parser;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
+
+use crate::recipes::{
+    metrics::{install_recorder, track_metrics},
+    shutdown::ShutdownController,
+};
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    /// Address the server should bind to
+    #[clap(long, env, default_value = "0.0.0.0:8080")]
+    pub bind_addr: SocketAddr,
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = Config::parse();
+    tracing_subscriber::FmtSubscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .finish()
+        .init();
+    let metrics = install_recorder();
+    let shutdown = ShutdownController::new();
+    shutdown.listen_for_signals();
+
+    let listener = TcpListener::bind(config.bind_addr).await.unwrap();
+    info!("Listening on {}", config.bind_addr);
+    let token = shutdown.token();
+    shutdown.spawn(async move {
+        axum::serve(listener, app(metrics))
+            .with_graceful_shutdown(token.cancelled_owned())
+            .await
+            .unwrap();
+    });
+    if shutdown.wait(Duration::from_secs(30)).await.is_err() {
+        warn!("Requests did not finish in time, exiting anyway");
+    }
+}
+
+pub fn app(metrics: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/", get(|| async { "Hello, world!" }))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/metrics", get(render_metrics))
+        .route_layer(middleware::from_fn(track_metrics))
+        .with_state(metrics)
+}
+
+async fn render_metrics(State(metrics): State<PrometheusHandle>) -> String {
+    metrics.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        extract::Request,
+        http::StatusCode,
+    };
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn get_body(app: Router, uri: &str) -> (StatusCode, String) {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn health_endpoint_answers() {
+        let metrics = PrometheusBuilder::new().build_recorder().handle();
+        let (status, body) = get_body(app(metrics), "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_by_route() {
+        // Local to this thread so tests don't fight over the global recorder
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _recorder = metrics::set_default_local_recorder(&recorder);
+        let app = app(recorder.handle());
+
+        get_body(app.clone(), "/healthz").await;
+        let (_, body) = get_body(app, "/metrics").await;
+        assert!(
+            body.contains(r#"http_requests_total{method="GET",route="/healthz",status="200"} 1"#)
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! Verifying signed webhooks from different providers
+//! Requires `cargo add axum bytes hex hmac sha2 serde_json thiserror`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net`
+//!
+//! Both providers sign `timestamp + separator + body` with HMAC-SHA256. Several secrets can be
+//! active at once so a secret can be rotated without rejecting webhooks signed with the old one.
+
+use std::{
+    marker::PhantomData,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRef, FromRequest, Request},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::Sha256;
+use tokio::net::TcpListener;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("Missing signature header")]
+    MissingSignature,
+    #[error("Malformed signature header")]
+    Malformed,
+    #[error("Timestamp is outside of the tolerance window")]
+    Expired,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Failed to read body")]
+    Body,
+    #[error("Invalid payload: {0}")]
+    Payload(#[from] serde_json::Error),
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            WebhookError::Payload(_) | WebhookError::Body => StatusCode::BAD_REQUEST,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// The unix timestamp and candidate signatures a provider sent along with the body
+pub struct SignatureHeader {
+    pub timestamp: u64,
+    pub signatures: Vec<Vec<u8>>,
+}
+
+pub trait WebhookProvider: Send + Sync + 'static {
+    fn parse_header(headers: &HeaderMap) -> Result<SignatureHeader, WebhookError>;
+    /// Feeds what the provider signs into the mac
+    fn signed_payload(mac: &mut Hmac<Sha256>, timestamp: u64, body: &[u8]);
+}
+
+/// `Stripe-Signature: t=<timestamp>,v1=<hex>[,v1=<hex>]` over `<timestamp>.<body>`
+pub struct Stripe;
+
+impl WebhookProvider for Stripe {
+    fn parse_header(headers: &HeaderMap) -> Result<SignatureHeader, WebhookError> {
+        let header = header_str(headers, "stripe-signature")?;
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for pair in header.split(',') {
+            match pair.trim().split_once('=') {
+                Some(("t", t)) => timestamp = Some(t.parse().map_err(|_| WebhookError::Malformed)?),
+                Some(("v1", sig)) => {
+                    signatures.push(hex::decode(sig).map_err(|_| WebhookError::Malformed)?)
+                }
+                // Other schemes like v0 are ignored
+                Some(_) => {}
+                None => return Err(WebhookError::Malformed),
+            }
+        }
+        match timestamp {
+            Some(timestamp) if !signatures.is_empty() => Ok(SignatureHeader {
+                timestamp,
+                signatures,
+            }),
+            _ => Err(WebhookError::Malformed),
+        }
+    }
+
+    fn signed_payload(mac: &mut Hmac<Sha256>, timestamp: u64, body: &[u8]) {
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+    }
+}
+
+/// `X-Slack-Request-Timestamp` and `X-Slack-Signature: v0=<hex>` over `v0:<timestamp>:<body>`
+pub struct Slack;
+
+impl WebhookProvider for Slack {
+    fn parse_header(headers: &HeaderMap) -> Result<SignatureHeader, WebhookError> {
+        let timestamp = header_str(headers, "x-slack-request-timestamp")?
+            .parse()
+            .map_err(|_| WebhookError::Malformed)?;
+        let signature = header_str(headers, "x-slack-signature")?
+            .strip_prefix("v0=")
+            .ok_or(WebhookError::Malformed)?;
+        Ok(SignatureHeader {
+            timestamp,
+            signatures: vec![hex::decode(signature).map_err(|_| WebhookError::Malformed)?],
+        })
+    }
+
+    fn signed_payload(mac: &mut Hmac<Sha256>, timestamp: u64, body: &[u8]) {
+        mac.update(b"v0:");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b":");
+        mac.update(body);
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, WebhookError> {
+    headers
+        .get(name)
+        .ok_or(WebhookError::MissingSignature)?
+        .to_str()
+        .map_err(|_| WebhookError::Malformed)
+}
+
+pub struct WebhookConfig<P> {
+    /// All currently accepted secrets. Put the new one first while rotating.
+    pub secrets: Vec<Vec<u8>>,
+    /// How far the timestamp may be off in either direction to limit replays
+    pub tolerance: Duration,
+    _provider: PhantomData<P>,
+}
+
+impl<P> Clone for WebhookConfig<P> {
+    fn clone(&self) -> Self {
+        Self {
+            secrets: self.secrets.clone(),
+            tolerance: self.tolerance,
+            _provider: PhantomData,
+        }
+    }
+}
+
+impl<P: WebhookProvider> WebhookConfig<P> {
+    pub fn new(secrets: Vec<Vec<u8>>, tolerance: Duration) -> Self {
+        Self {
+            secrets,
+            tolerance,
+            _provider: PhantomData,
+        }
+    }
+
+    /// Takes the current time explicitly so the tolerance window can be tested
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Result<(), WebhookError> {
+        let header = P::parse_header(headers)?;
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now.abs_diff(header.timestamp) > self.tolerance.as_secs() {
+            return Err(WebhookError::Expired);
+        }
+        let valid = self.secrets.iter().any(|secret| {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+            P::signed_payload(&mut mac, header.timestamp, body);
+            // verify_slice compares in constant time, so clone the mac for every signature
+            header
+                .signatures
+                .iter()
+                .any(|signature| mac.clone().verify_slice(signature).is_ok())
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(WebhookError::InvalidSignature)
+        }
+    }
+}
+
+/// Rejects the request unless it is signed by `P`, then parses the body as `T`
+pub struct VerifiedWebhook<P, T>(pub T, PhantomData<P>);
+
+#[async_trait]
+impl<S, P, T> FromRequest<S> for VerifiedWebhook<P, T>
+where
+    S: Send + Sync,
+    P: WebhookProvider,
+    T: DeserializeOwned,
+    WebhookConfig<P>: FromRef<S>,
+{
+    type Rejection = WebhookError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let config = WebhookConfig::<P>::from_ref(state);
+        let headers = req.headers().clone();
+        // The signature covers the exact bytes, so verify before parsing anything
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|_| WebhookError::Body)?;
+        config.verify(&headers, &body, SystemTime::now())?;
+        Ok(Self(serde_json::from_slice(&body)?, PhantomData))
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    stripe: WebhookConfig<Stripe>,
+    slack: WebhookConfig<Slack>,
+}
+
+impl FromRef<AppState> for WebhookConfig<Stripe> {
+    fn from_ref(state: &AppState) -> Self {
+        state.stripe.clone()
+    }
+}
+
+impl FromRef<AppState> for WebhookConfig<Slack> {
+    fn from_ref(state: &AppState) -> Self {
+        state.slack.clone()
+    }
+}
+
+#[derive(Deserialize)]
+struct StripeEvent {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct SlackEvent {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+async fn stripe_webhook(
+    VerifiedWebhook(event, ..): VerifiedWebhook<Stripe, StripeEvent>,
+) -> StatusCode {
+    println!("Stripe event {} of type {}", event.id, event.kind);
+    StatusCode::NO_CONTENT
+}
+
+async fn slack_webhook(
+    VerifiedWebhook(event, ..): VerifiedWebhook<Slack, SlackEvent>,
+) -> StatusCode {
+    println!("Slack event of type {}", event.kind);
+    StatusCode::NO_CONTENT
+}
+
+#[tokio::main]
+pub async fn main() {
+    let secrets = |var: &str| {
+        std::env::var(var)
+            .unwrap()
+            .split(',')
+            .map(|secret| secret.as_bytes().to_vec())
+            .collect()
+    };
+    let app = app(
+        WebhookConfig::new(secrets("STRIPE_WEBHOOK_SECRETS"), Duration::from_secs(300)),
+        WebhookConfig::new(secrets("SLACK_SIGNING_SECRETS"), Duration::from_secs(300)),
+    );
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+pub fn app(stripe: WebhookConfig<Stripe>, slack: WebhookConfig<Slack>) -> Router {
+    Router::new()
+        .route("/webhooks/stripe", post(stripe_webhook))
+        .route("/webhooks/slack", post(slack_webhook))
+        .with_state(AppState { stripe, slack })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::HeaderValue};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const TOLERANCE: Duration = Duration::from_secs(300);
+    const BODY: &[u8] = br#"{"id":"evt_1","type":"invoice.paid"}"#;
+
+    fn sign<P: WebhookProvider>(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        P::signed_payload(&mut mac, timestamp, body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn stripe_headers(header: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("stripe-signature", HeaderValue::from_str(header).unwrap());
+        headers
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn at(timestamp: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(timestamp)
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let config = WebhookConfig::<Stripe>::new(vec![b"secret".to_vec()], TOLERANCE);
+        let header = format!("t=1000,v1={}", sign::<Stripe>(b"secret", 1000, BODY));
+        assert!(config
+            .verify(&stripe_headers(&header), BODY, at(1000))
+            .is_ok());
+
+        let config = WebhookConfig::<Slack>::new(vec![b"secret".to_vec()], TOLERANCE);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-slack-request-timestamp",
+            HeaderValue::from_static("1000"),
+        );
+        let signature = format!("v0={}", sign::<Slack>(b"secret", 1000, BODY));
+        headers.insert(
+            "x-slack-signature",
+            HeaderValue::from_str(&signature).unwrap(),
+        );
+        assert!(config.verify(&headers, BODY, at(1000)).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let config = WebhookConfig::<Stripe>::new(vec![b"secret".to_vec()], TOLERANCE);
+        let verify =
+            |header: String, body: &[u8]| config.verify(&stripe_headers(&header), body, at(1000));
+        let signature = sign::<Stripe>(b"other", 1000, BODY);
+        assert!(matches!(
+            verify(format!("t=1000,v1={signature}"), BODY),
+            Err(WebhookError::InvalidSignature)
+        ));
+        // The timestamp is signed too, changing it breaks the signature
+        let signature = sign::<Stripe>(b"secret", 1000, BODY);
+        assert!(matches!(
+            verify(format!("t=1001,v1={signature}"), BODY),
+            Err(WebhookError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify(format!("t=1000,v1={signature}"), br#"{"id":"evt_2"}"#),
+            Err(WebhookError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify("t=1000,v1=not-hex".to_owned(), BODY),
+            Err(WebhookError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_tolerance() {
+        let config = WebhookConfig::<Stripe>::new(vec![b"secret".to_vec()], TOLERANCE);
+        let header = format!("t=1000,v1={}", sign::<Stripe>(b"secret", 1000, BODY));
+        let verify = |now| config.verify(&stripe_headers(&header), BODY, at(now));
+        assert!(verify(1300).is_ok());
+        assert!(verify(700).is_ok());
+        assert!(matches!(verify(1301), Err(WebhookError::Expired)));
+        assert!(matches!(verify(699), Err(WebhookError::Expired)));
+    }
+
+    #[test]
+    fn accepts_every_secret_while_rotating() {
+        let config =
+            WebhookConfig::<Stripe>::new(vec![b"new".to_vec(), b"old".to_vec()], TOLERANCE);
+        let verify = |header: String| config.verify(&stripe_headers(&header), BODY, at(1000));
+        for secret in [b"new", b"old"] {
+            let signature = sign::<Stripe>(secret, 1000, BODY);
+            assert!(verify(format!("t=1000,v1={signature}")).is_ok());
+        }
+        // Stripe sends one signature per active secret of its own
+        let header = format!(
+            "t=1000,v1={},v1={}",
+            sign::<Stripe>(b"unknown", 1000, BODY),
+            sign::<Stripe>(b"old", 1000, BODY)
+        );
+        assert!(verify(header).is_ok());
+        let signature = sign::<Stripe>(b"retired", 1000, BODY);
+        assert!(verify(format!("t=1000,v1={signature}")).is_err());
+    }
+
+    #[tokio::test]
+    async fn extractor_verifies_before_parsing() {
+        let app = app(
+            WebhookConfig::new(vec![b"secret".to_vec()], TOLERANCE),
+            WebhookConfig::new(vec![b"secret".to_vec()], TOLERANCE),
+        );
+        let send = |header: String, body: &'static [u8]| {
+            app.clone().oneshot(
+                Request::post("/webhooks/stripe")
+                    .header("stripe-signature", header)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let now = now();
+        let signature = sign::<Stripe>(b"secret", now, BODY);
+        let response = send(format!("t={now},v1={signature}"), BODY).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let signature = sign::<Stripe>(b"other", now, BODY);
+        let response = send(format!("t={now},v1={signature}"), BODY).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let stale = now - 301;
+        let signature = sign::<Stripe>(b"secret", stale, BODY);
+        let response = send(format!("t={stale},v1={signature}"), BODY)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Signed correctly but not a Stripe event
+        let signature = sign::<Stripe>(b"secret", now, b"[]");
+        let response = send(format!("t={now},v1={signature}"), b"[]")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,241 @@
+//! WebSocket chat where every client sees the messages of all others
+//! Requires `cargo add axum -F ws`
+//! `cargo add futures tokio-util tracing`
+//! `cargo add serde -F derive`
+//! `cargo add tokio -F macros -F rt-multi-thread -F signal -F net -F sync -F time`
+//!
+//! Connect with e.g. `websocat ws://localhost:8080/ws?name=alice`.
+//! On shutdown every client gets a close frame with code 1001 (going away) so it knows to reconnect
+//! instead of seeing the connection drop. See the `websocket_heartbeat` recipe for dealing with slow clients.
+//! See the `sse` recipe when clients only need to receive.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::{net::TcpListener, sync::broadcast, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// Clients that did not answer a ping or send anything else for this long are disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct Chat {
+    messages: broadcast::Sender<ChatMessage>,
+    shutdown: CancellationToken,
+}
+
+#[derive(Debug, Clone)]
+struct ChatMessage {
+    from: String,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Join {
+    name: String,
+}
+
+#[tokio::main]
+pub async fn main() {
+    let shutdown = CancellationToken::new();
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            tokio::signal::ctrl_c().await.unwrap();
+            shutdown.cancel();
+        }
+    });
+    // Upgraded connections are not tracked by the graceful shutdown so every socket watches the token itself
+    axum::serve(listener, app(shutdown.clone()))
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+        .unwrap();
+}
+
+/// Cancelling `shutdown` closes every socket
+pub fn app(shutdown: CancellationToken) -> Router {
+    let chat = Chat {
+        messages: broadcast::channel(1024).0,
+        shutdown,
+    };
+    Router::new().route("/ws", get(ws)).with_state(chat)
+}
+
+async fn ws(ws: WebSocketUpgrade, Query(join): Query<Join>, State(chat): State<Chat>) -> Response {
+    ws.on_upgrade(|socket| handle_socket(socket, join.name, chat))
+}
+
+async fn handle_socket(socket: WebSocket, name: String, chat: Chat) {
+    let (mut sink, mut stream) = socket.split();
+    // Subscribe before announcing so we don't miss anything sent in between
+    let mut incoming = chat.messages.subscribe();
+    let _ = chat.messages.send(ChatMessage {
+        from: "server".to_owned(),
+        text: format!("{name} joined"),
+    });
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            _ = chat.shutdown.cancelled() => {
+                let _ = sink
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Server is shutting down".into(),
+                    })))
+                    .await;
+                break;
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > IDLE_TIMEOUT {
+                    info!(name, "Client timed out");
+                    break;
+                }
+                if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+            message = incoming.recv() => match message {
+                // Don't echo our own messages back
+                Ok(message) if message.from == name => {}
+                Ok(message) => {
+                    let text = format!("{}: {}", message.from, message.text);
+                    if sink.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                // Missed some messages, the chat goes on
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            frame = stream.next() => {
+                match frame {
+                    Some(Ok(Message::Text(text))) => {
+                        let _ = chat.messages.send(ChatMessage { from: name.clone(), text });
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Pings of the client are answered by axum, pongs only update last_seen
+                    Some(Ok(_)) => {}
+                }
+                last_seen = Instant::now();
+            }
+        }
+    }
+    let _ = chat.messages.send(ChatMessage {
+        from: "server".to_owned(),
+        text: format!("{name} left"),
+    });
+    info!(name, "WebSocket disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    async fn serve(shutdown: CancellationToken) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(shutdown)).await.unwrap() });
+        addr
+    }
+
+    /// Does the handshake by hand like the `websocket_heartbeat` tests
+    async fn join(addr: SocketAddr, name: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /ws?name={name} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    /// A masked text frame, clients must mask and a zero key leaves the payload as is
+    async fn send_text(stream: &mut TcpStream, text: &str) {
+        let mut frame = vec![0x81, 0x80 | text.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(text.as_bytes());
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    /// The opcode and payload of the next frame other than a ping, the server doesn't mask
+    async fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        loop {
+            let opcode = stream.read_u8().await.unwrap() & 0x0f;
+            let len = stream.read_u8().await.unwrap() as usize;
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await.unwrap();
+            if opcode != 0x9 {
+                return (opcode, payload);
+            }
+        }
+    }
+
+    async fn read_text(stream: &mut TcpStream) -> String {
+        let (opcode, payload) = read_frame(stream).await;
+        assert_eq!(opcode, 0x1);
+        String::from_utf8(payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_reach_everyone_but_the_sender() {
+        let addr = serve(CancellationToken::new()).await;
+        let mut alice = join(addr, "alice").await;
+        assert_eq!(read_text(&mut alice).await, "server: alice joined");
+        let mut bob = join(addr, "bob").await;
+        assert_eq!(read_text(&mut bob).await, "server: bob joined");
+        assert_eq!(read_text(&mut alice).await, "server: bob joined");
+
+        send_text(&mut bob, "hi").await;
+        assert_eq!(read_text(&mut alice).await, "bob: hi");
+        send_text(&mut alice, "hello").await;
+        // Bob's own message was not echoed, the next one he sees is Alice's
+        assert_eq!(read_text(&mut bob).await, "alice: hello");
+
+        drop(bob);
+        assert_eq!(read_text(&mut alice).await, "server: bob left");
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_the_sockets_with_going_away() {
+        let shutdown = CancellationToken::new();
+        let addr = serve(shutdown.clone()).await;
+        let mut alice = join(addr, "alice").await;
+        assert_eq!(read_text(&mut alice).await, "server: alice joined");
+
+        shutdown.cancel();
+        let (opcode, payload) = read_frame(&mut alice).await;
+        assert_eq!(opcode, 0x8);
+        assert_eq!(
+            u16::from_be_bytes([payload[0], payload[1]]),
+            close_code::AWAY
+        );
+        assert_eq!(&payload[2..], b"Server is shutting down");
+    }
+}
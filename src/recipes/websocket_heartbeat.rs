@@ -0,0 +1,192 @@
+//! WebSocket heartbeats and protection against slow consumers
+//! Requires `cargo add axum -F ws`
+//! `cargo add futures tracing`
+//! `cargo add tokio -F macros -F rt-multi-thread -F net -F sync -F time`
+
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+use tracing::{debug, info};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// A client that did not send anything for this long is considered dead.
+/// It is disconnected at most `HEARTBEAT_INTERVAL` after the timeout passed.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+/// Messages waiting to be sent to one client. A client that lets this fill up gets disconnected.
+const SEND_QUEUE: usize = 64;
+
+#[tokio::main]
+pub async fn main() {
+    let (messages, _) = broadcast::channel::<String>(1024);
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app(messages)).await.unwrap();
+}
+
+/// Every text a client sends is broadcast to all clients
+pub fn app(messages: broadcast::Sender<String>) -> Router {
+    Router::new().route("/ws", get(ws)).with_state(messages)
+}
+
+async fn ws(ws: WebSocketUpgrade, State(messages): State<broadcast::Sender<String>>) -> Response {
+    ws.on_upgrade(|socket| handle_socket(socket, messages))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    Closed,
+    MissedHeartbeats,
+    SlowConsumer,
+}
+
+pub async fn handle_socket(socket: WebSocket, messages: broadcast::Sender<String>) {
+    let (mut sink, mut stream) = socket.split();
+    let (queue, mut queued) = mpsc::channel(SEND_QUEUE);
+    // Writing happens in its own task so a client that does not read can't keep us from reading
+    let mut writer = tokio::spawn(async move {
+        while let Some(message) = queued.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let mut incoming_messages = messages.subscribe();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+    let reason = loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    break Disconnect::MissedHeartbeats;
+                }
+                if queue.try_send(Message::Ping(Vec::new())).is_err() {
+                    break Disconnect::SlowConsumer;
+                }
+            }
+            message = incoming_messages.recv() => match message {
+                // try_send never waits so one slow client can't slow down everyone else
+                Ok(text) => if queue.try_send(Message::Text(text)).is_err() {
+                    break Disconnect::SlowConsumer;
+                },
+                // The client is so slow that even the broadcast buffer overflowed
+                Err(broadcast::error::RecvError::Lagged(_)) => break Disconnect::SlowConsumer,
+                Err(broadcast::error::RecvError::Closed) => break Disconnect::Closed,
+            },
+            frame = stream.next() => {
+                match frame {
+                    // Axum answers pings of the client by itself
+                    Some(Ok(Message::Text(text))) => {
+                        let _ = messages.send(text);
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break Disconnect::Closed,
+                    Some(Ok(_)) => {}
+                }
+                // Any frame, including our pongs, proves the client is still there
+                last_seen = Instant::now();
+            }
+        }
+    };
+    info!(?reason, "WebSocket disconnected");
+    drop(queue);
+    match reason {
+        // Give the writer a moment to send what's left and the close frame
+        Disconnect::Closed => {
+            if tokio::time::timeout(Duration::from_secs(1), &mut writer)
+                .await
+                .is_err()
+            {
+                writer.abort();
+            }
+        }
+        // The writer is most likely stuck on a full socket
+        Disconnect::MissedHeartbeats | Disconnect::SlowConsumer => {
+            debug!("Aborting writer");
+            writer.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Does the handshake by hand, a WebSocket client library would answer the pings by itself
+    async fn connect() -> tokio::net::TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app(broadcast::channel(16).0))
+                .await
+                .unwrap();
+        });
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+                  Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    /// Reads and discards frames, like pings, until the server closes the connection
+    async fn read_until_closed(mut reader: impl AsyncRead + Unpin) {
+        let mut buf = [0; 1024];
+        while reader.read(&mut buf).await.unwrap() > 0 {}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_clients_that_never_answer() {
+        let stream = connect().await;
+        let connected = Instant::now();
+        read_until_closed(stream).await;
+        let elapsed = connected.elapsed();
+        assert!(elapsed >= HEARTBEAT_TIMEOUT, "{elapsed:?}");
+        assert!(
+            elapsed <= HEARTBEAT_TIMEOUT + HEARTBEAT_INTERVAL,
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_clients_that_send_frames() {
+        let (reader, mut writer) = connect().await.into_split();
+        let closed = read_until_closed(reader);
+        tokio::pin!(closed);
+        for _ in 0..10 {
+            // A masked text frame "hi", clients must mask and a zero key leaves the payload as is
+            writer
+                .write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i'])
+                .await
+                .unwrap();
+            tokio::select! {
+                _ = &mut closed => panic!("Disconnected a client that sends frames"),
+                _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Supervising long running background tasks
+//! Requires `cargo add tokio-util -F rt`
+//! `cargo add tracing tracing-subscriber`
+//! `cargo add tokio -F macros -F rt-multi-thread -F time`
+//! Builds on the `shutdown` recipe for the signals and the shutdown timeout.
+//!
+//! Every worker is restarted with exponential backoff when it returns an error, panics or
+//! returns although no shutdown was requested. On shutdown workers get the cancelled token and are
+//! waited for instead of restarted.
+
+use std::{error::Error, future::Future, time::Duration};
+
+use tokio::time::Instant;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
+
+use crate::recipes::shutdown::ShutdownController;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// A worker that ran at least this long before failing starts over at `initial`
+    pub reset_after: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            reset_after: Duration::from_secs(300),
+        }
+    }
+}
+
+pub struct Supervisor {
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+    backoff: Backoff,
+}
+
+impl Supervisor {
+    pub fn new(shutdown: CancellationToken, backoff: Backoff) -> Self {
+        Self {
+            shutdown,
+            tracker: TaskTracker::new(),
+            backoff,
+        }
+    }
+
+    /// `worker` is called again for every restart so each run starts with fresh state
+    pub fn spawn<F, Fut>(&self, name: &'static str, worker: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), BoxError>> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let backoff = self.backoff;
+        self.tracker.spawn(async move {
+            let mut delay = backoff.initial;
+            loop {
+                let started = Instant::now();
+                // Spawning each run catches panics as a JoinError instead of taking down the supervisor
+                let result = tokio::spawn(worker(shutdown.clone())).await;
+                let stopping = shutdown.is_cancelled();
+                match result {
+                    Ok(Ok(())) if stopping => {}
+                    Ok(Ok(())) => warn!(worker = name, "Stopped unexpectedly"),
+                    Ok(Err(e)) => error!(worker = name, "Failed: {e}"),
+                    Err(e) => error!(worker = name, "Panicked: {e}"),
+                }
+                if stopping {
+                    break;
+                }
+                if started.elapsed() >= backoff.reset_after {
+                    delay = backoff.initial;
+                }
+                info!(worker = name, "Restarting in {delay:?}");
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+                delay = (delay * 2).min(backoff.max);
+            }
+            info!(worker = name, "Stopped");
+        });
+    }
+
+    /// Waits for all workers to stop, which they only do once the shutdown token is cancelled
+    pub async fn join(self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    tracing_subscriber::fmt::init();
+    let shutdown = ShutdownController::new();
+    shutdown.listen_for_signals();
+    let supervisor = Supervisor::new(shutdown.token(), Backoff::default());
+
+    supervisor.spawn("ticker", |shutdown| async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = interval.tick() => info!("Tick"),
+            }
+        }
+    });
+    supervisor.spawn("flaky", |shutdown| async move {
+        tokio::select! {
+            _ = shutdown.cancelled() => Ok(()),
+            _ = tokio::time::sleep(Duration::from_secs(3)) => Err("Lost connection".into()),
+        }
+    });
+
+    shutdown.spawn(supervisor.join());
+    if shutdown.wait(Duration::from_secs(30)).await.is_err() {
+        warn!("Workers did not stop in time, exiting anyway");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    const BACKOFF: Backoff = Backoff {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(4),
+        reset_after: Duration::from_secs(60),
+    };
+
+    /// The times a worker was started at, relative to the start of the test
+    #[derive(Clone, Default)]
+    struct Starts(Arc<Mutex<Vec<Duration>>>);
+
+    impl Starts {
+        fn record(&self, start: Instant) {
+            self.0.lock().unwrap().push(start.elapsed());
+        }
+
+        fn secs(&self) -> Vec<u64> {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(Duration::as_secs)
+                .collect()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_workers_are_restarted_with_backoff() {
+        let (start, starts) = (Instant::now(), Starts::default());
+        let shutdown = CancellationToken::new();
+        let supervisor = Supervisor::new(shutdown.clone(), BACKOFF);
+        let recorded = starts.clone();
+        supervisor.spawn("failing", move |_| {
+            recorded.record(start);
+            async { Err("Lost connection".into()) }
+        });
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        // Doubles up to the max of 4s
+        assert_eq!(starts.secs(), [0, 1, 3, 7, 11, 15, 19]);
+        shutdown.cancel();
+        supervisor.join().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panics_and_unexpected_returns_are_restarted() {
+        let (start, starts) = (Instant::now(), Starts::default());
+        let shutdown = CancellationToken::new();
+        let supervisor = Supervisor::new(shutdown.clone(), BACKOFF);
+        let recorded = starts.clone();
+        supervisor.spawn("panicking", move |_| {
+            recorded.record(start);
+            let run = recorded.0.lock().unwrap().len();
+            async move {
+                if run % 2 == 1 {
+                    panic!("Bug");
+                }
+                Ok(())
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(starts.secs(), [0, 1, 3]);
+        shutdown.cancel();
+        supervisor.join().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_runs_reset_the_backoff() {
+        let (start, starts) = (Instant::now(), Starts::default());
+        let shutdown = CancellationToken::new();
+        let supervisor = Supervisor::new(shutdown.clone(), BACKOFF);
+        let recorded = starts.clone();
+        supervisor.spawn("long", move |_| {
+            recorded.record(start);
+            let run = recorded.0.lock().unwrap().len();
+            async move {
+                // The third run is healthy for a while before it fails as well
+                if run == 3 {
+                    tokio::time::sleep(BACKOFF.reset_after).await;
+                }
+                Err("Lost connection".into())
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(70)).await;
+        assert_eq!(starts.secs(), [0, 1, 3, 64, 66]);
+        shutdown.cancel();
+        supervisor.join().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_stops_the_restarts() {
+        let starts = Starts::default();
+        let shutdown = CancellationToken::new();
+        let supervisor = Supervisor::new(shutdown.clone(), BACKOFF);
+        let recorded = starts.clone();
+        let start = Instant::now();
+        supervisor.spawn("ticker", move |shutdown| {
+            recorded.record(start);
+            async move {
+                shutdown.cancelled().await;
+                Ok(())
+            }
+        });
+        supervisor.spawn("failing", |_| async { Err("Lost connection".into()) });
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        shutdown.cancel();
+        // Would never return if a worker was restarted or not told to stop
+        tokio::time::timeout(Duration::from_secs(1), supervisor.join())
+            .await
+            .unwrap();
+        assert_eq!(starts.secs(), [0]);
+    }
+}
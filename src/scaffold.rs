@@ -0,0 +1,550 @@
+//! Turns the template into a project using only the picked recipes:
+//! `cargo run -- init --recipes axum,tracing,clap`
+//!
+//! Every picked recipe and the recipes it builds on are copied from `src/recipes` into `src/`.
+//! Their dependencies become regular dependencies in Cargo.toml and `main.rs` is rewritten to
+//! call the first recipe.
+//! The recipes that were not picked, their dependencies, `lib.rs` and this module are removed.
+//! Cargo.toml is edited with toml_edit, which keeps its comments and the sections the recipes don't
+//! touch. Like every dependency no picked recipe needs, toml_edit itself is removed.
+
+use std::{fs, path::Path};
+
+use toml_edit::{Array, DocumentMut, Item};
+
+const USAGE: &str = "Usage: cargo run -- init --recipes <recipe>[,<recipe>...]";
+
+/// A recipe as declared in `src/recipes/mod.rs`
+#[derive(Debug)]
+struct Recipe {
+    feature: String,
+    module: String,
+}
+
+impl Recipe {
+    /// Recipes can be picked by module (`axum_server`), feature (`axum-recipe`) or short name (`axum`)
+    fn matches(&self, name: &str) -> bool {
+        self.module == name
+            || self.feature == name
+            || self.feature.strip_suffix("-recipe") == Some(name)
+    }
+}
+
+pub fn init(args: &[String]) -> Result<(), String> {
+    let names = match args {
+        [flag, names] if flag == "--recipes" => names,
+        _ => return Err(USAGE.to_owned()),
+    };
+    let modules = scaffold(Path::new(env!("CARGO_MANIFEST_DIR")), names)?;
+    println!("Scaffolded {}, run it with `cargo run`", modules.join(", "));
+    Ok(())
+}
+
+/// Turns the template in `root` into the project, returns the modules of the copied recipes
+fn scaffold(root: &Path, names: &str) -> Result<Vec<String>, String> {
+    let available = recipes(&read(&root.join("src/recipes/mod.rs"))?);
+    let mut picked = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let Some(recipe) = available.iter().find(|r| r.matches(name)) else {
+            let known = available
+                .iter()
+                .map(|r| r.module.as_str())
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "Unknown recipe {name:?}, known recipes are: {}",
+                known.join(", ")
+            ));
+        };
+        if !picked.iter().any(|p: &&Recipe| p.module == recipe.module) {
+            picked.push(recipe);
+        }
+    }
+    if picked.is_empty() {
+        return Err(USAGE.to_owned());
+    }
+
+    let manifest = read(&root.join("Cargo.toml"))?
+        .parse::<DocumentMut>()
+        .map_err(|e| format!("Failed to parse Cargo.toml: {e}"))?;
+    // Recipes building on other recipes enable their features, those are copied as well
+    let mut i = 0;
+    while let Some(recipe) = picked.get(i) {
+        for item in feature_items(&manifest, &recipe.feature)? {
+            let Some(required) = available.iter().find(|r| r.feature == item) else {
+                continue;
+            };
+            if !picked.iter().any(|p| p.module == required.module) {
+                picked.push(required);
+            }
+        }
+        i += 1;
+    }
+    let manifest = patch_manifest(manifest, &available, &picked)?;
+    // Copy everything before touching the template so a failure leaves it usable
+    for recipe in &picked {
+        let from = root
+            .join("src/recipes")
+            .join(format!("{}.rs", recipe.module));
+        // The recipes end up next to main.rs instead of in the recipes module
+        let source = read(&from)?.replace("crate::recipes::", "crate::");
+        write(
+            &root.join("src").join(format!("{}.rs", recipe.module)),
+            &source,
+        )?;
+    }
+    write(&root.join("Cargo.toml"), &manifest)?;
+    write(&root.join("src/main.rs"), &main_rs(&picked))?;
+    let build_rs = root.join("build.rs");
+    match patch_build_rs(&read(&build_rs)?, &picked) {
+        Some(build_rs_content) => write(&build_rs, &build_rs_content)?,
+        None => {
+            fs::remove_file(&build_rs).map_err(|e| format!("Failed to remove build.rs: {e}"))?
+        }
+    }
+    fs::remove_dir_all(root.join("src/recipes"))
+        .map_err(|e| format!("Failed to remove src/recipes: {e}"))?;
+    for file in ["src/lib.rs", "src/scaffold.rs"] {
+        fs::remove_file(root.join(file)).map_err(|e| format!("Failed to remove {file}: {e}"))?;
+    }
+    // The integration tests and benchmarks use the recipes through the library which is gone now
+    for dir in ["tests", "benches"] {
+        let path = root.join(dir);
+        if path.exists() {
+            fs::remove_dir_all(&path).map_err(|e| format!("Failed to remove {dir}: {e}"))?;
+        }
+    }
+    Ok(picked.iter().map(|r| r.module.clone()).collect())
+}
+
+/// Parses the `#[cfg(feature = "..")] pub mod ..;` pairs
+fn recipes(mod_rs: &str) -> Vec<Recipe> {
+    let mut recipes = Vec::new();
+    let mut feature = None;
+    for line in mod_rs.lines().map(str::trim) {
+        if let Some(f) = line
+            .strip_prefix("#[cfg(feature = \"")
+            .and_then(|l| l.strip_suffix("\")]"))
+        {
+            feature = Some(f.to_owned());
+        } else if let Some(module) = line
+            .strip_prefix("pub mod ")
+            .and_then(|l| l.strip_suffix(';'))
+        {
+            if let Some(feature) = feature.take() {
+                recipes.push(Recipe {
+                    feature,
+                    module: module.to_owned(),
+                });
+            }
+        }
+    }
+    recipes
+}
+
+/// What a feature enables: `dep:<dependency>` or the features of other recipes
+fn feature_items<'a>(manifest: &'a DocumentMut, feature: &str) -> Result<Vec<&'a str>, String> {
+    let items = manifest
+        .get("features")
+        .and_then(|features| features.get(feature))
+        .and_then(Item::as_array)
+        .ok_or_else(|| format!("Feature {feature} is missing in Cargo.toml"))?;
+    Ok(items.iter().filter_map(|item| item.as_str()).collect())
+}
+
+/// Drops the recipe features and every dependency and build dependency not used by the picked
+/// recipes. The remaining dependencies are no longer optional, except for those of the features
+/// kept by `sub_features`.
+/// The benchmarks are removed along with `benches/`, and criterion which only they use.
+fn patch_manifest(
+    mut manifest: DocumentMut,
+    available: &[Recipe],
+    picked: &[&Recipe],
+) -> Result<String, String> {
+    let mut needed = Vec::new();
+    for recipe in picked {
+        let items = feature_items(&manifest, &recipe.feature)?;
+        needed.extend(
+            items
+                .into_iter()
+                .filter_map(|item| item.strip_prefix("dep:"))
+                .map(str::to_owned),
+        );
+    }
+    let sub_features = sub_features(&manifest, available, picked, &needed);
+    // `dep:<dependency>` and `<dependency>/<feature>` both need the dependency
+    let optional = sub_features
+        .iter()
+        .flat_map(|(_, items)| items)
+        .filter_map(|item| {
+            item.strip_prefix("dep:")
+                .or_else(|| Some(item.split_once('/')?.0.trim_end_matches('?')))
+        })
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if let Some(features) = manifest.get_mut("features").and_then(Item::as_table_mut) {
+        features.retain(|name, _| sub_features.iter().any(|(kept, _)| kept == name));
+        for (name, items) in &sub_features {
+            features[name] = toml_edit::value(items.iter().collect::<Array>());
+        }
+    }
+    if sub_features.is_empty() {
+        manifest.remove("features");
+    }
+    manifest.remove("bench");
+    if let Some(dev_dependencies) = manifest
+        .get_mut("dev-dependencies")
+        .and_then(Item::as_table_mut)
+    {
+        dev_dependencies.remove("criterion");
+    }
+
+    for section in ["dependencies", "build-dependencies"] {
+        let Some(dependencies) = manifest.get_mut(section).and_then(Item::as_table_mut) else {
+            continue;
+        };
+        dependencies.retain(|name, _| needed.iter().chain(&optional).any(|n| n == name));
+        for (name, spec) in dependencies.iter_mut() {
+            if needed.iter().any(|n| n == name.get()) {
+                make_required(spec);
+            }
+        }
+        if dependencies.is_empty() {
+            manifest.remove(section);
+        }
+    }
+    Ok(manifest.to_string())
+}
+
+/// Features switching on parts of a picked recipe, like `embed-assets = ["assets-recipe",
+/// "dep:rust-embed"]`, so the `#[cfg(feature = ..)]` in the recipe still works after scaffolding.
+/// The recipe features are dropped from them as the recipes are always compiled.
+fn sub_features(
+    manifest: &DocumentMut,
+    available: &[Recipe],
+    picked: &[&Recipe],
+    needed: &[String],
+) -> Vec<(String, Vec<String>)> {
+    let Some(features) = manifest.get("features").and_then(Item::as_table) else {
+        return Vec::new();
+    };
+    let is_picked = |feature: &str| picked.iter().any(|r| r.feature == feature);
+    let mut kept = Vec::new();
+    for (name, items) in features {
+        let Some(items) = items.as_array() else {
+            continue;
+        };
+        let items = items.iter().filter_map(|item| item.as_str());
+        let (enabled, items): (Vec<_>, Vec<_>) = items.partition(|i| features.contains_key(i));
+        let is_recipe = available.iter().any(|r| r.feature == name);
+        if is_recipe || enabled.is_empty() || !enabled.iter().all(|f| is_picked(f)) {
+            continue;
+        }
+        // `dep:` is only allowed for optional dependencies
+        let items = items
+            .into_iter()
+            .filter(|i| {
+                i.strip_prefix("dep:")
+                    .is_none_or(|d| !needed.iter().any(|n| n == d))
+            })
+            .map(str::to_owned)
+            .collect();
+        kept.push((name.to_owned(), items));
+    }
+    kept
+}
+
+/// Removes `optional = true`, what is left of `{ version = "1" }` reads nicer as `"1"`
+fn make_required(spec: &mut Item) {
+    let Some(table) = spec.as_inline_table_mut() else {
+        return;
+    };
+    table.remove("optional");
+    // The space before the closing brace belonged to the removed key
+    table.fmt();
+    let version = match table.get("version").and_then(|v| v.as_str()) {
+        Some(version) if table.len() == 1 => version.to_owned(),
+        _ => return,
+    };
+    *spec = toml_edit::value(version);
+}
+
+/// Keeps the code generation steps of the picked recipes, without any build.rs is not needed at all
+fn patch_build_rs(build_rs: &str, picked: &[&Recipe]) -> Option<String> {
+    let mut out = String::new();
+    let mut used = false;
+    let mut lines = build_rs.lines();
+    while let Some(line) = lines.next() {
+        let Some(feature) = line
+            .trim()
+            .strip_prefix("#[cfg(feature = \"")
+            .and_then(|l| l.strip_suffix("\")]"))
+        else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        if picked.iter().any(|r| r.feature == feature) {
+            used = true;
+        } else {
+            // The statement belonging to the attribute
+            lines.next();
+        }
+    }
+    used.then_some(out)
+}
+
+fn main_rs(picked: &[&Recipe]) -> String {
+    let (entry, others) = picked.split_first().expect("At least one recipe is picked");
+    let mut out = format!("mod {};\n", entry.module);
+    if !others.is_empty() {
+        out.push_str("// Not called yet, wire these into the main recipe\n");
+    }
+    for recipe in others {
+        out.push_str(&format!("#[allow(dead_code)]\nmod {};\n", recipe.module));
+    }
+    out.push_str(&format!(
+        "\nfn main() {{\n    {}::main();\n}}\n",
+        entry.module
+    ));
+    out
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOD_RS: &str = r#"
+#[cfg(feature = "axum-recipe")]
+pub mod axum_server;
+#[cfg(feature = "web-service")]
+pub mod web_service;
+#[cfg(feature = "assets-recipe")]
+pub mod assets;
+"#;
+
+    const MANIFEST: &str = r#"[package]
+name = "asdf"
+
+[features]
+# Comment
+axum-recipe = ["dep:axum", "dep:tokio"]
+web-service = ["dep:axum", "dep:clap", "axum-recipe"]
+assets-recipe = ["dep:axum"]
+embed-assets = ["assets-recipe", "dep:rust-embed"]
+
+[dependencies]
+axum = { version = "0.7", features = ["ws"], optional = true }
+clap = { version = "4.4", optional = true }
+rust-embed = { version = "8", optional = true }
+# Only for the template itself
+toml_edit = "0.25"
+tokio = { version = "1", optional = true }
+
+[dev-dependencies]
+criterion = "0.7"
+tokio = { version = "1", features = ["test-util"] }
+
+[build-dependencies]
+tonic-build = { version = "0.12", optional = true }
+
+# Benchmarks
+[[bench]]
+name = "decode"
+harness = false
+required-features = ["axum-recipe"]
+"#;
+
+    #[test]
+    fn parses_recipes() {
+        let recipes = recipes(MOD_RS);
+        assert_eq!(recipes.len(), 3);
+        assert!(recipes[0].matches("axum"));
+        assert!(recipes[0].matches("axum-recipe"));
+        assert!(recipes[0].matches("axum_server"));
+        assert!(recipes[1].matches("web-service"));
+        assert!(!recipes[1].matches("web"));
+    }
+
+    #[test]
+    fn keeps_only_needed_dependencies() {
+        let recipes = recipes(MOD_RS);
+        let manifest = patch_manifest(MANIFEST.parse().unwrap(), &recipes, &[&recipes[0]]).unwrap();
+        assert_eq!(
+            manifest,
+            r#"[package]
+name = "asdf"
+
+[dependencies]
+axum = { version = "0.7", features = ["ws"] }
+tokio = "1"
+
+[dev-dependencies]
+tokio = { version = "1", features = ["test-util"] }
+"#
+        );
+    }
+
+    #[test]
+    fn keeps_features_of_picked_recipes() {
+        let recipes = recipes(MOD_RS);
+        let manifest = patch_manifest(MANIFEST.parse().unwrap(), &recipes, &[&recipes[2]]).unwrap();
+        assert!(manifest.contains("[features]\nembed-assets = [\"dep:rust-embed\"]\n"));
+        assert!(manifest.contains("axum = { version = \"0.7\", features = [\"ws\"] }\n"));
+        assert!(manifest.contains("rust-embed = { version = \"8\", optional = true }\n"));
+
+        // Without the recipe its feature is gone too
+        let manifest = patch_manifest(MANIFEST.parse().unwrap(), &recipes, &[&recipes[0]]).unwrap();
+        assert!(!manifest.contains("embed-assets"));
+        assert!(!manifest.contains("rust-embed"));
+    }
+
+    #[test]
+    fn keeps_dependencies_whose_features_are_enabled() {
+        let console = Recipe {
+            feature: "console-recipe".to_owned(),
+            module: "console".to_owned(),
+        };
+        let manifest = r#"[features]
+console-recipe = ["dep:tracing"]
+tokio-console = ["console-recipe", "tokio?/tracing"]
+
+[dependencies]
+tokio = { version = "1", optional = true }
+tracing = { version = "0.1", optional = true }
+"#;
+        let manifest = patch_manifest(manifest.parse().unwrap(), &[], &[&console]).unwrap();
+        assert!(manifest.contains("tokio-console = [\"tokio?/tracing\"]"));
+        assert!(manifest.contains("tokio = { version = \"1\", optional = true }\n"));
+        assert!(manifest.contains("tracing = \"0.1\"\n"));
+    }
+
+    #[test]
+    fn keeps_build_dependencies_of_picked_recipes() {
+        let grpc = Recipe {
+            feature: "grpc-recipe".to_owned(),
+            module: "grpc".to_owned(),
+        };
+        let manifest = r#"[features]
+grpc-recipe = ["dep:tonic", "dep:tonic-build"]
+
+[dependencies]
+tonic = { version = "0.12", optional = true }
+
+[build-dependencies]
+tonic-build = { version = "0.12", optional = true }
+"#;
+        assert_eq!(
+            patch_manifest(manifest.parse().unwrap(), &[], &[&grpc]).unwrap(),
+            "\n[dependencies]\ntonic = \"0.12\"\n\n[build-dependencies]\ntonic-build = \"0.12\"\n"
+        );
+    }
+
+    #[test]
+    fn feature_items_include_recipe_features() {
+        let manifest = MANIFEST.parse().unwrap();
+        assert_eq!(
+            feature_items(&manifest, "web-service").unwrap(),
+            ["dep:axum", "dep:clap", "axum-recipe"]
+        );
+        assert!(feature_items(&manifest, "nope").is_err());
+    }
+
+    #[test]
+    fn build_rs_without_picked_recipes_is_removed() {
+        let recipes = recipes(MOD_RS);
+        let build_rs = "fn main() {\n    #[cfg(feature = \"web-service\")]\n    generate();\n}\n";
+        assert_eq!(patch_build_rs(build_rs, &[&recipes[0]]), None);
+        assert_eq!(
+            patch_build_rs(build_rs, &[&recipes[1]]).unwrap(),
+            "fn main() {\n    generate();\n}\n"
+        );
+    }
+
+    /// Scaffolds a copy of the template and lets cargo check the manifest
+    #[test]
+    fn scaffolded_project_is_a_valid_package() {
+        let template = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let root = std::env::temp_dir().join(format!("scaffold-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        for path in ["Cargo.toml", "build.rs", "src", "tests", "benches"] {
+            copy(&template.join(path), &root.join(path));
+        }
+
+        let modules = scaffold(&root, "axum,web-service,assets,console,panics").unwrap();
+        // The web service pulls in the recipes it builds on
+        assert_eq!(
+            modules,
+            [
+                "axum_server",
+                "web_service",
+                "assets",
+                "console",
+                "panics",
+                "metrics",
+                "shutdown"
+            ]
+        );
+        assert!(!root.join("benches").exists());
+        assert!(!root.join("tests").exists());
+        let manifest = read(&root.join("Cargo.toml")).unwrap();
+        // Cargo accepts a bench whose file is gone, so this is checked here
+        assert!(!manifest.contains("[[bench]]"));
+        assert!(!manifest.contains("criterion"));
+        assert!(!manifest.contains("toml_edit"));
+        assert!(manifest.contains("embed-assets = [\"dep:rust-embed\"]"));
+        assert!(
+            manifest.contains("tokio-console = [\"dep:console-subscriber\", \"tokio/tracing\"]")
+        );
+        // Named like its dependency, which stays optional
+        assert!(manifest.contains("sentry = [\"dep:sentry\"]"));
+        assert!(manifest.contains("\"rustls\"], optional = true }"));
+
+        let metadata = std::process::Command::new(env!("CARGO"))
+            .args([
+                "metadata",
+                "--format-version",
+                "1",
+                "--no-deps",
+                "--offline",
+            ])
+            .arg("--manifest-path")
+            .arg(root.join("Cargo.toml"))
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert!(
+            metadata.status.success(),
+            "{}",
+            String::from_utf8_lossy(&metadata.stderr)
+        );
+    }
+
+    fn copy(from: &Path, to: &Path) {
+        if from.is_dir() {
+            fs::create_dir_all(to).unwrap();
+            for entry in fs::read_dir(from).unwrap() {
+                let entry = entry.unwrap();
+                copy(&entry.path(), &to.join(entry.file_name()));
+            }
+        } else {
+            fs::copy(from, to).unwrap();
+        }
+    }
+
+    #[test]
+    fn main_calls_first_recipe() {
+        let recipes = recipes(MOD_RS);
+        let main_rs = main_rs(&[&recipes[1], &recipes[0]]);
+        assert!(main_rs.starts_with("mod web_service;\n"));
+        assert!(main_rs.contains("#[allow(dead_code)]\nmod axum_server;\n"));
+        assert!(main_rs.ends_with("fn main() {\n    web_service::main();\n}\n"));
+    }
+}
@@ -0,0 +1,84 @@
+//! Handlers are called through the router with `oneshot` so routing, extractors and responses
+//! are tested together without binding a socket.
+//! Run with `cargo test --features axum-recipe`
+#![cfg(feature = "axum-recipe")]
+
+use asdf::recipes::axum_server::app;
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+async fn body_string(res: Response) -> String {
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+fn post_json(uri: &str, body: Value) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn greets_by_name() {
+    let res = app()
+        .oneshot(Request::get("/hello/world").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_string(res).await, "Hello world");
+}
+
+#[tokio::test]
+async fn returns_json() {
+    let res = app()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = serde_json::from_str(&body_string(res).await).unwrap();
+    assert_eq!(body, json!({"foo": "foo", "bar": [98, 97, 114]}));
+}
+
+#[tokio::test]
+async fn decodes_json() {
+    let res = app()
+        .oneshot(post_json("/", json!({"foo": "foo", "bar": [98, 97, 114]})))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_string(res).await, "foo bar");
+}
+
+#[tokio::test]
+async fn rejects_invalid_chars() {
+    // 0xD800 is a surrogate and not a valid char
+    let res = app()
+        .oneshot(post_json("/", json!({"foo": "foo", "bar": [0xD800]})))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn rejects_malformed_json() {
+    let res = app()
+        .oneshot(post_json("/", json!({"foo": 1})))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn unknown_route_is_not_found() {
+    let res = app()
+        .oneshot(Request::get("/nope").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
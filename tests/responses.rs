@@ -0,0 +1,86 @@
+//! Snapshot tests with insta, the expected response bodies are stored in `tests/snapshots/`.
+//! A changed response fails the test and writes a `.snap.new` file next to the old snapshot,
+//! review it with `cargo insta review` (`cargo install cargo-insta`) and commit the accepted one.
+//! Run with `cargo test --features axum-recipe,error-recipe,ids-recipe`
+#![cfg(all(feature = "axum-recipe", feature = "error-recipe"))]
+
+use asdf::recipes::{axum_server::app, error::AppError};
+use axum::{
+    body::{to_bytes, Body},
+    http::Request,
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+/// Status and body together, a changed status shows up in the same diff as a changed body
+async fn snapshot(res: Response) -> Value {
+    let status = res.status().as_u16();
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    json!({
+        "status": status,
+        "body": serde_json::from_slice::<Value>(&bytes).unwrap(),
+    })
+}
+
+#[tokio::test]
+async fn return_json() {
+    let res = app()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    insta::assert_json_snapshot!(snapshot(res).await);
+}
+
+#[tokio::test]
+async fn error_bodies() {
+    let errors = [
+        AppError::NotFound("User"),
+        AppError::BadRequest("Expected a json object".to_owned()),
+        AppError::Unauthorized,
+        AppError::Conflict("Name is taken".to_owned()),
+        AppError::Json(serde_json::from_str::<Value>("{").unwrap_err()),
+        // Internal errors must not leak their details
+        AppError::Database(sqlx::Error::PoolTimedOut),
+    ];
+    let mut bodies = Vec::new();
+    for error in errors {
+        bodies.push(snapshot(error.into_response()).await);
+    }
+    insta::assert_json_snapshot!(bodies);
+}
+
+/// The ids are generated on every run, ULIDs even contain the time they were created at
+#[cfg(feature = "ids-recipe")]
+#[tokio::test]
+async fn redacts_values_that_change_between_runs() {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use asdf::recipes::ids::{self, Order, OrderId, UserId};
+
+    let user = UserId::new();
+    let orders = (1..=2)
+        .map(|amount| Order {
+            id: OrderId::new(),
+            user_id: user,
+            amount,
+        })
+        .collect();
+    let orders = Arc::new(Mutex::new(HashMap::from([(user, orders)])));
+    let res = ids::app(orders)
+        .oneshot(
+            Request::get(format!("/users/{user}/orders"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    // Selectors pick the fields to replace before comparing, the snapshot stays the same every run
+    insta::assert_json_snapshot!(snapshot(res).await, {
+        ".body[].id" => "[ulid]",
+        ".body[].user_id" => "[uuid]",
+    });
+}
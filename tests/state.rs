@@ -0,0 +1,51 @@
+//! The state is built with a config, pool and metrics handle made up for the test,
+//! nothing global is touched so tests with different configs can run side by side.
+//! Run with `cargo test --features state-recipe`
+#![cfg(feature = "state-recipe")]
+
+use std::time::Duration;
+
+use asdf::recipes::{
+    config::Config,
+    state::{app, AppState},
+};
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use sqlx::PgPool;
+use tower::ServiceExt;
+
+async fn state(bind_addr: &str) -> AppState {
+    let config = Config {
+        bind_addr: bind_addr.parse().unwrap(),
+        log_level: "info".to_owned(),
+        log_format: "pretty".to_owned(),
+        database_url: "postgres://localhost/unused".to_owned(),
+        request_timeout: Duration::from_secs(1),
+        smtp_url: None,
+        mail_from: "noreply@example.com".to_owned(),
+    };
+    // Lazy pools only connect on the first query, these tests don't run any
+    let db = PgPool::connect_lazy(&config.database_url).unwrap();
+    AppState::builder(config)
+        .db(db)
+        .metrics(PrometheusBuilder::new().build_recorder().handle())
+        .build()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn handlers_see_the_config_of_their_state() {
+    for addr in ["127.0.0.1:1000", "127.0.0.1:2000"] {
+        let res = app(state(addr).await)
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, format!("Hello from {addr}"));
+    }
+}